@@ -0,0 +1,65 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Measures the emulation's throughput on small synthetic roms.
+//!
+//! Run with `cargo bench`. The benchmark doesn't depend on a benchmarking
+//! framework, so it simply reports the time it took to emulate each workload.
+
+use std::time::{Duration, Instant};
+
+use gameboy_core::GameboyError;
+use gameboy_core::emulator::Emulator;
+
+/// The number of frames emulated by each run of a workload.
+const FRAMES: usize = 300;
+/// The number of runs, of which the fastest one is reported.
+const RUNS: usize = 5;
+
+/// Builds an emulator that runs the given code from the rom's entry point,
+/// with the display and the timer enabled.
+fn emulator(code: &[u8], headless: bool) -> Result<Emulator, GameboyError> {
+	// ld A, 0x91; ldh (LCDC), A; ld A, 0x05; ldh (TAC), A
+	let setup = [0x3e, 0x91, 0xe0, 0x40, 0x3e, 0x05, 0xe0, 0x07];
+
+	let mut rom = vec![0_u8; 0x8000];
+	rom[0x100..0x100 + setup.len()].copy_from_slice(&setup);
+	rom[0x100 + setup.len()..0x100 + setup.len() + code.len()].copy_from_slice(code);
+
+	Emulator::builder().rom(rom).headless(headless).build()
+}
+
+fn run(name: &str, code: &[u8], headless: bool) -> Result<Duration, GameboyError> {
+	let mut best = Duration::MAX;
+
+	for _ in 0..RUNS {
+		let mut emulator = emulator(code, headless)?;
+		let start = Instant::now();
+
+		for _ in 0..FRAMES {
+			emulator.run_frame()?;
+		}
+
+		best = best.min(start.elapsed());
+	}
+
+	println!("{:<12} {} frames in {:?}", name, FRAMES, best);
+
+	Ok(best)
+}
+
+fn main() -> Result<(), GameboyError> {
+	// inc B; jr -3
+	run("registers", &[0x04, 0x18, 0xfd], false)?;
+
+	// ld HL, 0xC000; ld DE, 0xD000; ld A, (HL); ld (DE), A; inc L; inc E; jr -6
+	run("memory", &[0x21, 0x00, 0xc0, 0x11, 0x00, 0xd0, 0x7e, 0x12, 0x2c, 0x1c, 0x18, 0xfa], false)?;
+
+	// ldh A, (LY); ldh A, (DIV); jr -6
+	run("io", &[0xf0, 0x44, 0xf0, 0x04, 0x18, 0xfa], false)?;
+
+	// The same as the first workload, without rendering the display.
+	run("headless", &[0x04, 0x18, 0xfd], true)?;
+
+	Ok(())
+}
@@ -7,23 +7,26 @@
 extern crate minifb;
 
 use std::fs;
+use std::io;
 use std::env;
 use std::fmt;
 use std::vec::Vec;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
-use gameboy_core::cpu::*;
 use gameboy_core::bus::joypad;
 use gameboy_core::GameboyError;
-use gameboy_core::config::Config;
-use gameboy_core::bus::cartridge::*;
+use gameboy_core::emulator::Emulator;
+use gameboy_core::pacing::FrameClock;
 
 const WIDTH: usize = 160;
 const HEIGHT: usize = 144;
 
+/// The emulation's speed while Tab is held.
+const TURBO_SPEED: u32 = 4;
+
 enum EmulatorError {
     Std(std::io::Error),
     Gameboy(GameboyError),
@@ -50,6 +53,115 @@ impl fmt::Debug for EmulatorError {
 	}
 }
 
+/// Plays the emulated audio through the default output device.
+///
+/// The emulation is paced by the display, so the audio device's clock drifts
+/// from it over time. The played samples are queued, and the queue is kept
+/// short by dropping its oldest samples, so the audio doesn't lag behind the
+/// video. When the queue runs out, silence is played until the next frame.
+#[cfg(feature = "audio")]
+mod audio {
+	use std::collections::VecDeque;
+	use std::sync::{Arc, Mutex};
+
+	use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+	use cpal::{Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+
+	use gameboy_core::emulator::Emulator;
+
+	/// The maximal time the queued samples may take to play, in seconds.
+	const MAX_LATENCY: f32 = 0.1;
+
+	/// Interleaved left and right samples that wait to be played.
+	type Queue = Arc<Mutex<VecDeque<i16>>>;
+
+	pub struct Audio {
+		queue: Queue,
+		/// Receives the samples that are drained from the emulator.
+		buffer: Vec<i16>,
+		sample_rate: u32,
+		/// The audio is played as long as the stream is alive.
+		_stream: Stream,
+	}
+
+	impl Audio {
+		/// Starts playing through the default output device.
+		pub fn new() -> Result<Self, String> {
+			let device = cpal::default_host().default_output_device().ok_or("No audio output device.")?;
+			let supported = device.default_output_config().map_err(|e| e.to_string())?;
+			let config = supported.config();
+			let queue = Queue::default();
+
+			let stream = match supported.sample_format() {
+				SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+				SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+				SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+				format => Err(format!("Unsupported sample format: {}", format)),
+			}?;
+			stream.play().map_err(|e| e.to_string())?;
+
+			Ok(Audio {
+				queue,
+				buffer: vec![0; 4096],
+				sample_rate: config.sample_rate.0,
+				_stream: stream,
+			})
+		}
+
+		/// The rate the emulator should generate samples at.
+		pub fn sample_rate(&self) -> u32 {
+			self.sample_rate
+		}
+
+		/// Moves the emulated samples to the playback queue.
+		pub fn queue_samples(&mut self, emulator: &mut Emulator) {
+			let mut queue = self.queue.lock().unwrap();
+
+			loop {
+				let count = emulator.drain_samples(&mut self.buffer);
+				if count == 0 {
+					break;
+				}
+				queue.extend(&self.buffer[..count]);
+			}
+
+			// Keep the audio in sync with the video.
+			let max_samples = 2 * (self.sample_rate as f32 * MAX_LATENCY) as usize;
+			if queue.len() > max_samples {
+				let excess = queue.len() - max_samples;
+				queue.drain(..excess);
+			}
+		}
+	}
+
+	fn build_stream<T>(device: &Device, config: &StreamConfig, queue: Queue) -> Result<Stream, String>
+		where T: SizedSample + FromSample<i16> {
+		let channels = config.channels as usize;
+
+		let play = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+			let mut queue = queue.lock().unwrap();
+
+			for frame in data.chunks_mut(channels) {
+				let left = queue.pop_front().unwrap_or(0);
+				let right = queue.pop_front().unwrap_or(0);
+
+				for (channel, sample) in frame.iter_mut().enumerate() {
+					let value = match (channels, channel) {
+						(1, _) => ((left as i32 + right as i32) / 2) as i16,
+						(_, 0) => left,
+						(_, 1) => right,
+						_ => 0,
+					};
+					*sample = T::from_sample(value);
+				}
+			}
+		};
+
+		device.build_output_stream(config, play, |err| eprintln!("Audio error: {}", err), None)
+			.map_err(|e| e.to_string())
+	}
+}
+
 // Maps minifb keys to emulator keys.
 fn map_input_key(key: &Key) -> joypad::Key {
 	match key {
@@ -65,19 +177,39 @@ fn map_input_key(key: &Key) -> joypad::Key {
 	}
 }
 
-fn update_key_state(cpu: &mut Cpu, window: &Window) {
+fn update_key_state(emulator: &mut Emulator, window: &Window) {
 	for key in [Key::Right, Key::Left, Key::Down, Key::Up, Key::Z, Key::X, Key::Space, Key::Enter].iter() {
 		let emulator_key = map_input_key(key);
 		let key_down: bool = window.is_key_down(*key);
 
 		if key_down {
-			cpu.with_controller(|joypad| joypad.down(emulator_key))
+			emulator.with_controller(|joypad| joypad.down(emulator_key))
 		} else {
-			cpu.with_controller(|joypad| joypad.up(emulator_key))
+			emulator.with_controller(|joypad| joypad.up(emulator_key))
 		}
 	}
 }
 
+/// Saves the frame's pixels as a PNG image.
+fn save_screenshot(path: &str, pixels: &[u32]) -> Result<(), EmulatorError> {
+	let mut encoder = png::Encoder::new(io::BufWriter::new(fs::File::create(path)?), WIDTH as u32, HEIGHT as u32);
+	encoder.set_color(png::ColorType::Rgb);
+	encoder.set_depth(png::BitDepth::Eight);
+
+	let data: Vec<u8> = pixels.iter()
+		.flat_map(|pixel| {
+			let [_, r, g, b] = pixel.to_be_bytes();
+			[r, g, b]
+		})
+		.collect();
+
+	encoder.write_header()
+		.and_then(|mut writer| writer.write_image_data(&data))
+		.map_err(io::Error::other)?;
+
+	Ok(())
+}
+
 fn main() -> Result<(), EmulatorError> {
 	// Initialize the frame buffer
 	let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
@@ -89,43 +221,84 @@ fn main() -> Result<(), EmulatorError> {
         WindowOptions::default(),
     ).unwrap_or_else(|e| { panic!("{}", e); });
 
-	// Initialize the cpu.
-	let config = Config::default();
-
-	// Load the cartridge.
+	// Load the cartridge and initialize the emulator.
 	let args: Vec<String> = env::args().collect();
 	let rom_fname = &args[1];
-	let mut rom: Box<[u8]> = fs::read(rom_fname)?.into();
-	let mut ram: Box<[u8]> = Cartridge::make_ram(&rom)?;
-	let mut cartridge = Cartridge::new(&mut rom, &mut ram)?;
+	let builder = Emulator::builder().rom(fs::read(rom_fname)?);
 
-	let mut cpu = Cpu::new(&config, &mut cartridge);
+	// Generate the audio at the output device's rate, or run muted without one.
+	#[cfg(feature = "audio")]
+	let mut audio = match audio::Audio::new() {
+		Ok(audio) => Some(audio),
+		Err(e) => {
+			println!("Warning: running muted, the audio output failed: {}", e);
+			None
+		}
+	};
+	#[cfg(feature = "audio")]
+	let builder = match &audio {
+		Some(audio) => builder.sample_rate(audio.sample_rate()),
+		None => builder,
+	};
+
+	let mut emulator = builder.build()?;
+	#[cfg(feature = "audio")]
+	emulator.set_audio_muted(audio.is_none());
 
-	// Start executing.
-	let mut cycles: usize = 0;
+	// Start executing, paced to the display's refresh rate.
+	let mut clock = FrameClock::new(emulator.cpu().config.model);
 	let mut total: usize = 0;
+	let mut paused = false;
 
 	while window.is_open() && !window.is_key_down(Key::Escape) {
-		match cpu.execute() {
-			Ok(elapsed) => { cycles += elapsed; total += elapsed; }
-			Err(err) => { 
-				println!("Total cycles: {:?}", total);
-				return Err(err.into());
+		// P toggles the pause, and Tab fast-forwards while it's held.
+		if window.is_key_pressed(Key::P, KeyRepeat::No) {
+			paused = !paused;
+			if paused {
+				emulator.pause()?;
 			}
+			window.set_title(if paused { "Gameboy (paused)" } else { "Gameboy" });
 		}
+		clock.set_speed(if window.is_key_down(Key::Tab) { TURBO_SPEED } else { 1 });
 
-		// Update the frame buffer every now and then..
-		// TODO change this to an actual precise time-based approach!
-		if cycles > 100000 {
-			cpu.flush(&mut buffer);
-			window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
-			
-			update_key_state(&mut cpu, &window);
+		// F12 saves the displayed frame.
+		if window.is_key_pressed(Key::F12, KeyRepeat::No) {
+			let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis());
+			let path = format!("screenshot-{}.png", millis);
+			save_screenshot(&path, &buffer)?;
+			println!("Saved {}", path);
+		}
 
-			cycles -= 100000;
-			sleep(Duration::from_millis(8));
+		update_key_state(&mut emulator, &window);
 
+		// The time that passes while paused is skipped.
+		let frames = clock.tick();
+		if paused {
+			window.update();
+			sleep(clock.until_next_frame());
+			continue;
 		}
+
+		for _ in 0..frames {
+			match emulator.run_frame() {
+				Ok(frame) => {
+					buffer.copy_from_slice(&frame.pixels);
+					total += frame.cycles;
+
+					#[cfg(feature = "audio")]
+					if let Some(audio) = audio.as_mut() {
+						audio.queue_samples(&mut emulator);
+					}
+				}
+				Err(err) => {
+					println!("Total cycles: {:?}", total);
+					return Err(err.into());
+				}
+			}
+		}
+
+		window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
+		sleep(clock.until_next_frame());
 	}
 
 	Ok(())
@@ -0,0 +1,82 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Prints the cartridge header of a rom, to help triage roms that don't boot.
+//!
+//! Usage: `cargo run --example rominfo -- <rom>`
+
+use std::env;
+use std::fs;
+use std::process;
+
+use gameboy_core::GameboyError;
+use gameboy_core::bus::cartridge::Cartridge;
+use gameboy_core::bus::cartridge::consts::*;
+
+/// Formats a validation's result.
+fn validity(valid: bool) -> &'static str {
+	if valid { "valid" } else { "INVALID" }
+}
+
+/// Formats a size as written in the header, next to the header's raw value.
+fn header_size(size: Result<usize, GameboyError>, value: u8) -> String {
+	match size {
+		Ok(size) => format!("{}KB (0x{:02x})", size / 1024, value),
+		Err(_) => format!("unknown (0x{:02x})", value),
+	}
+}
+
+fn print_info(rom: Vec<u8>) -> Result<(), GameboyError> {
+	let title = Cartridge::header_title(&rom)?;
+	let title: String = title.iter()
+		.take_while(|&&c| c != 0)
+		.map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '?' })
+		.collect();
+
+	println!("Title:           {}", title);
+	println!("Cartridge type:  {} (0x{:02x})",
+		Cartridge::type_name(&rom)?.unwrap_or("unknown"), rom[ROM_CARTRIDGE_TYPE]);
+	println!("GBC support:     {}", match rom[ROM_GAMEBOY_COLOR] {
+		0xC0 => "required",
+		0x80 => "supported",
+		_ => "no",
+	});
+	println!("SGB support:     {}", if rom[ROM_GAMEBOY_SUPER] == 0x03 { "yes" } else { "no" });
+	println!("ROM size:        {}, the file is {}KB",
+		header_size(Cartridge::rom_size(&rom), rom[ROM_SIZE]), rom.len() / 1024);
+	println!("RAM size:        {}", header_size(Cartridge::ram_size(&rom), rom[RAM_SIZE]));
+	println!("Header checksum: {}", validity(Cartridge::header_checksum_valid(&rom)?));
+	println!("Global checksum: {}", validity(Cartridge::global_checksum_valid(&rom)?));
+
+	// Loading the cartridge validates the rest of the header.
+	match Cartridge::from_rom(rom.into_boxed_slice()) {
+		Ok(_) => println!("Supported:       yes"),
+		Err(err) => println!("Supported:       no, {}", err),
+	}
+
+	Ok(())
+}
+
+fn main() {
+	let path = match env::args().nth(1) {
+		Some(path) => path,
+		None => {
+			eprintln!("Usage: rominfo <rom>");
+			process::exit(2);
+		}
+	};
+
+	let rom = match fs::read(&path) {
+		Ok(rom) => rom,
+		Err(err) => {
+			eprintln!("Failed to read {}: {}", path, err);
+			process::exit(1);
+		}
+	};
+
+	if let Err(err) = print_info(rom) {
+		eprintln!("{}: {}", path, err);
+		process::exit(1);
+	}
+}
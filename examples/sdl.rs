@@ -0,0 +1,197 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! An example emulator frontend using SDL2.
+//!
+//! The emulation is paced by a `FrameClock`, so it runs at the display's
+//! refresh rate regardless of the host's. The window can be resized freely,
+//! and the display is scaled to fit it, keeping its aspect ratio.
+//!
+//! Usage: `cargo run --release --features="sdl" --example sdl <rom-path> [scale]`
+
+extern crate sdl2;
+
+use std::fs;
+use std::env;
+use std::fmt;
+use std::vec::Vec;
+use std::thread::sleep;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Scancode;
+use sdl2::controller::Button;
+use sdl2::pixels::PixelFormatEnum;
+
+use gameboy_core::bus::joypad::Key;
+use gameboy_core::GameboyError;
+use gameboy_core::emulator::Emulator;
+use gameboy_core::pacing::FrameClock;
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+
+/// The window's initial scale, if it's not given.
+const DEFAULT_SCALE: u32 = 3;
+
+enum EmulatorError {
+	Std(std::io::Error),
+	Gameboy(GameboyError),
+	Sdl(String),
+}
+
+impl From<std::io::Error> for EmulatorError {
+	fn from(e: std::io::Error) -> Self {
+		EmulatorError::Std(e)
+	}
+}
+
+impl From<GameboyError> for EmulatorError {
+	fn from(e: GameboyError) -> Self {
+		EmulatorError::Gameboy(e)
+	}
+}
+
+impl From<String> for EmulatorError {
+	fn from(e: String) -> Self {
+		EmulatorError::Sdl(e)
+	}
+}
+
+impl fmt::Debug for EmulatorError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			EmulatorError::Std(ref err) => err.fmt(f),
+			EmulatorError::Gameboy(ref err) => err.fmt(f),
+			EmulatorError::Sdl(ref err) => f.write_str(err),
+		}
+	}
+}
+
+// Maps keyboard keys to emulator keys.
+fn map_scancode(scancode: Scancode) -> Option<Key> {
+	match scancode {
+		Scancode::Right => Some(Key::Right),
+		Scancode::Left => Some(Key::Left),
+		Scancode::Down => Some(Key::Down),
+		Scancode::Up => Some(Key::Up),
+		Scancode::Z => Some(Key::A),
+		Scancode::X => Some(Key::B),
+		Scancode::Space => Some(Key::Select),
+		Scancode::Return => Some(Key::Start),
+		_ => None,
+	}
+}
+
+// Maps game controller buttons to emulator keys.
+fn map_button(button: Button) -> Option<Key> {
+	match button {
+		Button::DPadRight => Some(Key::Right),
+		Button::DPadLeft => Some(Key::Left),
+		Button::DPadDown => Some(Key::Down),
+		Button::DPadUp => Some(Key::Up),
+		Button::A => Some(Key::A),
+		Button::B => Some(Key::B),
+		Button::Back => Some(Key::Select),
+		Button::Start => Some(Key::Start),
+		_ => None,
+	}
+}
+
+fn main() -> Result<(), EmulatorError> {
+	// Load the cartridge and initialize the emulator.
+	let args: Vec<String> = env::args().collect();
+	let rom_fname = &args[1];
+	let scale = args.get(2).and_then(|scale| scale.parse().ok()).unwrap_or(DEFAULT_SCALE);
+	let mut emulator = Emulator::builder()
+		.rom(fs::read(rom_fname)?)
+		.build()?;
+
+	let sdl = sdl2::init()?;
+	let video = sdl.video()?;
+	let game_controller = sdl.game_controller()?;
+
+	let window = video.window("Gameboy", WIDTH as u32 * scale, HEIGHT as u32 * scale)
+		.position_centered()
+		.resizable()
+		.build()
+		.map_err(|e| e.to_string())?;
+
+	// Scale the display to fit the window, keeping its aspect ratio.
+	let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+	canvas.set_logical_size(WIDTH as u32, HEIGHT as u32).map_err(|e| e.to_string())?;
+
+	let texture_creator = canvas.texture_creator();
+	let mut texture = texture_creator
+		.create_texture_streaming(PixelFormatEnum::ARGB8888, WIDTH as u32, HEIGHT as u32)
+		.map_err(|e| e.to_string())?;
+
+	// The controllers are closed when they're dropped.
+	let mut controllers = Vec::new();
+	let mut events = sdl.event_pump()?;
+
+	// Start executing, paced to the display's refresh rate.
+	let mut clock = FrameClock::new(emulator.cpu().config.model);
+
+	'running: loop {
+		for event in events.poll_iter() {
+			match event {
+				Event::Quit { .. } | Event::KeyDown { scancode: Some(Scancode::Escape), .. } => {
+					break 'running;
+				}
+				Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => {
+					if let Some(key) = map_scancode(scancode) {
+						emulator.with_controller(|joypad| joypad.down(key));
+					}
+				}
+				Event::KeyUp { scancode: Some(scancode), .. } => {
+					if let Some(key) = map_scancode(scancode) {
+						emulator.with_controller(|joypad| joypad.up(key));
+					}
+				}
+				Event::ControllerDeviceAdded { which, .. } => {
+					controllers.push(game_controller.open(which).map_err(|e| e.to_string())?);
+				}
+				Event::ControllerButtonDown { button, .. } => {
+					if let Some(key) = map_button(button) {
+						emulator.with_controller(|joypad| joypad.down(key));
+					}
+				}
+				Event::ControllerButtonUp { button, .. } => {
+					if let Some(key) = map_button(button) {
+						emulator.with_controller(|joypad| joypad.up(key));
+					}
+				}
+				_ => {}
+			}
+		}
+
+		// Run the frames that are due, and upload the last one if any of them changed.
+		let mut last_frame = None;
+		let mut dirty = false;
+		for _ in 0..clock.tick() {
+			let frame = emulator.run_frame()?;
+			dirty |= frame.dirty;
+			last_frame = Some(frame);
+		}
+
+		if let Some(frame) = last_frame.filter(|_| dirty) {
+			texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+				for (y, line) in frame.pixels.chunks(WIDTH).enumerate() {
+					for (x, pixel) in line.iter().enumerate() {
+						let offset = y * pitch + x * 4;
+						buffer[offset..offset + 4].copy_from_slice(&pixel.to_ne_bytes());
+					}
+				}
+			})?;
+		}
+
+		canvas.clear();
+		canvas.copy(&texture, None, None)?;
+		canvas.present();
+
+		sleep(clock.until_next_frame());
+	}
+
+	Ok(())
+}
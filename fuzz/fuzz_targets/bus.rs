@@ -0,0 +1,39 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads and writes random addresses through the system bus.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use gameboy_core::emulator::Emulator;
+
+fuzz_target!(|data: &[u8]| {
+	if data.len() < 2 {
+		return;
+	}
+
+	// The input starts with the cartridge's type and ram size, so that the
+	// bank controllers are covered.
+	let mut rom = vec![0_u8; 0x8000];
+	rom[0x147] = data[0];
+	rom[0x149] = data[1];
+
+	let mut emulator = match Emulator::builder().rom(rom).build() {
+		Ok(emulator) => emulator,
+		Err(_) => { return; }
+	};
+
+	// Each access is made of a kind, a little-endian address and a value.
+	for access in data[2..].chunks_exact(4) {
+		let address = u16::from_le_bytes([access[1], access[2]]);
+
+		// Errors are fine, only panics are reported.
+		match access[0] % 3 {
+			0 => { let _ = emulator.cpu_mut().mmap.read(address); }
+			1 => { let _ = emulator.cpu_mut().mmap.write(address, access[3]); }
+			_ => { let _ = emulator.execute(); }
+		}
+	}
+});
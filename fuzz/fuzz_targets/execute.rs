@@ -0,0 +1,50 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Executes random instruction streams.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use gameboy_core::config::IllegalOpcodePolicy;
+use gameboy_core::emulator::Emulator;
+
+/// The number of instructions executed for each input.
+const MAX_INSTRUCTIONS: usize = 10000;
+
+/// The address the input is placed at, right after the cartridge's header.
+const CODE_START: usize = 0x150;
+
+fuzz_target!(|data: &[u8]| {
+	if data.len() < 2 {
+		return;
+	}
+
+	// The input starts with the cartridge's type and ram size, so that the
+	// bank controllers are covered. The entry point jumps to the rest of it.
+	let mut rom = vec![0_u8; 0x8000];
+	rom[0x100..0x103].copy_from_slice(&[0xc3, CODE_START as u8, (CODE_START >> 8) as u8]);
+	rom[0x147] = data[0];
+	rom[0x149] = data[1];
+
+	let code = &data[2..];
+	let len = code.len().min(rom.len() - CODE_START);
+	rom[CODE_START..CODE_START + len].copy_from_slice(&code[..len]);
+
+	// Skip the illegal opcodes, so that the execution isn't cut short.
+	let mut emulator = match Emulator::builder()
+		.rom(rom)
+		.illegal_opcode(IllegalOpcodePolicy::Nop)
+		.build() {
+		Ok(emulator) => emulator,
+		Err(_) => { return; }
+	};
+
+	// Errors are fine, only panics are reported.
+	for _ in 0..MAX_INSTRUCTIONS {
+		if emulator.execute().is_err() {
+			break;
+		}
+	}
+});
@@ -0,0 +1,452 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! A libretro core, which lets libretro frontends such as RetroArch run the
+//! emulator.
+//!
+//! The frontend calls `retro_run` once per frame, which polls the joypad,
+//! emulates a frame and hands its video and audio to the frontend's
+//! callbacks. The core's state is kept per thread, as libretro calls the
+//! core from a single thread.
+//!
+//! To build the core, run:
+//! ```bash
+//! cargo build --release -p gameboy-core-libretro
+//! ```
+
+mod ffi;
+
+use std::cell::RefCell;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+
+use gameboy_core::bus::joypad::Key;
+use gameboy_core::bus::ppu::consts::{WIDTH, HEIGHT};
+use gameboy_core::cpu::timing::FRAME_RATE;
+use gameboy_core::emulator::Emulator;
+
+use ffi::*;
+
+/// The rate the audio is generated at.
+const SAMPLE_RATE: u32 = 48000;
+
+/// The joypad's buttons, and the keys they're mapped to.
+const BUTTONS: [(c_uint, Key); 8] = [
+	(RETRO_DEVICE_ID_JOYPAD_RIGHT, Key::Right),
+	(RETRO_DEVICE_ID_JOYPAD_LEFT, Key::Left),
+	(RETRO_DEVICE_ID_JOYPAD_UP, Key::Up),
+	(RETRO_DEVICE_ID_JOYPAD_DOWN, Key::Down),
+	(RETRO_DEVICE_ID_JOYPAD_A, Key::A),
+	(RETRO_DEVICE_ID_JOYPAD_B, Key::B),
+	(RETRO_DEVICE_ID_JOYPAD_SELECT, Key::Select),
+	(RETRO_DEVICE_ID_JOYPAD_START, Key::Start),
+];
+
+/// The frontend's callbacks, as registered by the `retro_set_*` functions.
+#[derive(Clone, Copy)]
+struct Callbacks {
+	environment: Option<retro_environment_t>,
+	video_refresh: Option<retro_video_refresh_t>,
+	audio_sample_batch: Option<retro_audio_sample_batch_t>,
+	input_poll: Option<retro_input_poll_t>,
+	input_state: Option<retro_input_state_t>,
+}
+
+impl Callbacks {
+	/// Initialize the callbacks before the frontend registers them.
+	const fn new() -> Self {
+		Callbacks {
+			environment: None,
+			video_refresh: None,
+			audio_sample_batch: None,
+			input_poll: None,
+			input_state: None,
+		}
+	}
+}
+
+/// The loaded game.
+struct Core {
+	emulator: Emulator,
+	/// The audio that is drained from the emulator on every frame.
+	samples: Vec<i16>,
+}
+
+thread_local! {
+	static CALLBACKS: RefCell<Callbacks> = const { RefCell::new(Callbacks::new()) };
+	static CORE: RefCell<Option<Core>> = const { RefCell::new(None) };
+}
+
+impl Core {
+	/// Emulates a single frame, and passes its output to the frontend.
+	fn run(&mut self, callbacks: &Callbacks) {
+		if let (Some(input_poll), Some(input_state)) = (callbacks.input_poll, callbacks.input_state) {
+			unsafe { input_poll() };
+
+			self.emulator.with_controller(|controller| {
+				for &(id, key) in BUTTONS.iter() {
+					if unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, id) } != 0 {
+						controller.down(key);
+					} else {
+						controller.up(key);
+					}
+				}
+			});
+		}
+
+		// libretro has no way to report errors, so the last frame is shown again.
+		let frame = self.emulator.run_frame().ok();
+
+		if let Some(video_refresh) = callbacks.video_refresh {
+			let (data, pitch) = match &frame {
+				Some(frame) => (frame.pixels.as_ptr() as *const c_void, WIDTH * 4),
+				None => (ptr::null(), 0),
+			};
+
+			unsafe { video_refresh(data, WIDTH as c_uint, HEIGHT as c_uint, pitch) };
+		}
+
+		loop {
+			let count = self.emulator.drain_samples(&mut self.samples);
+			if count == 0 {
+				break;
+			}
+
+			if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+				unsafe { audio_sample_batch(self.samples.as_ptr(), count / 2) };
+			}
+		}
+	}
+}
+
+/// Runs the closure on the loaded game, if any.
+fn with_core<T, F>(closure: F) -> Option<T>
+	where F: FnOnce(&mut Core) -> T {
+	CORE.with(|core| core.borrow_mut().as_mut().map(closure))
+}
+
+/// Registers a frontend's callback.
+fn set_callback<F>(closure: F)
+	where F: FnOnce(&mut Callbacks) {
+	CALLBACKS.with(|callbacks| closure(&mut callbacks.borrow_mut()));
+}
+
+/// Returns the version of the libretro API the core implements.
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+	RETRO_API_VERSION
+}
+
+/// Initializes the core.
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+/// Releases the core's resources.
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+	retro_unload_game();
+}
+
+/// Describes the core.
+///
+/// # Safety
+/// `info` must point to a writable `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut retro_system_info) {
+	*info = retro_system_info {
+		library_name: b"gameboy-core\0".as_ptr() as *const c_char,
+		library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+		valid_extensions: b"gb|gbc\0".as_ptr() as *const c_char,
+		need_fullpath: false,
+		block_extract: false,
+	};
+}
+
+/// Describes the display's geometry and the emulation's timing.
+///
+/// # Safety
+/// `info` must point to a writable `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut retro_system_av_info) {
+	*info = retro_system_av_info {
+		geometry: retro_game_geometry {
+			base_width: WIDTH as c_uint,
+			base_height: HEIGHT as c_uint,
+			max_width: WIDTH as c_uint,
+			max_height: HEIGHT as c_uint,
+			aspect_ratio: WIDTH as f32 / HEIGHT as f32,
+		},
+		timing: retro_system_timing {
+			fps: FRAME_RATE,
+			sample_rate: SAMPLE_RATE as f64,
+		},
+	};
+}
+
+/// Registers the frontend's environment callback.
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: retro_environment_t) {
+	set_callback(|callbacks| callbacks.environment = Some(callback));
+}
+
+/// Registers the frontend's video callback.
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: retro_video_refresh_t) {
+	set_callback(|callbacks| callbacks.video_refresh = Some(callback));
+}
+
+/// Registers the frontend's single-sample audio callback, which is unused
+/// since the audio is passed in batches.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: retro_audio_sample_t) {}
+
+/// Registers the frontend's audio callback.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: retro_audio_sample_batch_t) {
+	set_callback(|callbacks| callbacks.audio_sample_batch = Some(callback));
+}
+
+/// Registers the frontend's input polling callback.
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: retro_input_poll_t) {
+	set_callback(|callbacks| callbacks.input_poll = Some(callback));
+}
+
+/// Registers the frontend's input state callback.
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: retro_input_state_t) {
+	set_callback(|callbacks| callbacks.input_state = Some(callback));
+}
+
+/// Selects the device that is plugged into a port. Only the joypad is supported.
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+/// Resets the machine.
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+	with_core(|core| core.emulator.reset());
+}
+
+/// Emulates a single frame.
+#[no_mangle]
+pub extern "C" fn retro_run() {
+	let callbacks = CALLBACKS.with(|callbacks| *callbacks.borrow());
+
+	with_core(|core| core.run(&callbacks));
+}
+
+/// Returns the size of the savestates.
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+	with_core(|core| core.emulator.save_state().len()).unwrap_or(0)
+}
+
+/// Writes a savestate into the given buffer.
+///
+/// # Safety
+/// `data` must be null, or point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+	if data.is_null() {
+		return false;
+	}
+
+	let state = match with_core(|core| core.emulator.save_state()) {
+		Some(state) if state.len() <= size => state,
+		_ => return false,
+	};
+
+	ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+	true
+}
+
+/// Loads a savestate from the given buffer.
+///
+/// # Safety
+/// `data` must be null, or point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+	if data.is_null() {
+		return false;
+	}
+
+	let state = slice::from_raw_parts(data as *const u8, size);
+
+	with_core(|core| core.emulator.load_state(state).is_ok()).unwrap_or(false)
+}
+
+/// Removes the cheats, which aren't supported.
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+/// Applies a cheat, which isn't supported.
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// Loads the game's rom, which is passed in memory.
+///
+/// # Safety
+/// `game` must be null, or point to a valid `retro_game_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const retro_game_info) -> bool {
+	if game.is_null() || (*game).data.is_null() {
+		return false;
+	}
+
+	let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+
+	let environment = CALLBACKS.with(|callbacks| callbacks.borrow().environment);
+	let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+	match environment {
+		Some(environment) if environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) => {}
+		_ => return false,
+	}
+
+	let emulator = match Emulator::builder().rom(rom).sample_rate(SAMPLE_RATE).build() {
+		Ok(emulator) => emulator,
+		Err(_) => return false,
+	};
+
+	// A frame's worth of audio, with some headroom.
+	let samples = vec![0_i16; 2 * SAMPLE_RATE as usize / 30];
+
+	CORE.with(|core| *core.borrow_mut() = Some(Core { emulator, samples }));
+	true
+}
+
+/// Loads a game that consists of several files, which isn't supported.
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const retro_game_info, _num_info: usize) -> bool {
+	false
+}
+
+/// Unloads the game.
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+	CORE.with(|core| core.borrow_mut().take());
+}
+
+/// Returns the region of the machine.
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+	RETRO_REGION_NTSC
+}
+
+/// Returns the battery-backed ram, which the frontend persists.
+fn save_ram(core: &mut Core) -> Option<&mut [u8]> {
+	let emulator = &mut core.emulator;
+
+	if emulator.has_battery() && !emulator.cartridge_ram().is_empty() {
+		Some(emulator.cartridge_ram_mut())
+	} else {
+		None
+	}
+}
+
+/// Returns a memory region for the frontend to access directly.
+///
+/// Only the battery-backed ram is exposed, which the frontend loads and
+/// saves by itself. The region stays valid until the game is unloaded.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+	if id != RETRO_MEMORY_SAVE_RAM {
+		return ptr::null_mut();
+	}
+
+	with_core(|core| save_ram(core).map(|ram| ram.as_mut_ptr() as *mut c_void))
+		.flatten()
+		.unwrap_or(ptr::null_mut())
+}
+
+/// Returns the size of a memory region, see `retro_get_memory_data`.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+	if id != RETRO_MEMORY_SAVE_RAM {
+		return 0;
+	}
+
+	with_core(|core| save_ram(core).map_or(0, |ram| ram.len())).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+
+	thread_local! {
+		static VIDEO_FRAMES: Cell<usize> = const { Cell::new(0) };
+		static AUDIO_FRAMES: Cell<usize> = const { Cell::new(0) };
+	}
+
+	unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+		cmd == RETRO_ENVIRONMENT_SET_PIXEL_FORMAT && *(data as *const c_uint) == RETRO_PIXEL_FORMAT_XRGB8888
+	}
+
+	unsafe extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+		assert!(!data.is_null() && width == 160 && height == 144 && pitch == 640);
+		VIDEO_FRAMES.with(|frames| frames.set(frames.get() + 1));
+	}
+
+	unsafe extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+		AUDIO_FRAMES.with(|total| total.set(total.get() + frames));
+		frames
+	}
+
+	unsafe extern "C" fn input_poll() {}
+
+	unsafe extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+		(id == RETRO_DEVICE_ID_JOYPAD_START) as i16
+	}
+
+	#[test]
+	fn test_core() {
+		let mut rom = vec![0_u8; 0x8000];
+		// jr -2
+		rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]);
+		// An MBC3 cartridge with 8KB of battery-backed ram.
+		rom[0x147] = 0x13;
+		rom[0x149] = 0x02;
+		let game = retro_game_info {
+			path: ptr::null(),
+			data: rom.as_ptr() as *const c_void,
+			size: rom.len(),
+			meta: ptr::null(),
+		};
+
+		retro_set_environment(environment);
+		retro_set_video_refresh(video_refresh);
+		retro_set_audio_sample_batch(audio_sample_batch);
+		retro_set_input_poll(input_poll);
+		retro_set_input_state(input_state);
+		retro_init();
+		assert!(unsafe { retro_load_game(&game) });
+
+		for _ in 0..60 {
+			retro_run();
+		}
+
+		// A second of video and audio was passed to the frontend.
+		assert!(VIDEO_FRAMES.with(Cell::get) == 60);
+		let audio_frames = AUDIO_FRAMES.with(Cell::get);
+		assert!(audio_frames > 47000 && audio_frames < 49000);
+
+		// The battery-backed ram is exposed to the frontend.
+		assert!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM) == 0x2000);
+		assert!(!retro_get_memory_data(RETRO_MEMORY_SAVE_RAM).is_null());
+		assert!(retro_get_memory_data(RETRO_MEMORY_SAVE_RAM + 1).is_null());
+
+		// The savestates round-trip.
+		let mut state = vec![0_u8; retro_serialize_size()];
+		assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+		assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+		assert!(!unsafe { retro_unserialize(state.as_ptr() as *const c_void, 1) });
+		assert!(!unsafe { retro_unserialize(ptr::null(), state.len()) });
+		assert!(!unsafe { retro_serialize(ptr::null_mut(), state.len()) });
+
+		retro_deinit();
+		assert!(retro_serialize_size() == 0);
+	}
+}
@@ -0,0 +1,84 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! A ring buffer of the sound controller's output, drained by the frontend.
+
+use crate::resample::StereoFrame;
+
+/// The number of stereo frames the buffer holds, about 85ms at 48kHz.
+pub const SAMPLE_BUFFER_FRAMES: usize = 4096;
+
+/// Holds the generated frames until the frontend drains them.
+///
+/// When the buffer is full, the oldest frames are overwritten.
+#[derive(Clone)]
+pub struct SampleBuffer {
+	frames: [StereoFrame; SAMPLE_BUFFER_FRAMES],
+	/// The index of the oldest frame.
+	start: usize,
+	/// The number of frames in the buffer.
+	len: usize,
+}
+
+impl SampleBuffer {
+	/// Initialize an empty buffer.
+	pub fn new() -> Self {
+		SampleBuffer {
+			frames: [[0; 2]; SAMPLE_BUFFER_FRAMES],
+			start: 0,
+			len: 0,
+		}
+	}
+
+	/// Discards all of the frames.
+	pub fn clear(&mut self) {
+		self.start = 0;
+		self.len = 0;
+	}
+
+	/// Returns the number of frames in the buffer.
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Returns whether the buffer is empty.
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Appends a frame, overwriting the oldest frame if the buffer is full.
+	pub fn push(&mut self, frame: StereoFrame) {
+		let end = (self.start + self.len) % SAMPLE_BUFFER_FRAMES;
+		self.frames[end] = frame;
+
+		if self.len == SAMPLE_BUFFER_FRAMES {
+			self.start = (self.start + 1) % SAMPLE_BUFFER_FRAMES;
+		} else {
+			self.len += 1;
+		}
+	}
+
+	/// Moves the oldest frames into the given buffer, as interleaved left and
+	/// right samples.
+	///
+	/// Returns the number of samples written, which is always even.
+	pub fn drain(&mut self, samples: &mut [i16]) -> usize {
+		let count = self.len.min(samples.len() / 2);
+
+		for pair in samples.chunks_exact_mut(2).take(count) {
+			pair.copy_from_slice(&self.frames[self.start]);
+			self.start = (self.start + 1) % SAMPLE_BUFFER_FRAMES;
+		}
+
+		self.len -= count;
+
+		2 * count
+	}
+}
+
+impl Default for SampleBuffer {
+	fn default() -> Self {
+		SampleBuffer::new()
+	}
+}
@@ -0,0 +1,60 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! A timestamped log of the writes to the sound registers.
+//!
+//! The log captures the music as the game plays it, so it can be converted
+//! to VGM, or compared against the writes of a reference emulator.
+
+use alloc::vec::Vec;
+
+/// A single write to a sound register or to the wave pattern ram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterWrite {
+	/// The number of clock cycles since the logging started.
+	pub cycles: u64,
+	/// The written register.
+	pub address: u16,
+	/// The written value.
+	pub value: u8,
+}
+
+/// Records the writes, along with the time that passed between them.
+#[derive(Clone)]
+pub struct RegisterLog {
+	/// The number of clock cycles since the logging started.
+	cycles: u64,
+	writes: Vec<RegisterWrite>,
+}
+
+impl RegisterLog {
+	/// Initialize an empty log.
+	pub fn new() -> Self {
+		RegisterLog {
+			cycles: 0,
+			writes: Vec::new(),
+		}
+	}
+
+	/// Advances the log's clock.
+	pub fn advance(&mut self, cycles: usize) {
+		self.cycles += cycles as u64;
+	}
+
+	/// Records a write at the current time.
+	pub fn record(&mut self, address: u16, value: u8) {
+		self.writes.push(RegisterWrite { cycles: self.cycles, address, value });
+	}
+
+	/// Moves the recorded writes out of the log, while its clock keeps running.
+	pub fn take(&mut self) -> Vec<RegisterWrite> {
+		core::mem::take(&mut self.writes)
+	}
+}
+
+impl Default for RegisterLog {
+	fn default() -> Self {
+		RegisterLog::new()
+	}
+}
@@ -0,0 +1,986 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Emulate the gameboy's sound controller (APU).
+//!
+//! The controller has four channels: two square waves, a programmable wave
+//! and noise. The channels' length counters, volume envelopes and frequency
+//! sweep are clocked by the frame sequencer, which steps at 512Hz.
+//!
+//! The channels' output is mixed into stereo frames at the configured sample
+//! rate, and buffered until the frontend drains it with `Apu::drain_samples`.
+//! If `Config::resample` is set, the channels are mixed at about 1MHz instead,
+//! and resampled down to the sample rate.
+
+pub mod units;
+pub mod square;
+pub mod wave;
+pub mod noise;
+pub mod buffer;
+#[cfg(feature = "alloc")]
+pub mod log;
+
+use super::{Memory, Peripheral};
+use super::memory_range::*;
+
+use crate::GameboyError;
+use crate::config::*;
+use crate::cpu::interrupts::*;
+use crate::bus::rtc::CYCLES_PER_SECOND;
+use crate::resample::{Resampler, StereoFrame};
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use buffer::SampleBuffer;
+use square::Square;
+use wave::Wave;
+use noise::Noise;
+#[cfg(feature = "alloc")]
+use log::{RegisterLog, RegisterWrite};
+
+/// Sound controller related constants.
+#[allow(missing_docs)]
+pub mod consts {
+	use super::*;
+
+	pub const IO_NR10: u16 = 0xFF10;
+	pub const IO_NR11: u16 = 0xFF11;
+	pub const IO_NR12: u16 = 0xFF12;
+	pub const IO_NR13: u16 = 0xFF13;
+	pub const IO_NR14: u16 = 0xFF14;
+	pub const IO_NR21: u16 = 0xFF16;
+	pub const IO_NR22: u16 = 0xFF17;
+	pub const IO_NR23: u16 = 0xFF18;
+	pub const IO_NR24: u16 = 0xFF19;
+	pub const IO_NR30: u16 = 0xFF1A;
+	pub const IO_NR31: u16 = 0xFF1B;
+	pub const IO_NR32: u16 = 0xFF1C;
+	pub const IO_NR33: u16 = 0xFF1D;
+	pub const IO_NR34: u16 = 0xFF1E;
+	pub const IO_NR41: u16 = 0xFF20;
+	pub const IO_NR42: u16 = 0xFF21;
+	pub const IO_NR43: u16 = 0xFF22;
+	pub const IO_NR44: u16 = 0xFF23;
+	pub const IO_NR50: u16 = 0xFF24;
+	pub const IO_NR51: u16 = 0xFF25;
+	pub const IO_NR52: u16 = 0xFF26;
+
+	pub const MMAP_IO_SOUND: MemoryRange = make_range!(0xFF10, 0xFF26);
+	pub const MMAP_IO_WAVE: MemoryRange = make_range!(0xFF30, 0xFF3F);
+
+	/// The number of cycles between the frame sequencer's steps, at 512Hz.
+	pub const FRAME_SEQUENCER_CYCLES: usize = 8192;
+
+	/// Powers the sound controller.
+	pub const NR52_ENABLE: u8 = 0x80;
+
+	/// The rate the channels are mixed at before they're resampled, once
+	/// every 4 cycles.
+	pub const RESAMPLER_INPUT_RATE: u32 = CYCLES_PER_SECOND / 4;
+
+	/// The amplitude of a single channel's output step at the lowest master
+	/// volume, so the four channels at their full volume don't overflow.
+	pub const CHANNEL_AMPLITUDE: i16 = i16::MAX / (4 * 15 * 8);
+}
+
+use consts::*;
+
+/// The sound controller's channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+	/// The first square wave channel, with the frequency sweep.
+	Square1,
+	/// The second square wave channel.
+	Square2,
+	/// The programmable wave channel.
+	Wave,
+	/// The noise channel.
+	Noise,
+}
+
+impl Channel {
+	/// The channel's bit in NR51 and in the mute mask.
+	fn mask(&self) -> u8 {
+		1 << (*self as u8)
+	}
+}
+
+/// The sound controller's state.
+#[derive(Clone)]
+pub struct Apu {
+	square1: Square,
+	square2: Square,
+	wave: Wave,
+	noise: Noise,
+	/// Master volume and VIN panning.
+	nr50: u8,
+	/// Channels' panning.
+	nr51: u8,
+	/// Whether the sound controller is powered (NR52).
+	enabled: bool,
+	/// The emulated hardware model, which affects the power-off behavior.
+	model: HardwareModel,
+	/// The number of cycles until the frame sequencer's next step.
+	sequencer_cycles: usize,
+	/// The frame sequencer's current step, from 0 to 7.
+	sequencer_step: u8,
+	/// The number of frames mixed per second, where 0 disables the output.
+	mix_rate: u32,
+	/// The time since the last frame was mixed, in units of
+	/// `1 / (mix_rate * CYCLES_PER_SECOND)` seconds.
+	sample_clock: u32,
+	/// Converts the mixed frames to the sample rate, if enabled.
+	resampler: Option<Resampler>,
+	/// The generated frames, waiting for the frontend.
+	samples: SampleBuffer,
+	/// The channels that are left out of the mix, by the frontend.
+	muted_channels: u8,
+	/// Whether the whole output is silenced, by the frontend.
+	master_muted: bool,
+	/// The log of the writes to the sound registers, if it's enabled.
+	#[cfg(feature = "alloc")]
+	log: Option<RegisterLog>,
+}
+
+impl Apu {
+	/// Initialize a new sound controller.
+	pub fn new(config: &Config) -> Self {
+		let mut apu = Apu {
+			square1: Square::new(true),
+			square2: Square::new(false),
+			wave: Wave::new(),
+			noise: Noise::new(),
+			nr50: 0,
+			nr51: 0,
+			enabled: false,
+			model: config.model,
+			sequencer_cycles: FRAME_SEQUENCER_CYCLES,
+			sequencer_step: 0,
+			mix_rate: 0,
+			sample_clock: 0,
+			resampler: None,
+			samples: SampleBuffer::new(),
+			muted_channels: 0,
+			master_muted: false,
+			#[cfg(feature = "alloc")]
+			log: None,
+		};
+
+		apu.reset(config);
+
+		apu
+	}
+
+	/// Returns the number of cycles until the next frame should be mixed.
+	fn cycles_until_sample(&self) -> usize {
+		if self.mix_rate == 0 {
+			return usize::MAX;
+		}
+
+		let remaining = CYCLES_PER_SECOND - self.sample_clock;
+
+		remaining.div_ceil(self.mix_rate) as usize
+	}
+
+	/// Leaves the channel out of the mix, or puts it back.
+	///
+	/// Unlike the sound registers, the mutes are controlled only by the
+	/// frontend, and are kept across resets.
+	pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+		if muted {
+			self.muted_channels |= channel.mask();
+		} else {
+			self.muted_channels &= !channel.mask();
+		}
+	}
+
+	/// Returns whether the channel is left out of the mix.
+	pub fn muted(&self, channel: Channel) -> bool {
+		self.muted_channels & channel.mask() != 0
+	}
+
+	/// Mutes all of the channels except for the given one.
+	pub fn solo(&mut self, channel: Channel) {
+		self.muted_channels = 0x0F & !channel.mask();
+	}
+
+	/// Silences the whole output, which also skips the mixing.
+	pub fn set_master_muted(&mut self, muted: bool) {
+		self.master_muted = muted;
+	}
+
+	/// Returns whether the whole output is silenced.
+	pub fn master_muted(&self) -> bool {
+		self.master_muted
+	}
+
+	/// Starts logging the writes to the sound registers and to the wave
+	/// pattern ram, or stops and discards the log.
+	///
+	/// Like the mutes, the log is controlled by the frontend, and is kept
+	/// across resets.
+	#[cfg(feature = "alloc")]
+	pub fn set_logging(&mut self, enabled: bool) {
+		self.log = if enabled { Some(RegisterLog::new()) } else { None };
+	}
+
+	/// Moves the logged writes out of the log, which keeps logging.
+	///
+	/// Returns an empty log if the logging isn't enabled.
+	#[cfg(feature = "alloc")]
+	pub fn take_log(&mut self) -> Vec<RegisterWrite> {
+		self.log.as_mut().map_or_else(Vec::new, RegisterLog::take)
+	}
+
+	/// Mixes a frame into the buffer, through the resampler if it's enabled.
+	fn push_frame(&mut self) {
+		let frame = if self.master_muted { [0; 2] } else { self.mix() };
+		let samples = &mut self.samples;
+
+		match &mut self.resampler {
+			Some(resampler) => resampler.push(frame, |frame| samples.push(frame)),
+			None => samples.push(frame),
+		}
+	}
+
+	/// Mixes the channels' output into a stereo frame.
+	///
+	/// NR51 selects the channels that are panned to each side, and NR50
+	/// scales each side by its master volume. The VIN bits are ignored,
+	/// since no cartridge drives the VIN signal.
+	fn mix(&self) -> StereoFrame {
+		let output = self.output();
+		let mut frame = [0; 2];
+
+		// The left side is controlled by the upper nibbles.
+		for (side, shift) in frame.iter_mut().zip(&[4, 0]) {
+			let panning = (self.nr51 >> shift) & !self.muted_channels;
+			let volume = ((self.nr50 >> shift) & 0x07) as i16 + 1;
+
+			let sum: i16 = output.iter()
+				.enumerate()
+				.filter(|&(i, _)| panning & (1 << i) != 0)
+				.map(|(_, &output)| output as i16)
+				.sum();
+
+			*side = sum * volume * CHANNEL_AMPLITUDE;
+		}
+
+		frame
+	}
+
+	/// Moves the generated frames into the given buffer, as interleaved
+	/// left and right samples.
+	///
+	/// Returns the number of samples written.
+	pub fn drain_samples(&mut self, samples: &mut [i16]) -> usize {
+		self.samples.drain(samples)
+	}
+
+	/// Returns the number of stereo frames waiting to be drained.
+	pub fn available_frames(&self) -> usize {
+		self.samples.len()
+	}
+
+	/// Clocks the units that are due on the frame sequencer's current step.
+	fn step_sequencer(&mut self) {
+		let step = self.sequencer_step;
+
+		// The length counters are clocked at 256Hz.
+		if step & 1 == 0 {
+			self.square1.clock_length();
+			self.square2.clock_length();
+			self.wave.clock_length();
+			self.noise.clock_length();
+		}
+
+		// The frequency sweep is clocked at 128Hz.
+		if step == 2 || step == 6 {
+			self.square1.clock_sweep();
+		}
+
+		// The volume envelopes are clocked at 64Hz.
+		if step == 7 {
+			self.square1.clock_envelope();
+			self.square2.clock_envelope();
+			self.noise.clock_envelope();
+		}
+
+		self.sequencer_step = (step + 1) % 8;
+	}
+
+	/// Returns the channels' digital output, from 0 to 15 each.
+	pub fn output(&self) -> [u8; 4] {
+		[self.square1.output(), self.square2.output(), self.wave.output(), self.noise.output()]
+	}
+
+	/// Powers the sound controller on or off.
+	fn set_enabled(&mut self, enabled: bool) {
+		if enabled && !self.enabled {
+			// The frame sequencer restarts from its first step.
+			self.sequencer_cycles = FRAME_SEQUENCER_CYCLES;
+			self.sequencer_step = 0;
+		} else if !enabled && self.enabled {
+			self.power_off();
+		}
+
+		self.enabled = enabled;
+	}
+
+	/// Clears all of the sound registers, which silences the channels.
+	///
+	/// The wave pattern ram is kept, and so are the length counters on the
+	/// DMG.
+	fn power_off(&mut self) {
+		let lengths = [
+			self.square1.length.counter(),
+			self.square2.length.counter(),
+			self.wave.length.counter(),
+			self.noise.length.counter(),
+		];
+		let wave_ram = self.wave.wave_ram;
+
+		self.square1 = Square::new(true);
+		self.square2 = Square::new(false);
+		self.wave = Wave::new();
+		self.noise = Noise::new();
+		self.nr50 = 0;
+		self.nr51 = 0;
+
+		self.wave.wave_ram = wave_ram;
+
+		if self.model != HardwareModel::GBC {
+			self.square1.length.set_counter(lengths[0]);
+			self.square2.length.set_counter(lengths[1]);
+			self.wave.length.set_counter(lengths[2]);
+			self.noise.length.set_counter(lengths[3]);
+		}
+	}
+
+	/// Handles a write to a sound register while the controller is off.
+	///
+	/// The writes are ignored, except for the length counters on the DMG.
+	fn write_powered_off(&mut self, address: u16, value: u8) {
+		if self.model == HardwareModel::GBC {
+			return;
+		}
+
+		match address {
+			IO_NR11 => self.square1.length.load(value),
+			IO_NR21 => self.square2.length.load(value),
+			IO_NR31 => self.wave.length.load(value),
+			IO_NR41 => self.noise.length.load(value),
+			_ => {}
+		}
+	}
+
+	/// Returns the channels' status bits, as they're read from NR52.
+	fn channels_status(&self) -> u8 {
+		let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+
+		channels.iter()
+			.enumerate()
+			.fold(0, |status, (i, &enabled)| status | ((enabled as u8) << i))
+	}
+
+	/// Returns the register's bits that always read as 1.
+	fn read_mask(address: u16) -> u8 {
+		match address {
+			IO_NR10 => 0x80,
+			IO_NR11 | IO_NR21 => 0x3F,
+			IO_NR12 | IO_NR22 | IO_NR42 | IO_NR43 | IO_NR50 | IO_NR51 => 0x00,
+			IO_NR14 | IO_NR24 | IO_NR34 | IO_NR44 => 0xBF,
+			IO_NR30 => 0x7F,
+			IO_NR32 => 0x9F,
+			IO_NR52 => 0x70,
+			// Write-only and unused registers.
+			_ => 0xFF,
+		}
+	}
+}
+
+impl Memory for Apu {
+	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
+		#[cfg(feature = "alloc")]
+		if let Some(log) = &mut self.log {
+			log.record(address, value);
+		}
+
+		if !self.enabled && (IO_NR10..IO_NR52).contains(&address) {
+			self.write_powered_off(address, value);
+			return Ok(());
+		}
+
+		match address {
+			0xFF10..=0xFF14 => { self.square1.write((address - 0xFF10) as usize, value); }
+			0xFF15..=0xFF19 => { self.square2.write((address - 0xFF15) as usize, value); }
+			0xFF1A..=0xFF1E => { self.wave.write((address - 0xFF1A) as usize, value); }
+			0xFF1F..=0xFF23 => { self.noise.write((address - 0xFF1F) as usize, value); }
+			IO_NR50 => { self.nr50 = value; }
+			IO_NR51 => { self.nr51 = value; }
+			IO_NR52 => { self.set_enabled((value & NR52_ENABLE) != 0); }
+			memory_range!(MMAP_IO_WAVE) => {
+				self.wave.wave_ram[(address - 0xFF30) as usize] = value;
+			}
+			_ => {
+				return Err(GameboyError::BadAddress(address));
+			}
+		}
+
+		Ok(())
+	}
+
+	fn read(&self, address: u16) -> Result<u8, GameboyError> {
+		let value = match address {
+			0xFF10..=0xFF14 => self.square1.read((address - 0xFF10) as usize),
+			0xFF15..=0xFF19 => self.square2.read((address - 0xFF15) as usize),
+			0xFF1A..=0xFF1E => self.wave.read((address - 0xFF1A) as usize),
+			0xFF1F..=0xFF23 => self.noise.read((address - 0xFF1F) as usize),
+			IO_NR50 => self.nr50,
+			IO_NR51 => self.nr51,
+			IO_NR52 => {
+				if self.enabled { NR52_ENABLE | self.channels_status() } else { 0 }
+			}
+			memory_range!(MMAP_IO_WAVE) => {
+				return Ok(self.wave.wave_ram[(address - 0xFF30) as usize]);
+			}
+			_ => {
+				return Err(GameboyError::BadAddress(address));
+			}
+		};
+
+		Ok(value | Apu::read_mask(address))
+	}
+}
+
+/// The sound controller doesn't raise any interrupts.
+impl InterruptSource for Apu {
+	fn interrupts(&self) -> InterruptMask {
+		InterruptMask::empty()
+	}
+
+	fn clear(&mut self) {}
+}
+
+/// The generated frames and the resampler's history belong to the output,
+/// so they aren't serialized.
+#[cfg(feature = "alloc")]
+impl SaveState for Apu {
+	fn save_state(&self, writer: &mut StateWriter) {
+		self.square1.save_state(writer);
+		self.square2.save_state(writer);
+		self.wave.save_state(writer);
+		self.noise.save_state(writer);
+		writer.u8(self.nr50);
+		writer.u8(self.nr51);
+		writer.bool(self.enabled);
+		writer.usize(self.sequencer_cycles);
+		writer.u8(self.sequencer_step);
+		writer.u32(self.sample_clock);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.square1.load_state(reader)?;
+		self.square2.load_state(reader)?;
+		self.wave.load_state(reader)?;
+		self.noise.load_state(reader)?;
+		self.nr50 = reader.u8()?;
+		self.nr51 = reader.u8()?;
+		self.enabled = reader.bool()?;
+		self.sequencer_cycles = reader.usize()?;
+		self.sequencer_step = reader.u8()?;
+		self.sample_clock = reader.u32()?;
+
+		if !(1..=FRAME_SEQUENCER_CYCLES).contains(&self.sequencer_cycles) || self.sequencer_step > 7 ||
+			self.sample_clock >= CYCLES_PER_SECOND {
+			return Err(GameboyError::Io("The savestate holds an invalid sound controller state."));
+		}
+
+		self.samples.clear();
+		if let Some(resampler) = &mut self.resampler {
+			resampler.reset();
+		}
+
+		Ok(())
+	}
+}
+
+impl Peripheral for Apu {
+	/// Reset the peripheral to the state the boot rom leaves it in.
+	fn reset(&mut self, config: &Config) {
+		self.square1 = Square::new(true);
+		self.square2 = Square::new(false);
+		self.wave = Wave::new();
+		self.noise = Noise::new();
+		self.sequencer_cycles = FRAME_SEQUENCER_CYCLES;
+		self.sequencer_step = 0;
+		self.enabled = true;
+		self.model = config.model;
+		self.sample_clock = 0;
+		self.samples.clear();
+
+		self.resampler = match config.resample {
+			Some(quality) if config.sample_rate != 0 => {
+				Some(Resampler::new(RESAMPLER_INPUT_RATE, config.sample_rate, quality))
+			}
+			_ => None,
+		};
+		self.mix_rate = if self.resampler.is_some() { RESAMPLER_INPUT_RATE } else { config.sample_rate };
+
+		self.square1.write(1, 0x80);
+		self.square1.write(2, 0xF3);
+		self.nr50 = 0x77;
+		self.nr51 = 0xF3;
+
+		// The boot sound leaves the first channel playing, although silent.
+		if config.model != HardwareModel::SGB {
+			self.square1.enabled = true;
+		}
+	}
+
+	/// Update the channels' state according to the elapsed time, and
+	/// generate the frames that are due.
+	fn process(&mut self, cycles: usize) {
+		#[cfg(feature = "alloc")]
+		if let Some(log) = &mut self.log {
+			log.advance(cycles);
+		}
+
+		let mut cycles = cycles;
+
+		// Stop at the frame sequencer's steps and the frames' sampling points.
+		while cycles > 0 {
+			let step = cycles.min(self.sequencer_cycles).min(self.cycles_until_sample());
+			cycles -= step;
+
+			if self.enabled {
+				self.square1.process(step);
+				self.square2.process(step);
+				self.wave.process(step);
+				self.noise.process(step);
+
+				self.sequencer_cycles -= step;
+				if self.sequencer_cycles == 0 {
+					self.sequencer_cycles = FRAME_SEQUENCER_CYCLES;
+					self.step_sequencer();
+				}
+			}
+
+			if self.mix_rate != 0 {
+				self.sample_clock += step as u32 * self.mix_rate;
+				if self.sample_clock >= CYCLES_PER_SECOND {
+					self.sample_clock -= CYCLES_PER_SECOND;
+					self.push_frame();
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::resample::ResampleQuality;
+
+	#[test]
+	fn test_apu_registers() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config { model: HardwareModel::GB, ..Config::default() });
+
+		// The boot rom leaves the first channel on.
+		assert!(apu.read(IO_NR52)? == 0xF1);
+		assert!(apu.read(IO_NR11)? == 0xBF);
+		assert!(apu.read(IO_NR12)? == 0xF3);
+		assert!(apu.read(IO_NR50)? == 0x77);
+
+		// Unused bits read as 1.
+		apu.write(IO_NR10, 0x00)?;
+		assert!(apu.read(IO_NR10)? == 0x80);
+		apu.write(IO_NR32, 0xFF)?;
+		assert!(apu.read(IO_NR32)? == 0xFF);
+		apu.write(IO_NR32, 0x20)?;
+		assert!(apu.read(IO_NR32)? == 0xBF);
+
+		// Write-only registers and the unused registers read as 0xFF.
+		for &address in &[IO_NR13, IO_NR23, IO_NR31, IO_NR33, IO_NR41, 0xFF15, 0xFF1F] {
+			apu.write(address, 0x00)?;
+			assert!(apu.read(address)? == 0xFF);
+		}
+
+		apu.write(0xFF3F, 0x5A)?;
+		assert!(apu.read(0xFF3F)? == 0x5A);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_square() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config::default());
+
+		// Play a 50% duty cycle in full volume, for a single length tick.
+		apu.write(IO_NR11, 0x80 | 0x3F)?;
+		apu.write(IO_NR12, 0xF0)?;
+		apu.write(IO_NR13, 0xFF)?;
+		apu.write(IO_NR14, 0x80 | 0x40 | 0x07)?;
+		assert!(apu.read(IO_NR52)? & 0x01 != 0);
+
+		// The waveform steps every 4 cycles.
+		let mut samples = [0_u8; 8];
+		for sample in samples.iter_mut() {
+			apu.process(4);
+			*sample = apu.output()[0];
+		}
+		assert!(samples == [0, 0, 0, 0, 15, 15, 15, 15]);
+
+		// The length counter silences the channel on the sequencer's first step.
+		apu.process(FRAME_SEQUENCER_CYCLES);
+		assert!(apu.read(IO_NR52)? & 0x01 == 0);
+		assert!(apu.output()[0] == 0);
+
+		// A powered off DAC prevents the channel from playing.
+		apu.write(IO_NR12, 0x00)?;
+		apu.write(IO_NR14, 0x80)?;
+		assert!(apu.read(IO_NR52)? & 0x01 == 0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_square2() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config::default());
+
+		// Play a 12.5% duty cycle, with a decreasing envelope.
+		apu.write(IO_NR21, 0x30)?;
+		apu.write(IO_NR22, 0x21)?;
+		apu.write(IO_NR23, 0xFF)?;
+		apu.write(IO_NR24, 0x80 | 0x40 | 0x07)?;
+		assert!(apu.read(IO_NR52)? & 0x02 != 0);
+		assert!(apu.read(IO_NR21)? == 0x3F);
+		assert!(apu.read(IO_NR24)? == 0xFF);
+
+		// Only the last step of the waveform is high.
+		let mut samples = [0_u8; 8];
+		for sample in samples.iter_mut() {
+			apu.process(4);
+			*sample = apu.output()[1];
+		}
+		assert!(samples == [0, 0, 0, 0, 0, 0, 2, 0]);
+
+		// The envelope is clocked once every 8 steps of the sequencer.
+		apu.process(FRAME_SEQUENCER_CYCLES * 8);
+		assert!(apu.square2.envelope.volume == 1);
+
+		// The length counter expires after 16 ticks, 2 steps apart.
+		apu.process(FRAME_SEQUENCER_CYCLES * 22);
+		assert!(apu.read(IO_NR52)? & 0x02 != 0);
+		apu.process(FRAME_SEQUENCER_CYCLES);
+		assert!(apu.read(IO_NR52)? & 0x02 == 0);
+		assert!(apu.output() == [0, 0, 0, 0]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_wave() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config::default());
+
+		// The samples count from 0 to 15, twice.
+		for i in 0..0x10 {
+			apu.write(0xFF30 + i, (((2 * i % 16) << 4) | ((2 * i + 1) % 16)) as u8)?;
+		}
+
+		// The channel doesn't play while its DAC is off.
+		apu.write(IO_NR30, 0x00)?;
+		apu.write(IO_NR34, 0x80)?;
+		assert!(apu.read(IO_NR52)? & 0x04 == 0);
+
+		// Play the samples in full volume, a sample every 2 cycles.
+		apu.write(IO_NR30, 0x80)?;
+		apu.write(IO_NR32, 0x20)?;
+		apu.write(IO_NR33, 0xFF)?;
+		apu.write(IO_NR34, 0x80 | 0x07)?;
+		assert!(apu.read(IO_NR52)? & 0x04 != 0);
+
+		let mut samples = [0_u8; 4];
+		for sample in samples.iter_mut() {
+			apu.process(2);
+			*sample = apu.output()[2];
+		}
+		assert!(samples == [1, 2, 3, 4]);
+
+		// The volume code shifts the samples.
+		apu.write(IO_NR32, 0x60)?;
+		apu.process(2);
+		assert!(apu.output()[2] == 5 >> 2);
+		apu.write(IO_NR32, 0x00)?;
+		assert!(apu.output()[2] == 0);
+
+		// The position wraps around the wave pattern ram.
+		apu.write(IO_NR32, 0x20)?;
+		apu.process(2 * 27);
+		assert!(apu.output()[2] == 0);
+
+		// Powering off the DAC stops the channel.
+		apu.write(IO_NR30, 0x00)?;
+		assert!(apu.read(IO_NR52)? & 0x04 == 0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_noise() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config::default());
+
+		// Step the 15-bit shift register every 8 cycles, in full volume.
+		apu.write(IO_NR42, 0xF0)?;
+		apu.write(IO_NR43, 0x00)?;
+		apu.write(IO_NR44, 0x80)?;
+		assert!(apu.read(IO_NR52)? & 0x08 != 0);
+		assert!(apu.read(IO_NR43)? == 0x00);
+
+		// The register starts with all of its bits set, so the first
+		// 14 steps shift in zeros.
+		let mut samples = [0_u8; 16];
+		for sample in samples.iter_mut() {
+			apu.process(8);
+			*sample = apu.output()[3];
+		}
+		assert!(samples[..14].iter().all(|&sample| sample == 0));
+		assert!(samples[14] == 15);
+		assert!(apu.noise.lfsr == 0x2000);
+
+		// The 7-bit mode repeats every 127 steps.
+		apu.write(IO_NR43, 0x08)?;
+		apu.write(IO_NR44, 0x80)?;
+		let mut samples = [0_u8; 254];
+		for sample in samples.iter_mut() {
+			apu.process(8);
+			*sample = apu.output()[3];
+		}
+		assert!(samples[..127] == samples[127..]);
+		assert!(samples[..7] != samples[7..14]);
+
+		// The highest clock shifts stop the register.
+		apu.write(IO_NR43, 0xE0)?;
+		let lfsr = apu.noise.lfsr;
+		apu.process(FRAME_SEQUENCER_CYCLES);
+		assert!(apu.noise.lfsr == lfsr);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_samples() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config { sample_rate: 32768, ..Config::default() });
+
+		// A frame is generated every 128 cycles.
+		apu.process(127);
+		assert!(apu.available_frames() == 0);
+		apu.process(1 + 128 * 9);
+		assert!(apu.available_frames() == 10);
+
+		// Only whole frames are drained.
+		let mut samples = [0x7F7F_i16; 5];
+		assert!(apu.drain_samples(&mut samples) == 4);
+		assert!(samples[..4] == [0; 4] && samples[4] == 0x7F7F);
+		assert!(apu.available_frames() == 8);
+
+		// Play a square wave with a 75% duty cycle, which is high on the
+		// fourth step of its waveform.
+		apu.write(IO_NR21, 0xC0)?;
+		apu.write(IO_NR22, 0xF0)?;
+		apu.write(IO_NR23, 0xF8)?;
+		apu.write(IO_NR24, 0x80 | 0x07)?;
+		apu.process(128);
+
+		let mut samples = [0_i16; 32];
+		assert!(apu.drain_samples(&mut samples) == 18);
+		assert!(samples[16] == 15 * 8 * CHANNEL_AMPLITUDE && samples[17] == samples[16]);
+
+		// The oldest frames are overwritten when the buffer is full.
+		apu.process(128 * (buffer::SAMPLE_BUFFER_FRAMES + 10));
+		assert!(apu.available_frames() == buffer::SAMPLE_BUFFER_FRAMES);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_mixing() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config::default());
+
+		// Play the first channel in full volume, on the left side only.
+		apu.write(IO_NR11, 0xC0)?;
+		apu.write(IO_NR12, 0xF0)?;
+		apu.write(IO_NR13, 0xF8)?;
+		apu.write(IO_NR14, 0x80 | 0x07)?;
+		apu.write(IO_NR51, 0x10)?;
+		apu.process(128);
+		assert!(apu.output()[0] == 15);
+
+		// The VIN bits are readable, but don't affect the output.
+		apu.write(IO_NR50, 0x88 | 0x31)?;
+		assert!(apu.read(IO_NR50)? == 0xB9);
+		assert!(apu.mix() == [15 * 4 * CHANNEL_AMPLITUDE, 0]);
+
+		// Each side has its own master volume.
+		apu.write(IO_NR50, 0x07)?;
+		apu.write(IO_NR51, 0x11)?;
+		assert!(apu.mix() == [15 * CHANNEL_AMPLITUDE, 15 * 8 * CHANNEL_AMPLITUDE]);
+
+		// The channels that aren't panned to either side are muted.
+		apu.write(IO_NR51, 0xEE)?;
+		assert!(apu.mix() == [0, 0]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_mute() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config { sample_rate: 32768, ..Config::default() });
+
+		// Play the first channel in full volume, on both sides.
+		apu.write(IO_NR11, 0xC0)?;
+		apu.write(IO_NR12, 0xF0)?;
+		apu.write(IO_NR13, 0xF8)?;
+		apu.write(IO_NR14, 0x80 | 0x07)?;
+		apu.write(IO_NR51, 0x11)?;
+		apu.process(128);
+		assert!(apu.mix()[0] != 0);
+
+		// A muted channel is still played, but left out of the mix.
+		apu.set_muted(Channel::Square1, true);
+		assert!(apu.muted(Channel::Square1));
+		assert!(apu.mix() == [0, 0]);
+		assert!(apu.read(IO_NR52)? & 0x01 != 0);
+
+		apu.solo(Channel::Square1);
+		assert!(!apu.muted(Channel::Square1) && apu.muted(Channel::Noise));
+		assert!(apu.mix()[0] != 0);
+
+		// The master mute silences the generated frames.
+		apu.set_master_muted(true);
+		apu.drain_samples(&mut [0; 64]);
+		apu.process(128);
+		let mut samples = [0x7F7F_i16; 2];
+		assert!(apu.drain_samples(&mut samples) == 2);
+		assert!(samples == [0, 0]);
+
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "alloc")]
+	fn test_apu_log() -> Result<(), GameboyError> {
+		let mut apu = Apu::new(&Config::default());
+
+		// Nothing is logged until the logging is enabled.
+		apu.write(IO_NR50, 0x77)?;
+		apu.set_logging(true);
+		apu.process(100);
+		apu.write(IO_NR12, 0xF0)?;
+		apu.process(20);
+		apu.write(0xFF30, 0x12)?;
+
+		assert!(apu.take_log() == [
+			RegisterWrite { cycles: 100, address: IO_NR12, value: 0xF0 },
+			RegisterWrite { cycles: 120, address: 0xFF30, value: 0x12 },
+		]);
+
+		// The clock keeps running after the log is taken.
+		apu.write(IO_NR51, 0xFF)?;
+		assert!(apu.take_log() == [RegisterWrite { cycles: 120, address: IO_NR51, value: 0xFF }]);
+
+		apu.set_logging(false);
+		apu.write(IO_NR51, 0x00)?;
+		assert!(apu.take_log().is_empty());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_resampler() -> Result<(), GameboyError> {
+		let config = Config { sample_rate: 32768, ..Config::default() };
+		let mut direct = Apu::new(&config);
+		let mut linear = Apu::new(&Config { resample: Some(ResampleQuality::Linear), ..config });
+		let mut filtered = Apu::new(&Config { resample: Some(ResampleQuality::BandLimited), ..config });
+
+		// Play a square wave well above the output's nyquist frequency.
+		for apu in [&mut direct, &mut linear, &mut filtered] {
+			apu.write(IO_NR21, 0x80)?;
+			apu.write(IO_NR22, 0xF0)?;
+			apu.write(IO_NR23, 0xFF)?;
+			apu.write(IO_NR24, 0x80 | 0x07)?;
+			apu.process(CYCLES_PER_SECOND as usize / 64);
+		}
+
+		// The resamplers generate about as many frames as the direct sampling.
+		let expected = 32768 / 64;
+		assert!(direct.available_frames() == expected);
+		assert!(linear.available_frames().abs_diff(expected) <= 1);
+		assert!(filtered.available_frames().abs_diff(expected) <= 64);
+
+		// The direct sampling always hits the same step of the waveform, while
+		// the band-limited resampler settles around the wave's average.
+		let mut samples = [0_i16; 2 * 512];
+		let count = direct.drain_samples(&mut samples);
+		assert!(samples[..count].iter().all(|&sample| sample == samples[0]));
+
+		let count = filtered.drain_samples(&mut samples);
+		let average = 15 * 8 * CHANNEL_AMPLITUDE / 2;
+		let settled = &samples[count - 64..count];
+		assert!(settled.iter().all(|&sample| (sample - average).abs() < average / 8));
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_apu_power_off() -> Result<(), GameboyError> {
+		let mut dmg = Apu::new(&Config { model: HardwareModel::GB, ..Config::default() });
+		let mut cgb = Apu::new(&Config { model: HardwareModel::GBC, ..Config::default() });
+
+		for apu in [&mut dmg, &mut cgb] {
+			apu.write(IO_NR21, 0x3F)?;
+			apu.write(IO_NR22, 0xF0)?;
+			apu.write(IO_NR24, 0xC0)?;
+			apu.write(0xFF30, 0x5A)?;
+
+			// Powering off clears the registers and silences the channels.
+			apu.write(IO_NR52, 0x00)?;
+			assert!(apu.read(IO_NR52)? == 0x70);
+			assert!(apu.read(IO_NR10)? == 0x80);
+			assert!(apu.read(IO_NR21)? == 0x3F);
+			assert!(apu.read(IO_NR22)? == 0x00);
+			assert!(apu.read(IO_NR50)? == 0x00 && apu.read(IO_NR51)? == 0x00);
+			assert!(apu.output() == [0; 4]);
+
+			// The registers can't be written while the controller is off,
+			// but the wave pattern ram can.
+			apu.write(IO_NR22, 0xF0)?;
+			apu.write(IO_NR50, 0x77)?;
+			apu.write(0xFF31, 0xA5)?;
+			assert!(apu.read(IO_NR22)? == 0x00 && apu.read(IO_NR50)? == 0x00);
+			assert!(apu.read(0xFF30)? == 0x5A && apu.read(0xFF31)? == 0xA5);
+
+			// Only the power bit of NR52 is writable.
+			apu.write(IO_NR52, 0x8F)?;
+			assert!(apu.read(IO_NR52)? == 0xF0);
+			apu.write(IO_NR22, 0xF0)?;
+			assert!(apu.read(IO_NR22)? == 0xF0);
+		}
+
+		// The DMG keeps its length counters, and they can be written while off.
+		assert!(dmg.square2.length.counter() == 1);
+		assert!(cgb.square2.length.counter() == 0);
+
+		dmg.write(IO_NR52, 0x00)?;
+		dmg.write(IO_NR41, 0x3E)?;
+		cgb.write(IO_NR52, 0x00)?;
+		cgb.write(IO_NR41, 0x3E)?;
+		assert!(dmg.noise.length.counter() == 2);
+		assert!(cgb.noise.length.counter() == 0);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,196 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! The noise channel (channel 4).
+//!
+//! The channel outputs pseudo-random noise, generated by a linear feedback
+//! shift register.
+
+use super::units::*;
+
+#[cfg(feature = "alloc")]
+use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+/// The shift register's base periods, in cycles, selected by the divisor code.
+const DIVISORS: [usize; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// The noise channel's state.
+#[derive(Clone)]
+pub struct Noise {
+	/// Whether the channel is playing.
+	pub(super) enabled: bool,
+	/// The amount the divisor's frequency is shifted by.
+	clock_shift: u8,
+	/// Whether the shift register is 7 bits wide, rather than 15 bits.
+	width_7bit: bool,
+	/// Selects the base divisor of the shift register's frequency.
+	divisor_code: u8,
+	/// The number of cycles left until the shift register's next step.
+	timer: usize,
+	/// The linear feedback shift register, 15 bits wide.
+	pub(super) lfsr: u16,
+	pub(super) length: LengthCounter,
+	pub(super) envelope: Envelope,
+}
+
+impl Noise {
+	/// Initialize a silent channel.
+	pub fn new() -> Self {
+		Noise {
+			enabled: false,
+			clock_shift: 0,
+			width_7bit: false,
+			divisor_code: 0,
+			timer: 0,
+			lfsr: 0x7FFF,
+			length: LengthCounter::new(64),
+			envelope: Envelope::new(),
+		}
+	}
+
+	/// Writes the channel's register, given its index (NR41-NR44).
+	pub fn write(&mut self, register: usize, value: u8) {
+		match register {
+			1 => {
+				self.length.load(value);
+			}
+			2 => {
+				self.envelope.write(value);
+				if !self.envelope.dac_enabled() {
+					self.enabled = false;
+				}
+			}
+			3 => {
+				self.clock_shift = value >> 4;
+				self.width_7bit = (value & 0x08) != 0;
+				self.divisor_code = value & 0x07;
+			}
+			_ => {
+				self.length.enabled = (value & 0x40) != 0;
+
+				if value & 0x80 != 0 {
+					self.trigger();
+				}
+			}
+		}
+	}
+
+	/// Reads the channel's register, given its index (NR41-NR44).
+	///
+	/// The write-only bits read as 0.
+	pub fn read(&self, register: usize) -> u8 {
+		match register {
+			1 => 0,
+			2 => self.envelope.read(),
+			3 => (self.clock_shift << 4) | if self.width_7bit { 0x08 } else { 0 } | self.divisor_code,
+			_ => if self.length.enabled { 0x40 } else { 0 },
+		}
+	}
+
+	/// Restarts the channel, with all of the shift register's bits set.
+	fn trigger(&mut self) {
+		self.enabled = self.envelope.dac_enabled();
+		self.timer = self.period();
+		self.lfsr = 0x7FFF;
+		self.length.trigger();
+		self.envelope.trigger();
+	}
+
+	/// The number of cycles between the shift register's steps.
+	fn period(&self) -> usize {
+		DIVISORS[self.divisor_code as usize] << self.clock_shift
+	}
+
+	/// Advances the shift register according to the elapsed time.
+	pub fn process(&mut self, cycles: usize) {
+		// The shift register isn't clocked with the two highest clock shifts.
+		if !self.enabled || self.clock_shift >= 14 {
+			return;
+		}
+
+		let mut cycles = cycles;
+
+		while cycles >= self.timer {
+			cycles -= self.timer;
+			self.timer = self.period();
+			self.step();
+		}
+
+		self.timer -= cycles;
+	}
+
+	/// Shifts the register, feeding back the XOR of its two lowest bits.
+	fn step(&mut self) {
+		let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+
+		self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+		// The 7-bit mode copies the feedback to the 7th bit as well.
+		if self.width_7bit {
+			self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+		}
+	}
+
+	/// Advances the length counter.
+	pub fn clock_length(&mut self) {
+		if self.length.clock() {
+			self.enabled = false;
+		}
+	}
+
+	/// Advances the volume envelope.
+	pub fn clock_envelope(&mut self) {
+		self.envelope.clock();
+	}
+
+	/// Returns the channel's digital output, from 0 to 15.
+	///
+	/// The channel plays when the register's lowest bit is clear.
+	pub fn output(&self) -> u8 {
+		if !self.enabled || self.lfsr & 0x01 != 0 {
+			return 0;
+		}
+
+		self.envelope.volume
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Noise {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bool(self.enabled);
+		writer.u8(self.clock_shift);
+		writer.bool(self.width_7bit);
+		writer.u8(self.divisor_code);
+		writer.usize(self.timer);
+		writer.u16(self.lfsr);
+		self.length.save_state(writer);
+		self.envelope.save_state(writer);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.enabled = reader.bool()?;
+		self.clock_shift = reader.u8()?;
+		self.width_7bit = reader.bool()?;
+		self.divisor_code = reader.u8()?;
+		self.timer = reader.usize()?;
+		self.lfsr = reader.u16()?;
+		self.length.load_state(reader)?;
+		self.envelope.load_state(reader)?;
+
+		if self.divisor_code as usize >= DIVISORS.len() || self.clock_shift > 15 {
+			return Err(GameboyError::Io("The savestate holds an invalid noise channel."));
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for Noise {
+	fn default() -> Self {
+		Noise::new()
+	}
+}
@@ -0,0 +1,206 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! The square wave channels (channels 1 and 2).
+//!
+//! Both channels output a square wave with a programmable duty cycle, volume
+//! envelope and length. Only the first channel has a frequency sweep.
+
+use super::units::*;
+
+#[cfg(feature = "alloc")]
+use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+/// The waveforms of the duty cycles, 12.5%, 25%, 50% and 75%.
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+	[0, 0, 0, 0, 0, 0, 0, 1],
+	[1, 0, 0, 0, 0, 0, 0, 1],
+	[1, 0, 0, 0, 0, 1, 1, 1],
+	[0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// A square wave channel's state.
+#[derive(Clone)]
+pub struct Square {
+	/// Whether the channel is playing.
+	pub(super) enabled: bool,
+	/// The selected duty cycle.
+	duty: u8,
+	/// The current step within the duty cycle's waveform.
+	duty_position: usize,
+	/// The 11-bit frequency value, the waveform steps every
+	/// `(2048 - frequency) * 4` cycles.
+	frequency: u16,
+	/// The number of cycles left until the next waveform step.
+	timer: usize,
+	pub(super) length: LengthCounter,
+	pub(super) envelope: Envelope,
+	/// The frequency sweep, which exists only on the first channel.
+	sweep: Option<Sweep>,
+}
+
+impl Square {
+	/// Initialize a silent channel, with or without a frequency sweep.
+	pub fn new(with_sweep: bool) -> Self {
+		Square {
+			enabled: false,
+			duty: 0,
+			duty_position: 0,
+			frequency: 0,
+			timer: 0,
+			length: LengthCounter::new(64),
+			envelope: Envelope::new(),
+			sweep: if with_sweep { Some(Sweep::new()) } else { None },
+		}
+	}
+
+	/// Writes the channel's register, given its index (NRx0-NRx4).
+	pub fn write(&mut self, register: usize, value: u8) {
+		match register {
+			0 => {
+				if let Some(sweep) = &mut self.sweep {
+					sweep.write(value);
+				}
+			}
+			1 => {
+				self.duty = value >> 6;
+				self.length.load(value);
+			}
+			2 => {
+				self.envelope.write(value);
+				// Powering off the DAC silences the channel.
+				if !self.envelope.dac_enabled() {
+					self.enabled = false;
+				}
+			}
+			3 => {
+				self.frequency = (self.frequency & 0x700) | value as u16;
+			}
+			_ => {
+				self.frequency = (self.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+				self.length.enabled = (value & 0x40) != 0;
+
+				if value & 0x80 != 0 {
+					self.trigger();
+				}
+			}
+		}
+	}
+
+	/// Reads the channel's register, given its index (NRx0-NRx4).
+	///
+	/// The write-only bits read as 0.
+	pub fn read(&self, register: usize) -> u8 {
+		match register {
+			0 => self.sweep.as_ref().map_or(0, |sweep| sweep.read()),
+			1 => self.duty << 6,
+			2 => self.envelope.read(),
+			3 => 0,
+			_ => if self.length.enabled { 0x40 } else { 0 },
+		}
+	}
+
+	/// Restarts the channel.
+	fn trigger(&mut self) {
+		self.enabled = self.envelope.dac_enabled();
+		self.timer = self.period();
+		self.length.trigger();
+		self.envelope.trigger();
+
+		if let Some(sweep) = &mut self.sweep {
+			if sweep.trigger(self.frequency) {
+				self.enabled = false;
+			}
+		}
+	}
+
+	/// The number of cycles between waveform steps.
+	fn period(&self) -> usize {
+		(2048 - self.frequency as usize) * 4
+	}
+
+	/// Advances the waveform according to the elapsed time.
+	pub fn process(&mut self, cycles: usize) {
+		let mut cycles = cycles;
+
+		while cycles >= self.timer {
+			cycles -= self.timer;
+			self.timer = self.period();
+			self.duty_position = (self.duty_position + 1) % 8;
+		}
+
+		self.timer -= cycles;
+	}
+
+	/// Advances the length counter.
+	pub fn clock_length(&mut self) {
+		if self.length.clock() {
+			self.enabled = false;
+		}
+	}
+
+	/// Advances the volume envelope.
+	pub fn clock_envelope(&mut self) {
+		self.envelope.clock();
+	}
+
+	/// Advances the frequency sweep, if the channel has one.
+	pub fn clock_sweep(&mut self) {
+		if let Some(sweep) = &mut self.sweep {
+			match sweep.clock() {
+				SweepEvent::Unchanged => {}
+				SweepEvent::Frequency(frequency) => { self.frequency = frequency; }
+				SweepEvent::Overflow => { self.enabled = false; }
+			}
+		}
+	}
+
+	/// Returns the channel's digital output, from 0 to 15.
+	pub fn output(&self) -> u8 {
+		if !self.enabled {
+			return 0;
+		}
+
+		DUTY_CYCLES[self.duty as usize][self.duty_position] * self.envelope.volume
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Square {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bool(self.enabled);
+		writer.u8(self.duty);
+		writer.usize(self.duty_position);
+		writer.u16(self.frequency);
+		writer.usize(self.timer);
+		self.length.save_state(writer);
+		self.envelope.save_state(writer);
+
+		if let Some(sweep) = &self.sweep {
+			sweep.save_state(writer);
+		}
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.enabled = reader.bool()?;
+		self.duty = reader.u8()?;
+		self.duty_position = reader.usize()?;
+		self.frequency = reader.u16()?;
+		self.timer = reader.usize()?;
+		self.length.load_state(reader)?;
+		self.envelope.load_state(reader)?;
+
+		if let Some(sweep) = &mut self.sweep {
+			sweep.load_state(reader)?;
+		}
+
+		if self.duty > 3 || self.duty_position >= 8 || self.frequency > 2047 {
+			return Err(GameboyError::Io("The savestate holds an invalid square channel."));
+		}
+
+		Ok(())
+	}
+}
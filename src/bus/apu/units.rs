@@ -0,0 +1,319 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Building blocks that are shared by the sound channels.
+//!
+//! The units are clocked by the APU's frame sequencer: the length counters
+//! at 256Hz, the frequency sweep at 128Hz and the volume envelopes at 64Hz.
+
+#[cfg(feature = "alloc")]
+use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+/// Silences the channel after a programmable amount of time.
+#[derive(Clone)]
+pub struct LengthCounter {
+	/// Whether the counter silences the channel when it expires.
+	pub enabled: bool,
+	counter: u16,
+	/// The counter's initial value, 64 for most of the channels.
+	max: u16,
+}
+
+impl LengthCounter {
+	/// Initialize a length counter with the given maximal length.
+	pub fn new(max: u16) -> Self {
+		LengthCounter { enabled: false, counter: 0, max }
+	}
+
+	/// Loads the length from the channel's length register.
+	pub fn load(&mut self, value: u8) {
+		self.counter = self.max - (value as u16 & (self.max - 1));
+	}
+
+	/// Returns the number of ticks left until the channel is silenced.
+	pub fn counter(&self) -> u16 {
+		self.counter
+	}
+
+	/// Sets the number of ticks left, used to keep the counter while the
+	/// sound controller is powered off.
+	pub fn set_counter(&mut self, counter: u16) {
+		self.counter = counter;
+	}
+
+	/// Reloads an expired counter when the channel is triggered.
+	pub fn trigger(&mut self) {
+		if self.counter == 0 {
+			self.counter = self.max;
+		}
+	}
+
+	/// Advances the counter.
+	///
+	/// Returns whether the channel should be silenced.
+	pub fn clock(&mut self) -> bool {
+		if !self.enabled || self.counter == 0 {
+			return false;
+		}
+
+		self.counter -= 1;
+		self.counter == 0
+	}
+}
+
+/// Changes the channel's volume periodically.
+#[derive(Clone)]
+pub struct Envelope {
+	/// The volume that is loaded when the channel is triggered.
+	initial_volume: u8,
+	/// Whether the volume increases or decreases.
+	increase: bool,
+	/// The number of ticks between volume changes, where 0 stops the envelope.
+	period: u8,
+	/// The current volume, from 0 to 15.
+	pub volume: u8,
+	timer: u8,
+}
+
+impl Envelope {
+	/// Initialize a silent envelope.
+	pub fn new() -> Self {
+		Envelope {
+			initial_volume: 0,
+			increase: false,
+			period: 0,
+			volume: 0,
+			timer: 0,
+		}
+	}
+
+	/// Writes the envelope's register (NRx2).
+	pub fn write(&mut self, value: u8) {
+		self.initial_volume = value >> 4;
+		self.increase = (value & 0x08) != 0;
+		self.period = value & 0x07;
+	}
+
+	/// Reads the envelope's register (NRx2).
+	pub fn read(&self) -> u8 {
+		(self.initial_volume << 4) | if self.increase { 0x08 } else { 0 } | self.period
+	}
+
+	/// Returns whether the channel's DAC is powered, which is controlled by
+	/// the upper 5 bits of the envelope's register.
+	pub fn dac_enabled(&self) -> bool {
+		self.read() & 0xF8 != 0
+	}
+
+	/// Restarts the envelope when the channel is triggered.
+	pub fn trigger(&mut self) {
+		self.volume = self.initial_volume;
+		self.timer = self.period;
+	}
+
+	/// Advances the envelope.
+	pub fn clock(&mut self) {
+		if self.period == 0 {
+			return;
+		}
+
+		self.timer = self.timer.saturating_sub(1);
+		if self.timer != 0 {
+			return;
+		}
+		self.timer = self.period;
+
+		if self.increase && self.volume < 15 {
+			self.volume += 1;
+		} else if !self.increase && self.volume > 0 {
+			self.volume -= 1;
+		}
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for LengthCounter {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bool(self.enabled);
+		writer.u16(self.counter);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.enabled = reader.bool()?;
+		self.counter = reader.u16()?;
+
+		if self.counter > self.max {
+			return Err(GameboyError::Io("The savestate holds an invalid length counter."));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Envelope {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.u8(self.read());
+		writer.u8(self.volume);
+		writer.u8(self.timer);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.write(reader.u8()?);
+		self.volume = reader.u8()?;
+		self.timer = reader.u8()?;
+
+		if self.volume > 15 {
+			return Err(GameboyError::Io("The savestate holds an invalid envelope volume."));
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for Envelope {
+	fn default() -> Self {
+		Envelope::new()
+	}
+}
+
+/// The outcome of a sweep's tick.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SweepEvent {
+	/// The frequency is unchanged.
+	Unchanged,
+	/// The channel's frequency should be updated.
+	Frequency(u16),
+	/// The frequency overflowed, and the channel should be silenced.
+	Overflow,
+}
+
+/// Changes the frequency of the first square channel periodically.
+#[derive(Clone)]
+pub struct Sweep {
+	/// The number of ticks between frequency changes, where 0 stops the sweep.
+	period: u8,
+	/// Whether the frequency decreases.
+	negate: bool,
+	/// The amount the frequency is shifted by to calculate its change.
+	shift: u8,
+	enabled: bool,
+	timer: u8,
+	/// The frequency the sweep works on.
+	shadow: u16,
+}
+
+impl Sweep {
+	/// Initialize a disabled sweep.
+	pub fn new() -> Self {
+		Sweep {
+			period: 0,
+			negate: false,
+			shift: 0,
+			enabled: false,
+			timer: 0,
+			shadow: 0,
+		}
+	}
+
+	/// Writes the sweep's register (NR10).
+	pub fn write(&mut self, value: u8) {
+		self.period = (value >> 4) & 0x07;
+		self.negate = (value & 0x08) != 0;
+		self.shift = value & 0x07;
+	}
+
+	/// Reads the sweep's register (NR10).
+	pub fn read(&self) -> u8 {
+		(self.period << 4) | if self.negate { 0x08 } else { 0 } | self.shift
+	}
+
+	/// Restarts the sweep from the given frequency, when the channel is triggered.
+	///
+	/// Returns whether the channel should be silenced, because the next
+	/// frequency overflows.
+	pub fn trigger(&mut self, frequency: u16) -> bool {
+		self.shadow = frequency;
+		self.timer = self.reload_value();
+		self.enabled = self.period != 0 || self.shift != 0;
+
+		self.shift != 0 && self.next_frequency() > 2047
+	}
+
+	/// Advances the sweep.
+	pub fn clock(&mut self) -> SweepEvent {
+		self.timer = self.timer.saturating_sub(1);
+		if self.timer != 0 {
+			return SweepEvent::Unchanged;
+		}
+		self.timer = self.reload_value();
+
+		if !self.enabled || self.period == 0 {
+			return SweepEvent::Unchanged;
+		}
+
+		let frequency = self.next_frequency();
+		if frequency > 2047 {
+			return SweepEvent::Overflow;
+		}
+		if self.shift == 0 {
+			return SweepEvent::Unchanged;
+		}
+
+		self.shadow = frequency;
+
+		// The overflow check is performed again with the new frequency.
+		if self.next_frequency() > 2047 {
+			return SweepEvent::Overflow;
+		}
+
+		SweepEvent::Frequency(frequency)
+	}
+
+	/// The timer treats a period of 0 as 8.
+	fn reload_value(&self) -> u8 {
+		if self.period == 0 { 8 } else { self.period }
+	}
+
+	fn next_frequency(&self) -> u16 {
+		let delta = self.shadow >> self.shift;
+
+		if self.negate {
+			self.shadow - delta
+		} else {
+			self.shadow + delta
+		}
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Sweep {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.u8(self.read());
+		writer.bool(self.enabled);
+		writer.u8(self.timer);
+		writer.u16(self.shadow);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.write(reader.u8()?);
+		self.enabled = reader.bool()?;
+		self.timer = reader.u8()?;
+		self.shadow = reader.u16()?;
+
+		if self.shadow > 2047 {
+			return Err(GameboyError::Io("The savestate holds an invalid sweep frequency."));
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for Sweep {
+	fn default() -> Self {
+		Sweep::new()
+	}
+}
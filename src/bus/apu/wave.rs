@@ -0,0 +1,198 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! The wave channel (channel 3).
+//!
+//! The channel plays a 32-sample waveform from the wave pattern ram,
+//! with 4 bits per sample.
+
+use super::units::*;
+
+#[cfg(feature = "alloc")]
+use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+/// The size of the wave pattern ram, in bytes.
+pub const WAVE_RAM_SIZE: usize = 0x10;
+
+/// The amount each volume code shifts the samples by, where the first code mutes the channel.
+const VOLUME_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+
+/// The number of 4-bit samples in the wave pattern ram.
+const WAVE_SAMPLES: usize = 2 * WAVE_RAM_SIZE;
+
+/// The wave channel's state.
+#[derive(Clone)]
+pub struct Wave {
+	/// Whether the channel is playing.
+	pub(super) enabled: bool,
+	/// Whether the channel's DAC is powered (NR30).
+	dac_enabled: bool,
+	/// The output level, shifting the samples by 0 (mute), 0, 1 or 2 bits.
+	volume_code: u8,
+	/// The 11-bit frequency value, the channel steps to the next sample
+	/// every `(2048 - frequency) * 2` cycles.
+	frequency: u16,
+	/// The number of cycles left until the next sample.
+	timer: usize,
+	/// The index of the sample that is played.
+	position: usize,
+	/// The last sample read from the wave pattern ram.
+	sample: u8,
+	pub(super) length: LengthCounter,
+	/// The waveform's samples, the high nibble is played first.
+	pub(super) wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl Wave {
+	/// Initialize a silent channel.
+	pub fn new() -> Self {
+		Wave {
+			enabled: false,
+			dac_enabled: false,
+			volume_code: 0,
+			frequency: 0,
+			timer: 0,
+			position: 0,
+			sample: 0,
+			length: LengthCounter::new(256),
+			wave_ram: [0; WAVE_RAM_SIZE],
+		}
+	}
+
+	/// Writes the channel's register, given its index (NR30-NR34).
+	pub fn write(&mut self, register: usize, value: u8) {
+		match register {
+			0 => {
+				self.dac_enabled = (value & 0x80) != 0;
+				if !self.dac_enabled {
+					self.enabled = false;
+				}
+			}
+			1 => {
+				self.length.load(value);
+			}
+			2 => {
+				self.volume_code = (value >> 5) & 0x03;
+			}
+			3 => {
+				self.frequency = (self.frequency & 0x700) | value as u16;
+			}
+			_ => {
+				self.frequency = (self.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+				self.length.enabled = (value & 0x40) != 0;
+
+				if value & 0x80 != 0 {
+					self.trigger();
+				}
+			}
+		}
+	}
+
+	/// Reads the channel's register, given its index (NR30-NR34).
+	///
+	/// The write-only bits read as 0.
+	pub fn read(&self, register: usize) -> u8 {
+		match register {
+			0 => if self.dac_enabled { 0x80 } else { 0 },
+			1 => 0,
+			2 => self.volume_code << 5,
+			3 => 0,
+			_ => if self.length.enabled { 0x40 } else { 0 },
+		}
+	}
+
+	/// Restarts the channel from the first sample.
+	///
+	/// The sample buffer isn't refilled, so the last sample is played until
+	/// the next step.
+	fn trigger(&mut self) {
+		self.enabled = self.dac_enabled;
+		self.timer = self.period();
+		self.position = 0;
+		self.length.trigger();
+	}
+
+	/// The number of cycles between samples.
+	fn period(&self) -> usize {
+		(2048 - self.frequency as usize) * 2
+	}
+
+	/// Advances the playback according to the elapsed time.
+	pub fn process(&mut self, cycles: usize) {
+		if !self.enabled {
+			return;
+		}
+
+		let mut cycles = cycles;
+
+		while cycles >= self.timer {
+			cycles -= self.timer;
+			self.timer = self.period();
+			self.position = (self.position + 1) % WAVE_SAMPLES;
+
+			// The high nibble of each byte is played first.
+			let byte = self.wave_ram[self.position / 2];
+			self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+		}
+
+		self.timer -= cycles;
+	}
+
+	/// Advances the length counter.
+	pub fn clock_length(&mut self) {
+		if self.length.clock() {
+			self.enabled = false;
+		}
+	}
+
+	/// Returns the channel's digital output, from 0 to 15.
+	pub fn output(&self) -> u8 {
+		if !self.enabled {
+			return 0;
+		}
+
+		self.sample >> VOLUME_SHIFTS[self.volume_code as usize]
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Wave {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bool(self.enabled);
+		writer.bool(self.dac_enabled);
+		writer.u8(self.volume_code);
+		writer.u16(self.frequency);
+		writer.usize(self.timer);
+		writer.usize(self.position);
+		writer.u8(self.sample);
+		self.length.save_state(writer);
+		writer.bytes(&self.wave_ram);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.enabled = reader.bool()?;
+		self.dac_enabled = reader.bool()?;
+		self.volume_code = reader.u8()?;
+		self.frequency = reader.u16()?;
+		self.timer = reader.usize()?;
+		self.position = reader.usize()?;
+		self.sample = reader.u8()?;
+		self.length.load_state(reader)?;
+		reader.bytes(&mut self.wave_ram)?;
+
+		if self.volume_code > 3 || self.frequency > 2047 || self.position >= WAVE_SAMPLES {
+			return Err(GameboyError::Io("The savestate holds an invalid wave channel."));
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for Wave {
+	fn default() -> Self {
+		Wave::new()
+	}
+}
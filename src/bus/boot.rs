@@ -0,0 +1,68 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Emulate the boot rom, that is mapped over the cartridge on power-on.
+
+use super::Memory;
+use super::memory_range::*;
+use super::cartridge::Storage;
+
+use crate::GameboyError;
+
+/// Boot rom related constants.
+#[allow(missing_docs)]
+pub mod consts {
+	use super::*;
+
+	/// The size of the original GameBoy's boot rom.
+	pub const BOOT_ROM_SIZE: usize = 0x100;
+	/// The size of the GameBoy Color's boot rom.
+	pub const BOOT_ROM_SIZE_CGB: usize = 0x900;
+
+	pub const MMAP_BOOT_ROM: MemoryRange = make_range!(0x0000, 0x00FF);
+	/// The GameBoy Color's boot rom is also mapped after the cartridge header.
+	pub const MMAP_BOOT_ROM_CGB: MemoryRange = make_range!(0x0200, 0x08FF);
+}
+
+use consts::*;
+
+/// The boot rom, mapped over the cartridge until the software unmaps it
+/// by writing to the boot register.
+pub struct BootRom<'a> {
+	data: Storage<'a>,
+}
+
+impl<'a> BootRom<'a> {
+	/// Initialize a new boot rom given its raw data.
+	pub fn new(data: Storage<'a>) -> Result<Self, GameboyError> {
+		if data.len() != BOOT_ROM_SIZE && data.len() != BOOT_ROM_SIZE_CGB {
+			return Err(GameboyError::Io("Invalid boot rom size."));
+		}
+
+		Ok(BootRom { data })
+	}
+
+	/// Returns whether the given address is currently served by the boot rom.
+	pub fn maps(&self, address: u16) -> bool {
+		match address {
+			memory_range!(MMAP_BOOT_ROM) => true,
+			memory_range!(MMAP_BOOT_ROM_CGB) => self.data.len() == BOOT_ROM_SIZE_CGB,
+			_ => false,
+		}
+	}
+}
+
+impl<'a> Memory for BootRom<'a> {
+	fn write(&mut self, address: u16, _value: u8) -> Result<(), GameboyError> {
+		Err(GameboyError::BadAddress(address))
+	}
+
+	fn read(&self, address: u16) -> Result<u8, GameboyError> {
+		if !self.maps(address) {
+			return Err(GameboyError::BadAddress(address));
+		}
+
+		Ok(self.data[address as usize])
+	}
+}
@@ -13,8 +13,14 @@ use super::Memory;
 use super::consts::*;
 use super::memory_range::*;
 
+use core::ops::{Deref, DerefMut};
+
 #[cfg(feature = "alloc")]
 use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::vec;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 /// cartridge addresses-related constants.
 #[allow(missing_docs)]
@@ -41,6 +47,12 @@ pub mod consts {
 	pub const ROM_SIZE: usize = 0x0148;
 	/// The number of RAM banks supported in the cartridge.
 	pub const RAM_SIZE: usize = 0x0149;
+	/// The checksum of the header's bytes from the title to the version.
+	pub const ROM_HEADER_CHECKSUM: usize = 0x014D;
+	/// The checksum of the whole rom, in big-endian.
+	pub const ROM_GLOBAL_CHECKSUM: usize = 0x014E;
+	/// The size of the rom's header, including the entry point and the logo.
+	pub const ROM_HEADER_SIZE: usize = 0x0150;
 
 	/// The size of each rom bank
 	pub const ROM_BANK_SIZE: usize = 0x4000;
@@ -89,7 +101,7 @@ pub enum CartridgeType {
 
 /// Type-1 Memory bank controller has two models that determines the memory layout
 /// at runtime.
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum MemoryModel {
 	/// 2MB ROM, 8KB RAM
 	MoreRom,
@@ -106,33 +118,101 @@ macro_rules! bank_number {
 	($value:tt, $num_bits:tt) => (value & ((1 << $num_bits) - 1))
 }
 
+/// Backing memory of the cartridge's rom and ram.
+///
+/// The cartridge is zero-copy by default, borrowing buffers that are owned by
+/// the caller. When the `alloc` feature is enabled, it may also own them.
+pub enum Storage<'a> {
+	/// A buffer that is owned by the caller.
+	Borrowed(&'a mut [u8]),
+	/// A heap-allocated buffer that is owned by the cartridge.
+	#[cfg(feature = "alloc")]
+	Owned(Box<[u8]>),
+}
+
+impl<'a> Deref for Storage<'a> {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match self {
+			Storage::Borrowed(data) => data,
+			#[cfg(feature = "alloc")]
+			Storage::Owned(data) => data,
+		}
+	}
+}
+
+impl<'a> DerefMut for Storage<'a> {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		match self {
+			Storage::Borrowed(data) => data,
+			#[cfg(feature = "alloc")]
+			Storage::Owned(data) => data,
+		}
+	}
+}
+
 /// The game's cartridge
 #[allow(dead_code)]
 pub struct Cartridge<'a> {
-	rom: &'a mut [u8],
-	ram: &'a mut [u8],
+	rom: Storage<'a>,
+	ram: Storage<'a>,
 	cart_type: CartridgeType,
 	rtc: Rtc,
 	rom_bank: u8,
 	ram_bank: u8,
 	ram_enabled: bool,
 	rtc_mapped: bool,
+	/// Whether the ram was written to since it was last saved.
+	ram_dirty: bool,
+	/// Whether the game disabled the ram after writing to it, since it was
+	/// last saved.
+	ram_released: bool,
+}
+
+/// A copy of the cartridge's mutable state, see `Cartridge::snapshot`.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct CartridgeSnapshot {
+	ram: Box<[u8]>,
+	rtc: Rtc,
+	rom_bank: u8,
+	ram_bank: u8,
+	memory_model: MemoryModel,
+	ram_enabled: bool,
+	rtc_mapped: bool,
+	ram_dirty: bool,
+	ram_released: bool,
 }
 
 impl<'a> Cartridge<'a> {
 	/// Initialize a new cartridge given its raw data.
 	pub fn new(rom: &'a mut [u8], ram: &'a mut [u8]) -> Result<Self, GameboyError> {
-		// Make sure that the rom contains at least a single bank
-		assert!(rom.len() == Cartridge::rom_size(rom)?);
-		assert!(ram.len() == Cartridge::ram_size(rom)?);
+		Cartridge::with_storage(Storage::Borrowed(rom), Storage::Borrowed(ram))
+	}
+
+	/// Initialize a new cartridge given its rom and ram storage.
+	pub fn with_storage(rom: Storage<'a>, ram: Storage<'a>) -> Result<Self, GameboyError> {
+		// Make sure that the rom contains the cartridge header
+		if rom.len() <= RAM_SIZE {
+			return Err(GameboyError::Cartridge("The ROM is too small."));
+		}
+
+		// Make sure that the rom and ram sizes match the cartridge header
+		if rom.len() != Cartridge::rom_size(&rom)? {
+			return Err(GameboyError::Cartridge("ROM size doesn't match its header."));
+		}
+		if ram.len() != Cartridge::ram_size(&rom)? {
+			return Err(GameboyError::Cartridge("RAM size doesn't match the ROM header."));
+		}
 
 		// Find out the type of the cartridge
 		let cart_type = match rom[ROM_CARTRIDGE_TYPE] {
 			0x00 | 0x08 | 0x09 => CartridgeType::RomOnly,
-			0x01 | 0x02 | 0x03 => CartridgeType::MBC1(MemoryModel::MoreRom),
+			0x01..=0x03 => CartridgeType::MBC1(MemoryModel::MoreRom),
 			0x05 | 0x06 => CartridgeType::MBC2,
-			0x0F | 0x10 | 0x11 | 0x12 | 0x13 => CartridgeType::MBC3,
-			0x19 | 0x1A | 0x1C | 0x1D | 0x1E => CartridgeType::MBC5,
+			0x0F..=0x13 => CartridgeType::MBC3,
+			0x19 | 0x1A | 0x1C..=0x1E => CartridgeType::MBC5,
 			_ => { return Err(GameboyError::Cartridge("Invalid cartridge type.")); }
 		};
 
@@ -145,6 +225,8 @@ impl<'a> Cartridge<'a> {
 			ram_bank: 0,
 			ram_enabled: false,
 			rtc_mapped: false,
+			ram_dirty: false,
+			ram_released: false,
 		};
 
 		Ok(cart)
@@ -155,11 +237,142 @@ impl<'a> Cartridge<'a> {
 		&self.rom[memory_offset_range!(ROM_GAME_TITLE)]
 	}
 
+	/// Update the cartridge's state according to the elapsed time.
+	pub fn process(&mut self, cycles: usize) {
+		if CartridgeType::MBC3 == self.cart_type {
+			self.rtc.tick(cycles);
+		}
+	}
+
+	/// Returns the rom's global checksum, as stored in its header.
+	pub fn global_checksum(&self) -> u16 {
+		u16::from_be_bytes([self.rom[ROM_GLOBAL_CHECKSUM], self.rom[ROM_GLOBAL_CHECKSUM + 1]])
+	}
+
+	/// Returns the cartridge's real-time clock.
+	pub fn rtc(&self) -> &Rtc {
+		&self.rtc
+	}
+
+	/// Returns the cartridge's real-time clock for mutation.
+	pub fn rtc_mut(&mut self) -> &mut Rtc {
+		&mut self.rtc
+	}
+
+	/// Returns the cartridge's ram.
+	pub fn ram(&self) -> &[u8] {
+		&self.ram
+	}
+
+	/// Returns the cartridge's ram for mutation, such as importing a save
+	/// from another emulator.
+	///
+	/// Unlike writes through the bus, the changes aren't tracked by `ram_dirty`.
+	pub fn ram_mut(&mut self) -> &mut [u8] {
+		&mut self.ram
+	}
+
+	/// Returns whether the cartridge's ram is kept by a battery.
+	pub fn has_battery(&self) -> bool {
+		matches!(self.rom[ROM_CARTRIDGE_TYPE], 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
+	}
+
+	/// Returns whether the ram was written to since it was last marked as saved.
+	pub fn ram_dirty(&self) -> bool {
+		self.ram_dirty
+	}
+
+	/// Returns whether the game disabled the ram after writing to it, since
+	/// it was last marked as saved.
+	///
+	/// Games disable the ram once they're done writing their save, so it's
+	/// a safe point to persist it.
+	pub fn ram_released(&self) -> bool {
+		self.ram_released
+	}
+
+	/// Marks the ram's current content as saved.
+	pub fn clear_ram_dirty(&mut self) {
+		self.ram_dirty = false;
+		self.ram_released = false;
+	}
+
 	/// Selects whether the ram is enabled for writing.
 	pub fn set_ram_enabled(&mut self, enable: bool) {
 		self.ram_enabled = enable;
 	}
 
+	/// Resets the bank controller to its power-on state, keeping the ram's
+	/// content and the real-time clock.
+	pub fn reset(&mut self) {
+		self.rom_bank = 0;
+		self.ram_bank = 0;
+		self.set_memory_model(MemoryModel::MoreRom);
+		self.ram_enabled = false;
+		self.rtc_mapped = false;
+	}
+
+	/// Returns the MBC1's memory model, which is `MoreRom` for the other
+	/// bank controllers.
+	fn memory_model(&self) -> MemoryModel {
+		match self.cart_type {
+			CartridgeType::MBC1(model) => model,
+			_ => MemoryModel::MoreRom,
+		}
+	}
+
+	/// Selects the MBC1's memory model, if the cartridge has one.
+	fn set_memory_model(&mut self, model: MemoryModel) {
+		if let CartridgeType::MBC1(ref mut current) = self.cart_type {
+			*current = model;
+		}
+	}
+
+	/// Enables or disables the ram on the game's request, marking the ram as
+	/// released if it's disabled after being written to.
+	fn enable_ram(&mut self, enabled: bool) {
+		if self.ram_enabled && !enabled && self.ram_dirty {
+			self.ram_released = true;
+		}
+		self.ram_enabled = enabled;
+	}
+
+	/// Copies the cartridge's ram and bank controller state.
+	#[cfg(feature = "alloc")]
+	pub fn snapshot(&self) -> CartridgeSnapshot {
+		CartridgeSnapshot {
+			ram: self.ram.to_vec().into_boxed_slice(),
+			rtc: self.rtc.clone(),
+			rom_bank: self.rom_bank,
+			ram_bank: self.ram_bank,
+			memory_model: self.memory_model(),
+			ram_enabled: self.ram_enabled,
+			rtc_mapped: self.rtc_mapped,
+			ram_dirty: self.ram_dirty,
+			ram_released: self.ram_released,
+		}
+	}
+
+	/// Restores the state copied by `snapshot`.
+	#[cfg(feature = "alloc")]
+	pub fn restore(&mut self, snapshot: &CartridgeSnapshot) -> Result<(), GameboyError> {
+		if snapshot.ram.len() != self.ram.len() {
+			return Err(GameboyError::Cartridge("The snapshot's RAM size doesn't match the cartridge."));
+		}
+
+		self.ram.copy_from_slice(&snapshot.ram);
+		self.rtc = snapshot.rtc.clone();
+		self.rom_bank = snapshot.rom_bank;
+		self.ram_bank = snapshot.ram_bank;
+		self.set_memory_model(snapshot.memory_model);
+		self.ram_enabled = snapshot.ram_enabled;
+		self.rtc_mapped = snapshot.rtc_mapped;
+		self.ram_dirty = snapshot.ram_dirty;
+		self.ram_released = snapshot.ram_released;
+
+		Ok(())
+	}
+
 	/// Set the current active rom bank of the cartridge.
 	///
 	/// The command to set the rom bank is given by writing to a corresponding
@@ -168,7 +381,7 @@ impl<'a> Cartridge<'a> {
 		// TODO implement this. The implementation should depend on the cartridge type.
 		match address {
 			memory_range!(ROM_BANK_SELECT) => {
-				let num_banks = Cartridge::num_rom_banks(self.rom)?;
+				let num_banks = Cartridge::num_rom_banks(&self.rom)?;
 
 				if value >= num_banks {
 					return Err(GameboyError::BadValue(value));
@@ -226,40 +439,108 @@ impl<'a> Cartridge<'a> {
 
 	/// Implementation of `write` for CartridgeType::MBC1 devices.
 	fn write_mbc1(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
-		// Get the MBC's current memory model
-		let model_select: &mut MemoryModel = match self.cart_type {
-			CartridgeType::MBC1(ref mut model) => { model }
-			_ => {
-				return Err(GameboyError::Cartridge("MBC1 memory model was expected."));
-			}
-		};
 		// The write operation's implications depends on the address
 		// that we're writing to, as some address ranges are reserved
 		// for swapping memory model or changing the active rom bank.
 		match address {
+			memory_range!(RAM_ENABLE_SELECT) => {
+				// Writing 0x0A to the lower bits enables the ram, otherwise it's disabled.
+				self.enable_ram((value & 0x0F) == 0x0A);
+				Ok(())
+			}
+			memory_range!(ROM_BANK_SELECT) => {
+				// The lower 5 bits of the rom bank, where bank 0 selects bank 1.
+				self.rom_bank = match value & 0x1F {
+					0 => 1,
+					bank => bank,
+				};
+				Ok(())
+			}
+			memory_range!(RAM_BANK_SELECT) => {
+				// Either the upper bits of the rom bank or the ram bank,
+				// depending on the memory model.
+				self.ram_bank = value & 0x03;
+				Ok(())
+			}
 			memory_range!(MEMORY_MODEL_SELECT) => {
 				// Change active memory model.
-				*model_select = match value & 1 {
+				self.set_memory_model(match value & 1 {
 					0 => { MemoryModel::MoreRom }
 					_ => { MemoryModel::MoreRam }
+				});
+				Ok(())
+			}
+			memory_range!(MMAP_RAM_BANK_SW) => {
+				if !self.ram_enabled {
+					return Err(GameboyError::Io("Ram is not enabled for writing."));
+				}
+
+				let ram_offset = self.ram_offset_mbc1(address);
+				if self.ram.len() <= ram_offset {
+					return Err(GameboyError::Cartridge("write_mbc1: Invalid ram bank number."));
+				}
+
+				// Perform the actual write.
+				self.ram[ram_offset] = value;
+				self.ram_dirty = true;
+				Ok(())
+			}
+			_ => Err(GameboyError::BadAddress(address))
+		}
+	}
+
+	/// Implementation of `read` for CartridgeType::MBC1 devices.
+	fn read_mbc1(&self, address: u16) -> Result<u8, GameboyError> {
+		let num_banks = Cartridge::num_rom_banks(&self.rom)? as usize;
+		// The upper bits of the rom bank also apply to the first bank in the
+		// MoreRam model.
+		let upper_bank = (self.ram_bank as usize) << 5;
+
+		match address {
+			memory_range!(MMAP_ROM_BANK0) => {
+				let bank = match self.memory_model() {
+					MemoryModel::MoreRom => 0,
+					MemoryModel::MoreRam => upper_bank % num_banks,
 				};
-				return Ok(());
+
+				Ok(self.rom[bank * ROM_BANK_SIZE + address as usize])
 			}
-			memory_range!(ROM_BANK_SELECT) => {
-				// Change active rom bank.
-				self.set_rom_bank(address, value)?;
-				return Ok(());
+			memory_range!(MMAP_ROM_BANK_SW) => {
+				// Get the data from the switchable rom bank.
+				let lower_bank = if self.rom_bank == 0 { 1 } else { self.rom_bank } as usize;
+				let bank = (upper_bank | lower_bank) % num_banks;
+				let bank_offset: usize = address as usize - range_start!(MMAP_ROM_BANK_SW);
+
+				Ok(self.rom[bank * ROM_BANK_SIZE + bank_offset])
 			}
-			_ => {
-				// The rest of the layout depends on the memory model.
-				match model_select {
-					MemoryModel::MoreRom => { unimplemented!(); }
-					MemoryModel::MoreRam => { unimplemented!(); }
+			memory_range!(MMAP_RAM_BANK_SW) => {
+				// Make sure that we can currently read from this region.
+				if !self.ram_enabled {
+					return Err(GameboyError::Io("Ram is not enabled for reading."));
+				}
+
+				let ram_offset = self.ram_offset_mbc1(address);
+				if self.ram.len() <= ram_offset {
+					return Err(GameboyError::Cartridge("read_mbc1: Invalid ram bank number."));
 				}
+
+				Ok(self.ram[ram_offset])
 			}
+			_ => Err(GameboyError::BadAddress(address))
 		}
 	}
 
+	/// Returns the ram offset of the given MBC1 address, whose bank is only
+	/// switchable in the MoreRam model.
+	fn ram_offset_mbc1(&self, address: u16) -> usize {
+		let bank = match self.memory_model() {
+			MemoryModel::MoreRom => 0,
+			MemoryModel::MoreRam => self.ram_bank as usize,
+		};
+
+		RAM_BANK_SIZE * bank + (address as usize - range_start!(MMAP_RAM_BANK_SW))
+	}
+
 	/// Implementation of `write` for CartridgeType::MBC3 devices.
 	fn write_mbc3(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
 		// The memory model here must be MBC3.
@@ -272,13 +553,13 @@ impl<'a> Cartridge<'a> {
 			memory_range!(RAM_ENABLE_SELECT) => {
 				// Writing bits 1 and 3 to this range enables the ram and rtc registers,
 				// otherwise they'll be disabled.
-				self.ram_enabled = (value & 0x0A) != 0;
-				return Ok(());
+				self.enable_ram((value & 0x0A) != 0);
+				Ok(())
 			}
 			memory_range!(ROM_BANK_SELECT) => {
 				// Change active rom bank.
 				self.set_rom_bank(address, value)?;
-				return Ok(());
+				Ok(())
 			}
 			memory_range!(RAM_BANK_SELECT) => {
 				if RTC_CONTROL_RANGE.contains(&value) {
@@ -290,12 +571,12 @@ impl<'a> Cartridge<'a> {
 					self.set_ram_bank(value)?;
 					self.rtc_mapped = false;
 				}
-				return Ok(());
+				Ok(())
 			}
 			memory_range!(CLOCK_DATA_LATCH) => {
 				// Update the clock's registers.
 				self.rtc.latch(value);
-				return Ok(());
+				Ok(())
 			}
 			memory_range!(MMAP_RAM_BANK_SW) => {
 				if !self.ram_enabled {
@@ -316,8 +597,9 @@ impl<'a> Cartridge<'a> {
 
 					// Perform the actual write.
 					self.ram[ram_offset] = value;
+					self.ram_dirty = true;
 				}
-				return Ok(());
+				Ok(())
 			}
 			_ => Err(GameboyError::BadAddress(address))
 		}
@@ -410,6 +692,91 @@ impl<'a> Cartridge<'a> {
 		Ok(num_banks)
 	}
 
+	/// Get the title of the game, as written in the given rom's header.
+	pub fn header_title(rom: &[u8]) -> Result<&[u8], GameboyError> {
+		Cartridge::check_header(rom)?;
+
+		Ok(&rom[memory_offset_range!(ROM_GAME_TITLE)])
+	}
+
+	/// Describes the cartridge's hardware, as written in the given rom's
+	/// header, such as "MBC3+RAM+BATTERY".
+	///
+	/// Returns `None` for unknown cartridge types.
+	pub fn type_name(rom: &[u8]) -> Result<Option<&'static str>, GameboyError> {
+		Cartridge::check_header(rom)?;
+
+		let name = match rom[ROM_CARTRIDGE_TYPE] {
+			0x00 => "ROM ONLY",
+			0x01 => "MBC1",
+			0x02 => "MBC1+RAM",
+			0x03 => "MBC1+RAM+BATTERY",
+			0x05 => "MBC2",
+			0x06 => "MBC2+BATTERY",
+			0x08 => "ROM+RAM",
+			0x09 => "ROM+RAM+BATTERY",
+			0x0B => "MMM01",
+			0x0C => "MMM01+RAM",
+			0x0D => "MMM01+RAM+BATTERY",
+			0x0F => "MBC3+TIMER+BATTERY",
+			0x10 => "MBC3+TIMER+RAM+BATTERY",
+			0x11 => "MBC3",
+			0x12 => "MBC3+RAM",
+			0x13 => "MBC3+RAM+BATTERY",
+			0x19 => "MBC5",
+			0x1A => "MBC5+RAM",
+			0x1B => "MBC5+RAM+BATTERY",
+			0x1C => "MBC5+RUMBLE",
+			0x1D => "MBC5+RUMBLE+RAM",
+			0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+			0x20 => "MBC6",
+			0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+			0xFC => "POCKET CAMERA",
+			0xFD => "BANDAI TAMA5",
+			0xFE => "HuC3",
+			0xFF => "HuC1+RAM+BATTERY",
+			_ => return Ok(None),
+		};
+
+		Ok(Some(name))
+	}
+
+	/// Returns whether the given rom's header checksum matches its header,
+	/// which the boot rom verifies before running the game.
+	pub fn header_checksum_valid(rom: &[u8]) -> Result<bool, GameboyError> {
+		Cartridge::check_header(rom)?;
+
+		let checksum = rom[range_start!(ROM_GAME_TITLE)..ROM_HEADER_CHECKSUM].iter()
+			.fold(0_u8, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1));
+
+		Ok(checksum == rom[ROM_HEADER_CHECKSUM])
+	}
+
+	/// Returns whether the given rom's global checksum matches its contents.
+	///
+	/// The hardware never verifies the global checksum, so many homebrew
+	/// games leave it out.
+	pub fn global_checksum_valid(rom: &[u8]) -> Result<bool, GameboyError> {
+		Cartridge::check_header(rom)?;
+
+		let checksum = rom.iter()
+			.enumerate()
+			.filter(|&(offset, _)| offset != ROM_GLOBAL_CHECKSUM && offset != ROM_GLOBAL_CHECKSUM + 1)
+			.fold(0_u16, |checksum, (_, &byte)| checksum.wrapping_add(byte as u16));
+		let expected = u16::from_be_bytes([rom[ROM_GLOBAL_CHECKSUM], rom[ROM_GLOBAL_CHECKSUM + 1]]);
+
+		Ok(checksum == expected)
+	}
+
+	/// Makes sure that the given rom contains the whole header.
+	fn check_header(rom: &[u8]) -> Result<(), GameboyError> {
+		if rom.len() < ROM_HEADER_SIZE {
+			return Err(GameboyError::Cartridge("The ROM is too small."));
+		}
+
+		Ok(())
+	}
+
 	/// Create a ram buffer for the cartridge.
 	#[inline(always)]
 	#[cfg(feature = "alloc")]
@@ -431,25 +798,103 @@ impl<'a> Cartridge<'a> {
 	}
 }
 
+#[cfg(feature = "alloc")]
+impl Cartridge<'static> {
+	/// Initialize a new cartridge that owns the given rom, and allocates
+	/// its ram on the heap.
+	pub fn from_rom(rom: Box<[u8]>) -> Result<Self, GameboyError> {
+		if rom.len() <= RAM_SIZE {
+			return Err(GameboyError::Cartridge("The ROM is too small."));
+		}
+
+		let ram: Box<[u8]> = Cartridge::make_ram(&rom)?;
+
+		Cartridge::with_storage(Storage::Owned(rom), Storage::Owned(ram))
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> SaveState for Cartridge<'a> {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.usize(self.ram.len());
+		writer.bytes(&self.ram);
+		self.rtc.save_state(writer);
+		writer.u8(self.rom_bank);
+		writer.u8(self.ram_bank);
+		writer.bool(self.ram_enabled);
+		writer.bool(self.rtc_mapped);
+		writer.bool(self.ram_dirty);
+		writer.bool(self.memory_model() == MemoryModel::MoreRam);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		if reader.usize()? != self.ram.len() {
+			return Err(GameboyError::Io("The savestate's RAM size doesn't match the cartridge."));
+		}
+
+		// The state is validated before any of it is restored.
+		let mut ram = vec![0_u8; self.ram.len()];
+		reader.bytes(&mut ram)?;
+		let mut rtc = self.rtc.clone();
+		rtc.load_state(reader)?;
+		let rom_bank = reader.u8()?;
+		let ram_bank = reader.u8()?;
+		let ram_enabled = reader.bool()?;
+		let rtc_mapped = reader.bool()?;
+		let ram_dirty = reader.bool()?;
+		let memory_model = if reader.bool()? { MemoryModel::MoreRam } else { MemoryModel::MoreRom };
+
+		// The MBC1's bank registers are masked by the banks that exist when they're used.
+		let (rom_bank_valid, ram_bank_valid) = match self.cart_type {
+			CartridgeType::MBC1(_) => (rom_bank < 0x20, ram_bank < 4),
+			_ => (
+				rom_bank < Cartridge::num_rom_banks(&self.rom)?,
+				ram_bank == 0 || RAM_BANK_SIZE * (ram_bank as usize) < self.ram.len(),
+			),
+		};
+
+		if !rom_bank_valid {
+			return Err(GameboyError::Io("The savestate's rom bank doesn't exist."));
+		}
+		if !ram_bank_valid {
+			return Err(GameboyError::Io("The savestate's ram bank doesn't exist."));
+		}
+
+		self.ram.copy_from_slice(&ram);
+		self.rtc = rtc;
+		self.rom_bank = rom_bank;
+		self.ram_bank = ram_bank;
+		self.set_memory_model(memory_model);
+		self.ram_enabled = ram_enabled;
+		self.rtc_mapped = rtc_mapped;
+		self.ram_dirty = ram_dirty;
+		// The save point isn't part of the state, the ram is saved by the
+		// interval instead.
+		self.ram_released = false;
+
+		Ok(())
+	}
+}
+
 impl<'a> Memory for Cartridge<'a> {
 	/// Write data into the cartridge.
 	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
 		match self.cart_type {
 			// No bank controller
 			CartridgeType::RomOnly => {
-				return self.write_romonly(address, value);
+				self.write_romonly(address, value)
 			}
 			// Type-1 bank controller
 			CartridgeType::MBC1(_) => {
-				return self.write_mbc1(address, value);
+				self.write_mbc1(address, value)
 			}
 			// Type-3 bank controller
 			CartridgeType::MBC3 => {
-				return self.write_mbc3(address, value);
+				self.write_mbc3(address, value)
 			}
 			_ => {
 				// These cartridge types are currently not implemented.
-				return Err(GameboyError::NotImplemented);
+				Err(GameboyError::NotImplemented)
 			}
 		}
 	}
@@ -459,20 +904,25 @@ impl<'a> Memory for Cartridge<'a> {
 		match self.cart_type {
 			// No bank controller
 			CartridgeType::RomOnly => {
-				return self.read_romonly(address);
+				self.read_romonly(address)
+			}
+			// Type-1 bank controller
+			CartridgeType::MBC1(_) => {
+				self.read_mbc1(address)
 			}
 			// Type-3 bank controller
 			CartridgeType::MBC3 => {
-				return self.read_mbc3(address);
+				self.read_mbc3(address)
 			}
 			_ => {
 				// These cartridge types are currently not implemented.
-				return Err(GameboyError::NotImplemented);
+				Err(GameboyError::NotImplemented)
 			}
 		}
 	}
 }
 
+/// Cartridge test utilities.
 #[cfg(test)]
 #[allow(dead_code)]
 pub mod tests {
@@ -541,9 +991,91 @@ pub mod tests {
 
 		// Initialize the ram for reading & writing.
 		cart.write(ram_enable, 0x0A)?;
+		assert!(!cart.ram_dirty());
 		cart.write(ram_start, 0x13)?;
 		assert!(0x13 == cart.read(ram_start)?);
 
+		// The write is tracked until the ram is saved.
+		assert!(cart.ram_dirty() && cart.ram()[0] == 0x13);
+		cart.clear_ram_dirty();
+		assert!(!cart.ram_dirty());
+
+		// Direct writes to the ram are visible through the bus, but not tracked.
+		cart.ram_mut()[1] = 0x37;
+		assert!(cart.read(ram_start + 1)? == 0x37 && !cart.ram_dirty());
+
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "alloc")]
+	fn test_mbc1_banking() -> Result<(), GameboyError> {
+		// A 1MB rom with 32KB of ram, whose banks start with their number.
+		let mut rom = vec![0_u8; 64 * ROM_BANK_SIZE];
+		rom[ROM_CARTRIDGE_TYPE] = 0x03;
+		rom[ROM_SIZE] = 0x05;
+		rom[RAM_SIZE] = 0x03;
+		for bank in 1..64 {
+			rom[bank * ROM_BANK_SIZE] = bank as u8;
+		}
+
+		let mut cart = Cartridge::from_rom(rom.into_boxed_slice())?;
+
+		// Selecting bank 0 selects bank 1 instead.
+		assert!(cart.read(0x4000)? == 1);
+		cart.write(0x2000, 0x00)?;
+		assert!(cart.read(0x4000)? == 1);
+		cart.write(0x2000, 0x05)?;
+		assert!(cart.read(0x4000)? == 5);
+
+		// The second register holds the upper bits of the rom bank.
+		cart.write(0x4000, 0x01)?;
+		assert!(cart.read(0x4000)? == 0x25);
+		assert!(cart.read(0x0000)? == 0);
+
+		// In the MoreRam model, they also apply to the first bank, and select the ram bank.
+		cart.write(0x6000, 0x01)?;
+		assert!(cart.read(0x0000)? == 0x20);
+
+		cart.write(0x0000, 0x0A)?;
+		cart.write(0xA000, 0x42)?;
+		cart.write(0x4000, 0x02)?;
+		assert!(cart.read(0xA000)? == 0);
+		cart.write(0x4000, 0x01)?;
+		assert!(cart.read(0xA000)? == 0x42);
+		assert!(cart.ram()[RAM_BANK_SIZE] == 0x42);
+
+		// Disabling the ram after writing to it releases it.
+		cart.write(0x0000, 0x00)?;
+		assert!(cart.read(0xA000).is_err() && cart.ram_released());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_header() -> Result<(), GameboyError> {
+		let mut rom = empty_rom(CartridgeType::MBC3);
+
+		assert!(Cartridge::header_title(&rom)? == TEST_CARTRIDGE_TITLE);
+		assert!(Cartridge::type_name(&rom)? == Some("MBC3+RAM+BATTERY"));
+
+		// The checksums of an empty rom don't match.
+		assert!(!Cartridge::header_checksum_valid(&rom)?);
+		assert!(!Cartridge::global_checksum_valid(&rom)?);
+
+		let header_checksum = rom[0x134..0x14D].iter()
+			.fold(0_u8, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1));
+		rom[ROM_HEADER_CHECKSUM] = header_checksum;
+		assert!(Cartridge::header_checksum_valid(&rom)?);
+
+		let global_checksum = rom.iter().fold(0_u16, |checksum, &byte| checksum.wrapping_add(byte as u16));
+		rom[ROM_GLOBAL_CHECKSUM..ROM_GLOBAL_CHECKSUM + 2].copy_from_slice(&global_checksum.to_be_bytes());
+		assert!(Cartridge::global_checksum_valid(&rom)?);
+
+		rom[ROM_CARTRIDGE_TYPE] = 0x42;
+		assert!(Cartridge::type_name(&rom)?.is_none());
+		assert!(Cartridge::type_name(&rom[..0x100]).is_err());
+
 		Ok(())
 	}
 }
@@ -0,0 +1,174 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Emulate the GameBoy Color's video ram DMA controller.
+//!
+//! The controller's registers are kept here, while the actual copying is
+//! performed by the system bus, which has access to both the source and
+//! the video ram.
+
+use super::Memory;
+use super::memory_range::*;
+
+use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+/// HDMA related constants.
+#[allow(missing_docs)]
+pub mod consts {
+	use super::*;
+
+	pub const IO_HDMA1: u16 = 0xFF51;
+	pub const IO_HDMA2: u16 = 0xFF52;
+	pub const IO_HDMA3: u16 = 0xFF53;
+	pub const IO_HDMA4: u16 = 0xFF54;
+	pub const IO_HDMA5: u16 = 0xFF55;
+
+	pub const MMAP_IO_HDMA: MemoryRange = make_range!(0xFF51, 0xFF55);
+
+	/// The number of bytes that are copied at once.
+	pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+	/// The number of cycles the cpu is stalled for every copied block,
+	/// in normal speed mode.
+	pub const HDMA_BLOCK_CYCLES: usize = 32;
+}
+
+use consts::*;
+
+/// The video ram DMA controller's state.
+#[derive(Clone)]
+pub struct Hdma {
+	source: u16,
+	destination: u16,
+	/// The number of blocks left to copy, minus one.
+	remaining: u8,
+	/// Whether a transfer is copying a block every H-Blank.
+	hblank_active: bool,
+	/// Whether a general purpose transfer should be performed immediately.
+	general_pending: bool,
+}
+
+impl Hdma {
+	/// Initialize a new HDMA controller.
+	pub fn new() -> Self {
+		Hdma {
+			source: 0,
+			destination: 0,
+			remaining: 0x7F,
+			hblank_active: false,
+			general_pending: false,
+		}
+	}
+
+	/// Returns whether a transfer is copying a block every H-Blank.
+	pub fn hblank_active(&self) -> bool {
+		self.hblank_active
+	}
+
+	/// Returns whether a general purpose transfer is waiting to be performed.
+	pub fn general_pending(&self) -> bool {
+		self.general_pending
+	}
+
+	/// Advances the transfer by a single block.
+	///
+	/// Returns the block's source and destination addresses, or `None`
+	/// if no transfer is in progress.
+	pub fn next_block(&mut self) -> Option<(u16, u16)> {
+		if !self.hblank_active && !self.general_pending {
+			return None;
+		}
+
+		let block = (self.source, self.destination);
+
+		self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+		// The destination always resides within the video ram.
+		self.destination = 0x8000 | (self.destination.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FF0);
+
+		if self.remaining == 0 {
+			self.hblank_active = false;
+			self.general_pending = false;
+		}
+		self.remaining = self.remaining.wrapping_sub(1) & 0x7F;
+
+		Some(block)
+	}
+}
+
+impl Default for Hdma {
+	fn default() -> Self {
+		Hdma::new()
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Hdma {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.u16(self.source);
+		writer.u16(self.destination);
+		writer.u8(self.remaining);
+		writer.bool(self.hblank_active);
+		writer.bool(self.general_pending);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.source = reader.u16()?;
+		self.destination = reader.u16()?;
+		self.remaining = reader.u8()?;
+		self.hblank_active = reader.bool()?;
+		self.general_pending = reader.bool()?;
+
+		Ok(())
+	}
+}
+
+impl Memory for Hdma {
+	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
+		match address {
+			IO_HDMA1 => {
+				self.source = (self.source & 0x00F0) | ((value as u16) << 8);
+			}
+			IO_HDMA2 => {
+				// The lower 4 bits are ignored.
+				self.source = (self.source & 0xFF00) | ((value & 0xF0) as u16);
+			}
+			IO_HDMA3 => {
+				// The destination always resides within the video ram.
+				self.destination = 0x8000 | (self.destination & 0x00F0) | (((value & 0x1F) as u16) << 8);
+			}
+			IO_HDMA4 => {
+				self.destination = 0x8000 | (self.destination & 0x1F00) | ((value & 0xF0) as u16);
+			}
+			IO_HDMA5 => {
+				if self.hblank_active && (value & 0x80) == 0 {
+					// Writing bit 7 as zero cancels an active H-Blank transfer.
+					self.hblank_active = false;
+				} else {
+					self.remaining = value & 0x7F;
+					self.hblank_active = (value & 0x80) != 0;
+					self.general_pending = !self.hblank_active;
+				}
+			}
+			_ => {
+				return Err(GameboyError::BadAddress(address));
+			}
+		}
+
+		Ok(())
+	}
+
+	fn read(&self, address: u16) -> Result<u8, GameboyError> {
+		match address {
+			// The source and destination registers are write-only.
+			IO_HDMA1 | IO_HDMA2 | IO_HDMA3 | IO_HDMA4 => Ok(0xFF),
+			IO_HDMA5 => {
+				// Bit 7 is cleared while a transfer is active.
+				let inactive = if self.hblank_active { 0 } else { 0x80 };
+				Ok(inactive | self.remaining)
+			}
+			_ => Err(GameboyError::BadAddress(address)),
+		}
+	}
+}
@@ -0,0 +1,201 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Emulate the interrupt controller, which owns the IF and IE registers.
+
+use super::Memory;
+use super::io::consts::{IO_IF, IO_IE};
+
+use crate::GameboyError;
+use crate::cpu::interrupts::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+/// The IF register's bits that belong to interrupts, the rest read as 1.
+const IF_MASK: u8 = 0x1F;
+
+/// Collects the interrupts raised by the peripherals and presents the
+/// enabled ones to the cpu.
+///
+/// The controller is mapped to the IF and IE registers.
+/// Requested interrupts are kept in IF regardless of IE, so they're only
+/// masked when they're dispatched, see `pending`.
+#[derive(Clone)]
+pub struct InterruptController {
+	/// The IF register, the requested interrupts.
+	flag: InterruptMask,
+	/// The IE register, the enabled interrupts.
+	/// All of its bits are writable, although only 5 belong to interrupts.
+	enable: InterruptMask,
+}
+
+impl InterruptController {
+	/// Initialize a controller without requested or enabled interrupts.
+	pub fn new() -> Self {
+		InterruptController {
+			flag: InterruptMask::empty(),
+			enable: InterruptMask::empty(),
+		}
+	}
+
+	/// Clears the IF and IE registers.
+	pub fn reset(&mut self) {
+		self.flag = InterruptMask::empty();
+		self.enable = InterruptMask::empty();
+	}
+
+	/// Returns the requested interrupts (IF).
+	pub fn flag(&self) -> InterruptMask {
+		self.flag
+	}
+
+	/// Sets the requested interrupts (IF).
+	pub fn set_flag(&mut self, flag: InterruptMask) {
+		self.flag = InterruptMask::from_bits(flag.bits() & IF_MASK);
+	}
+
+	/// Returns the enabled interrupts (IE).
+	pub fn enable(&self) -> InterruptMask {
+		self.enable
+	}
+
+	/// Sets the enabled interrupts (IE).
+	pub fn set_enable(&mut self, enable: InterruptMask) {
+		self.enable = enable;
+	}
+
+	/// Requests the given interrupt, as if a peripheral raised it.
+	pub fn request(&mut self, interrupt: Interrupt) {
+		self.flag.insert(interrupt);
+	}
+
+	/// Moves the source's interrupts into the IF register.
+	pub fn collect(&mut self, source: &mut dyn InterruptSource) {
+		self.flag |= source.interrupts();
+		source.clear();
+	}
+
+	/// Returns the interrupts that are both requested and enabled, which
+	/// wake a halting cpu.
+	pub fn pending(&self) -> InterruptMask {
+		self.flag & self.enable
+	}
+
+	/// Returns the pending interrupt with the highest priority, and removes
+	/// it from the IF register.
+	pub fn acknowledge(&mut self) -> Option<Interrupt> {
+		let interrupt = self.pending().iter().next();
+
+		if let Some(interrupt) = interrupt {
+			self.flag.remove(interrupt);
+		}
+
+		interrupt
+	}
+}
+
+impl Default for InterruptController {
+	fn default() -> Self {
+		InterruptController::new()
+	}
+}
+
+impl Memory for InterruptController {
+	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
+		match address {
+			IO_IF => {
+				self.set_flag(InterruptMask::from_bits(value));
+			}
+			IO_IE => {
+				self.set_enable(InterruptMask::from_bits(value));
+			}
+			_ => {
+				panic!("Write operation is not implemented for {:x}", address);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn read(&self, address: u16) -> Result<u8, GameboyError> {
+		match address {
+			IO_IF => {
+				Ok(self.flag.bits() | !IF_MASK)
+			}
+			IO_IE => {
+				Ok(self.enable.bits())
+			}
+			_ => {
+				panic!("Read operation is not implemented for {:x}", address);
+			}
+		}
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for InterruptController {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.u8(self.flag.bits());
+		writer.u8(self.enable.bits());
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.set_flag(InterruptMask::from_bits(reader.u8()?));
+		self.enable = InterruptMask::from_bits(reader.u8()?);
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Source(InterruptMask);
+
+	impl InterruptSource for Source {
+		fn interrupts(&self) -> InterruptMask {
+			self.0
+		}
+
+		fn clear(&mut self) {
+			self.0 = InterruptMask::empty();
+		}
+	}
+
+	#[test]
+	fn test_registers() -> Result<(), GameboyError> {
+		let mut controller = InterruptController::new();
+
+		// IF's unused bits read as 1, while IE keeps all of its bits.
+		controller.write(IO_IF, 0xFF)?;
+		controller.write(IO_IE, 0xA4)?;
+		assert!(controller.read(IO_IF)? == 0xFF && controller.flag().bits() == 0x1F);
+		assert!(controller.read(IO_IE)? == 0xA4);
+
+		controller.write(IO_IF, 0)?;
+		assert!(controller.read(IO_IF)? == 0xE0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_collect() -> Result<(), GameboyError> {
+		let mut controller = InterruptController::new();
+		let mut source = Source([Interrupt::Serial, Interrupt::LcdStat].iter().copied().collect());
+
+		// Disabled interrupts are requested, but they aren't pending.
+		controller.collect(&mut source);
+		assert!(source.0.is_empty());
+		assert!(controller.flag().bits() == 0x0A && controller.pending().is_empty());
+
+		// The pending interrupts are acknowledged by priority.
+		controller.write(IO_IE, 0x1F)?;
+		assert!(controller.acknowledge() == Some(Interrupt::LcdStat));
+		assert!(controller.acknowledge() == Some(Interrupt::Serial));
+		assert!(controller.acknowledge().is_none());
+		assert!(controller.read(IO_IF)? == 0xE0);
+
+		Ok(())
+	}
+}
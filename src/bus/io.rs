@@ -9,6 +9,8 @@ use super::memory_range::*;
 
 use crate::config::*;
 use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 #[allow(unused, missing_docs)]
 pub mod consts {
@@ -21,30 +23,12 @@ pub mod consts {
 	pub const IO_SB: u16 = 0xFF01;
 	pub const IO_SC: u16 = 0xFF02;
 	pub const IO_IF: u16 = 0xFF0F;
-	pub const IO_NR10: u16 = 0xFF10;
-	pub const IO_NR11: u16 = 0xFF11;
-	pub const IO_NR12: u16 = 0xFF12;
-	pub const IO_NR13: u16 = 0xFF13;
-	pub const IO_NR14: u16 = 0xFF14;
-	pub const IO_NR21: u16 = 0xFF16;
-	pub const IO_NR22: u16 = 0xFF17;
-	pub const IO_NR23: u16 = 0xFF18;
-	pub const IO_NR24: u16 = 0xFF19;
-	pub const IO_NR30: u16 = 0xFF1A;
-	pub const IO_NR31: u16 = 0xFF1B;
-	pub const IO_NR32: u16 = 0xFF1C;
-	pub const IO_NR33: u16 = 0xFF1D;
-	pub const IO_NR34: u16 = 0xFF1E;
-	pub const IO_NR41: u16 = 0xFF20;
-	pub const IO_NR42: u16 = 0xFF21;
-	pub const IO_NR43: u16 = 0xFF22;
-	pub const IO_NR44: u16 = 0xFF23;
-	pub const IO_NR50: u16 = 0xFF24;
-	pub const IO_NR51: u16 = 0xFF25;
-	pub const IO_NR52: u16 = 0xFF26;
-	pub const IO_WAVE_PATTERN: MemoryRange = make_range!(0xFF30, 0xFF3F);
 
 	pub const IO_DMA: u16 = 0xFF46;
+	/// Prepares a speed switch (GBC).
+	pub const IO_KEY1: u16 = 0xFF4D;
+	/// Writing a non-zero value unmaps the boot rom.
+	pub const IO_BOOT: u16 = 0xFF50;
 
 	pub const IO_IE: u16 = 0xFFFF;
 
@@ -58,9 +42,12 @@ macro_rules! port_offset {
 use consts::*;
 
 /// Handles read and write operation on I/O registers.
+#[derive(Clone)]
 pub struct IoPorts {
 	/// Registers that are mapped to the range 0xFF00-0xFF4B.
 	registers: [u8; IO_SIZE],
+	/// The emulated hardware model, some registers exist only on the GBC.
+	model: HardwareModel,
 }
 
 impl IoPorts {
@@ -68,6 +55,7 @@ impl IoPorts {
 	pub fn new(config: &Config) -> Self {
 		let mut io = IoPorts {
 			registers: [0_u8; IO_SIZE],
+			model: config.model,
 		};
 
 		// Reset the registers' state.
@@ -78,37 +66,63 @@ impl IoPorts {
 
 	/// Reset the I/O registers.
 	pub fn reset(&mut self, config: &Config) {
-		self.registers[port_offset!(IO_NR10)] = 0x80;
-		self.registers[port_offset!(IO_NR10)] = 0x80;
-		self.registers[port_offset!(IO_NR11)] = 0xBF;
-		self.registers[port_offset!(IO_NR12)] = 0xF3;
-		self.registers[port_offset!(IO_NR14)] = 0xBF;
-		self.registers[port_offset!(IO_NR21)] = 0x3F;
-		self.registers[port_offset!(IO_NR22)] = 0x00;
-		self.registers[port_offset!(IO_NR24)] = 0xBF;
-		self.registers[port_offset!(IO_NR30)] = 0x7F;
-		self.registers[port_offset!(IO_NR31)] = 0xFF;
-		self.registers[port_offset!(IO_NR32)] = 0x9F;
-		self.registers[port_offset!(IO_NR34)] = 0xBF;
-		self.registers[port_offset!(IO_NR41)] = 0xFF;
-		self.registers[port_offset!(IO_NR42)] = 0x00;
-		self.registers[port_offset!(IO_NR43)] = 0x00;
-		self.registers[port_offset!(IO_NR44)] = 0xBF;
-		self.registers[port_offset!(IO_NR50)] = 0x77;
-		self.registers[port_offset!(IO_NR51)] = 0xF3;
-		self.registers[port_offset!(IO_NR52)] = match config.model {
-			HardwareModel::SGB => 0xF0,
-			_ => 0xF1,
-		};
+		self.registers = [0_u8; IO_SIZE];
+		self.model = config.model;
+	}
+
+	/// Returns the register's bits that always read as 1.
+	///
+	/// Unimplemented registers read as 0xFF, and the GBC-only registers
+	/// are unimplemented on the rest of the models.
+	fn read_mask(&self, address: u16) -> u8 {
+		let cgb = self.model == HardwareModel::GBC;
+
+		match address {
+			// Video ram bank select.
+			0xFF4F if cgb => 0xFE,
+			// Infrared communications port.
+			0xFF56 if cgb => 0x3C,
+			// Object priority mode.
+			0xFF6C if cgb => 0xFE,
+			// Work ram bank select.
+			0xFF70 if cgb => 0xF8,
+			// Undocumented registers.
+			0xFF72..=0xFF74 if cgb => 0x00,
+			0xFF75 if cgb => 0x8F,
+			// The audio channels' output, which is read-only.
+			0xFF76 | 0xFF77 if cgb => 0x00,
+			_ => 0xFF,
+		}
+	}
+
+	/// Returns whether the register can be written.
+	fn writable(&self, address: u16) -> bool {
+		match address {
+			// The audio channels' output.
+			0xFF76 | 0xFF77 => false,
+			_ => self.read_mask(address) != 0xFF,
+		}
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for IoPorts {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bytes(&self.registers);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		reader.bytes(&mut self.registers)
 	}
 }
 
 impl Memory for IoPorts {
 	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
 		match address {
-			// Specific behaviors will be added here.
 			memory_range!(MMAP_IO_PORTS) => {
-				self.registers[port_offset!(address)] = value;
+				if self.writable(address) {
+					self.registers[port_offset!(address)] = value;
+				}
 				Ok(())
 			}
 			_ => {
@@ -119,9 +133,8 @@ impl Memory for IoPorts {
 
 	fn read(&self, address: u16) -> Result<u8, GameboyError> {
 		match address {
-			// Specific behaviors will be added here.
 			memory_range!(MMAP_IO_PORTS) => {
-				Ok(self.registers[port_offset!(address)])
+				Ok(self.registers[port_offset!(address)] | self.read_mask(address))
 			}
 			_ => {
 				Err(GameboyError::BadAddress(address))
@@ -129,3 +142,35 @@ impl Memory for IoPorts {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_register_masks() -> Result<(), GameboyError> {
+		let mut dmg = IoPorts::new(&Config { model: HardwareModel::GB, ..Config::default() });
+		let mut cgb = IoPorts::new(&Config { model: HardwareModel::GBC, ..Config::default() });
+
+		// Unmapped registers read as 0xFF.
+		for &address in &[0xFF03, 0xFF08, 0xFF0E, 0xFF27, 0xFF7F] {
+			dmg.write(address, 0x00)?;
+			assert!(dmg.read(address)? == 0xFF);
+		}
+
+		// GBC-only registers are unmapped in other models.
+		dmg.write(0xFF72, 0x12)?;
+		cgb.write(0xFF72, 0x12)?;
+		assert!(dmg.read(0xFF72)? == 0xFF);
+		assert!(cgb.read(0xFF72)? == 0x12);
+
+		cgb.write(0xFF75, 0x00)?;
+		assert!(cgb.read(0xFF75)? == 0x8F);
+
+		// The audio output registers are read-only.
+		cgb.write(0xFF76, 0x42)?;
+		assert!(cgb.read(0xFF76)? == 0x00);
+
+		Ok(())
+	}
+}
@@ -4,18 +4,25 @@
 #![allow(missing_docs)]
 //! Gameboy's joypad controller.
 
-use super::Memory;
+use super::{Memory, Peripheral};
 
 use crate::GameboyError;
+use crate::config::Config;
 use crate::cpu::interrupts::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 pub mod consts {
 	pub const IO_P1: u16 = 0xFF00;
+
+	/// The nominal frame rate that turbo rates are converted by.
+	pub const TURBO_FRAME_RATE: u8 = 60;
 }
 
 use consts::*;
 
 /// The matrix layout for the P1 register, according to the Gameboy CPU manual.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Key {
 	Right,
 	Left,
@@ -40,6 +47,11 @@ impl Key {
 			Key::Start => 128,
 		}
 	}
+
+	/// The key's bit index in the P1 matrix.
+	fn index(&self) -> usize {
+		self.value().trailing_zeros() as usize
+	}
 }
 
 pub trait Controller {
@@ -48,13 +60,32 @@ pub trait Controller {
 
 	/// Mark the given key as released.
 	fn up(&mut self, key: Key);
+
+	/// Set the key's autofire rate, in presses per second.
+	///
+	/// While the key is held, it's repeatedly pressed and released at the
+	/// given rate. `None` disables the autofire.
+	///
+	/// Controllers without autofire ignore it by default.
+	fn set_turbo(&mut self, _key: Key, _rate: Option<u8>) {}
 }
 
+#[derive(Clone)]
 pub struct Joypad {
 	data: u8,
 	/// If true, P15 out port is being selected, otherwise P14 is used.
 	select: u8,
 	interrupt_flag: InterruptMask,
+	/// The keys that are held by the user.
+	held: u8,
+	/// The number of frames between autofire toggles, for each key,
+	/// or 0 if the key's autofire is disabled.
+	turbo_frames: [u8; 8],
+	/// The number of frames each key has been held for.
+	held_frames: [u16; 8],
+	/// The joypad that's read in the SGB's multiplayer mode, if enabled.
+	/// Only the first joypad is connected.
+	player: Option<u8>,
 }
 
 
@@ -62,24 +93,98 @@ impl Joypad {
 	/// Initialize a new timer instance.
 	pub fn new() -> Self {
 		Joypad {
-			data: 0,
+			data: 0xFF,
 			select: 0,
-			interrupt_flag: 0,
+			interrupt_flag: InterruptMask::empty(),
+			held: 0,
+			turbo_frames: [0; 8],
+			held_frames: [0; 8],
+			player: None,
 		}
 	}
 
-	/// Update the joypad's state according to the elapsed time.
-	pub fn process(&mut self, _cycles: usize) {}
+	/// Advance the autofire of the held keys by a single frame.
+	pub fn frame(&mut self) {
+		for index in 0..8 {
+			if self.held & (1 << index) != 0 && self.turbo_frames[index] != 0 {
+				let period = 2 * self.turbo_frames[index] as u16;
+				self.held_frames[index] = (self.held_frames[index] + 1) % period;
+			}
+		}
+
+		self.update();
+	}
+
+	/// Returns the keys that are currently pressed, as a mask of `Key` values.
+	pub fn pressed(&self) -> u8 {
+		!self.data
+	}
+
+	/// Returns the key's autofire rate, in presses per second.
+	pub fn turbo(&self, key: Key) -> Option<u8> {
+		match self.turbo_frames[key.index()] {
+			0 => None,
+			frames => Some(TURBO_FRAME_RATE / (2 * frames)),
+		}
+	}
+
+	/// Selects the joypad that's read in the SGB's multiplayer mode, or
+	/// `None` if it's disabled.
+	pub fn set_player(&mut self, player: Option<u8>) {
+		self.player = player;
+	}
+
+	/// Recompute the keys' state, raising an interrupt on every new press.
+	fn update(&mut self) {
+		let mut pressed = self.held;
+
+		for index in 0..8 {
+			let frames = self.turbo_frames[index] as u16;
+			// Autofire keys are released during the second half of each period.
+			if frames != 0 && self.held_frames[index] >= frames {
+				pressed &= !(1 << index);
+			}
+		}
+
+		if pressed & self.data != 0 {
+			self.interrupt_flag.insert(Interrupt::Joypad);
+		}
+
+		self.data = !pressed;
+	}
+}
+
+impl Default for Joypad {
+	fn default() -> Self {
+		Joypad::new()
+	}
 }
 
 impl Controller for Joypad {
 	fn down(&mut self, key: Key) {
-		self.data &= !key.value();
-		self.interrupt_flag |= Interrupt::Joypad.value();
+		if self.held & key.value() == 0 {
+			self.held |= key.value();
+			self.held_frames[key.index()] = 0;
+		}
+		self.update();
 	}
 
 	fn up(&mut self, key: Key) {
-		self.data |= key.value();
+		self.held &= !key.value();
+		self.update();
+	}
+
+	fn set_turbo(&mut self, key: Key, rate: Option<u8>) {
+		self.turbo_frames[key.index()] = match rate {
+			Some(rate) if rate > 0 => {
+				// Round to the closest number of frames.
+				let rate = rate.min(TURBO_FRAME_RATE / 2);
+				(TURBO_FRAME_RATE + rate) / (2 * rate)
+			}
+			_ => 0,
+		};
+		self.held_frames[key.index()] = 0;
+		self.update();
 	}
 }
 
@@ -89,7 +194,7 @@ impl InterruptSource for Joypad {
 	}
 
 	fn clear(&mut self) {
-		self.interrupt_flag = 0;
+		self.interrupt_flag = InterruptMask::empty();
 	}
 }
 
@@ -105,6 +210,14 @@ impl Memory for Joypad {
 	fn read(&self, address: u16) -> Result<u8, GameboyError> {
 		assert!(address == IO_P1);
 
+		match self.player {
+			// The SGB returns the selected joypad's ID when no keys are selected.
+			Some(player) if self.select & 0x30 == 0x30 => { return Ok(self.select | (0xF - player)); }
+			// The other joypads aren't connected, so their keys are released.
+			Some(player) if player != 0 => { return Ok(self.select | 0xF); }
+			_ => {}
+		}
+
 		if self.select & 0x20 == 0 {
 			Ok(self.select | ((self.data >> 4) & 0xf))
 		} else if self.select & 0x10 == 0 {
@@ -114,3 +227,98 @@ impl Memory for Joypad {
 		}
 	}
 }
+
+#[cfg(feature = "alloc")]
+impl SaveState for Joypad {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.u8(self.data);
+		writer.u8(self.select);
+		writer.u8(self.interrupt_flag.bits());
+		writer.u8(self.held);
+		writer.bytes(&self.turbo_frames);
+		for frames in self.held_frames.iter() {
+			writer.u16(*frames);
+		}
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.data = reader.u8()?;
+		self.select = reader.u8()?;
+		self.interrupt_flag = InterruptMask::from_bits(reader.u8()?);
+		self.held = reader.u8()?;
+		reader.bytes(&mut self.turbo_frames)?;
+		for frames in self.held_frames.iter_mut() {
+			*frames = reader.u16()?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Peripheral for Joypad {
+	/// Reset the peripheral to boot state.
+	///
+	/// The keys that are held by the user, and their autofire rates, are kept.
+	fn reset(&mut self, _config: &Config) {
+		self.select = 0;
+		self.held_frames = [0; 8];
+		self.data = !self.held;
+		self.interrupt_flag = InterruptMask::empty();
+	}
+
+	/// Update the joypad's state according to the elapsed time.
+	fn process(&mut self, _cycles: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_turbo() -> Result<(), GameboyError> {
+		let mut joypad = Joypad::new();
+		joypad.write(IO_P1, 0x10)?;
+		assert!(joypad.read(IO_P1)? & 0xf == 0xf);
+
+		// Toggle every 3 frames.
+		joypad.set_turbo(Key::A, Some(10));
+		assert!(joypad.turbo(Key::A) == Some(10));
+
+		joypad.down(Key::A);
+		assert!(joypad.interrupts() == InterruptMask::from(Interrupt::Joypad));
+		joypad.clear();
+
+		let mut presses = 0;
+		// The buttons are selected, so A is the lowest bit.
+		for _ in 0..60 {
+			let was_pressed = joypad.read(IO_P1)? & 1 == 0;
+			joypad.frame();
+			let pressed = joypad.read(IO_P1)? & 1 == 0;
+
+			// Every synthetic press raises an interrupt.
+			if pressed && !was_pressed {
+				presses += 1;
+				assert!(joypad.interrupts() == InterruptMask::from(Interrupt::Joypad));
+			} else {
+				assert!(joypad.interrupts().is_empty());
+			}
+			joypad.clear();
+		}
+		// A second of 10Hz autofire, besides the initial press.
+		assert!(presses == 10);
+
+		// Keys without autofire stay pressed.
+		joypad.down(Key::B);
+		joypad.clear();
+		for _ in 0..10 {
+			joypad.frame();
+			assert!(joypad.read(IO_P1)? & 2 == 0);
+		}
+
+		joypad.up(Key::A);
+		joypad.set_turbo(Key::A, None);
+		assert!(joypad.turbo(Key::A).is_none());
+
+		Ok(())
+	}
+}
@@ -17,6 +17,16 @@ pub trait MemoryBounds<const S: u32> {
 
 impl<const S: u32> MemoryBounds<S> for () { }
 
+/// Returns whether the given memory range contains the address.
+///
+/// Unlike `memory_range`, the range doesn't have to be a constant.
+pub fn contains(range: MemoryRange, address: u16) -> bool {
+	let start = (range >> 16) as u16;
+	let end = (range & 0xFFFF) as u16;
+
+	(start..=end).contains(&address)
+}
+
 /// Make a memory range constant.
 ///
 /// * `start` - Start address (inclusive).
@@ -63,6 +73,7 @@ macro_rules! range_size {
 /// # }
 /// ```
 #[macro_export]
+#[allow(clippy::tabs_in_doc_comments)]
 macro_rules! memory_range {
 	($range:tt) => {
 		<() as MemoryBounds<$range>>::START..=<() as MemoryBounds<$range>>::END
@@ -81,6 +92,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_memory_range() {
     	const FIRST_5_BYTES: MemoryRange = make_range!(0, 4);
 
@@ -93,5 +105,8 @@ mod tests {
 			memory_range!(FIRST_5_BYTES) => { assert!(false); }
 			_ => {}
 		};
+
+		assert!(contains(FIRST_5_BYTES, 4));
+		assert!(!contains(FIRST_5_BYTES, 5));
     }
 }
@@ -13,20 +13,44 @@ pub mod rtc;
 pub mod ram;
 pub mod ppu;
 pub mod io;
+pub mod boot;
+pub mod hdma;
+pub mod serial;
+pub mod apu;
+pub mod sgb;
+pub mod interrupts;
 
 use io::*;
 use ram::*;
+use boot::*;
+use hdma::*;
+use serial::*;
+use apu::*;
+use sgb::*;
+use interrupts::*;
 use ppu::*;
 use timer::*;
 use joypad::*;
 use cartridge::*;
 use memory_range::*;
 use timer::consts::MMAP_IO_TIMER;
-use ppu::consts::{MMAP_IO_DISPLAY, MMAP_IO_PALETTES};
+use serial::consts::MMAP_IO_SERIAL;
+use apu::consts::{MMAP_IO_SOUND, MMAP_IO_WAVE};
+use sgb::consts::TRANSFER_SIZE;
+use hdma::consts::{MMAP_IO_HDMA, IO_HDMA5, HDMA_BLOCK_SIZE, HDMA_BLOCK_CYCLES};
+use ppu::consts::{MMAP_IO_DISPLAY, MMAP_IO_PALETTES, OAM_SIZE};
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
 
 use crate::GameboyError;
-use crate::config::Config;
+use crate::config::{Config, HardwareModel, Accuracy, EchoRamPolicy, RtcSource};
+use crate::warning::{Warning, Warnings};
 use crate::cpu::interrupts::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 /// Bus locations-related constants.
 #[allow(missing_docs)]
@@ -66,147 +90,777 @@ pub trait Memory {
 	fn read(&self, address: u16) -> Result<u8, GameboyError>;
 }
 
+/// A hardware unit that's clocked by the system, and may raise interrupts.
+///
+/// The bus holds its peripherals through this trait, so they're reset and
+/// advanced uniformly.
+pub trait Peripheral: Memory + InterruptSource {
+	/// Reset the peripheral to boot state.
+	fn reset(&mut self, config: &Config);
+
+	/// Update the peripheral's state according to the elapsed time.
+	fn process(&mut self, cycles: usize);
+
+	/// Returns the number of cycles until the peripheral's next event that
+	/// must be processed on time, such as an interrupt.
+	fn next_event(&self) -> usize {
+		usize::MAX
+	}
+}
+
 /// A virtual representation of Gameboy (Color) memory bus.
 ///
 /// This implementation provides memory/peripheral abstraction.
 pub struct SystemBus<'a> {
-	pub(crate) cartridge: &'a mut Cartridge<'a>,
+	pub(crate) cartridge: Cartridge<'a>,
+	pub(crate) boot_rom: Option<BootRom<'a>>,
+	/// Whether the boot rom is mapped over the cartridge.
+	boot_rom_mapped: bool,
 	pub(crate) ppu: Ppu,
 	pub(crate) io: IoPorts,
 	pub(crate) timer: Timer,
 	pub(crate) joypad: Joypad,
 	pub(crate) ram: InternalRam,
+	pub(crate) hdma: Hdma,
+	pub(crate) serial: Serial,
+	pub(crate) apu: Apu,
+	/// The Super GameBoy's state, which is only used by the SGB model.
+	pub(crate) sgb: Sgb,
 
-	/// The IF register.
-	pub interrupt_flag: InterruptMask,
-	/// The IE register.
-	pub interrupt_enable: InterruptMask,
+	/// The interrupt controller, which owns the IF and IE registers.
+	pub interrupts: InterruptController,
+	/// The last value written to the DMA register.
+	dma: u8,
+	/// The number of cycles since the OAM DMA transfer started, if it's in progress.
+	oam_dma_cycles: Option<usize>,
+	/// The emulator's configuration.
+	config: Config,
+	/// Non-fatal conditions detected while accessing the bus.
+	pub(crate) warnings: Warnings,
+	/// Whether the cpu runs in double speed mode (GBC).
+	double_speed: bool,
+	/// Whether the next STOP instruction switches the cpu's speed (GBC).
+	speed_switch_armed: bool,
+	/// The number of cycles the cpu should wait for a DMA transfer.
+	pub(crate) stall_cycles: usize,
+	/// The number of cycles the peripherals haven't been advanced by yet.
+	pending_cycles: usize,
+	/// The number of pending cycles at which a peripheral has an event that
+	/// must be processed on time, such as an interrupt.
+	deadline: usize,
+	/// Peripherals mapped by the user, see `SystemBus::map_region`.
+	#[cfg(feature = "alloc")]
+	mappings: Vec<(MemoryRange, Box<dyn Memory + 'a>)>,
 }
 
-/// An abstraction for fetching mutable and immutable regions.
-macro_rules! get_region {
-	($name:tt $(,$mut_:tt)*) => {
-		/// Returns the region that contains the given address.
-		fn $name(&$($mut_)* self, address: u16) -> Result<&$($mut_)* dyn Memory, GameboyError> {
-			match address {
-				// Cartridge-mapped offsets
-				memory_range!(MMAP_ROM_BANK0) |
-				memory_range!(MMAP_ROM_BANK_SW) |
-				memory_range!(MMAP_RAM_BANK_SW) => {
-					Ok(&$($mut_)* (*self.cartridge))
-				}
+/// A copy of the bus's state, see `SystemBus::snapshot`.
+///
+/// The configuration and the user's mappings aren't part of the snapshot.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct BusSnapshot {
+	cartridge: CartridgeSnapshot,
+	boot_rom_mapped: bool,
+	// The larger peripherals are kept on the heap.
+	ppu: Box<Ppu>,
+	io: IoPorts,
+	timer: Timer,
+	joypad: Joypad,
+	ram: Box<InternalRam>,
+	hdma: Hdma,
+	serial: Serial,
+	apu: Box<Apu>,
+	sgb: Box<Sgb>,
+	interrupts: InterruptController,
+	dma: u8,
+	oam_dma_cycles: Option<usize>,
+	warnings: Warnings,
+	double_speed: bool,
+	speed_switch_armed: bool,
+	stall_cycles: usize,
+	pending_cycles: usize,
+	deadline: usize,
+}
 
-				// Internal RAM
-				memory_range!(MMAP_RAM_INTERNAL) |
-				memory_range!(MMAP_RAM_ECHO) |
-				memory_range!(MMAP_RAM_HIGH) => {
-					Ok(&$($mut_)* self.ram)
-				}
+/// Calls the given method of the peripheral that contains the address.
+///
+/// The peripherals are matched directly rather than through a `dyn Memory`,
+/// so each access is a static call that can be inlined.
+macro_rules! dispatch {
+	($self:ident, $address:expr, $method:ident($($arg:expr),*)) => {
+		match $address {
+			// Cartridge-mapped offsets
+			memory_range!(MMAP_ROM_BANK0) |
+			memory_range!(MMAP_ROM_BANK_SW) |
+			memory_range!(MMAP_RAM_BANK_SW) => {
+				$self.cartridge.$method($($arg),*)
+			}
 
-				// Timer
-				memory_range!(MMAP_IO_TIMER) => {
-					Ok(&$($mut_)* self.timer)
-				}
+			// Internal RAM
+			memory_range!(MMAP_RAM_INTERNAL) |
+			memory_range!(MMAP_RAM_ECHO) |
+			memory_range!(MMAP_RAM_HIGH) => {
+				$self.ram.$method($($arg),*)
+			}
 
-				// DMA and internal IO registers
-				io::consts::IO_DMA |
-				io::consts::IO_IF |
-				io::consts::IO_IE => {
-					Ok(&$($mut_)* *self)
-				}
+			// Timer
+			memory_range!(MMAP_IO_TIMER) => {
+				$self.timer.$method($($arg),*)
+			}
 
-				// Display
-				memory_range!(MMAP_IO_DISPLAY) |
-				memory_range!(MMAP_IO_PALETTES) |
-				memory_range!(MMAP_VIDEO_RAM) |
-				memory_range!(MMAP_SPRITE_OAM) => {
-					Ok(&$($mut_)* self.ppu)
-				}
+			// Serial port
+			memory_range!(MMAP_IO_SERIAL) => {
+				$self.serial.$method($($arg),*)
+			}
 
-				// Joypad
-				joypad::consts::IO_P1 => {
-					Ok(&$($mut_)* self.joypad)
-				}
+			// Sound controller
+			memory_range!(MMAP_IO_SOUND) |
+			memory_range!(MMAP_IO_WAVE) => {
+				$self.apu.$method($($arg),*)
+			}
 
-				// I/O registers
-				memory_range!(MMAP_IO_PORTS) => {
-					Ok(&$($mut_)* self.io)
-				}
-				_ => {
-					Err(GameboyError::Io("Accessed an unmapped region."))
-				}
+			// Display
+			memory_range!(MMAP_IO_DISPLAY) |
+			memory_range!(MMAP_IO_PALETTES) |
+			memory_range!(MMAP_VIDEO_RAM) |
+			memory_range!(MMAP_SPRITE_OAM) => {
+				$self.ppu.$method($($arg),*)
+			}
+
+			// Joypad
+			joypad::consts::IO_P1 => {
+				$self.joypad.$method($($arg),*)
+			}
+
+			// I/O registers
+			memory_range!(MMAP_IO_PORTS) => {
+				$self.io.$method($($arg),*)
+			}
+			_ => {
+				Err(GameboyError::Io("Accessed an unmapped region."))
 			}
 		}
 	}
 }
 
+/// Applies the given expression to each of the peripherals held by the bus,
+/// see `Peripheral`.
+///
+/// The peripherals are visited in a fixed order, without a `dyn Peripheral`.
+macro_rules! for_each_peripheral {
+	($self:ident, $peripheral:ident => $body:expr) => {
+		{ let $peripheral = &mut $self.ppu; $body; }
+		{ let $peripheral = &mut $self.timer; $body; }
+		{ let $peripheral = &mut $self.joypad; $body; }
+		{ let $peripheral = &mut $self.serial; $body; }
+		{ let $peripheral = &mut $self.apu; $body; }
+	}
+}
+
 impl<'a> SystemBus<'a> {
 	/// Initialize a new address space.
-	pub fn new(config: &'a Config, cartridge: &'a mut Cartridge<'a>) -> Self {
+	pub fn new(config: &Config, cartridge: Cartridge<'a>) -> Self {
 		SystemBus {
 			cartridge,
-			ppu: Ppu::new(),
+			boot_rom: None,
+			boot_rom_mapped: false,
+			ppu: Ppu::new(config),
 			io: IoPorts::new(config),
 			timer: Timer::new(config),
 			joypad: Joypad::new(),
 			ram: InternalRam::new(),
-			interrupt_flag: 0,
-			interrupt_enable: 0,
+			hdma: Hdma::new(),
+			serial: Serial::new(config),
+			apu: Apu::new(config),
+			sgb: Sgb::new(),
+			interrupts: InterruptController::new(),
+			dma: 0xFF,
+			oam_dma_cycles: None,
+			config: *config,
+			warnings: Warnings::new(),
+			double_speed: false,
+			speed_switch_armed: false,
+			stall_cycles: 0,
+			pending_cycles: 0,
+			deadline: 0,
+			#[cfg(feature = "alloc")]
+			mappings: Vec::new(),
 		}
 	}
 
 	/// Update the system bus peripehrals' state according to
 	/// the elapsed time.
-	pub fn process(&mut self, cycles: usize) {
-		let elapsed = if cycles > 0 { cycles } else { 4 };
+	///
+	/// The peripherals are only advanced once one of them reaches its next
+	/// event, or once the cpu accesses them. Use `sync` to advance them
+	/// before inspecting their state.
+	pub fn process(&mut self, cycles: usize) -> Result<(), GameboyError> {
+		self.pending_cycles += if cycles > 0 { cycles } else { 4 };
+
+		if self.pending_cycles >= self.deadline {
+			self.sync()?;
+		}
+
+		// The joypad and the serial port may also be changed by the frontend.
+		self.collect_interrupts();
+
+		Ok(())
+	}
+
+	/// Advances the peripherals by the pending cycles.
+	pub fn sync(&mut self) -> Result<(), GameboyError> {
+		let elapsed = core::mem::take(&mut self.pending_cycles);
+
+		if elapsed > 0 {
+			self.advance(elapsed)?;
+			self.collect_interrupts();
+		}
+
+		self.deadline = self.next_event();
+
+		Ok(())
+	}
+
+	/// Returns the number of cycles until the next event of any peripheral.
+	fn next_event(&self) -> usize {
+		// The OAM DMA transfer and the ppu's OAM scan affect each other.
+		if self.oam_dma_cycles.is_some() {
+			return 0;
+		}
+
+		// The display counts half of the cycles in double speed.
+		let display = if self.double_speed {
+			self.ppu.next_event().saturating_mul(2)
+		} else {
+			self.ppu.next_event()
+		};
+
+		display.min(self.timer.next_event()).min(self.serial.next_event())
+	}
+
+	/// Advances the peripherals by the given number of cycles.
+	fn advance(&mut self, elapsed: usize) -> Result<(), GameboyError> {
+		// The display, the sound controller and the cartridge's clock aren't
+		// affected by the cpu's speed, so they see half of the cycles in double speed.
+		let elapsed_normal = if self.double_speed { elapsed / 2 } else { elapsed };
+		let hblank = self.ppu.mode() == PpuMode::Hblank;
 
-		self.ppu.process(elapsed);
+		// The OAM DMA runs in the cpu's speed.
+		self.ppu.set_oam_dma_active(self.oam_dma_cycles.is_some());
+		self.oam_dma(elapsed)?;
+
+		let frame_count = self.ppu.frame_count();
+
+		// The host's time advances the real-time clock instead, see `RtcSource`.
+		if self.config.rtc_source == RtcSource::Emulated {
+			self.cartridge.process(elapsed_normal);
+		}
+		self.ppu.process(elapsed_normal);
 		self.timer.process(elapsed);
 		self.joypad.process(elapsed);
+		self.serial.process(elapsed);
+		self.apu.process(elapsed_normal);
 
-		// Update interrupts state
-		self.interrupt_flag |= self.ppu.interrupts();
-		self.interrupt_flag |= self.timer.interrupts();
-		self.interrupt_flag |= self.joypad.interrupts();
-		self.interrupt_flag &= self.interrupt_enable;
+		// The joypad's autofire is driven by the frames.
+		if self.ppu.frame_count() != frame_count {
+			self.joypad.frame();
+
+			// The SGB captures the transferred data from the completed frame.
+			if let Some(transfer) = self.sgb.take_transfer() {
+				let mut data = [0; TRANSFER_SIZE];
+				self.ppu.vram_transfer(&mut data);
+				self.sgb.transfer(transfer, &data);
+			}
+		}
+
+		// Copy the next HDMA block when entering H-Blank.
+		if !hblank && self.ppu.mode() == PpuMode::Hblank && self.hdma.hblank_active() {
+			self.hdma_block()?;
+		}
+
+		Ok(())
+	}
+
+	/// Moves the peripherals' interrupts into the interrupt controller.
+	fn collect_interrupts(&mut self) {
+		for_each_peripheral!(self, peripheral => self.interrupts.collect(peripheral));
+	}
+
+	/// Resets the peripherals to boot state, dropping the cycles they
+	/// weren't advanced by yet.
+	pub fn reset_peripherals(&mut self) {
+		let config = self.config;
+
+		for_each_peripheral!(self, peripheral => peripheral.reset(&config));
+		// The joypad's multiplayer mode follows the SGB, which isn't a peripheral.
+		self.joypad.set_player(self.sgb.player());
+
+		self.pending_cycles = 0;
+		self.deadline = self.next_event();
+	}
+
+	/// Resets the bus to its power-on state, as if the machine was power
+	/// cycled without reloading the cartridge.
+	///
+	/// The peripherals, the I/O registers, the cartridge's bank controller and
+	/// the SGB's state are reset and the boot rom is mapped again, while the
+	/// memory is kept.
+	pub fn reset(&mut self) {
+		self.cartridge.reset();
+		self.boot_rom_mapped = self.boot_rom.is_some();
+		self.io.reset(&self.config);
+		self.hdma = Hdma::new();
+		self.sgb = Sgb::new();
+		self.interrupts.reset();
+		self.dma = 0xFF;
+		self.oam_dma_cycles = None;
+		self.double_speed = false;
+		self.speed_switch_armed = false;
+		self.stall_cycles = 0;
+
+		self.reset_peripherals();
+	}
+
+	/// Returns whether the boot rom is mapped over the cartridge.
+	pub fn boot_rom_mapped(&self) -> bool {
+		self.boot_rom_mapped
+	}
+
+	/// Returns whether accessing the given address depends on the peripherals'
+	/// state, so they must be advanced by the pending cycles first.
+	fn needs_sync(address: u16, write: bool) -> bool {
+		match address {
+			// Writes to the cartridge's controller may latch its clock.
+			memory_range!(MMAP_ROM_BANK0) |
+			memory_range!(MMAP_ROM_BANK_SW) => write,
+			memory_range!(MMAP_VIDEO_RAM) |
+			memory_range!(MMAP_RAM_INTERNAL) |
+			memory_range!(MMAP_RAM_ECHO) |
+			memory_range!(MMAP_RAM_HIGH) => false,
+			_ => true,
+		}
+	}
+
+	/// Returns the display, for inspecting its state.
+	pub fn ppu(&self) -> &Ppu {
+		&self.ppu
+	}
+
+	/// Returns the cartridge, for inspecting its state.
+	pub fn cartridge(&self) -> &Cartridge<'a> {
+		&self.cartridge
+	}
+
+	/// Returns the cartridge for mutation, such as accessing its ram.
+	pub fn cartridge_mut(&mut self) -> &mut Cartridge<'a> {
+		&mut self.cartridge
+	}
 
-		self.ppu.clear();
-		self.timer.clear();
-		self.joypad.clear();
+	/// Returns the timer peripheral, for inspecting its state.
+	pub fn timer(&self) -> &Timer {
+		&self.timer
+	}
+
+	/// Returns the I/O registers that aren't handled by a peripheral.
+	///
+	/// The serial port's registers are handled by `SystemBus::serial`.
+	pub fn io(&self) -> &IoPorts {
+		&self.io
+	}
+
+	/// Returns the serial port, for inspecting its state.
+	pub fn serial(&self) -> &Serial {
+		&self.serial
+	}
+
+	/// Returns the serial port for mutation, used to link it to another device.
+	pub fn serial_mut(&mut self) -> &mut Serial {
+		&mut self.serial
+	}
+
+	/// Returns the sound controller, for inspecting its state.
+	pub fn apu(&self) -> &Apu {
+		&self.apu
+	}
+
+	/// Returns the sound controller for mutation, used to drain its output.
+	pub fn apu_mut(&mut self) -> &mut Apu {
+		&mut self.apu
+	}
+
+	/// Returns the Super GameBoy's state, for inspecting it.
+	pub fn sgb(&self) -> &Sgb {
+		&self.sgb
+	}
+
+	/// Returns the interrupts that are both requested and enabled, which
+	/// wake a halting cpu.
+	pub fn pending_interrupts(&self) -> InterruptMask {
+		self.interrupts.pending()
+	}
+
+	/// Returns whether the cpu runs in double speed mode.
+	pub fn double_speed(&self) -> bool {
+		self.double_speed
+	}
+
+	/// Switches the cpu's speed if it was prepared by writing to KEY1.
+	///
+	/// Returns whether the speed was switched.
+	pub fn switch_speed(&mut self) -> Result<bool, GameboyError> {
+		if !self.speed_switch_armed {
+			return Ok(false);
+		}
+
+		// The pending cycles were counted in the previous speed.
+		self.sync()?;
+
+		self.double_speed = !self.double_speed;
+		self.speed_switch_armed = false;
+		self.deadline = self.next_event();
+
+		Ok(true)
+	}
+
+	/// Progresses the OAM DMA transfer, if in progress.
+	///
+	/// After a single cycle of setup, a byte is copied every cycle.
+	fn oam_dma(&mut self, cycles: usize) -> Result<(), GameboyError> {
+		let elapsed = match self.oam_dma_cycles {
+			Some(elapsed) => elapsed,
+			None => { return Ok(()); }
+		};
+
+		let source: u16 = (self.dma as u16) << 8;
+		let copied = (elapsed / 4).saturating_sub(1).min(OAM_SIZE);
+		let due = ((elapsed + cycles) / 4).saturating_sub(1).min(OAM_SIZE);
+
+		for i in copied..due {
+			let data = self.peek(source + (i as u16))?;
+			self.ppu.oam()[i] = data;
+		}
+
+		self.oam_dma_cycles = if due == OAM_SIZE { None } else { Some(elapsed + cycles) };
+
+		Ok(())
+	}
+
+	/// Copies a single HDMA block into the video ram, and stalls the cpu
+	/// for the duration of the copy.
+	fn hdma_block(&mut self) -> Result<(), GameboyError> {
+		if let Some((source, destination)) = self.hdma.next_block() {
+			for i in 0..HDMA_BLOCK_SIZE {
+				let data = self.peek(source.wrapping_add(i))?;
+				self.ppu.write(destination + i, data)?;
+			}
+
+			// The copy takes the same time in both speeds, which is twice
+			// as many cpu cycles in double speed.
+			self.stall_cycles += if self.double_speed {
+				2 * HDMA_BLOCK_CYCLES
+			} else {
+				HDMA_BLOCK_CYCLES
+			};
+		}
+
+		Ok(())
+	}
+
+	/// Maps a peripheral over the given range, which is accessed instead of
+	/// the built-in regions. This allows emulating cartridge add-ons, or
+	/// exposing host services to homebrew.
+	///
+	/// Mappings that overlap take precedence over the earlier ones.
+	#[cfg(feature = "alloc")]
+	pub fn map_region(&mut self, range: MemoryRange, peripheral: Box<dyn Memory + 'a>) {
+		self.mappings.push((range, peripheral));
+	}
+
+	/// Removes the latest peripheral that was mapped over the given range.
+	#[cfg(feature = "alloc")]
+	pub fn unmap_region(&mut self, range: MemoryRange) -> Option<Box<dyn Memory + 'a>> {
+		let index = self.mappings.iter().rposition(|(mapped, _)| *mapped == range)?;
+
+		Some(self.mappings.remove(index).1)
+	}
+
+	/// Returns the user-mapped peripheral that contains the given address, if any.
+	#[cfg(feature = "alloc")]
+	fn mapping(&self, address: u16) -> Option<&(dyn Memory + 'a)> {
+		self.mappings.iter().rev()
+			.find(|(range, _)| contains(*range, address))
+			.map(|(_, peripheral)| peripheral.as_ref())
+	}
+
+	/// Returns the user-mapped peripheral that contains the given address for
+	/// mutation, if any.
+	#[cfg(feature = "alloc")]
+	fn mapping_mut(&mut self, address: u16) -> Option<&mut (dyn Memory + 'a)> {
+		self.mappings.iter_mut().rev()
+			.find(|(range, _)| contains(*range, address))
+			.map(|(_, peripheral)| peripheral.as_mut())
+	}
+
+	/// Returns whether the given address is one of the registers that are
+	/// implemented by the system bus itself.
+	fn is_internal_register(address: u16) -> bool {
+		matches!(address,
+			io::consts::IO_DMA |
+			io::consts::IO_KEY1 |
+			memory_range!(MMAP_IO_HDMA) |
+			io::consts::IO_BOOT |
+			io::consts::IO_IF |
+			io::consts::IO_IE)
 	}
 
 	/// Handle reading from a memory region.
 	/// The function calls the relevent peripheral's implementation.
 	pub fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
-		let peripheral = self.region_mut(address)?;
+		#[cfg(feature = "alloc")]
+		if let Some(peripheral) = self.mapping_mut(address) {
+			return peripheral.write(address, value);
+		}
 
-		peripheral.write(address, value)
+		if let memory_range!(MMAP_RAM_ECHO) = address {
+			match self.config.echo_ram {
+				EchoRamPolicy::Mirror => {}
+				EchoRamPolicy::Warn => { self.warnings.push(Warning::EchoRamWrite(address)); }
+				EchoRamPolicy::OpenBus => { return Ok(()); }
+			}
+		}
+
+		if !SystemBus::needs_sync(address, true) {
+			let result = dispatch!(self, address, write(address, value));
+			return self.recover(result, (), Warning::BadWrite(address));
+		}
+
+		self.sync()?;
+
+		let result = if SystemBus::is_internal_register(address) {
+			self.write_register(address, value)
+		} else {
+			dispatch!(self, address, write(address, value))
+		};
+		self.recover(result, (), Warning::BadWrite(address))?;
+
+		// The SGB receives its commands through the joypad's lines.
+		if address == joypad::consts::IO_P1 && self.config.model == HardwareModel::SGB {
+			self.sgb.write_joypad(value);
+			self.joypad.set_player(self.sgb.player());
+		}
+
+		// The write may have changed when the next event occurs.
+		self.deadline = self.next_event();
+
+		Ok(())
 	}
 
 	/// Handle writing to a memory region.
 	/// The function calls the relevent peripheral's implementation.
-	pub fn read(&self, address: u16) -> Result<u8, GameboyError> {
-		let peripheral = self.region(address)?;
-		
-		peripheral.read(address)
+	pub fn read(&mut self, address: u16) -> Result<u8, GameboyError> {
+		if SystemBus::needs_sync(address, false) {
+			self.sync()?;
+		}
+
+		// Only the cpu's reads are warned about, the rest of the peeks are silent.
+		if let memory_range!(MMAP_RAM_ECHO) = address {
+			if self.config.echo_ram == EchoRamPolicy::Warn {
+				self.warnings.push(Warning::EchoRamRead(address));
+			}
+		}
+
+		let result = self.peek(address);
+		self.recover(result, 0xFF, Warning::BadRead(address))
 	}
 
-	/// Returns a waiting interrupt and removes it from the queue.
-	pub fn fetch_interrupt(&mut self) -> Option<Interrupt> {
-		let mut iter = InterruptIter::new(self.interrupt_flag);
-		let interrupt = iter.next();
+	/// Replaces a failed access's error with the given value and warning,
+	/// see `Config::lenient`.
+	fn recover<T>(&self, result: Result<T, GameboyError>, value: T, warning: Warning) -> Result<T, GameboyError> {
+		match result {
+			Err(_) if self.config.lenient => {
+				self.warnings.push(warning);
+				Ok(value)
+			}
+			result => result,
+		}
+	}
 
-		// Remove the fetched interrupt (if any) from the interrupt register.
-		self.interrupt_flag = iter.mask;
+	/// Reads a 16-bit little-endian value, the low byte first.
+	pub fn read16(&mut self, address: u16) -> Result<u16, GameboyError> {
+		let low = self.read(address)? as u16;
+		let high = self.read(address.wrapping_add(1))? as u16;
 
-		interrupt
+		Ok((high << 8) | low)
 	}
 
-	// Get an immutable region
-	get_region!(region);
+	/// Writes a 16-bit little-endian value, the low byte first.
+	pub fn write16(&mut self, address: u16, value: u16) -> Result<(), GameboyError> {
+		self.write(address, value as u8)?;
+		self.write(address.wrapping_add(1), (value >> 8) as u8)
+	}
+
+	/// Fills the buffer with consecutive bytes, starting at the given address.
+	///
+	/// The address wraps around at the end of the address space.
+	pub fn read_slice(&mut self, address: u16, buffer: &mut [u8]) -> Result<(), GameboyError> {
+		for (index, value) in buffer.iter_mut().enumerate() {
+			*value = self.read(address.wrapping_add(index as u16))?;
+		}
+
+		Ok(())
+	}
+
+	/// Writes the slice's bytes consecutively, starting at the given address.
+	///
+	/// The address wraps around at the end of the address space.
+	pub fn write_slice(&mut self, address: u16, data: &[u8]) -> Result<(), GameboyError> {
+		for (index, value) in data.iter().enumerate() {
+			self.write(address.wrapping_add(index as u16), *value)?;
+		}
+
+		Ok(())
+	}
+
+	/// Writes the complete array's bytes to the relevant memory region,
+	/// see `SystemBus::write_slice`.
+	pub fn write_all(&mut self, address: u16, array: &[u8]) -> Result<(), GameboyError> {
+		self.write_slice(address, array)
+	}
+
+	/// Reads a memory region without advancing the peripherals, so the value
+	/// may be out of date by the cycles since they were last advanced.
+	///
+	/// Unlike `read`, reading the echo ram doesn't raise a warning.
+	pub fn peek(&self, address: u16) -> Result<u8, GameboyError> {
+		#[cfg(feature = "alloc")]
+		if let Some(peripheral) = self.mapping(address) {
+			return peripheral.read(address);
+		}
+
+		// The boot rom is mapped over the cartridge until it's disabled.
+		if let Some(boot_rom) = self.boot_rom.as_ref().filter(|_| self.boot_rom_mapped) {
+			if boot_rom.maps(address) {
+				return boot_rom.read(address);
+			}
+		}
+
+		if let memory_range!(MMAP_RAM_ECHO) = address {
+			match self.config.echo_ram {
+				EchoRamPolicy::Mirror | EchoRamPolicy::Warn => {}
+				EchoRamPolicy::OpenBus => { return Ok(0xFF); }
+			}
+		}
+
+		if SystemBus::is_internal_register(address) {
+			return self.read_register(address);
+		}
+
+		dispatch!(self, address, read(address))
+	}
+
+	/// Fills the buffer with the whole address space, see `dump_range`.
+	pub fn dump(&self, buffer: &mut [u8; 0x10000]) -> Result<(), GameboyError> {
+		self.dump_range(make_range!(0x0000, 0xFFFF), buffer)
+	}
+
+	/// Fills the buffer with the given memory range, for debuggers' memory
+	/// views and crash dumps.
+	///
+	/// The memory is read without side effects, see `peek`. Addresses that
+	/// can't be read, such as a disabled cartridge ram, are dumped as 0xFF.
+	pub fn dump_range(&self, range: MemoryRange, buffer: &mut [u8]) -> Result<(), GameboyError> {
+		let start = (range >> 16) as u16;
+		let end = (range & 0xFFFF) as u16;
+
+		if end < start || buffer.len() != (end - start) as usize + 1 {
+			return Err(GameboyError::Io("The buffer doesn't match the memory range's size."));
+		}
+
+		for (address, value) in (start..=end).zip(buffer.iter_mut()) {
+			*value = self.peek(address).unwrap_or(0xFF);
+		}
+
+		Ok(())
+	}
+
+	/// Returns whether the given address belongs to the rom or the I/O registers.
+	pub fn is_rom_or_io(address: u16) -> bool {
+		matches!(address,
+			memory_range!(MMAP_ROM_BANK0) |
+			memory_range!(MMAP_ROM_BANK_SW) |
+			memory_range!(MMAP_IO_PORTS) |
+			memory_range!(MMAP_INTERRUPT_EN))
+	}
+
+	/// Maps the given boot rom over the cartridge.
+	pub fn set_boot_rom(&mut self, boot_rom: BootRom<'a>) {
+		self.boot_rom = Some(boot_rom);
+		self.boot_rom_mapped = true;
+	}
+
+	/// Copies the state of the bus and its peripherals, including the
+	/// cartridge's ram and bank controller.
+	#[cfg(feature = "alloc")]
+	pub fn snapshot(&self) -> BusSnapshot {
+		BusSnapshot {
+			cartridge: self.cartridge.snapshot(),
+			boot_rom_mapped: self.boot_rom_mapped,
+			ppu: Box::new(self.ppu.clone()),
+			io: self.io.clone(),
+			timer: self.timer.clone(),
+			joypad: self.joypad.clone(),
+			ram: Box::new(self.ram.clone()),
+			hdma: self.hdma.clone(),
+			serial: self.serial.clone(),
+			apu: Box::new(self.apu.clone()),
+			sgb: Box::new(self.sgb.clone()),
+			interrupts: self.interrupts.clone(),
+			dma: self.dma,
+			oam_dma_cycles: self.oam_dma_cycles,
+			warnings: self.warnings.clone(),
+			double_speed: self.double_speed,
+			speed_switch_armed: self.speed_switch_armed,
+			stall_cycles: self.stall_cycles,
+			pending_cycles: self.pending_cycles,
+			deadline: self.deadline,
+		}
+	}
+
+	/// Restores the state copied by `snapshot`.
+	///
+	/// The snapshot must have been taken with the same cartridge.
+	#[cfg(feature = "alloc")]
+	pub fn restore(&mut self, snapshot: &BusSnapshot) -> Result<(), GameboyError> {
+		self.cartridge.restore(&snapshot.cartridge)?;
+		self.boot_rom_mapped = snapshot.boot_rom_mapped && self.boot_rom.is_some();
+		self.ppu.clone_from(&snapshot.ppu);
+		self.io.clone_from(&snapshot.io);
+		self.timer.clone_from(&snapshot.timer);
+		self.joypad.clone_from(&snapshot.joypad);
+		self.ram.clone_from(&snapshot.ram);
+		self.hdma.clone_from(&snapshot.hdma);
+		self.serial.clone_from(&snapshot.serial);
+		self.apu.clone_from(&snapshot.apu);
+		self.sgb.clone_from(&snapshot.sgb);
+		self.interrupts.clone_from(&snapshot.interrupts);
+		self.dma = snapshot.dma;
+		self.oam_dma_cycles = snapshot.oam_dma_cycles;
+		self.warnings.clone_from(&snapshot.warnings);
+		self.double_speed = snapshot.double_speed;
+		self.speed_switch_armed = snapshot.speed_switch_armed;
+		self.stall_cycles = snapshot.stall_cycles;
+		self.pending_cycles = snapshot.pending_cycles;
+		self.deadline = snapshot.deadline;
+
+		Ok(())
+	}
+
+	/// Returns the enabled waiting interrupt with the highest priority, and
+	/// removes it from the queue.
+	pub fn fetch_interrupt(&mut self) -> Option<Interrupt> {
+		self.interrupts.acknowledge()
+	}
 
-	// Get a mutable region
-	get_region!(region_mut, mut);
 }
 
 /// Certain registers needs access to multiple peripherals.
@@ -215,9 +869,9 @@ mod private {
 	use super::*;
 
 	// Implement read/write operations for internal registers.
-	impl<'a> Memory for SystemBus<'a> {
+	impl<'a> SystemBus<'a> {
 
-		fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
+		pub(super) fn write_register(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
 			match address {
 				io::consts::IO_DMA => {
 					// The (non-GBC's double-speed) clock speed is 4.194304 MHz.
@@ -225,44 +879,88 @@ mod private {
 					// DMA transfer takes 152 microseconds, meaning that it takes ~640 clock cycles.
 					// The cycle-accurate gameboy docs describes the operation precisely.
 
-					// TODO we need to make the dma transfer realistic instead of performing
-					// it immediately, and allowing copy only from permitted addresses.
-					let source: u16 = (value as u16) << 8;
+					// TODO allow copying only from permitted addresses.
+					// The transfer progresses as the bus is processed, restarting
+					// any transfer in progress.
+					self.dma = value;
+					self.oam_dma_cycles = Some(0);
 
-					// Perform the transfer.
-					for i in 0..0xa0 {
-						let data = self.read(source + (i as u16))?;
-						self.ppu.oam()[i] = data;
+					// The fast path copies the whole transfer at once.
+					if self.config.accuracy == Accuracy::Fast {
+						self.oam_dma(4 * (OAM_SIZE + 1))?;
+						self.ppu.set_oam_dma_active(false);
 					}
 
 					Ok(())
 				}
-				io::consts::IO_IF => {
-					self.interrupt_flag = value;
+				io::consts::IO_KEY1 => {
+					if self.config.model == HardwareModel::GBC {
+						self.speed_switch_armed = (value & 1) != 0;
+					}
 
 					Ok(())
 				}
-				io::consts::IO_IE => {
-					self.interrupt_enable = value;
+				memory_range!(MMAP_IO_HDMA) => {
+					if self.config.model != HardwareModel::GBC {
+						return Ok(());
+					}
+
+					self.hdma.write(address, value)?;
+
+					// General purpose transfers are performed at once.
+					if address == IO_HDMA5 {
+						while self.hdma.general_pending() {
+							self.hdma_block()?;
+						}
+					}
 
 					Ok(())
 				}
+				io::consts::IO_BOOT => {
+					// Any non-zero value unmaps the boot rom until the next reset.
+					if value != 0 {
+						self.boot_rom_mapped = false;
+					}
+
+					Ok(())
+				}
+				io::consts::IO_IF | io::consts::IO_IE => {
+					self.interrupts.write(address, value)
+				}
 				_ => {
 					panic!("Write operation not implemented for register: {}", address);
 				}
 			}
 		}
 
-		fn read(&self, address: u16) -> Result<u8, GameboyError> {
+		pub(super) fn read_register(&self, address: u16) -> Result<u8, GameboyError> {
 			match address {
 				io::consts::IO_DMA => {
-					Ok(0)
+					// The register holds the last written source address.
+					Ok(self.dma)
 				}
-				io::consts::IO_IF => {
-					Ok(self.interrupt_flag)
+				io::consts::IO_KEY1 => {
+					if self.config.model != HardwareModel::GBC {
+						return Ok(0xFF);
+					}
+
+					let speed = if self.double_speed { 0x80 } else { 0 };
+					let armed = if self.speed_switch_armed { 1 } else { 0 };
+
+					Ok(0x7E | speed | armed)
+				}
+				memory_range!(MMAP_IO_HDMA) => {
+					if self.config.model != HardwareModel::GBC {
+						return Ok(0xFF);
+					}
+
+					self.hdma.read(address)
 				}
-				io::consts::IO_IE => {
-					Ok(self.interrupt_enable)
+				io::consts::IO_BOOT => {
+					Ok(0xFF)
+				}
+				io::consts::IO_IF | io::consts::IO_IE => {
+					self.interrupts.read(address)
 				}
 				_ => {
 					panic!("Read operation not implemented for register: {}", address);
@@ -272,14 +970,64 @@ mod private {
 	}
 }
 
-#[cfg(test)]
-impl<'a> SystemBus<'a> {
-	/// Writes the complete array's bytes to the relevant memory region.
-	pub fn write_all(&mut self, address: u16, array: &[u8]) -> Result<(), GameboyError> {
-		for (index, value) in array.iter().enumerate() {
-			self.write(address + (index as u16), *value)?;
+/// The warnings and the user's mappings aren't part of the state.
+#[cfg(feature = "alloc")]
+impl<'a> SaveState for SystemBus<'a> {
+	fn save_state(&self, writer: &mut StateWriter) {
+		self.cartridge.save_state(writer);
+		writer.bool(self.boot_rom_mapped);
+		self.ppu.save_state(writer);
+		self.io.save_state(writer);
+		self.timer.save_state(writer);
+		self.joypad.save_state(writer);
+		self.ram.save_state(writer);
+		self.hdma.save_state(writer);
+		self.serial.save_state(writer);
+		self.apu.save_state(writer);
+
+		self.interrupts.save_state(writer);
+		writer.u8(self.dma);
+		writer.bool(self.oam_dma_cycles.is_some());
+		writer.usize(self.oam_dma_cycles.unwrap_or(0));
+		writer.bool(self.double_speed);
+		writer.bool(self.speed_switch_armed);
+		writer.usize(self.stall_cycles);
+		writer.usize(self.pending_cycles);
+		writer.usize(self.deadline);
+
+		self.sgb.save_state(writer);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.cartridge.load_state(reader)?;
+		self.boot_rom_mapped = reader.bool()?;
+		if self.boot_rom_mapped && self.boot_rom.is_none() {
+			return Err(GameboyError::Io("The savestate requires a boot rom."));
 		}
 
+		self.ppu.load_state(reader)?;
+		self.io.load_state(reader)?;
+		self.timer.load_state(reader)?;
+		self.joypad.load_state(reader)?;
+		self.ram.load_state(reader)?;
+		self.hdma.load_state(reader)?;
+		self.serial.load_state(reader)?;
+		self.apu.load_state(reader)?;
+
+		self.interrupts.load_state(reader)?;
+		self.dma = reader.u8()?;
+		let oam_dma_active = reader.bool()?;
+		let oam_dma_cycles = reader.usize()?;
+		self.oam_dma_cycles = if oam_dma_active { Some(oam_dma_cycles) } else { None };
+		self.double_speed = reader.bool()?;
+		self.speed_switch_armed = reader.bool()?;
+		self.stall_cycles = reader.usize()?;
+		self.pending_cycles = reader.usize()?;
+		self.deadline = reader.usize()?;
+
+		self.sgb.load_state(reader)?;
+		self.joypad.set_player(self.sgb.player());
+
 		Ok(())
 	}
 }
@@ -289,6 +1037,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_memory_range() {
     	let int_enable_ptr: u16 = 0xFFFF;
     	let ram_ptr: u16 = 0xA100;
@@ -303,4 +1052,370 @@ mod tests {
     		_ => { assert!(false); }
     	}
     }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_dma_register() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let mut bus = SystemBus::new(&Config::default(), cartridge);
+
+    	// Copy the sprites from the internal ram.
+    	bus.write(0xC000, 0x42)?;
+    	bus.write(0xC09F, 0x24)?;
+    	bus.write(io::consts::IO_DMA, 0xC0)?;
+
+    	// The register reads back the last written value.
+    	assert!(bus.read(io::consts::IO_DMA)? == 0xC0);
+
+    	// A byte is copied every cycle, after a single cycle of setup.
+    	bus.process(4)?;
+    	assert!(bus.ppu.oam()[0] == 0);
+    	bus.process(4)?;
+    	assert!(bus.ppu.oam()[0] == 0x42);
+
+    	// The OAM is inaccessible to the cpu during the transfer.
+    	bus.write(0xFE00, 0x11)?;
+    	assert!(bus.read(0xFE00)? == 0xFF);
+
+    	for _ in 0..(OAM_SIZE - 2) {
+    		bus.process(4)?;
+    	}
+    	assert!(bus.ppu.oam()[0x9F] == 0);
+    	bus.process(4)?;
+    	assert!(bus.ppu.oam()[0x9F] == 0x24);
+    	assert!(bus.oam_dma_cycles.is_none());
+
+    	bus.process(4)?;
+    	assert!(bus.read(0xFE00)? == 0x42);
+    	bus.write(0xFE9F, 0x11)?;
+    	assert!(bus.read(0xFE9F)? == 0x11);
+
+    	// The fast path completes the transfer at once.
+    	bus.config.accuracy = Accuracy::Fast;
+    	bus.write(0xC000, 0x11)?;
+    	bus.write(io::consts::IO_DMA, 0xC0)?;
+    	assert!(bus.ppu.oam()[0] == 0x11 && bus.ppu.oam()[0x9F] == 0x24);
+    	assert!(bus.oam_dma_cycles.is_none() && bus.read(0xFE00)? == 0x11);
+
+    	Ok(())
+    }
+
+    /// A peripheral that holds a single value at all of its addresses.
+    #[cfg(feature = "alloc")]
+    struct Latch(u8);
+
+    #[cfg(feature = "alloc")]
+    impl Memory for Latch {
+    	fn write(&mut self, _address: u16, value: u8) -> Result<(), GameboyError> {
+    		self.0 = value;
+    		Ok(())
+    	}
+
+    	fn read(&self, _address: u16) -> Result<u8, GameboyError> {
+    		Ok(self.0)
+    	}
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_map_region() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let mut bus = SystemBus::new(&Config::default(), cartridge);
+
+    	// The unusable region can be mapped.
+    	assert!(bus.read(0xFEA0).is_err());
+    	bus.map_region(make_range!(0xFEA0, 0xFEFF), Box::new(Latch(0x42)));
+    	assert!(bus.read(0xFEFF)? == 0x42);
+    	bus.write(0xFEA0, 0x24)?;
+    	assert!(bus.read(0xFEB0)? == 0x24);
+
+    	// The mappings take precedence over the built-in regions, and over each other.
+    	bus.write(0xC000, 0x11)?;
+    	bus.map_region(make_range!(0xC000, 0xC0FF), Box::new(Latch(0x22)));
+    	bus.map_region(make_range!(0xC000, 0xC000), Box::new(Latch(0x33)));
+    	assert!(bus.read(0xC000)? == 0x33);
+    	assert!(bus.read(0xC001)? == 0x22);
+
+    	assert!(bus.unmap_region(make_range!(0xC000, 0xC000)).is_some());
+    	assert!(bus.read(0xC000)? == 0x22);
+    	assert!(bus.unmap_region(make_range!(0xC000, 0xC0FF)).is_some());
+    	assert!(bus.read(0xC000)? == 0x11);
+    	assert!(bus.unmap_region(make_range!(0xC000, 0xC0FF)).is_none());
+
+    	Ok(())
+    }
+
+    /// Processes the bus until the ppu enters the next H-Blank.
+    fn run_until_hblank(bus: &mut SystemBus) -> Result<(), GameboyError> {
+    	while bus.ppu.mode() == PpuMode::Hblank {
+    		bus.process(4)?;
+    	}
+    	while bus.ppu.mode() != PpuMode::Hblank {
+    		bus.process(4)?;
+    	}
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_scheduler() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let mut bus = SystemBus::new(&Config::default(), cartridge);
+
+    	bus.write(timer::consts::IO_DIV, 0)?;
+    	bus.write(timer::consts::IO_TIMA, 0xFE)?;
+    	bus.write(timer::consts::IO_TAC, 0x05)?;
+    	bus.write(0xFFFF, Interrupt::Timer.value())?;
+
+    	// The overflow is raised on time, 4 cycles after TIMA overflows, although
+    	// nothing accessed the timer.
+    	for _ in 0..8 {
+    		bus.process(4)?;
+    		assert!(bus.interrupts.flag().is_empty());
+    	}
+    	bus.process(4)?;
+    	assert!(bus.interrupts.flag() == InterruptMask::from(Interrupt::Timer));
+
+    	// Reading a register catches its peripheral up first.
+    	for _ in 0..55 {
+    		bus.process(4)?;
+    	}
+    	assert!(bus.read(timer::consts::IO_DIV)? == 1);
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_reset_peripherals() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let mut bus = SystemBus::new(&Config::default(), cartridge);
+
+    	bus.write(timer::consts::IO_TAC, 0x05)?;
+    	bus.write(serial::consts::IO_SB, 0x42)?;
+    	bus.write(0xFF43, 0x10)?;
+    	bus.write(0xC000, 0x24)?;
+
+    	// The peripherals are reset, while the memory is kept.
+    	bus.reset_peripherals();
+    	assert!(bus.read(timer::consts::IO_TAC)? == 0);
+    	assert!(bus.read(serial::consts::IO_SB)? == 0);
+    	assert!(bus.read(0xFF43)? == 0);
+    	assert!(bus.read(0xC000)? == 0x24);
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_sgb_commands() -> Result<(), GameboyError> {
+    	use sgb::consts::*;
+    	use sgb::tests::packet_writes;
+    	use ppu::consts::CYCLES_PER_FRAME;
+
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let config = Config { model: HardwareModel::SGB, ..Config::default() };
+    	let mut bus = SystemBus::new(&config, cartridge);
+
+    	// The joypad's ID is read when no keys are selected, after MLT_REQ.
+    	for value in packet_writes(&[(CMD_MLT_REQ << 3) | 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]) {
+    		bus.write(joypad::consts::IO_P1, value)?;
+    	}
+    	assert!(bus.read(joypad::consts::IO_P1)? == 0x3F);
+    	bus.write(joypad::consts::IO_P1, 0x10)?;
+    	bus.write(joypad::consts::IO_P1, 0x30)?;
+    	assert!(bus.read(joypad::consts::IO_P1)? == 0x3E);
+
+    	// The VRAM transfer is done once the frame is completed.
+    	for value in packet_writes(&[(CMD_PCT_TRN << 3) | 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]) {
+    		bus.write(joypad::consts::IO_P1, value)?;
+    	}
+    	assert!(bus.sgb().pending_transfer() == Some(Transfer::BorderMap));
+    	for _ in 0..CYCLES_PER_FRAME / 4 {
+    		bus.process(4)?;
+    	}
+    	bus.sync()?;
+    	assert!(bus.sgb().pending_transfer().is_none());
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_wide_access() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let mut bus = SystemBus::new(&Config::default(), cartridge);
+
+    	// 16-bit values are little-endian.
+    	bus.write16(0xC000, 0x1234)?;
+    	assert!(bus.read(0xC000)? == 0x34 && bus.read(0xC001)? == 0x12);
+    	assert!(bus.read16(0xC000)? == 0x1234);
+
+    	bus.write_slice(0xC100, &[1, 2, 3, 4])?;
+    	let mut buffer = [0_u8; 3];
+    	bus.read_slice(0xC101, &mut buffer)?;
+    	assert!(buffer == [2, 3, 4]);
+
+    	// The address wraps around the address space.
+    	bus.write_slice(0xFFFE, &[0x5A, 0x01, 0x02])?;
+    	assert!(bus.read(0xFFFE)? == 0x5A && bus.read(0xFFFF)? == 0x01);
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_hdma_double_speed() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let config = Config { model: HardwareModel::GBC, ..Config::default() };
+    	let mut bus = SystemBus::new(&config, cartridge);
+
+    	for i in 0..0x40 {
+    		bus.write(0xC000 + i, i as u8)?;
+    	}
+
+    	// Copy 2 blocks from 0xC000 to 0x8000 during H-Blanks.
+    	bus.write_all(0xFF51, &[0xC0, 0x00, 0x00, 0x00, 0x81])?;
+    	assert!(bus.read(IO_HDMA5)? == 0x01);
+
+    	run_until_hblank(&mut bus)?;
+    	assert!(bus.read(0x800F)? == 0x0F && bus.read(0x8010)? == 0);
+    	assert!(bus.stall_cycles == HDMA_BLOCK_CYCLES);
+    	assert!(bus.read(IO_HDMA5)? == 0x00);
+    	bus.stall_cycles = 0;
+
+    	// Switch to double speed.
+    	bus.write(io::consts::IO_KEY1, 0x01)?;
+    	assert!(bus.read(io::consts::IO_KEY1)? == 0x7F);
+    	assert!(bus.switch_speed()?);
+    	assert!(bus.read(io::consts::IO_KEY1)? == 0xFE);
+
+    	// A line takes twice as many cpu cycles in double speed.
+    	let mut cycles = 0;
+    	while bus.ppu.mode() == PpuMode::Hblank {
+    		bus.process(4)?;
+    		cycles += 4;
+    	}
+    	while bus.ppu.mode() != PpuMode::Hblank {
+    		bus.process(4)?;
+    		cycles += 4;
+    	}
+    	assert!(cycles == 2 * 456);
+
+    	// The block is still 0x10 bytes, but it stalls the cpu twice as long.
+    	assert!(bus.read(0x801F)? == 0x1F && bus.read(0x8020)? == 0);
+    	assert!(bus.stall_cycles == 2 * HDMA_BLOCK_CYCLES);
+    	assert!(bus.read(IO_HDMA5)? == 0xFF);
+    	bus.stall_cycles = 0;
+
+    	// The transfer is complete.
+    	run_until_hblank(&mut bus)?;
+    	assert!(bus.stall_cycles == 0);
+
+    	// General purpose transfers copy everything at once.
+    	bus.write_all(0xFF51, &[0xC0, 0x20, 0x00, 0x20, 0x01])?;
+    	assert!(bus.read(0x803F)? == 0x3F);
+    	assert!(bus.stall_cycles == 4 * HDMA_BLOCK_CYCLES);
+    	assert!(bus.read(IO_HDMA5)? == 0xFF);
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_echo_ram_policy() -> Result<(), GameboyError> {
+    	for &policy in &[EchoRamPolicy::Mirror, EchoRamPolicy::Warn, EchoRamPolicy::OpenBus] {
+    		let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    		let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    		let config = Config { echo_ram: policy, ..Config::default() };
+    		let mut bus = SystemBus::new(&config, cartridge);
+
+    		bus.write(0xC010, 0x42)?;
+    		bus.write(0xE020, 0x24)?;
+
+    		match policy {
+    			EchoRamPolicy::Mirror | EchoRamPolicy::Warn => {
+    				assert!(bus.read(0xE010)? == 0x42);
+    				assert!(bus.read(0xC020)? == 0x24);
+    			}
+    			EchoRamPolicy::OpenBus => {
+    				assert!(bus.read(0xE010)? == 0xFF);
+    				assert!(bus.read(0xC020)? == 0);
+    			}
+    		}
+
+    		// Peeking doesn't warn, like the dma transfers and the dumps.
+    		bus.peek(0xE030)?;
+
+    		if policy == EchoRamPolicy::Warn {
+    			assert!(bus.warnings.pop() == Some(Warning::EchoRamWrite(0xE020)));
+    			assert!(bus.warnings.pop() == Some(Warning::EchoRamRead(0xE010)));
+    		}
+    		assert!(bus.warnings.is_empty());
+    	}
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_lenient() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let mut bus = SystemBus::new(&Config::default(), cartridge);
+
+    	// The cartridge's ram is disabled.
+    	assert!(bus.write(0xA000, 0x42).is_err());
+    	assert!(bus.read(0xA000).is_err());
+
+    	// The failed accesses act like an open bus, and are reported instead.
+    	bus.config.lenient = true;
+    	bus.write(0xA000, 0x42)?;
+    	assert!(bus.read(0xA000)? == 0xFF);
+    	assert!(bus.warnings.pop() == Some(Warning::BadWrite(0xA000)));
+    	assert!(bus.warnings.pop() == Some(Warning::BadRead(0xA000)));
+
+    	// Peeking doesn't report anything.
+    	assert!(bus.peek(0xA000).is_err() && bus.warnings.is_empty());
+
+    	Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_dump() -> Result<(), GameboyError> {
+    	let rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
+    	let cartridge = Cartridge::from_rom(rom.to_vec().into_boxed_slice())?;
+    	let config = Config { echo_ram: EchoRamPolicy::Warn, ..Config::default() };
+    	let mut bus = SystemBus::new(&config, cartridge);
+
+    	bus.write(0xC010, 0x42)?;
+    	bus.write(0xFF80, 0x24)?;
+    	bus.write(0xFFFF, 0x1F)?;
+
+    	let mut memory = alloc::boxed::Box::new([0; 0x10000]);
+    	bus.dump(&mut memory)?;
+    	assert!(memory[0x0147] == rom[0x0147]);
+    	assert!(memory[0xC010] == 0x42 && memory[0xE010] == 0x42);
+    	assert!(memory[0xFF80] == 0x24 && memory[0xFFFF] == 0x1F);
+    	// The cartridge's ram is disabled.
+    	assert!(memory[0xA000] == 0xFF);
+    	// Dumping the echo ram doesn't raise warnings.
+    	assert!(bus.warnings.is_empty());
+
+    	let mut hram = [0; 0x7F];
+    	bus.dump_range(MMAP_RAM_HIGH, &mut hram)?;
+    	assert!(hram[..] == memory[0xFF80..0xFFFF]);
+    	assert!(bus.dump_range(MMAP_RAM_HIGH, &mut hram[1..]).is_err());
+
+    	Ok(())
+    }
 }
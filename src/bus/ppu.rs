@@ -3,12 +3,24 @@
 
 //! Gameboy's lcd controller / picture processing unit.
 
-use super::Memory;
+use super::{Memory, Peripheral};
 use super::consts::*;
 use super::memory_range::*;
+use super::sgb::consts::TRANSFER_SIZE;
 
 use crate::GameboyError;
+use crate::config::{Config, HardwareModel};
 use crate::cpu::interrupts::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+#[cfg(feature = "embedded-graphics")]
+use embedded_graphics_core::{
+	draw_target::DrawTarget,
+	geometry::{Point, Size},
+	pixelcolor::Rgb888,
+	primitives::Rectangle,
+};
 
 #[allow(unused, missing_docs)]
 pub mod consts {
@@ -43,10 +55,28 @@ pub mod consts {
 	pub const OAM_SIZE: usize = 0xa0;
 
 	pub const NUM_SPRITES: usize = 40;
+	/// The number of sprites the hardware draws on a single line.
+	pub const MAX_LINE_SPRITES: usize = 10;
+
+	/// The number of tiles in the video ram's tile data (0x8000-0x97FF).
+	pub const TILE_COUNT: usize = 384;
+	/// The number of bytes each tile takes, 2 for each of its 8 lines.
+	pub const TILE_BYTES: usize = 16;
+	/// The number of tiles in each row of a decoded tileset.
+	pub const TILESET_COLUMNS: usize = 16;
+	/// The dimensions of a decoded tileset, in pixels.
+	pub const TILESET_WIDTH: usize = TILESET_COLUMNS * 8;
+	pub const TILESET_HEIGHT: usize = (TILE_COUNT / TILESET_COLUMNS) * 8;
+
+	/// The size of a decoded background or window map's side, in pixels.
+	pub const MAP_SIZE: usize = 256;
 
 	pub const WIDTH: usize = 160;
 	pub const HEIGHT: usize = 144;
 
+	/// The number of clock cycles it takes to draw a single frame.
+	pub const CYCLES_PER_FRAME: usize = 70224;
+
 	pub const PALETTE: [Color; 4] = [
 		0x081820,
 		0x346856,
@@ -60,6 +90,67 @@ use consts::*;
 /// Represents a single color within a palette.
 type Color = u32;
 
+/// The tile maps that can be rendered for debugging, see `Ppu::render_map`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileMap {
+	/// The map selected by LCDC for the background.
+	Background,
+	/// The map selected by LCDC for the window.
+	Window,
+}
+
+/// The layers that are drawn to the display, used as a debugging aid.
+///
+/// A hidden background or window is drawn as their transparent color, so
+/// it doesn't change the sprites' priority. Unlike LCDC, which the game can
+/// read back, the layers are only controlled by the frontend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layers {
+	/// Whether the background is drawn.
+	pub background: bool,
+	/// Whether the window is drawn.
+	pub window: bool,
+	/// Whether the sprites are drawn.
+	pub sprites: bool,
+}
+
+impl Default for Layers {
+	fn default() -> Self {
+		Layers { background: true, window: true, sprites: true }
+	}
+}
+
+/// Statistics of a frame's rendering, to help profiling why a game flickers
+/// or misses its raster effects, see `Ppu::frame_stats`.
+///
+/// The statistics are gathered while rendering the lines, so a headless
+/// ppu doesn't gather them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+	/// The sprites the hardware wouldn't have drawn, since their lines
+	/// already had `MAX_LINE_SPRITES` sprites.
+	pub dropped_sprites: usize,
+	/// The cycles the hardware would have extended the pixel transfer (mode 3)
+	/// by, for the scrolling, the window and the sprites. The ppu keeps
+	/// the mode's length fixed, so this is an estimate.
+	pub mode3_extension: usize,
+	/// The lines the window was drawn on.
+	pub window_lines: usize,
+}
+
+/// The part of the background that's visible on the display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+	/// The background's column at the display's left edge (SCX).
+	pub x: u8,
+	/// The background's line at the display's top edge (SCY).
+	pub y: u8,
+	/// The viewport's width, which wraps around the background's right edge.
+	pub width: usize,
+	/// The viewport's height, which wraps around the background's bottom edge.
+	pub height: usize,
+}
+
 /// The lcd controller peripheral has four states, and 154 cycles between
 /// these states corresponds to a single frame when the LCD is on.
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -73,10 +164,17 @@ pub enum PpuMode {
 
 /// The gameboy's lcd controller.
 #[allow(unused)]
+#[derive(Clone)]
 pub struct Ppu {
 	buffer: [Color; WIDTH * HEIGHT],
 	vram: [u8; VRAM_SIZE],
 	oam: [u8; OAM_SIZE],
+	/// The sprites' attributes as seen by the current line's OAM scan.
+	line_oam: [u8; OAM_SIZE],
+	/// The number of sprites that were scanned in the current line.
+	oam_scan_index: usize,
+	/// Whether an OAM DMA transfer currently occupies the OAM.
+	oam_dma_active: bool,
 
 	lcdc: Lcdc,
 	stat: Stat,
@@ -92,13 +190,26 @@ pub struct Ppu {
 
 	mode: PpuMode,
 	mode_counter: usize,
+	frame_count: usize,
 	interrupt_flag: InterruptMask,
+	/// The layers that are drawn, which aren't affected by a reset.
+	layers: Layers,
+	/// Whether the pixels are rendered, see `Config::headless`.
+	headless: bool,
+	/// The statistics of the frame that's being rendered.
+	stats: FrameStats,
+	/// The statistics of the last completed frame.
+	last_stats: FrameStats,
+	/// The colors of the 4 shades, see `Config::palette`.
+	palette: [Color; 4],
 }
 
+#[derive(Clone)]
 struct Lcdc {
 	data: u8,
 }
 
+#[derive(Clone)]
 struct Stat {
 	// Consists of bits 2-6 (RW).
 	data: u8,
@@ -117,11 +228,14 @@ struct SpriteData {
 
 impl Ppu {
 	/// Initialize a new ppu instance.
-	pub fn new() -> Self {
+	pub fn new(config: &Config) -> Self {
 		let mut ppu = Ppu {
 			buffer: [0; WIDTH * HEIGHT],
 			vram: [0; VRAM_SIZE],
 			oam: [0; OAM_SIZE],
+			line_oam: [0; OAM_SIZE],
+			oam_scan_index: 0,
+			oam_dma_active: false,
 			lcdc: Lcdc::new(),
 			stat: Stat::new(),
 			scy: 0,
@@ -135,17 +249,27 @@ impl Ppu {
 			wx: 0,
 			mode: PpuMode::SearchOam,
 			mode_counter: 0,
-			interrupt_flag: 0,
+			frame_count: 0,
+			interrupt_flag: InterruptMask::empty(),
+			layers: Layers::default(),
+			headless: config.headless,
+			stats: FrameStats::default(),
+			last_stats: FrameStats::default(),
+			palette: Ppu::palette(config),
 		};
 
-		ppu.reset();
+		ppu.reset_registers();
 
 		ppu
 	}
 
-	/// Reset this peripheral to boot state.
-	pub fn reset(&mut self) {
+	/// Sets the registers to their boot state.
+	fn reset_registers(&mut self) {
 		self.mode = PpuMode::SearchOam;
+		self.mode_counter = 0;
+		self.oam_scan_index = 0;
+		self.ly = 0x00;
+		self.stats = FrameStats::default();
 		self.lcdc.reset();
 		self.stat.reset();
 		self.stat.set_mode(self.mode);
@@ -164,87 +288,75 @@ impl Ppu {
 		frame_buffer.copy_from_slice(&self.buffer);
 	}
 
-	/// Getter for the OAM region's buffer.
-	pub fn oam(&mut self) -> &mut [u8] {
-		&mut self.oam
+	/// Returns the display's output, row by row.
+	pub fn screen(&self) -> &[u32] {
+		&self.buffer
 	}
 
-	/// Update the ppu's state according to the elapsed time.
-	pub fn process(&mut self, cycles: usize) {
-		if !self.lcdc.power() {
-			// LCD is powered off.
-			return;
-		}
-
-		self.mode_counter += cycles;
-
-		match self.mode {
-			// Searching OAM
-			PpuMode::SearchOam => {
-				// Enter scanline if finished
-				if self.mode_counter >= 80 {
-					self.mode_counter -= 80;
-					self.set_mode(PpuMode::RenderLine);
-				}
-			}
+	/// Draws the display's output to the given target's top-left corner,
+	/// scaling each pixel up to a `scale` by `scale` square (a scale of 0 is
+	/// treated as 1).
+	///
+	/// The pixels are streamed in a single `fill_contiguous` call, so targets
+	/// such as SPI displays don't need an intermediate frame buffer.
+	#[cfg(feature = "embedded-graphics")]
+	pub fn draw<D>(&self, target: &mut D, scale: u32) -> Result<(), D::Error>
+	where
+		D: DrawTarget,
+		D::Color: From<Rgb888>,
+	{
+		let scale = scale.max(1);
+		let width = WIDTH as u32 * scale;
+		let area = Rectangle::new(Point::zero(), Size::new(width, HEIGHT as u32 * scale));
+
+		let colors = (0..area.size.height).flat_map(|y| {
+			let line = &self.buffer[(y / scale) as usize * WIDTH..][..WIDTH];
+
+			(0..width).map(move |x| {
+				let color = line[(x / scale) as usize];
+				Rgb888::new((color >> 16) as u8, (color >> 8) as u8, color as u8).into()
+			})
+		});
+
+		target.fill_contiguous(&area, colors)
+	}
 
-			PpuMode::RenderLine => {
-				if self.mode_counter >= 172 {
-					self.mode_counter -= 172;
-					self.render_line();
-					self.set_mode(PpuMode::Hblank);
+	/// Returns the number of frames that were completed so far.
+	pub fn frame_count(&self) -> usize {
+		self.frame_count
+	}
 
-					// Check if should prompt an interrupt when getting to Hblank mode.
-					if self.stat.hblank_check_enable() {
-						self.interrupt_flag |= Interrupt::LcdStat.value();
-					}
-				}
-			}
+	/// Returns the rendering statistics of the last completed frame.
+	pub fn frame_stats(&self) -> FrameStats {
+		self.last_stats
+	}
 
-			PpuMode::Hblank => {
-				if self.mode_counter >= 204 {
-					self.mode_counter -= 204;
-					// Move to the next line
-					self.ly += 1;
-					// Set the concidence flag
-					self.refresh_lyc_signal();
+	/// Returns the ppu's current mode.
+	pub fn mode(&self) -> PpuMode {
+		self.mode
+	}
 
-					if self.ly == 144 {
-						// Start V-Blank.
-						self.set_mode(PpuMode::Vblank);
-						self.interrupt_flag |= Interrupt::VerticalBlank.value();
-						// Check if should prompt an interrupt when getting to V-blank mode.
-						if self.stat.vblank_check_enable() {
-							self.interrupt_flag |= Interrupt::LcdStat.value();
-						}
-					} else {
-						self.set_mode(PpuMode::SearchOam);
-					}
-				}
-			}
+	/// Returns the line that is currently drawn (LY), where the lines from
+	/// 144 onwards belong to the V-Blank.
+	pub fn ly(&self) -> u8 {
+		self.ly
+	}
 
-			PpuMode::Vblank => {
-				if self.mode_counter >= 456 {
-					self.mode_counter -= 456;
-					// Move to the next line
-					self.ly += 1;
-					self.refresh_lyc_signal();
+	/// Returns whether the lcd is currently powered on.
+	pub fn lcd_enabled(&self) -> bool {
+		self.lcdc.power()
+	}
 
-					// TODO Make sure that it's actually 154 (it might be 153)
-					if self.ly == 154 {
-						// Start searching OAM
-						self.ly = 0;
-						self.refresh_lyc_signal();
-						self.set_mode(PpuMode::SearchOam);
+	/// Marks whether an OAM DMA transfer is in progress.
+	///
+	/// While the transfer occupies the OAM, the ppu's OAM scan reads 0xFF.
+	pub fn set_oam_dma_active(&mut self, active: bool) {
+		self.oam_dma_active = active;
+	}
 
-						// Check if should prompt an interrupt when getting to SearchOam mode.
-						if self.stat.oam_check_enable() {
-							self.interrupt_flag |= Interrupt::LcdStat.value();
-						}
-					}
-				}
-			}
-		}
+	/// Getter for the OAM region's buffer.
+	pub fn oam(&mut self) -> &mut [u8] {
+		&mut self.oam
 	}
 
 	fn set_mode(&mut self, mode: PpuMode) {
@@ -256,8 +368,26 @@ impl Ppu {
 		self.stat.set_lyc_signal(self.lyc == self.ly);
 
 		if self.stat.signal != 0 && self.stat.lyc_check_enable() {
-			self.interrupt_flag |= Interrupt::LcdStat.value();
+			self.interrupt_flag.insert(Interrupt::LcdStat);
+		}
+	}
+
+	/// Scans the sprites' attributes up to the given sprite for the current line.
+	fn scan_oam(&mut self, until: usize) {
+		for i in self.oam_scan_index..until {
+			let range = (i * 4)..(i * 4 + 4);
+
+			if self.oam_dma_active {
+				// The OAM is occupied by the DMA, so the scan reads garbage.
+				for byte in &mut self.line_oam[range] {
+					*byte = 0xFF;
+				}
+			} else {
+				self.line_oam[range.clone()].copy_from_slice(&self.oam[range]);
+			}
 		}
+
+		self.oam_scan_index = self.oam_scan_index.max(until);
 	}
 
 	/// Perform the ppu's line rendering logic.
@@ -266,11 +396,55 @@ impl Ppu {
 
 		// Wipe the buffer's line
 		for x in 0..WIDTH {
-			self.buffer[line_offset + x] = PALETTE[0];
+			self.buffer[line_offset + x] = self.palette[0];
 		}
 
 		self.draw_bg();
-		self.draw_sprites();
+
+		// The scrolling discards the first tile's hidden pixels.
+		self.stats.mode3_extension += (self.scx % 8) as usize;
+		if self.lcdc.window_enable() && self.wy < self.ly {
+			self.stats.window_lines += 1;
+			self.stats.mode3_extension += 6;
+		}
+
+		if self.lcdc.sprites_enable() {
+			self.count_sprites();
+
+			if self.layers.sprites {
+				self.draw_sprites();
+			}
+		}
+	}
+
+	/// Gathers the statistics of the sprites on the current line, as the
+	/// hardware would select up to `MAX_LINE_SPRITES` of them in the OAM's order.
+	fn count_sprites(&mut self) {
+		let mut count = 0;
+		let sprite_height = if self.lcdc.sprite_size() { 16 } else { 8 };
+
+		for i in 0..NUM_SPRITES {
+			let sprite_addr = i * 4;
+			let sprite_data = SpriteData::new(&self.line_oam[sprite_addr..sprite_addr+4],
+											  self.lcdc.sprite_size());
+
+			// The sprites are selected by their lines alone, regardless of their x.
+			if self.ly.wrapping_sub(sprite_data.y) >= sprite_height {
+				continue;
+			}
+
+			if count == MAX_LINE_SPRITES {
+				self.stats.dropped_sprites += 1;
+				continue;
+			}
+
+			// Each sprite's fetch stalls the pixel transfer by 6 to 11 cycles,
+			// depending on its alignment to the background's tiles.
+			let alignment = sprite_data.x.wrapping_add(8).wrapping_add(self.scx) % 8;
+			self.stats.mode3_extension += 11 - (alignment as usize).min(5);
+
+			count += 1;
+		}
 	}
 
 	fn draw_bg(&mut self) {
@@ -290,12 +464,13 @@ impl Ppu {
 
 		// Iterate over the current line in the x-axis and draw the pixels.
 		for x in 0..WIDTH {
-			let screen_x = if show_window && x as u8 >= wx { x as u8 - wx } else { self.scx.wrapping_add(x as u8) };
+			let in_window = show_window && x as u8 >= wx;
+			let screen_x = if in_window { x as u8 - wx } else { self.scx.wrapping_add(x as u8) };
 			let tile_x = ((screen_x as u16) >> 3) & 31;
 
 			// Get the base offset of the background.
 			let base_offset = [0x1800, 0x1c00][
-				if show_window && x as u8 >= wx {
+				if in_window {
 					if self.lcdc.window_tilemap() { 1 } else { 0 }
 				} else if self.lcdc.bg_tilemap() {
 					1
@@ -310,10 +485,10 @@ impl Ppu {
 				tile_number as usize
 			} else {
 				((tile_number as i8) as usize).wrapping_add(128)
-			} as usize * 16;
+			} * 16;
 
 			let tileset_select = if self.lcdc.tileset() { 0 } else { 0x800 };
-			let tile_data_offset = (tileset_select + tile_offset) as usize + (screen_y as usize % 8) * 2;
+			let tile_data_offset = tileset_select + tile_offset + (screen_y as usize % 8) * 2;
 			let tile_data = &self.vram[tile_data_offset..tile_data_offset+2];
 
 			let tile_x = screen_x % 8;
@@ -321,10 +496,11 @@ impl Ppu {
 			// Get the color from the background's palette.
 			let color_low = if tile_data[0] & (0x80 >> tile_x) != 0 { 1 } else { 0 };
 			let color_high = if tile_data[1] & (0x80 >> tile_x) != 0 { 2 } else { 0 };
-			let color_index = color_high | color_low;
+			let hidden = if in_window { !self.layers.window } else { !self.layers.background };
+			let color_index = if hidden { 0 } else { color_high | color_low };
 
 			let color = Ppu::get_color(self.bgp, color_index);
-			self.buffer[line_offset + x] = PALETTE[color];
+			self.buffer[line_offset + x] = self.palette[color];
 		}
 	}
 
@@ -334,8 +510,8 @@ impl Ppu {
 		let sprite_height = if self.lcdc.sprite_size() { 16 } else { 8 };
 
 		for i in 0..NUM_SPRITES {
-			let sprite_addr = (i as usize) * 4;
-			let sprite_data = SpriteData::new(&self.oam[sprite_addr..sprite_addr+4],
+			let sprite_addr = i * 4;
+			let sprite_data = SpriteData::new(&self.line_oam[sprite_addr..sprite_addr+4],
 											  self.lcdc.sprite_size());
 
 			// Check whether the sprite is out of bounds
@@ -385,13 +561,171 @@ impl Ppu {
 				// Draw the pixel
 				let offset = line_offset + sprite_data.x.wrapping_add(x) as usize;
 
-				if !sprite_data.sprite_behind() || self.buffer[offset] == PALETTE[3] {
-					self.buffer[offset] = PALETTE[color];
+				if !sprite_data.sprite_behind() || self.buffer[offset] == self.palette[3] {
+					self.buffer[offset] = self.palette[color];
 				}
 			}
 		}
 	}
 
+	/// Returns the layers that are drawn to the display.
+	pub fn layers(&self) -> Layers {
+		self.layers
+	}
+
+	/// Selects the layers that are drawn to the display.
+	pub fn set_layers(&mut self, layers: Layers) {
+		self.layers = layers;
+	}
+
+	/// Returns whether the pixels are rendered.
+	pub fn headless(&self) -> bool {
+		self.headless
+	}
+
+	/// Selects whether the pixels are rendered, overriding `Config::headless`
+	/// until the next reset.
+	pub fn set_headless(&mut self, headless: bool) {
+		self.headless = headless;
+	}
+
+	/// Returns the video ram's contents.
+	pub fn vram(&self) -> &[u8] {
+		&self.vram
+	}
+
+	/// Captures the tile data of the displayed background, as done by the
+	/// Super GameBoy's VRAM transfers.
+	///
+	/// The first 256 tiles of the background map, in rows of 20 tiles, are
+	/// copied in order, according to the current LCDC settings.
+	pub fn vram_transfer(&self, buffer: &mut [u8; TRANSFER_SIZE]) {
+		let base_offset = if self.lcdc.bg_tilemap() { 0x1c00 } else { 0x1800 };
+
+		for (index, tile_data) in buffer.chunks_mut(TILE_BYTES).enumerate() {
+			let tile_number = self.vram[base_offset + (index / 20) * 32 + index % 20];
+
+			// The second tileset is addressed with signed tile numbers.
+			let tile = if self.lcdc.tileset() {
+				tile_number as usize
+			} else {
+				((tile_number as i8) as usize).wrapping_add(256)
+			};
+
+			let offset = tile * TILE_BYTES;
+			tile_data.copy_from_slice(&self.vram[offset..offset + TILE_BYTES]);
+		}
+	}
+
+	/// Decodes all the tiles of the given video ram bank into color indices
+	/// (0-3), for debugging tools such as tile viewers.
+	///
+	/// The buffer is laid out as an image of `TILESET_WIDTH` by `TILESET_HEIGHT`
+	/// pixels, with `TILESET_COLUMNS` tiles in each row, ordered by their
+	/// address. Only the first bank is emulated.
+	pub fn decode_tiles(&self, bank: usize, buffer: &mut [u8]) -> Result<(), GameboyError> {
+		Ppu::check_tileset(bank, buffer.len())?;
+
+		for (index, pixel) in buffer.iter_mut().enumerate() {
+			*pixel = self.tileset_pixel(index % TILESET_WIDTH, index / TILESET_WIDTH);
+		}
+
+		Ok(())
+	}
+
+	/// Decodes all the tiles of the given video ram bank into the display's
+	/// colors, through the given palette (formatted as BGP).
+	///
+	/// The buffer is laid out as in `Ppu::decode_tiles`.
+	pub fn render_tiles(&self, bank: usize, palette: u8, buffer: &mut [u32]) -> Result<(), GameboyError> {
+		Ppu::check_tileset(bank, buffer.len())?;
+
+		for (index, pixel) in buffer.iter_mut().enumerate() {
+			let color = self.tileset_pixel(index % TILESET_WIDTH, index / TILESET_WIDTH);
+			*pixel = self.palette[Ppu::get_color(palette, color)];
+		}
+
+		Ok(())
+	}
+
+	/// Renders the whole 256x256 background or window map into the display's
+	/// colors, according to the current LCDC and BGP settings.
+	///
+	/// The map is rendered regardless of whether it's enabled. See
+	/// `Ppu::viewport` for the part of the background that's visible.
+	pub fn render_map(&self, map: TileMap, buffer: &mut [u32]) -> Result<(), GameboyError> {
+		if buffer.len() != MAP_SIZE * MAP_SIZE {
+			return Err(GameboyError::Io("The buffer doesn't match the map's size."));
+		}
+
+		let high_map = match map {
+			TileMap::Background => self.lcdc.bg_tilemap(),
+			TileMap::Window => self.lcdc.window_tilemap(),
+		};
+		let base_offset = if high_map { 0x1c00 } else { 0x1800 };
+
+		for (index, pixel) in buffer.iter_mut().enumerate() {
+			let (x, y) = (index % MAP_SIZE, index / MAP_SIZE);
+			let tile_number = self.vram[base_offset + (y / 8) * 32 + x / 8];
+
+			// The second tileset is addressed with signed tile numbers.
+			let tile = if self.lcdc.tileset() {
+				tile_number as usize
+			} else {
+				((tile_number as i8) as usize).wrapping_add(256)
+			};
+
+			let color = self.tileset_pixel((tile % TILESET_COLUMNS) * 8 + x % 8, (tile / TILESET_COLUMNS) * 8 + y % 8);
+			*pixel = self.palette[Ppu::get_color(self.bgp, color)];
+		}
+
+		Ok(())
+	}
+
+	/// Returns the part of the background that's currently visible.
+	pub fn viewport(&self) -> Viewport {
+		Viewport {
+			x: self.scx,
+			y: self.scy,
+			width: WIDTH,
+			height: HEIGHT,
+		}
+	}
+
+	fn check_tileset(bank: usize, len: usize) -> Result<(), GameboyError> {
+		if bank != 0 {
+			return Err(GameboyError::NotImplemented);
+		}
+		if len != TILESET_WIDTH * TILESET_HEIGHT {
+			return Err(GameboyError::Io("The buffer doesn't match the tileset's size."));
+		}
+
+		Ok(())
+	}
+
+	/// Returns the color index of a pixel within the decoded tileset.
+	fn tileset_pixel(&self, x: usize, y: usize) -> u8 {
+		let tile = (y / 8) * TILESET_COLUMNS + x / 8;
+		// The tile takes 2 bytes for each line.
+		let offset = tile * TILE_BYTES + (y % 8) * 2;
+		let shift = 7 - (x % 8);
+
+		let color_low = (self.vram[offset] >> shift) & 1;
+		let color_high = (self.vram[offset + 1] >> shift) & 1;
+
+		(color_high << 1) | color_low
+	}
+
+	/// Returns the colors of the shades, where the SGB model keeps the
+	/// default ones for `Sgb::colorize` to recognize.
+	fn palette(config: &Config) -> [Color; 4] {
+		if config.model == HardwareModel::SGB {
+			PALETTE
+		} else {
+			config.palette
+		}
+	}
+
 	fn get_color(palette: u8, color: u8) -> usize {
 		match palette >> (2 * color) & 0x03 {
 			0x00 => 3,
@@ -402,6 +736,12 @@ impl Ppu {
 	}
 }
 
+impl Default for Ppu {
+	fn default() -> Self {
+		Ppu::new(&Config::default())
+	}
+}
+
 impl Memory for Ppu {
 	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
 		match address {
@@ -423,7 +763,13 @@ impl Memory for Ppu {
 				let offset = address as usize - range_start!(MMAP_VIDEO_RAM);
 				self.vram[offset] = value;
 			}
-			_ => panic!("Ppu::write: register {:x} is not implemented", address)
+			memory_range!(MMAP_SPRITE_OAM) => {
+				// The OAM is inaccessible while the DMA occupies it.
+				if !self.oam_dma_active {
+					self.oam[address as usize - range_start!(MMAP_SPRITE_OAM)] = value;
+				}
+			}
+			_ => { return Err(GameboyError::BadAddress(address)); }
 		}
 
 		Ok(())
@@ -450,7 +796,15 @@ impl Memory for Ppu {
 				let offset = address as usize - range_start!(MMAP_VIDEO_RAM);
 				self.vram[offset]
 			}
-			_ => panic!("Ppu::read: register {:x} is not implemented", address)
+			memory_range!(MMAP_SPRITE_OAM) => {
+				// The OAM is inaccessible while the DMA occupies it.
+				if self.oam_dma_active {
+					0xFF
+				} else {
+					self.oam[address as usize - range_start!(MMAP_SPRITE_OAM)]
+				}
+			}
+			_ => { return Err(GameboyError::BadAddress(address)); }
 		};
 
 		Ok(result)
@@ -463,11 +817,190 @@ impl InterruptSource for Ppu {
 	}
 
 	fn clear(&mut self) {
-		self.interrupt_flag = 0;
+		self.interrupt_flag = InterruptMask::empty();
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Ppu {
+	fn save_state(&self, writer: &mut StateWriter) {
+		for color in self.buffer.iter() {
+			writer.u32(*color);
+		}
+		writer.bytes(&self.vram);
+		writer.bytes(&self.oam);
+		writer.bytes(&self.line_oam);
+		writer.usize(self.oam_scan_index);
+		writer.bool(self.oam_dma_active);
+
+		writer.u8(self.lcdc.data);
+		writer.u8(self.stat.data);
+		writer.u8(self.stat.signal);
+		for register in [self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+			writer.u8(register);
+		}
+
+		writer.u8(self.stat.mode);
+		writer.usize(self.mode_counter);
+		writer.usize(self.frame_count);
+		writer.u8(self.interrupt_flag.bits());
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		for color in self.buffer.iter_mut() {
+			*color = reader.u32()?;
+		}
+		reader.bytes(&mut self.vram)?;
+		reader.bytes(&mut self.oam)?;
+		reader.bytes(&mut self.line_oam)?;
+		self.oam_scan_index = reader.usize()?;
+		self.oam_dma_active = reader.bool()?;
+
+		self.lcdc.data = reader.u8()?;
+		self.stat.data = reader.u8()?;
+		self.stat.signal = reader.u8()?;
+		for register in [&mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc, &mut self.bgp,
+			&mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx] {
+			*register = reader.u8()?;
+		}
+
+		// The mode is serialized as its STAT bits.
+		self.mode = match reader.u8()? {
+			0 => PpuMode::Hblank,
+			1 => PpuMode::Vblank,
+			2 => PpuMode::SearchOam,
+			3 => PpuMode::RenderLine,
+			_ => { return Err(GameboyError::Io("The savestate holds an invalid ppu mode.")); }
+		};
+		self.stat.set_mode(self.mode);
+		self.mode_counter = reader.usize()?;
+		self.frame_count = reader.usize()?;
+		self.interrupt_flag = InterruptMask::from_bits(reader.u8()?);
+
+		// Lines outside of the display are only reached during the V-Blank.
+		let visible = self.mode != PpuMode::Vblank;
+		if self.ly >= 154 || (visible && self.ly as usize >= HEIGHT) || self.oam_scan_index > NUM_SPRITES {
+			return Err(GameboyError::Io("The savestate holds an invalid ppu state."));
+		}
+
+		Ok(())
 	}
 }
 
 #[allow(unused)]
+impl Peripheral for Ppu {
+	/// Reset this peripheral to boot state.
+	fn reset(&mut self, config: &Config) {
+		self.reset_registers();
+		self.headless = config.headless;
+		self.palette = Ppu::palette(config);
+	}
+
+	/// Returns the number of cycles until the next mode change.
+	fn next_event(&self) -> usize {
+		if !self.lcdc.power() {
+			return usize::MAX;
+		}
+
+		let duration: usize = match self.mode {
+			PpuMode::SearchOam => 80,
+			PpuMode::RenderLine => 172,
+			PpuMode::Hblank => 204,
+			PpuMode::Vblank => 456,
+		};
+
+		duration.saturating_sub(self.mode_counter)
+	}
+
+	/// Update the ppu's state according to the elapsed time.
+	fn process(&mut self, cycles: usize) {
+		if !self.lcdc.power() {
+			// LCD is powered off.
+			return;
+		}
+
+		self.mode_counter += cycles;
+
+		match self.mode {
+			// Searching OAM
+			PpuMode::SearchOam => {
+				// Each sprite takes 2 cycles to scan, only for the rendering.
+				if !self.headless {
+					self.scan_oam((self.mode_counter / 2).min(NUM_SPRITES));
+				}
+
+				// Enter scanline if finished
+				if self.mode_counter >= 80 {
+					self.mode_counter -= 80;
+					self.oam_scan_index = 0;
+					self.set_mode(PpuMode::RenderLine);
+				}
+			}
+
+			PpuMode::RenderLine => {
+				if self.mode_counter >= 172 {
+					self.mode_counter -= 172;
+					if !self.headless {
+						self.render_line();
+					}
+					self.set_mode(PpuMode::Hblank);
+
+					// Check if should prompt an interrupt when getting to Hblank mode.
+					if self.stat.hblank_check_enable() {
+						self.interrupt_flag.insert(Interrupt::LcdStat);
+					}
+				}
+			}
+
+			PpuMode::Hblank => {
+				if self.mode_counter >= 204 {
+					self.mode_counter -= 204;
+					// Move to the next line
+					self.ly += 1;
+					// Set the concidence flag
+					self.refresh_lyc_signal();
+
+					if self.ly == 144 {
+						// Start V-Blank, the frame is complete.
+						self.set_mode(PpuMode::Vblank);
+						self.frame_count = self.frame_count.wrapping_add(1);
+						self.last_stats = core::mem::take(&mut self.stats);
+						self.interrupt_flag.insert(Interrupt::VerticalBlank);
+						// Check if should prompt an interrupt when getting to V-blank mode.
+						if self.stat.vblank_check_enable() {
+							self.interrupt_flag.insert(Interrupt::LcdStat);
+						}
+					} else {
+						self.set_mode(PpuMode::SearchOam);
+					}
+				}
+			}
+
+			PpuMode::Vblank => {
+				if self.mode_counter >= 456 {
+					self.mode_counter -= 456;
+					// Move to the next line
+					self.ly += 1;
+					self.refresh_lyc_signal();
+
+					// TODO Make sure that it's actually 154 (it might be 153)
+					if self.ly == 154 {
+						// Start searching OAM
+						self.ly = 0;
+						self.refresh_lyc_signal();
+						self.set_mode(PpuMode::SearchOam);
+
+						// Check if should prompt an interrupt when getting to SearchOam mode.
+						if self.stat.oam_check_enable() {
+							self.interrupt_flag.insert(Interrupt::LcdStat);
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
 impl Lcdc {
 	pub fn new() -> Self {
 		Lcdc { data: 0 }
@@ -598,3 +1131,298 @@ impl SpriteData {
 		self.tile_attr & (1 << 7) != 0
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Processes the ppu until it completes the next line.
+	fn render_next_line(ppu: &mut Ppu) {
+		while ppu.mode() == PpuMode::Hblank {
+			ppu.process(4);
+		}
+		while ppu.mode() != PpuMode::Hblank {
+			ppu.process(4);
+		}
+	}
+
+	#[test]
+	fn test_status() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+
+		ppu.write(IO_LCDC, 0x91)?;
+		assert!(ppu.lcd_enabled() && ppu.mode() == PpuMode::SearchOam);
+
+		render_next_line(&mut ppu);
+		assert!(ppu.mode() == PpuMode::Hblank && ppu.ly() == 0);
+
+		// The frame is complete once the V-Blank starts.
+		while ppu.mode() != PpuMode::Vblank {
+			ppu.process(4);
+		}
+		assert!(ppu.ly() == 144 && ppu.ly() == ppu.read(IO_LY)?);
+		assert!(ppu.frame_count() == 1);
+
+		// The state is frozen while the lcd is off.
+		ppu.write(IO_LCDC, 0x11)?;
+		ppu.process(456);
+		assert!(!ppu.lcd_enabled() && ppu.ly() == 144);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_oam_dma_arbitration() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+		let mut frame = vec![0; WIDTH * HEIGHT];
+
+		// Enable the sprites, and draw a solid sprite over lines 0-7.
+		ppu.write(IO_LCDC, 0x93)?;
+		for address in 0x8010..0x8020 {
+			ppu.write(address, 0xFF)?;
+		}
+		ppu.oam()[0..4].copy_from_slice(&[16, 8, 1, 0]);
+
+		render_next_line(&mut ppu);
+
+		// The scan can't read the OAM while the DMA occupies it.
+		ppu.set_oam_dma_active(true);
+		render_next_line(&mut ppu);
+		ppu.set_oam_dma_active(false);
+
+		render_next_line(&mut ppu);
+
+		// Sprites that were scanned before the DMA started are still visible.
+		while ppu.mode() == PpuMode::Hblank {
+			ppu.process(4);
+		}
+		ppu.process(40);
+		ppu.set_oam_dma_active(true);
+		render_next_line(&mut ppu);
+
+		ppu.flush(&mut frame);
+		assert!(frame[0] == PALETTE[0]);
+		assert!(frame[WIDTH] == PALETTE[3]);
+		assert!(frame[2 * WIDTH] == PALETTE[0]);
+		assert!(frame[3 * WIDTH] == PALETTE[0]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_decode_tiles() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+		let mut indices = vec![0; TILESET_WIDTH * TILESET_HEIGHT];
+		let mut colors = vec![0; TILESET_WIDTH * TILESET_HEIGHT];
+
+		// The second tile's first line holds the colors 0-3, from left to right.
+		ppu.write(0x8010, 0b0101_0000)?;
+		ppu.write(0x8011, 0b0011_0000)?;
+		// The 17th tile starts the tileset's second row.
+		ppu.write(0x8100, 0x80)?;
+
+		ppu.decode_tiles(0, &mut indices)?;
+		assert!(indices[8..13] == [0, 1, 2, 3, 0]);
+		assert!(indices[8 * TILESET_WIDTH] == 1);
+
+		ppu.render_tiles(0, 0xE4, &mut colors)?;
+		assert!(colors[8..12] == [PALETTE[3], PALETTE[2], PALETTE[1], PALETTE[0]]);
+
+		assert!(ppu.decode_tiles(1, &mut indices).is_err());
+		assert!(ppu.decode_tiles(0, &mut indices[1..]).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_render_map() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+		let mut map = vec![0; MAP_SIZE * MAP_SIZE];
+
+		// Place a solid tile at the background's second column, using the
+		// signed tileset, and another one at the window's first column.
+		ppu.write(IO_LCDC, 0xC1)?;
+		for address in 0x9010..0x9020 {
+			ppu.write(address, 0xFF)?;
+		}
+		ppu.write(0x9801, 0x01)?;
+		ppu.write(0x9C00, 0x01)?;
+		ppu.write(IO_SCX, 0x12)?;
+		ppu.write(IO_SCY, 0x34)?;
+
+		ppu.render_map(TileMap::Background, &mut map)?;
+		assert!(map[7] == PALETTE[3] && map[8] == PALETTE[0]);
+		assert!(map[MAP_SIZE * 7 + 15] == PALETTE[0]);
+		assert!(map[MAP_SIZE * 8 + 8] == PALETTE[3]);
+
+		ppu.render_map(TileMap::Window, &mut map)?;
+		assert!(map[0] == PALETTE[0] && map[8] == PALETTE[3]);
+
+		assert!(ppu.viewport() == Viewport { x: 0x12, y: 0x34, width: WIDTH, height: HEIGHT });
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_vram_transfer() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+		let mut data = [0; TRANSFER_SIZE];
+
+		// The second line of the map starts with the 21st tile, which is
+		// taken from the signed tileset.
+		ppu.write(IO_LCDC, 0x81)?;
+		ppu.write(0x9820, 0x80)?;
+		ppu.write(0x8800, 0x42)?;
+		ppu.write(0x9000, 0x24)?;
+
+		ppu.vram_transfer(&mut data);
+		assert!(data[0] == 0x24);
+		assert!(data[20 * TILE_BYTES] == 0x42);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_layers() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+		let mut frame = vec![0; WIDTH * HEIGHT];
+
+		// A solid background tile under a solid sprite, at the first line.
+		ppu.write(IO_LCDC, 0x93)?;
+		for address in 0x8000..0x8020 {
+			ppu.write(address, 0xFF)?;
+		}
+		ppu.oam()[0..4].copy_from_slice(&[16, 16, 1, 0]);
+		ppu.write(IO_BGP, 0xE4)?;
+		ppu.write(IO_OBP0, 0x40)?;
+
+		ppu.set_layers(Layers { sprites: false, ..Layers::default() });
+		render_next_line(&mut ppu);
+		ppu.set_layers(Layers { background: false, ..Layers::default() });
+		render_next_line(&mut ppu);
+
+		ppu.flush(&mut frame);
+		assert!(frame[8] == PALETTE[0]);
+		assert!(frame[WIDTH] == PALETTE[3] && frame[WIDTH + 8] == PALETTE[2]);
+
+		// The hidden layers don't affect the registers.
+		assert!(ppu.read(IO_LCDC)? == 0x93);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_frame_stats() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+
+		// 12 solid sprites over lines 0-7, and the window from line 101.
+		ppu.write(IO_LCDC, 0xB3)?;
+		for address in 0x8010..0x8020 {
+			ppu.write(address, 0xFF)?;
+		}
+		for sprite in 0..12 {
+			let x = if sprite == 11 { 100 } else { 8 };
+			ppu.oam()[sprite * 4..sprite * 4 + 4].copy_from_slice(&[16, x, 1, 0]);
+		}
+		ppu.write(IO_WY, 100)?;
+		ppu.write(IO_WX, 7)?;
+
+		while ppu.frame_count() == 0 {
+			ppu.process(4);
+		}
+
+		let stats = ppu.frame_stats();
+		assert!(stats.dropped_sprites == 2 * 8);
+		assert!(stats.window_lines == 43);
+		assert!(stats.mode3_extension == 8 * 10 * 11 + 43 * 6);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_sprites_enable() -> Result<(), GameboyError> {
+		let mut ppu = Ppu::new(&Config::default());
+		let mut frame = vec![0; WIDTH * HEIGHT];
+
+		// Draw a solid sprite over lines 0-7.
+		ppu.write(IO_LCDC, 0x93)?;
+		for address in 0x8010..0x8020 {
+			ppu.write(address, 0xFF)?;
+		}
+		ppu.oam()[0..4].copy_from_slice(&[16, 8, 1, 0]);
+
+		// Toggle the sprites between lines.
+		render_next_line(&mut ppu);
+		ppu.write(IO_LCDC, 0x91)?;
+		render_next_line(&mut ppu);
+		ppu.write(IO_LCDC, 0x93)?;
+		render_next_line(&mut ppu);
+
+		// The sprite is drawn over the background's lightest color.
+		ppu.flush(&mut frame);
+		assert!(frame[0] == PALETTE[0]);
+		assert!(frame[WIDTH] == PALETTE[3]);
+		assert!(frame[2 * WIDTH] == PALETTE[0]);
+
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "embedded-graphics")]
+	fn test_draw() -> Result<(), GameboyError> {
+		use std::vec::Vec;
+		use embedded_graphics_core::{Pixel, geometry::OriginDimensions};
+
+		/// A target that records the drawn pixels.
+		struct Target(Vec<Rgb888>);
+
+		impl OriginDimensions for Target {
+			fn size(&self) -> Size {
+				Size::new(2 * WIDTH as u32, 2 * HEIGHT as u32)
+			}
+		}
+
+		impl DrawTarget for Target {
+			type Color = Rgb888;
+			type Error = core::convert::Infallible;
+
+			fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+			where
+				I: IntoIterator<Item = Pixel<Rgb888>>,
+			{
+				for Pixel(point, color) in pixels {
+					self.0[point.y as usize * 2 * WIDTH + point.x as usize] = color;
+				}
+				Ok(())
+			}
+		}
+
+		let mut ppu = Ppu::new(&Config::default());
+
+		// Draw a solid sprite over lines 0-7.
+		ppu.write(IO_LCDC, 0x93)?;
+		for address in 0x8010..0x8020 {
+			ppu.write(address, 0xFF)?;
+		}
+		ppu.oam()[0..4].copy_from_slice(&[16, 8, 1, 0]);
+		for _ in 0..3 {
+			render_next_line(&mut ppu);
+		}
+
+		let mut frame = vec![0; WIDTH * HEIGHT];
+		ppu.flush(&mut frame);
+		assert!(frame[..3 * WIDTH].iter().any(|&color| color != frame[0]));
+
+		// Each pixel is scaled up to a 2x2 square.
+		let mut target = Target(vec![Rgb888::new(0, 0, 0); 4 * WIDTH * HEIGHT]);
+		let _ = ppu.draw(&mut target, 2);
+
+		for (index, drawn) in target.0.iter().enumerate() {
+			let (x, y) = (index % (2 * WIDTH), index / (2 * WIDTH));
+			let color = frame[(y / 2) * WIDTH + x / 2];
+			assert!(*drawn == Rgb888::new((color >> 16) as u8, (color >> 8) as u8, color as u8));
+		}
+
+		Ok(())
+	}
+}
@@ -8,8 +8,11 @@ use super::consts::*;
 use super::memory_range::*;
 
 use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 /// Gameboy's internal memory.
+#[derive(Clone)]
 pub struct InternalRam {
 	data: [u8; range_size!(MMAP_RAM_INTERNAL)],
 	high_data: [u8; range_size!(MMAP_RAM_HIGH)],
@@ -31,10 +34,10 @@ impl InternalRam {
 	fn offset(&self, address: u16) -> usize {
 		match address {
 			memory_range!(MMAP_RAM_INTERNAL) => {
-				(address as usize - range_start!(MMAP_RAM_INTERNAL)) as usize
+				address as usize - range_start!(MMAP_RAM_INTERNAL)
 			}
 			memory_range!(MMAP_RAM_ECHO) => {
-				(address as usize - range_start!(MMAP_RAM_ECHO)) as usize
+				address as usize - range_start!(MMAP_RAM_ECHO)
 			}
 			_ => {
 				panic!();
@@ -46,7 +49,7 @@ impl InternalRam {
 	fn hram_offset(&self, address: u16) -> usize {
 		match address {
 			memory_range!(MMAP_RAM_HIGH) => {
-				(address as usize - range_start!(MMAP_RAM_HIGH)) as usize
+				address as usize - range_start!(MMAP_RAM_HIGH)
 			}
 			_ => {
 				panic!();
@@ -55,6 +58,25 @@ impl InternalRam {
 	}
 }
 
+impl Default for InternalRam {
+	fn default() -> Self {
+		InternalRam::new()
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for InternalRam {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bytes(&self.data);
+		writer.bytes(&self.high_data);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		reader.bytes(&mut self.data)?;
+		reader.bytes(&mut self.high_data)
+	}
+}
+
 impl Memory for InternalRam {
 	/// Write to the internal ram.
 	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
@@ -3,27 +3,48 @@
 
 #![deny(missing_docs)]
 //! Emulate the real time clock, that appears in type-3 MBCs.
-//! TODO implement the RTC's functionality.
 
 use super::Memory;
 use crate::GameboyError;
 use core::ops::RangeInclusive;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 /// The rtc registers are mapped to 0xA000-0xBF00 whenever
 /// a value within the control range is written to the RAM/RTC select
 /// register.
 pub const RTC_CONTROL_RANGE: RangeInclusive<u8> = 0x8..=0xC;
 
+/// The clock's oscillator is emulated using the cpu's clock, so that the
+/// seconds tick at the same emulated moments on every run.
+pub const CYCLES_PER_SECOND: u32 = crate::cpu::timing::CLOCK_RATE;
+
+/// The size of a serialized `RtcState`.
+pub const RTC_STATE_SIZE: usize = 14;
+
 /// The cartridge's real-time clock registers.
 ///
 /// Internally, the clock is incremented using an internal counter,
 /// and the registers are updated whenever the clock data is latched
 /// by the software.
-#[allow(dead_code)]
+#[derive(Clone)]
 pub struct Rtc {
 	registers: [u8; 5],
+	clock: [u8; 5],
 	active_register: u8,
-	counter: usize,
+	counter: u32,
+	latch_value: u8,
+}
+
+/// The clock's state, as should be persisted alongside the cartridge's ram.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RtcState {
+	/// The running clock's registers.
+	pub clock: [u8; 5],
+	/// The registers' values as of the last latch.
+	pub latched: [u8; 5],
+	/// The number of cycles elapsed since the last tick of the seconds counter.
+	pub subsecond_cycles: u32,
 }
 
 enum RtcRegister {
@@ -34,13 +55,24 @@ enum RtcRegister {
 	Flags = 4,
 }
 
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+const FLAG_DAYS_MSB: u8 = 1 << 0;
+const FLAG_HALT: u8 = 1 << 6;
+const FLAG_CARRY: u8 = 1 << 7;
+
+/// The bits that are implemented by each of the clock's registers.
+const REGISTER_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, FLAG_CARRY | FLAG_HALT | FLAG_DAYS_MSB];
+
 impl Rtc {
 	/// Create a new real-time clock.
 	pub fn new() -> Self {
 		Rtc {
 			registers: [0_u8; 5],
+			clock: [0_u8; 5],
 			active_register: 0,
 			counter: 0,
+			latch_value: 0xFF,
 		}
 	}
 
@@ -74,17 +106,84 @@ impl Rtc {
 		self.registers[RtcRegister::Flags as usize]
 	}
 
-	/// Increment the clock.
-	pub fn tick(&self, _cycles: usize) {
-		// unimplemented!();
+	/// Returns the number of cycles elapsed since the seconds counter last ticked.
+	pub fn subsecond_cycles(&self) -> u32 {
+		self.counter
+	}
+
+	/// Sets the running clock to the given time, and restarts the current
+	/// second. The halt flag is kept, and the day counter's carry is cleared.
+	///
+	/// * `days` - The day counter, from 0 to 511.
+	/// * `hours`, `minutes`, `seconds` - The time of the day.
+	pub fn set_time(&mut self, days: u16, hours: u8, minutes: u8, seconds: u8) -> Result<(), GameboyError> {
+		if days > 511 || hours > 23 || minutes > 59 || seconds > 59 {
+			return Err(GameboyError::Io("The time is out of the clock's range."));
+		}
+
+		let flags = &mut self.clock[RtcRegister::Flags as usize];
+		*flags = (*flags & FLAG_HALT) | ((days >> 8) as u8 & FLAG_DAYS_MSB);
+
+		self.clock[RtcRegister::Seconds as usize] = seconds;
+		self.clock[RtcRegister::Minutes as usize] = minutes;
+		self.clock[RtcRegister::Hours as usize] = hours;
+		self.clock[RtcRegister::DaysLow as usize] = days as u8;
+		self.counter = 0;
+
+		Ok(())
+	}
+
+	/// Advances the running clock by whole seconds, unless it's halted.
+	///
+	/// This is how the clock is advanced by the host's time, see
+	/// `RtcSource::WallClock`.
+	pub fn advance_seconds(&mut self, seconds: u64) {
+		if (self.clock[RtcRegister::Flags as usize] & FLAG_HALT) != 0 {
+			return;
+		}
+
+		let mut seconds = seconds;
+
+		// Out-of-range values take a while to wrap around, so they're counted
+		// second by second until the clock is back in range.
+		while seconds > 0 && !self.in_range() {
+			self.increment_seconds();
+			seconds -= 1;
+		}
+
+		self.increment_days(seconds / SECONDS_PER_DAY);
+
+		for _ in 0..seconds % SECONDS_PER_DAY {
+			self.increment_seconds();
+		}
+	}
+
+	/// Increment the clock according to the elapsed cpu cycles.
+	pub fn tick(&mut self, cycles: usize) {
+		if (self.clock[RtcRegister::Flags as usize] & FLAG_HALT) != 0 {
+			return;
+		}
+
+		let elapsed = self.counter as usize + cycles;
+		let cycles_per_second = CYCLES_PER_SECOND as usize;
+
+		self.counter = (elapsed % cycles_per_second) as u32;
+
+		for _ in 0..(elapsed / cycles_per_second) {
+			self.increment_seconds();
+		}
 	}
 
 	/// Fetch the clock data into the rtc's registers.
 	///
 	/// The latching process consists of writing 0x00 and then 0x01 to
 	/// the Latch Clock Data register.
-	pub fn latch(&mut self, _value: u8) {
-		// unimplemented!();
+	pub fn latch(&mut self, value: u8) {
+		if self.latch_value == 0x00 && value == 0x01 {
+			self.registers = self.clock;
+		}
+
+		self.latch_value = value;
 	}
 
 	/// Set the currently memory mapped RTC register.
@@ -97,18 +196,341 @@ impl Rtc {
 
 		Err(GameboyError::BadValue(value))
 	}
+
+	/// Returns the clock's state for persistence.
+	pub fn state(&self) -> RtcState {
+		RtcState {
+			clock: self.clock,
+			latched: self.registers,
+			subsecond_cycles: self.counter,
+		}
+	}
+
+	/// Restores a previously persisted clock state.
+	pub fn set_state(&mut self, state: &RtcState) {
+		self.clock = state.clock;
+		self.registers = state.latched;
+		self.counter = state.subsecond_cycles % CYCLES_PER_SECOND;
+	}
+
+	/// Returns whether the running clock holds a valid time of the day.
+	fn in_range(&self) -> bool {
+		self.clock[RtcRegister::Seconds as usize] < 60 &&
+			self.clock[RtcRegister::Minutes as usize] < 60 &&
+			self.clock[RtcRegister::Hours as usize] < 24
+	}
+
+	/// Advances the 9-bit days counter, which sets the carry flag on overflow.
+	fn increment_days(&mut self, days: u64) {
+		let days_low = self.clock[RtcRegister::DaysLow as usize] as u64;
+		let flags = &mut self.clock[RtcRegister::Flags as usize];
+		let days = ((((*flags & FLAG_DAYS_MSB) as u64) << 8) | days_low) + days;
+
+		*flags = (*flags & !FLAG_DAYS_MSB) | ((days >> 8) as u8 & FLAG_DAYS_MSB);
+		if days >= 512 {
+			*flags |= FLAG_CARRY;
+		}
+		self.clock[RtcRegister::DaysLow as usize] = days as u8;
+	}
+
+	/// Advance the running clock by a single second.
+	fn increment_seconds(&mut self) {
+		let clock = &mut self.clock;
+
+		// Each counter only carries when it passes its last valid value,
+		// so out-of-range values count up to the register's limit and wrap
+		// around without carrying.
+		clock[RtcRegister::Seconds as usize] = (clock[RtcRegister::Seconds as usize] + 1) & 0x3F;
+		if clock[RtcRegister::Seconds as usize] != 60 {
+			return;
+		}
+		clock[RtcRegister::Seconds as usize] = 0;
+
+		clock[RtcRegister::Minutes as usize] = (clock[RtcRegister::Minutes as usize] + 1) & 0x3F;
+		if clock[RtcRegister::Minutes as usize] != 60 {
+			return;
+		}
+		clock[RtcRegister::Minutes as usize] = 0;
+
+		clock[RtcRegister::Hours as usize] = (clock[RtcRegister::Hours as usize] + 1) & 0x1F;
+		if clock[RtcRegister::Hours as usize] != 24 {
+			return;
+		}
+		clock[RtcRegister::Hours as usize] = 0;
+
+		self.increment_days(1);
+	}
+}
+
+impl RtcState {
+	/// Serializes the state, in little-endian byte order.
+	pub fn to_bytes(&self) -> [u8; RTC_STATE_SIZE] {
+		let mut data = [0_u8; RTC_STATE_SIZE];
+
+		data[0..5].copy_from_slice(&self.clock);
+		data[5..10].copy_from_slice(&self.latched);
+		data[10..14].copy_from_slice(&self.subsecond_cycles.to_le_bytes());
+
+		data
+	}
+
+	/// Deserializes a state that was serialized with `to_bytes`.
+	pub fn from_bytes(data: &[u8]) -> Result<Self, GameboyError> {
+		if data.len() != RTC_STATE_SIZE {
+			return Err(GameboyError::Io("Invalid rtc state size."));
+		}
+
+		let mut state = RtcState::default();
+		let mut subsecond_cycles = [0_u8; 4];
+
+		state.clock.copy_from_slice(&data[0..5]);
+		state.latched.copy_from_slice(&data[5..10]);
+		subsecond_cycles.copy_from_slice(&data[10..14]);
+		state.subsecond_cycles = u32::from_le_bytes(subsecond_cycles);
+
+		Ok(state)
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Rtc {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bytes(&self.registers);
+		writer.bytes(&self.clock);
+		writer.u8(self.active_register);
+		writer.u32(self.counter);
+		writer.u8(self.latch_value);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		reader.bytes(&mut self.registers)?;
+		reader.bytes(&mut self.clock)?;
+		self.active_register = reader.u8()?;
+		self.counter = reader.u32()? % CYCLES_PER_SECOND;
+		self.latch_value = reader.u8()?;
+
+		if self.active_register as usize >= self.registers.len() {
+			return Err(GameboyError::Io("The savestate holds an invalid rtc register."));
+		}
+
+		Ok(())
+	}
+}
+
+impl Default for Rtc {
+	fn default() -> Self {
+		Rtc::new()
+	}
 }
 
 impl Memory for Rtc {
 	/// Writes to the rtc's currently active register.
-	fn write(&mut self, _address: u16, _value: u8) -> Result<(), GameboyError> {
-		// unimplemented!();
+	fn write(&mut self, _address: u16, value: u8) -> Result<(), GameboyError> {
+		let register = self.active_register as usize;
+
+		if register == RtcRegister::Seconds as usize {
+			// Writing the seconds resets the sub-second counter.
+			self.counter = 0;
+		}
+
+		// Unimplemented bits are ignored, the halt flag freezes the
+		// clock and the carry flag stays set until it's cleared.
+		let value = value & REGISTER_MASKS[register];
+		self.clock[register] = value;
+		self.registers[register] = value;
 		Ok(())
 	}
 
 	/// Reads the rtc's currently active register.
 	fn read(&self, _address: u16) -> Result<u8, GameboyError> {
-		// unimplemented!();
-		Ok(0x31)
+		Ok(self.registers[self.active_register as usize])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_subsecond_ticks() -> Result<(), GameboyError> {
+		let mut rtc = Rtc::new();
+
+		// A second passes only after the exact amount of cycles.
+		rtc.tick((CYCLES_PER_SECOND - 4) as usize);
+		rtc.latch(0);
+		rtc.latch(1);
+		assert!(rtc.seconds() == 0);
+		assert!(rtc.subsecond_cycles() == CYCLES_PER_SECOND - 4);
+
+		rtc.tick(8);
+		rtc.latch(0);
+		rtc.latch(1);
+		assert!(rtc.seconds() == 1);
+		assert!(rtc.subsecond_cycles() == 4);
+
+		// Writing the seconds register restarts the current second.
+		rtc.set_active_register(0x08)?;
+		rtc.write(0xA000, 30)?;
+		assert!(rtc.subsecond_cycles() == 0);
+		assert!(rtc.read(0xA000)? == 30);
+
+		Ok(())
+	}
+
+	/// Writes the given value to an rtc register through its memory interface.
+	fn write_register(rtc: &mut Rtc, register: u8, value: u8) -> Result<(), GameboyError> {
+		rtc.set_active_register(0x08 + register)?;
+		rtc.write(0xA000, value)
+	}
+
+	/// Latches the clock and reads the given rtc register.
+	fn read_register(rtc: &mut Rtc, register: u8) -> Result<u8, GameboyError> {
+		rtc.latch(0);
+		rtc.latch(1);
+		rtc.set_active_register(0x08 + register)?;
+		rtc.read(0xA000)
+	}
+
+	#[test]
+	fn test_halt() -> Result<(), GameboyError> {
+		let mut rtc = Rtc::new();
+
+		// Games halt the clock while setting it.
+		write_register(&mut rtc, 4, FLAG_HALT)?;
+		write_register(&mut rtc, 0, 58)?;
+		write_register(&mut rtc, 2, 23)?;
+
+		rtc.tick(CYCLES_PER_SECOND as usize * 10);
+		assert!(read_register(&mut rtc, 0)? == 58);
+		assert!(read_register(&mut rtc, 2)? == 23);
+
+		// The clock resumes from the stored values.
+		write_register(&mut rtc, 4, 0)?;
+		rtc.tick(CYCLES_PER_SECOND as usize);
+		assert!(read_register(&mut rtc, 0)? == 59);
+
+		rtc.tick(CYCLES_PER_SECOND as usize * 60);
+		assert!(read_register(&mut rtc, 0)? == 59);
+		assert!(read_register(&mut rtc, 1)? == 1);
+		assert!(read_register(&mut rtc, 2)? == 23);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_days_carry() -> Result<(), GameboyError> {
+		let mut rtc = Rtc::new();
+
+		// 255 days, 23:59:59
+		write_register(&mut rtc, 0, 59)?;
+		write_register(&mut rtc, 1, 59)?;
+		write_register(&mut rtc, 2, 23)?;
+		write_register(&mut rtc, 3, 0xFF)?;
+
+		// The days counter's MSB is set after day 255.
+		rtc.tick(CYCLES_PER_SECOND as usize);
+		assert!(read_register(&mut rtc, 3)? == 0);
+		assert!(read_register(&mut rtc, 4)? == FLAG_DAYS_MSB);
+
+		// The days counter wraps at 512, and sets the carry flag.
+		write_register(&mut rtc, 0, 59)?;
+		write_register(&mut rtc, 1, 59)?;
+		write_register(&mut rtc, 2, 23)?;
+		write_register(&mut rtc, 3, 0xFF)?;
+		rtc.tick(CYCLES_PER_SECOND as usize);
+		assert!(read_register(&mut rtc, 3)? == 0);
+		assert!(read_register(&mut rtc, 4)? == FLAG_CARRY);
+
+		// The carry flag is sticky until it's cleared by the software.
+		rtc.tick(CYCLES_PER_SECOND as usize * 3600 * 24);
+		assert!(read_register(&mut rtc, 3)? == 1);
+		assert!(read_register(&mut rtc, 4)? == FLAG_CARRY);
+
+		write_register(&mut rtc, 4, 0)?;
+		assert!(read_register(&mut rtc, 4)? == 0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_out_of_range_values() -> Result<(), GameboyError> {
+		let mut rtc = Rtc::new();
+
+		// Unimplemented bits are ignored.
+		write_register(&mut rtc, 0, 0xFF)?;
+		assert!(read_register(&mut rtc, 0)? == 0x3F);
+		write_register(&mut rtc, 2, 0xFF)?;
+		assert!(read_register(&mut rtc, 2)? == 0x1F);
+		write_register(&mut rtc, 4, !FLAG_HALT)?;
+		assert!(read_register(&mut rtc, 4)? == FLAG_CARRY | FLAG_DAYS_MSB);
+
+		// Invalid seconds count up to 63 and wrap without carrying.
+		write_register(&mut rtc, 0, 61)?;
+		write_register(&mut rtc, 1, 0)?;
+		rtc.tick(CYCLES_PER_SECOND as usize);
+		assert!(read_register(&mut rtc, 0)? == 62);
+		rtc.tick(CYCLES_PER_SECOND as usize * 2);
+		assert!(read_register(&mut rtc, 0)? == 0);
+		assert!(read_register(&mut rtc, 1)? == 0);
+
+		// Invalid hours wrap at 32 without incrementing the days.
+		write_register(&mut rtc, 0, 59)?;
+		write_register(&mut rtc, 1, 59)?;
+		write_register(&mut rtc, 2, 31)?;
+		write_register(&mut rtc, 3, 0)?;
+		rtc.tick(CYCLES_PER_SECOND as usize);
+		assert!(read_register(&mut rtc, 2)? == 0);
+		assert!(read_register(&mut rtc, 3)? == 0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_state_persistence() -> Result<(), GameboyError> {
+		let mut rtc = Rtc::new();
+		rtc.tick(CYCLES_PER_SECOND as usize * 61 + 1234);
+
+		let data = rtc.state().to_bytes();
+		let mut restored = Rtc::new();
+		restored.set_state(&RtcState::from_bytes(&data)?);
+
+		assert!(restored.state() == rtc.state());
+		assert!(restored.subsecond_cycles() == 1234);
+
+		// The restored clock keeps ticking from the same moment.
+		restored.tick((CYCLES_PER_SECOND - 1234) as usize);
+		restored.latch(0);
+		restored.latch(1);
+		assert!(restored.minutes() == 1 && restored.seconds() == 2);
+
+		assert!(RtcState::from_bytes(&data[1..]).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_set_time() -> Result<(), GameboyError> {
+		let mut rtc = Rtc::new();
+		rtc.tick(1234);
+
+		rtc.set_time(300, 23, 59, 58)?;
+		assert!(rtc.subsecond_cycles() == 0);
+		assert!(read_register(&mut rtc, 3)? == 44);
+		assert!(read_register(&mut rtc, 4)? == FLAG_DAYS_MSB);
+
+		// The host's time advances the clock by whole seconds.
+		rtc.advance_seconds(3);
+		assert!(read_register(&mut rtc, 0)? == 1 && read_register(&mut rtc, 2)? == 0);
+		assert!(read_register(&mut rtc, 3)? == 45);
+
+		// Long periods wrap the days, and set the carry.
+		rtc.advance_seconds(1000 * 24 * 3600);
+		assert!(read_register(&mut rtc, 4)? & FLAG_CARRY != 0);
+		assert!(read_register(&mut rtc, 0)? == 1);
+
+		assert!(rtc.set_time(512, 0, 0, 0).is_err());
+		assert!(rtc.set_time(0, 24, 0, 0).is_err());
+
+		Ok(())
 	}
 }
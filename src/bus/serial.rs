@@ -0,0 +1,406 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Emulate the gameboy's serial port.
+//!
+//! A transfer exchanges the contents of SB with the linked device, one bit
+//! at a time. The side that uses its internal clock (the master) drives the
+//! transfer, while the other side (the slave) waits for the master's clock.
+//!
+//! The serial port doesn't know what it's linked to. Once a linked master
+//! finishes shifting a byte out, the byte is held until the link delivers the
+//! byte received in exchange, see `Serial::take_outgoing`.
+//!
+//! Without a link, nothing drives the data line, so a master's transfer
+//! completes on time with 0xFF received, and a slave keeps waiting.
+
+use super::{Memory, Peripheral};
+use super::memory_range::*;
+
+use crate::GameboyError;
+use crate::config::*;
+use crate::cpu::interrupts::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+
+/// Serial port related constants.
+#[allow(missing_docs)]
+pub mod consts {
+	use super::*;
+
+	pub const IO_SB: u16 = 0xFF01;
+	pub const IO_SC: u16 = 0xFF02;
+
+	pub const MMAP_IO_SERIAL: MemoryRange = make_range!(0xFF01, 0xFF02);
+
+	/// The number of cycles it takes to transfer a byte with the internal
+	/// clock, at 8192Hz.
+	pub const SERIAL_TRANSFER_CYCLES: usize = 8 * 512;
+	/// The number of cycles it takes to transfer a byte with the GBC's fast
+	/// internal clock, at 262144Hz.
+	pub const SERIAL_FAST_TRANSFER_CYCLES: usize = 8 * 16;
+
+	/// Starts a transfer, cleared when the transfer completes.
+	pub const SC_TRANSFER: u8 = 0x80;
+	/// Selects the fast clock (GBC).
+	pub const SC_FAST_CLOCK: u8 = 0x02;
+	/// Selects the internal clock, making this side the master.
+	pub const SC_INTERNAL_CLOCK: u8 = 0x01;
+}
+
+use consts::*;
+
+/// The serial port's state.
+#[derive(Clone)]
+pub struct Serial {
+	/// Serial transfer data.
+	sb: u8,
+	/// Serial transfer control.
+	sc: u8,
+	/// The emulated hardware model, the fast clock exists only on the GBC.
+	model: HardwareModel,
+	/// The number of cycles left until the master finishes shifting its byte.
+	cycles_left: Option<usize>,
+	/// The byte the master shifted out, waiting to be exchanged.
+	outgoing: Option<u8>,
+	/// Whether a device is linked, which is set by the frontend and isn't a
+	/// part of the machine's state.
+	linked: bool,
+	interrupt_flag: InterruptMask,
+}
+
+impl Serial {
+	/// Initialize a new serial port.
+	pub fn new(config: &Config) -> Self {
+		let mut serial = Serial {
+			sb: 0,
+			sc: 0,
+			model: config.model,
+			cycles_left: None,
+			outgoing: None,
+			linked: false,
+			interrupt_flag: InterruptMask::empty(),
+		};
+
+		serial.reset(config);
+
+		serial
+	}
+
+	/// Returns the serial transfer data register (SB).
+	pub fn sb(&self) -> u8 {
+		self.sb
+	}
+
+	/// Returns the serial transfer control register (SC), without its unused bits.
+	pub fn sc(&self) -> u8 {
+		self.sc
+	}
+
+	/// Returns whether a transfer is in progress.
+	pub fn transferring(&self) -> bool {
+		self.sc & SC_TRANSFER != 0
+	}
+
+	/// Returns whether this side drives the transfer's clock.
+	pub fn master(&self) -> bool {
+		self.sc & SC_INTERNAL_CLOCK != 0
+	}
+
+	/// Returns whether a device is linked to the serial port.
+	pub fn linked(&self) -> bool {
+		self.linked
+	}
+
+	/// Marks whether a device is linked to the serial port, so the master's
+	/// transfers wait for it to exchange the bytes.
+	///
+	/// Unlinking completes a transfer that waits for the link with 0xFF.
+	pub fn set_linked(&mut self, linked: bool) {
+		self.linked = linked;
+		self.complete_unlinked();
+	}
+
+	/// Takes the byte the master finished shifting out, if any.
+	///
+	/// The transfer completes once the byte received in exchange is passed to
+	/// `complete_transfer`.
+	pub fn take_outgoing(&mut self) -> Option<u8> {
+		self.outgoing.take()
+	}
+
+	/// Completes the master's transfer with the byte received from the link.
+	pub fn complete_transfer(&mut self, received: u8) {
+		self.sb = received;
+		self.sc &= !SC_TRANSFER;
+		self.interrupt_flag.insert(Interrupt::Serial);
+	}
+
+	/// Clocks a byte in from a linked master, and returns the byte shifted out.
+	///
+	/// The byte is only exchanged if a transfer is waiting for an external
+	/// clock, otherwise the master receives 0xFF, as if nothing was linked.
+	pub fn clock_in(&mut self, received: u8) -> u8 {
+		if !self.transferring() || self.master() {
+			return 0xFF;
+		}
+
+		let sent = self.sb;
+		self.complete_transfer(received);
+
+		sent
+	}
+
+	/// Completes a transfer that waits for the link with 0xFF, if nothing is linked.
+	fn complete_unlinked(&mut self) {
+		if !self.linked && self.outgoing.take().is_some() {
+			self.complete_transfer(0xFF);
+		}
+	}
+
+	fn read_mask(&self) -> u8 {
+		match self.model {
+			HardwareModel::GBC => 0x7C,
+			_ => 0x7E,
+		}
+	}
+}
+
+impl Memory for Serial {
+	fn write(&mut self, address: u16, value: u8) -> Result<(), GameboyError> {
+		match address {
+			IO_SB => {
+				self.sb = value;
+			}
+			IO_SC => {
+				self.sc = value & !self.read_mask();
+
+				self.outgoing = None;
+				self.cycles_left = if self.transferring() && self.master() {
+					if self.sc & SC_FAST_CLOCK != 0 {
+						Some(SERIAL_FAST_TRANSFER_CYCLES)
+					} else {
+						Some(SERIAL_TRANSFER_CYCLES)
+					}
+				} else {
+					None
+				};
+			}
+			_ => {
+				return Err(GameboyError::BadAddress(address));
+			}
+		}
+
+		Ok(())
+	}
+
+	fn read(&self, address: u16) -> Result<u8, GameboyError> {
+		match address {
+			IO_SB => Ok(self.sb),
+			IO_SC => Ok(self.sc | self.read_mask()),
+			_ => Err(GameboyError::BadAddress(address)),
+		}
+	}
+}
+
+impl InterruptSource for Serial {
+	fn interrupts(&self) -> InterruptMask {
+		self.interrupt_flag
+	}
+
+	fn clear(&mut self) {
+		self.interrupt_flag = InterruptMask::empty();
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Serial {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.u8(self.sb);
+		writer.u8(self.sc);
+		writer.bool(self.cycles_left.is_some());
+		writer.usize(self.cycles_left.unwrap_or(0));
+		writer.bool(self.outgoing.is_some());
+		writer.u8(self.outgoing.unwrap_or(0));
+		writer.u8(self.interrupt_flag.bits());
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.sb = reader.u8()?;
+		self.sc = reader.u8()?;
+		let transferring = reader.bool()?;
+		let cycles_left = reader.usize()?;
+		self.cycles_left = if transferring { Some(cycles_left) } else { None };
+		let outgoing = reader.bool()?;
+		let byte = reader.u8()?;
+		self.outgoing = if outgoing { Some(byte) } else { None };
+		self.interrupt_flag = InterruptMask::from_bits(reader.u8()?);
+
+		// The state might have been saved while a link was waiting.
+		self.complete_unlinked();
+
+		Ok(())
+	}
+}
+
+impl Peripheral for Serial {
+	/// Reset the peripheral to boot state.
+	fn reset(&mut self, config: &Config) {
+		self.sb = 0;
+		self.sc = 0;
+		self.model = config.model;
+		self.cycles_left = None;
+		self.outgoing = None;
+	}
+
+	/// Update the transfer's state according to the elapsed time.
+	fn process(&mut self, cycles: usize) {
+		if let Some(cycles_left) = self.cycles_left {
+			if cycles_left <= cycles {
+				self.cycles_left = None;
+
+				// Without a link, the data line is pulled up.
+				if self.linked {
+					self.outgoing = Some(self.sb);
+				} else {
+					self.complete_transfer(0xFF);
+				}
+			} else {
+				self.cycles_left = Some(cycles_left - cycles);
+			}
+		}
+	}
+
+	/// Returns the number of cycles until the master finishes shifting its byte.
+	fn next_event(&self) -> usize {
+		self.cycles_left.unwrap_or(usize::MAX)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_serial_exchange() -> Result<(), GameboyError> {
+		let config = Config::default();
+		let mut master = Serial::new(&config);
+		let mut slave = Serial::new(&config);
+		master.set_linked(true);
+
+		master.write(IO_SB, 0x12)?;
+		slave.write(IO_SB, 0x34)?;
+
+		// The slave waits for the master's clock.
+		slave.write(IO_SC, SC_TRANSFER)?;
+		master.write(IO_SC, SC_TRANSFER | SC_INTERNAL_CLOCK)?;
+		assert!(master.read(IO_SC)? == 0xFF);
+
+		master.process(SERIAL_TRANSFER_CYCLES - 4);
+		assert!(master.take_outgoing().is_none());
+		master.process(4);
+
+		// The bytes are exchanged when the master's byte is shifted out.
+		let sent = master.take_outgoing().expect("The transfer should have completed");
+		let received = slave.clock_in(sent);
+		master.complete_transfer(received);
+
+		assert!(master.sb() == 0x34 && slave.sb() == 0x12);
+		assert!(!master.transferring() && !slave.transferring());
+		assert!(master.interrupts() == InterruptMask::from(Interrupt::Serial));
+		assert!(slave.interrupts() == InterruptMask::from(Interrupt::Serial));
+
+		// A side that isn't waiting for a transfer doesn't respond.
+		assert!(slave.clock_in(0x56) == 0xFF);
+		assert!(slave.sb() == 0x12);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_serial_registers() -> Result<(), GameboyError> {
+		let mut dmg = Serial::new(&Config { model: HardwareModel::GB, ..Config::default() });
+		let mut cgb = Serial::new(&Config { model: HardwareModel::GBC, ..Config::default() });
+
+		// Unused bits read as 1, and the fast clock exists only on the GBC.
+		dmg.write(IO_SC, 0x00)?;
+		cgb.write(IO_SC, 0x00)?;
+		assert!(dmg.read(IO_SC)? == 0x7E);
+		assert!(cgb.read(IO_SC)? == 0x7C);
+
+		// The getters skip the unused bits.
+		dmg.write(IO_SB, 0x5A)?;
+		dmg.write(IO_SC, 0xFF)?;
+		cgb.write(IO_SC, 0xFF)?;
+		assert!(dmg.sb() == 0x5A);
+		assert!(dmg.sc() == 0x81);
+		assert!(cgb.sc() == 0x83);
+
+		// The fast clock shortens the transfer.
+		cgb.set_linked(true);
+		cgb.process(SERIAL_FAST_TRANSFER_CYCLES);
+		assert!(cgb.take_outgoing() == Some(0));
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_serial_disconnected() -> Result<(), GameboyError> {
+		let mut serial = Serial::new(&Config::default());
+
+		// The master's transfer completes on time, with 0xFF received.
+		serial.write(IO_SB, 0x42)?;
+		serial.write(IO_SC, SC_TRANSFER | SC_INTERNAL_CLOCK)?;
+		serial.process(SERIAL_TRANSFER_CYCLES - 4);
+		assert!(serial.transferring());
+		serial.process(4);
+
+		assert!(!serial.transferring() && serial.sb() == 0xFF);
+		assert!(serial.take_outgoing().is_none());
+		assert!(serial.interrupts() == InterruptMask::from(Interrupt::Serial));
+
+		// A slave's transfer waits for an external clock.
+		serial.clear();
+		serial.write(IO_SC, SC_TRANSFER)?;
+		serial.process(2 * SERIAL_TRANSFER_CYCLES);
+		assert!(serial.transferring() && serial.interrupts().is_empty());
+
+		// Unlinking completes the transfer that waits for the link.
+		serial.set_linked(true);
+		serial.write(IO_SB, 0x42)?;
+		serial.write(IO_SC, SC_TRANSFER | SC_INTERNAL_CLOCK)?;
+		serial.process(SERIAL_TRANSFER_CYCLES);
+		assert!(serial.transferring());
+		serial.set_linked(false);
+
+		assert!(!serial.transferring() && serial.sb() == 0xFF);
+		assert!(serial.take_outgoing().is_none());
+		assert!(serial.interrupts() == InterruptMask::from(Interrupt::Serial));
+
+		Ok(())
+	}
+
+	#[cfg(feature = "alloc")]
+	#[test]
+	fn test_serial_load_unlinked() -> Result<(), GameboyError> {
+		let mut linked = Serial::new(&Config::default());
+		let mut unlinked = Serial::new(&Config::default());
+		linked.set_linked(true);
+
+		// Save while the master's byte waits for the link.
+		linked.write(IO_SB, 0x42)?;
+		linked.write(IO_SC, SC_TRANSFER | SC_INTERNAL_CLOCK)?;
+		linked.process(SERIAL_TRANSFER_CYCLES);
+		let mut writer = StateWriter::new();
+		linked.save_state(&mut writer);
+
+		// Without a link, the transfer completes with 0xFF once loaded.
+		let state = writer.into_inner();
+		unlinked.load_state(&mut StateReader::new(&state))?;
+		assert!(!unlinked.transferring() && unlinked.sb() == 0xFF);
+		assert!(unlinked.take_outgoing().is_none());
+
+		Ok(())
+	}
+}
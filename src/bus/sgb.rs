@@ -0,0 +1,904 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Super GameBoy emulation.
+//!
+//! The SGB displays the gameboy's screen inside a 256x224 border, which the
+//! game transfers through the video ram: the SGB captures 4KB of tile data
+//! from the displayed background, see `Ppu::vram_transfer`.
+//!
+//! The game sends commands to the SGB through the joypad's P14 and P15 lines.
+//! Each command is made of 1-7 packets of 16 bytes, where every packet starts
+//! with a reset pulse (both lines low), followed by its 128 bits from the
+//! least significant bit (P14 low for 0, P15 low for 1) and a 0 stop bit.
+//! The lines are set high between the pulses. The first byte of a command
+//! holds its code (upper 5 bits) and its number of packets (lower 3 bits).
+
+use crate::GameboyError;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+use super::ppu::consts::{WIDTH, HEIGHT, PALETTE};
+
+/// SGB-related constants.
+#[allow(missing_docs)]
+pub mod consts {
+	/// The dimensions of the border, in pixels.
+	pub const SGB_WIDTH: usize = 256;
+	pub const SGB_HEIGHT: usize = 224;
+
+	/// The position of the gameboy's screen within the border.
+	pub const SCREEN_X: usize = 48;
+	pub const SCREEN_Y: usize = 40;
+
+	/// The number of bytes that are captured by each VRAM transfer.
+	pub const TRANSFER_SIZE: usize = 0x1000;
+
+	/// The number of bytes taken by the border's 256 tiles, which are
+	/// transferred in two halves.
+	pub const BORDER_TILES_SIZE: usize = 0x2000;
+	/// The number of bytes each border tile takes, 4 for each of its 8 lines.
+	pub const BORDER_TILE_BYTES: usize = 32;
+
+	/// The border's dimensions, in tiles.
+	pub const BORDER_COLUMNS: usize = 32;
+	pub const BORDER_ROWS: usize = 28;
+
+	/// The border's map takes 32x32 entries in the transfer, followed by
+	/// the border's palettes.
+	pub const BORDER_PALETTES_OFFSET: usize = 0x800;
+	/// The border uses 4 palettes of 16 colors.
+	pub const BORDER_PALETTES: usize = 4;
+	pub const BORDER_PALETTE_SIZE: usize = 16;
+
+	/// The size of a single command packet, and the maximal number of
+	/// packets in a command.
+	pub const PACKET_SIZE: usize = 16;
+	pub const MAX_PACKETS: usize = 7;
+
+	/// The screen's dimensions in tiles, which the attributes apply to.
+	pub const ATTR_COLUMNS: usize = 20;
+	pub const ATTR_ROWS: usize = 18;
+
+	/// The number of palettes that can be applied to the screen, and the
+	/// number of system palettes that are transferred by PAL_TRN.
+	pub const PALETTES: usize = 4;
+	pub const SYSTEM_PALETTES: usize = 512;
+
+	/// The attribute files that are transferred by ATTR_TRN, each holding
+	/// 2 bits per tile.
+	pub const ATTR_FILES: usize = 45;
+	pub const ATTR_FILE_SIZE: usize = ATTR_COLUMNS * ATTR_ROWS / 4;
+
+	/// The palette used until the game sets its own, matching the colors
+	/// of the other models, from the lightest to the darkest.
+	pub const DEFAULT_PALETTE: [u16; 4] = [0x6bfc, 0x3b11, 0x29a6, 0x1061];
+
+	// Command codes
+	pub const CMD_PAL01: u8 = 0x00;
+	pub const CMD_PAL23: u8 = 0x01;
+	pub const CMD_PAL03: u8 = 0x02;
+	pub const CMD_PAL12: u8 = 0x03;
+	pub const CMD_ATTR_BLK: u8 = 0x04;
+	pub const CMD_ATTR_LIN: u8 = 0x05;
+	pub const CMD_ATTR_DIV: u8 = 0x06;
+	pub const CMD_ATTR_CHR: u8 = 0x07;
+	pub const CMD_PAL_SET: u8 = 0x0a;
+	pub const CMD_PAL_TRN: u8 = 0x0b;
+	pub const CMD_MLT_REQ: u8 = 0x11;
+	pub const CMD_CHR_TRN: u8 = 0x13;
+	pub const CMD_PCT_TRN: u8 = 0x14;
+	pub const CMD_ATTR_TRN: u8 = 0x15;
+	pub const CMD_ATTR_SET: u8 = 0x16;
+	pub const CMD_MASK_EN: u8 = 0x17;
+}
+
+use consts::*;
+
+/// The data that's captured from the display by a VRAM transfer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transfer {
+	/// The border's tiles, where `high` selects the tiles 0x80-0xFF (CHR_TRN).
+	BorderTiles {
+		/// Whether the second half of the tiles is transferred.
+		high: bool,
+	},
+	/// The border's map and palettes (PCT_TRN).
+	BorderMap,
+	/// The system palettes (PAL_TRN).
+	Palettes,
+	/// The attribute files (ATTR_TRN).
+	Attributes,
+}
+
+/// How the gameboy's screen is masked (MASK_EN), usually while the game
+/// transfers data through the video ram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+	/// The screen is displayed.
+	None,
+	/// The screen keeps displaying its last frame.
+	Freeze,
+	/// The screen is black.
+	Black,
+	/// The screen is filled with the palettes' shared color 0.
+	Color0,
+}
+
+/// The Super GameBoy's state.
+#[derive(Clone)]
+pub struct Sgb {
+	/// The border's tiles, in the SNES's 4 bits per pixel format.
+	border_tiles: [u8; BORDER_TILES_SIZE],
+	/// The border's visible map entries, each holding a tile number, a
+	/// palette and flipping flags.
+	border_map: [u16; BORDER_COLUMNS * BORDER_ROWS],
+	/// The border's palettes, as 15-bit BGR colors.
+	border_palettes: [u16; BORDER_PALETTES * BORDER_PALETTE_SIZE],
+
+	/// The packets of the command that's being received.
+	command: [u8; PACKET_SIZE * MAX_PACKETS],
+	/// The number of packets received for the current command.
+	packets: u8,
+	/// The number of bits received for the current packet, including its stop bit.
+	bits: u8,
+	/// Whether a packet is being received.
+	receiving: bool,
+	/// Whether both lines were set high since the last pulse.
+	armed: bool,
+	/// The last value written to P14 and P15.
+	lines: u8,
+
+	/// The palettes that are applied to the screen, as 15-bit BGR colors.
+	palettes: [[u16; 4]; PALETTES],
+	/// The system palettes, that PAL_SET selects from.
+	system_palettes: [u16; SYSTEM_PALETTES * 4],
+	/// The palette of each of the screen's tiles.
+	attributes: [u8; ATTR_COLUMNS * ATTR_ROWS],
+	/// The attribute files, that ATTR_SET selects from.
+	attribute_files: [u8; ATTR_FILES * ATTR_FILE_SIZE],
+	mask: Mask,
+	/// The number of joypads (MLT_REQ), and the one that's currently selected.
+	players: u8,
+	player: u8,
+	/// The VRAM transfer that's done on the next frame, if any.
+	pending_transfer: Option<Transfer>,
+}
+
+impl Sgb {
+	/// Initialize a new SGB instance, with an empty border.
+	pub fn new() -> Self {
+		Sgb {
+			border_tiles: [0; BORDER_TILES_SIZE],
+			border_map: [0; BORDER_COLUMNS * BORDER_ROWS],
+			border_palettes: [0; BORDER_PALETTES * BORDER_PALETTE_SIZE],
+			command: [0; PACKET_SIZE * MAX_PACKETS],
+			packets: 0,
+			bits: 0,
+			receiving: false,
+			armed: false,
+			lines: 0,
+			palettes: [DEFAULT_PALETTE; PALETTES],
+			system_palettes: [0; SYSTEM_PALETTES * 4],
+			attributes: [0; ATTR_COLUMNS * ATTR_ROWS],
+			attribute_files: [0; ATTR_FILES * ATTR_FILE_SIZE],
+			mask: Mask::None,
+			players: 1,
+			player: 0,
+			pending_transfer: None,
+		}
+	}
+
+	/// Receives the command packets from the writes to the P1 register.
+	pub fn write_joypad(&mut self, value: u8) {
+		let lines = value & 0x30;
+		let previous = core::mem::replace(&mut self.lines, lines);
+
+		match lines {
+			// A reset pulse starts a new packet.
+			0x00 => {
+				self.receiving = true;
+				self.armed = false;
+				self.bits = 0;
+			}
+			0x30 => {
+				self.armed = self.receiving;
+
+				// The next joypad is selected when P15 goes high, outside of packets.
+				if !self.receiving && previous == 0x10 {
+					self.player = (self.player + 1) % self.players;
+				}
+			}
+			_ if self.armed => {
+				self.armed = false;
+				self.receive_bit(lines == 0x10);
+			}
+			_ => {}
+		}
+	}
+
+	/// Handles a single bit of the packet that's being received.
+	fn receive_bit(&mut self, bit: bool) {
+		let bits = self.bits as usize;
+		self.bits += 1;
+
+		if bits < PACKET_SIZE * 8 {
+			let byte = &mut self.command[self.packets as usize * PACKET_SIZE + bits / 8];
+			if bits & 7 == 0 {
+				*byte = 0;
+			}
+			*byte |= (bit as u8) << (bits % 8);
+			return;
+		}
+
+		// The packet ends with its stop bit, which must be 0.
+		self.receiving = false;
+		if bit {
+			self.packets = 0;
+			return;
+		}
+
+		self.packets += 1;
+		let length = (self.command[0] & 7).max(1);
+		if self.packets >= length {
+			self.packets = 0;
+			self.execute();
+		}
+	}
+
+	/// Executes the command that was received.
+	fn execute(&mut self) {
+		let data = self.command;
+
+		match data[0] >> 3 {
+			CMD_PAL01 => { self.set_palettes(0, 1, &data); }
+			CMD_PAL23 => { self.set_palettes(2, 3, &data); }
+			CMD_PAL03 => { self.set_palettes(0, 3, &data); }
+			CMD_PAL12 => { self.set_palettes(1, 2, &data); }
+			CMD_ATTR_BLK => { self.attr_blk(&data); }
+			CMD_ATTR_LIN => { self.attr_lin(&data); }
+			CMD_ATTR_DIV => { self.attr_div(&data); }
+			CMD_ATTR_CHR => { self.attr_chr(&data); }
+			CMD_PAL_SET => {
+				for (index, palette) in self.palettes.iter_mut().enumerate() {
+					let id = (u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]) & 0x1ff) as usize;
+					palette.copy_from_slice(&self.system_palettes[id * 4..id * 4 + 4]);
+				}
+				if data[9] & 0x80 != 0 {
+					self.apply_attribute_file(data[9] & 0x3f);
+				}
+				if data[9] & 0x40 != 0 {
+					self.mask = Mask::None;
+				}
+			}
+			CMD_ATTR_SET => {
+				self.apply_attribute_file(data[1] & 0x3f);
+				if data[1] & 0x40 != 0 {
+					self.mask = Mask::None;
+				}
+			}
+			CMD_MLT_REQ => {
+				self.players = match data[1] & 3 {
+					1 => 2,
+					3 => 4,
+					_ => 1,
+				};
+				self.player = 0;
+			}
+			CMD_MASK_EN => {
+				self.mask = match data[1] & 3 {
+					0 => Mask::None,
+					1 => Mask::Freeze,
+					2 => Mask::Black,
+					_ => Mask::Color0,
+				};
+			}
+			CMD_PAL_TRN => { self.pending_transfer = Some(Transfer::Palettes); }
+			CMD_CHR_TRN => { self.pending_transfer = Some(Transfer::BorderTiles { high: data[1] & 1 != 0 }); }
+			CMD_PCT_TRN => { self.pending_transfer = Some(Transfer::BorderMap); }
+			CMD_ATTR_TRN => { self.pending_transfer = Some(Transfer::Attributes); }
+			// The sound, the SNES's memory and the other commands aren't emulated.
+			_ => {}
+		}
+	}
+
+	/// Sets two palettes (PAL01, PAL23, PAL03, PAL12), and the color 0 that's
+	/// shared by all of the palettes.
+	fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+		let color = |index: usize| u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]);
+
+		for palette in self.palettes.iter_mut() {
+			palette[0] = color(0);
+		}
+		for index in 1..4 {
+			self.palettes[first][index] = color(index);
+			self.palettes[second][index] = color(index + 3);
+		}
+	}
+
+	/// Applies palettes to the inside, the outside and the border of
+	/// rectangular blocks (ATTR_BLK).
+	fn attr_blk(&mut self, data: &[u8]) {
+		let count = (data[1] as usize).min((data.len() - 2) / 6);
+
+		for block in data[2..2 + count * 6].chunks(6) {
+			let control = block[0] & 7;
+			let inside = block[1] & 3;
+			let outside = (block[1] >> 4) & 3;
+			let (x1, y1, x2, y2) = (block[2] & 0x1f, block[3] & 0x1f, block[4] & 0x1f, block[5] & 0x1f);
+
+			// If only the inside or the outside is set, it also applies to the border.
+			let border = match control {
+				1 => Some(inside),
+				4 => Some(outside),
+				_ if control & 2 != 0 => Some((block[1] >> 2) & 3),
+				_ => None,
+			};
+
+			for (index, attribute) in self.attributes.iter_mut().enumerate() {
+				let x = (index % ATTR_COLUMNS) as u8;
+				let y = (index / ATTR_COLUMNS) as u8;
+				let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+				let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+
+				if on_border {
+					if let Some(border) = border {
+						*attribute = border;
+					}
+				} else if within {
+					if control & 1 != 0 {
+						*attribute = inside;
+					}
+				} else if control & 4 != 0 {
+					*attribute = outside;
+				}
+			}
+		}
+	}
+
+	/// Applies palettes to whole rows or columns (ATTR_LIN).
+	fn attr_lin(&mut self, data: &[u8]) {
+		let count = (data[1] as usize).min(data.len() - 2);
+
+		for &line in &data[2..2 + count] {
+			let number = (line & 0x1f) as usize;
+			let palette = (line >> 5) & 3;
+
+			if line & 0x80 != 0 {
+				// A horizontal line.
+				if number < ATTR_ROWS {
+					self.attributes[number * ATTR_COLUMNS..(number + 1) * ATTR_COLUMNS].fill(palette);
+				}
+			} else if number < ATTR_COLUMNS {
+				for row in 0..ATTR_ROWS {
+					self.attributes[row * ATTR_COLUMNS + number] = palette;
+				}
+			}
+		}
+	}
+
+	/// Divides the screen into two parts and the line between them (ATTR_DIV).
+	fn attr_div(&mut self, data: &[u8]) {
+		let after = data[1] & 3;
+		let before = (data[1] >> 2) & 3;
+		let on_line = (data[1] >> 4) & 3;
+		let horizontal = data[1] & 0x40 != 0;
+		let line = data[2] as usize;
+
+		for (index, attribute) in self.attributes.iter_mut().enumerate() {
+			let position = if horizontal { index / ATTR_COLUMNS } else { index % ATTR_COLUMNS };
+
+			*attribute = match position.cmp(&line) {
+				core::cmp::Ordering::Less => before,
+				core::cmp::Ordering::Equal => on_line,
+				core::cmp::Ordering::Greater => after,
+			};
+		}
+	}
+
+	/// Applies palettes to consecutive tiles (ATTR_CHR).
+	fn attr_chr(&mut self, data: &[u8]) {
+		let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+		let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+		let vertical = data[5] & 1 != 0;
+
+		// Each byte holds 4 tiles, from its most significant bits.
+		let tiles = data[6..].iter().flat_map(|byte| (0..4).map(move |index| (byte >> (6 - index * 2)) & 3));
+
+		for palette in tiles.take(count.min(ATTR_COLUMNS * ATTR_ROWS)) {
+			if x >= ATTR_COLUMNS || y >= ATTR_ROWS {
+				break;
+			}
+			self.attributes[y * ATTR_COLUMNS + x] = palette;
+
+			if vertical {
+				y += 1;
+				if y == ATTR_ROWS {
+					y = 0;
+					x += 1;
+				}
+			} else {
+				x += 1;
+				if x == ATTR_COLUMNS {
+					x = 0;
+					y += 1;
+				}
+			}
+		}
+	}
+
+	/// Applies one of the transferred attribute files to the screen.
+	fn apply_attribute_file(&mut self, file: u8) {
+		let file = file as usize;
+		if file >= ATTR_FILES {
+			return;
+		}
+
+		let data = &self.attribute_files[file * ATTR_FILE_SIZE..(file + 1) * ATTR_FILE_SIZE];
+		for (index, attribute) in self.attributes.iter_mut().enumerate() {
+			*attribute = (data[index / 4] >> (6 - (index % 4) * 2)) & 3;
+		}
+	}
+
+	/// Returns the joypad that's currently read through P1, if the multiplayer
+	/// mode was requested (MLT_REQ).
+	pub fn player(&self) -> Option<u8> {
+		match self.players {
+			1 => None,
+			_ => Some(self.player),
+		}
+	}
+
+	/// Returns the palettes that are applied to the screen.
+	pub fn palettes(&self) -> &[[u16; 4]; PALETTES] {
+		&self.palettes
+	}
+
+	/// Returns the palette of each of the screen's tiles, row by row.
+	pub fn attributes(&self) -> &[u8] {
+		&self.attributes
+	}
+
+	/// Returns how the screen is masked.
+	pub fn mask(&self) -> Mask {
+		self.mask
+	}
+
+	/// Returns the VRAM transfer that's done on the next frame, if any.
+	pub fn pending_transfer(&self) -> Option<Transfer> {
+		self.pending_transfer
+	}
+
+	/// Removes the pending VRAM transfer, so it can be done.
+	pub(crate) fn take_transfer(&mut self) -> Option<Transfer> {
+		self.pending_transfer.take()
+	}
+
+	/// Stores the data of a VRAM transfer, captured by `Ppu::vram_transfer`.
+	pub fn transfer(&mut self, transfer: Transfer, data: &[u8; TRANSFER_SIZE]) {
+		match transfer {
+			Transfer::BorderTiles { high } => { self.transfer_border_tiles(data, high); }
+			Transfer::BorderMap => { self.transfer_border_map(data); }
+			Transfer::Palettes => {
+				for (index, color) in self.system_palettes.iter_mut().enumerate() {
+					*color = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+				}
+			}
+			Transfer::Attributes => {
+				self.attribute_files.copy_from_slice(&data[..ATTR_FILES * ATTR_FILE_SIZE]);
+			}
+		}
+	}
+
+	/// Stores the transferred border tiles (CHR_TRN), where `high` selects
+	/// whether they're the tiles 0x80-0xFF.
+	pub fn transfer_border_tiles(&mut self, data: &[u8; TRANSFER_SIZE], high: bool) {
+		let offset = if high { TRANSFER_SIZE } else { 0 };
+		self.border_tiles[offset..offset + TRANSFER_SIZE].copy_from_slice(data);
+	}
+
+	/// Stores the transferred border map and palettes (PCT_TRN).
+	pub fn transfer_border_map(&mut self, data: &[u8; TRANSFER_SIZE]) {
+		for (index, entry) in self.border_map.iter_mut().enumerate() {
+			*entry = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+		}
+
+		let palettes = &data[BORDER_PALETTES_OFFSET..];
+		for (index, color) in self.border_palettes.iter_mut().enumerate() {
+			*color = u16::from_le_bytes([palettes[index * 2], palettes[index * 2 + 1]]);
+		}
+	}
+
+	/// Colorizes the gameboy's screen, into a buffer of `WIDTH` by `HEIGHT`
+	/// pixels.
+	///
+	/// Each of the screen's shades is replaced by its color in the palette
+	/// of its tile (see `attributes`). While the screen is frozen, the
+	/// buffer is left unchanged.
+	pub fn colorize(&self, screen: &[u32], buffer: &mut [u32]) {
+		assert!(screen.len() == WIDTH * HEIGHT && buffer.len() == WIDTH * HEIGHT);
+
+		if self.mask == Mask::Freeze {
+			return;
+		}
+
+		for (index, pixel) in buffer.iter_mut().enumerate() {
+			*pixel = self.screen_pixel(screen, index % WIDTH, index / WIDTH);
+		}
+	}
+
+	/// Composites the colorized gameboy's screen inside the border, into a
+	/// buffer of `SGB_WIDTH` by `SGB_HEIGHT` pixels.
+	///
+	/// The border's transparent pixels show the screen, or the palettes'
+	/// shared color 0 outside of it. While the screen is frozen, its pixels
+	/// in the buffer are left unchanged.
+	pub fn render_border(&self, screen: &[u32], buffer: &mut [u32]) -> Result<(), GameboyError> {
+		if screen.len() != WIDTH * HEIGHT || buffer.len() != SGB_WIDTH * SGB_HEIGHT {
+			return Err(GameboyError::Io("The buffer doesn't match the border's size."));
+		}
+
+		for (index, pixel) in buffer.iter_mut().enumerate() {
+			let (x, y) = (index % SGB_WIDTH, index / SGB_WIDTH);
+
+			*pixel = match self.border_pixel(x, y) {
+				Some(color) => color,
+				None => {
+					let in_screen = (SCREEN_X..SCREEN_X + WIDTH).contains(&x) &&
+						(SCREEN_Y..SCREEN_Y + HEIGHT).contains(&y);
+
+					if !in_screen {
+						Sgb::rgb(self.palettes[0][0])
+					} else if self.mask == Mask::Freeze {
+						*pixel
+					} else {
+						self.screen_pixel(screen, x - SCREEN_X, y - SCREEN_Y)
+					}
+				}
+			};
+		}
+
+		Ok(())
+	}
+
+	/// Returns the colorized screen's color at the given pixel.
+	fn screen_pixel(&self, screen: &[u32], x: usize, y: usize) -> u32 {
+		match self.mask {
+			Mask::Black => 0,
+			Mask::Color0 => Sgb::rgb(self.palettes[0][0]),
+			_ => {
+				// The display's palette goes from the darkest shade to the lightest.
+				let pixel = screen[y * WIDTH + x];
+				let shade = PALETTE.iter().position(|&color| color == pixel).map_or(0, |index| 3 - index);
+				let palette = self.attributes[(y / 8) * ATTR_COLUMNS + x / 8] as usize;
+
+				Sgb::rgb(self.palettes[palette][shade])
+			}
+		}
+	}
+
+	/// Returns the border's color at the given pixel, or `None` if it's transparent.
+	fn border_pixel(&self, x: usize, y: usize) -> Option<u32> {
+		let entry = self.border_map[(y / 8) * BORDER_COLUMNS + x / 8];
+
+		let tile = (entry & 0xff) as usize;
+		// The border uses the SNES's palettes 4-7.
+		let palette = ((entry >> 10) & 3) as usize;
+		let tile_x = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+		let tile_y = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+
+		// Each line is held in 4 bit planes, where the last two follow the
+		// tile's first 16 bytes.
+		let offset = tile * BORDER_TILE_BYTES + tile_y * 2;
+		let shift = 7 - tile_x;
+		let planes = [
+			self.border_tiles[offset],
+			self.border_tiles[offset + 1],
+			self.border_tiles[offset + 16],
+			self.border_tiles[offset + 17],
+		];
+
+		let color = planes.iter().enumerate()
+			.fold(0, |color, (plane, bits)| color | (((bits >> shift) & 1) << plane)) as usize;
+
+		match color {
+			0 => None,
+			_ => Some(Sgb::rgb(self.border_palettes[palette * BORDER_PALETTE_SIZE + color])),
+		}
+	}
+
+	/// Converts a 15-bit BGR color into the display's 24-bit RGB colors.
+	pub fn rgb(color: u16) -> u32 {
+		let expand = |value: u16| -> u32 {
+			let value = (value & 0x1f) as u32;
+			(value << 3) | (value >> 2)
+		};
+
+		(expand(color) << 16) | (expand(color >> 5) << 8) | expand(color >> 10)
+	}
+}
+
+impl Default for Sgb {
+	fn default() -> Self {
+		Sgb::new()
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Sgb {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.bytes(&self.border_tiles);
+		for entry in self.border_map.iter() {
+			writer.u16(*entry);
+		}
+		for color in self.border_palettes.iter() {
+			writer.u16(*color);
+		}
+
+		writer.bytes(&self.command);
+		writer.u8(self.packets);
+		writer.u8(self.bits);
+		writer.bool(self.receiving);
+		writer.bool(self.armed);
+		writer.u8(self.lines);
+		for color in self.palettes.iter().flatten().chain(self.system_palettes.iter()) {
+			writer.u16(*color);
+		}
+		writer.bytes(&self.attributes);
+		writer.bytes(&self.attribute_files);
+		writer.u8(self.mask as u8);
+		writer.u8(self.players);
+		writer.u8(self.player);
+		writer.u8(match self.pending_transfer {
+			None => 0,
+			Some(Transfer::BorderTiles { high: false }) => 1,
+			Some(Transfer::BorderTiles { high: true }) => 2,
+			Some(Transfer::BorderMap) => 3,
+			Some(Transfer::Palettes) => 4,
+			Some(Transfer::Attributes) => 5,
+		});
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		reader.bytes(&mut self.border_tiles)?;
+		for entry in self.border_map.iter_mut() {
+			*entry = reader.u16()?;
+		}
+		for color in self.border_palettes.iter_mut() {
+			*color = reader.u16()?;
+		}
+
+		reader.bytes(&mut self.command)?;
+		self.packets = reader.u8()?;
+		self.bits = reader.u8()?;
+		self.receiving = reader.bool()?;
+		self.armed = reader.bool()?;
+		self.lines = reader.u8()?;
+		for color in self.palettes.iter_mut().flatten().chain(self.system_palettes.iter_mut()) {
+			*color = reader.u16()?;
+		}
+		reader.bytes(&mut self.attributes)?;
+		reader.bytes(&mut self.attribute_files)?;
+		self.mask = match reader.u8()? {
+			0 => Mask::None,
+			1 => Mask::Freeze,
+			2 => Mask::Black,
+			3 => Mask::Color0,
+			_ => { return Err(GameboyError::Io("The savestate holds an invalid SGB mask.")); }
+		};
+		self.players = reader.u8()?;
+		self.player = reader.u8()?;
+		self.pending_transfer = match reader.u8()? {
+			0 => None,
+			1 => Some(Transfer::BorderTiles { high: false }),
+			2 => Some(Transfer::BorderTiles { high: true }),
+			3 => Some(Transfer::BorderMap),
+			4 => Some(Transfer::Palettes),
+			5 => Some(Transfer::Attributes),
+			_ => { return Err(GameboyError::Io("The savestate holds an invalid SGB transfer.")); }
+		};
+
+		// The packet's position must fit in its buffer.
+		let valid_players = matches!(self.players, 1 | 2 | 4) && self.player < self.players;
+		if self.packets as usize >= MAX_PACKETS || self.bits as usize > PACKET_SIZE * 8 || !valid_players ||
+			self.attributes.iter().any(|&palette| palette as usize >= PALETTES) {
+			return Err(GameboyError::Io("The savestate holds an invalid SGB state."));
+		}
+
+		Ok(())
+	}
+}
+
+/// SGB test utilities.
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+
+	/// Returns the values written to P1 to send the given packet.
+	pub fn packet_writes(packet: &[u8; PACKET_SIZE]) -> impl Iterator<Item = u8> + '_ {
+		let bits = (0..PACKET_SIZE * 8).map(move |bit| packet[bit / 8] & (1 << (bit % 8)) != 0);
+
+		// A reset pulse, the packet's bits and a stop bit, separated by high lines.
+		[0x00_u8, 0x30].iter().copied()
+			.chain(bits.chain(core::iter::once(false)).flat_map(|bit| [if bit { 0x10 } else { 0x20 }, 0x30]))
+	}
+
+	fn send(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) {
+		for value in packet_writes(packet) {
+			sgb.write_joypad(value);
+		}
+	}
+
+	fn attribute(sgb: &Sgb, x: usize, y: usize) -> u8 {
+		sgb.attributes()[y * ATTR_COLUMNS + x]
+	}
+
+	#[test]
+	fn test_commands() {
+		let mut sgb = Sgb::new();
+
+		// PAL12 sets the shared color 0 as well.
+		send(&mut sgb, &[(CMD_PAL12 << 3) | 1, 0x11, 0, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0, 0]);
+		assert!(sgb.palettes()[1] == [0x11, 1, 2, 3] && sgb.palettes()[2] == [0x11, 4, 5, 6]);
+		assert!(sgb.palettes()[0] == [0x11, DEFAULT_PALETTE[1], DEFAULT_PALETTE[2], DEFAULT_PALETTE[3]]);
+
+		// ATTR_BLK over two packets, with a block that sets its inside, border
+		// and outside, and a block that only sets its inside (and its border).
+		let mut first = [0; PACKET_SIZE];
+		first[..14].copy_from_slice(&[(CMD_ATTR_BLK << 3) | 2, 2, 7, 0x39, 2, 2, 6, 5, 1, 0x00, 10, 10, 11, 11]);
+		send(&mut sgb, &first);
+		assert!(sgb.attributes().iter().all(|&palette| palette == 0));
+		send(&mut sgb, &[0; PACKET_SIZE]);
+
+		assert!(attribute(&sgb, 3, 3) == 1 && attribute(&sgb, 4, 4) == 1);
+		assert!(attribute(&sgb, 2, 3) == 2 && attribute(&sgb, 6, 5) == 2 && attribute(&sgb, 4, 2) == 2);
+		assert!(attribute(&sgb, 0, 0) == 3 && attribute(&sgb, 7, 3) == 3 && attribute(&sgb, 19, 17) == 3);
+		assert!(attribute(&sgb, 10, 10) == 0 && attribute(&sgb, 11, 11) == 0);
+
+		// ATTR_LIN sets a whole row and a whole column.
+		send(&mut sgb, &[(CMD_ATTR_LIN << 3) | 1, 2, 0x80 | 0x20 | 1, 0x40 | 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(attribute(&sgb, 0, 1) == 1 && attribute(&sgb, 19, 1) == 1 && attribute(&sgb, 5, 1) == 2);
+		assert!(attribute(&sgb, 5, 0) == 2 && attribute(&sgb, 5, 17) == 2 && attribute(&sgb, 0, 0) == 3);
+
+		// ATTR_CHR sets consecutive tiles, wrapping to the next row.
+		send(&mut sgb, &[(CMD_ATTR_CHR << 3) | 1, 18, 2, 3, 0, 0, 0b0111_1100, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(attribute(&sgb, 18, 2) == 1 && attribute(&sgb, 19, 2) == 3 && attribute(&sgb, 0, 3) == 3);
+		assert!(attribute(&sgb, 1, 3) == 3);
+
+		// ATTR_DIV splits the screen by a horizontal line.
+		send(&mut sgb, &[(CMD_ATTR_DIV << 3) | 1, 0x40 | 0x20 | 0x04 | 0x03, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(attribute(&sgb, 0, 8) == 1 && attribute(&sgb, 19, 9) == 2 && attribute(&sgb, 0, 10) == 3);
+
+		// MASK_EN, and a packet with an invalid stop bit that's ignored.
+		send(&mut sgb, &[(CMD_MASK_EN << 3) | 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(sgb.mask() == Mask::Black);
+
+		let writes: std::vec::Vec<u8> = packet_writes(&[(CMD_MASK_EN << 3) | 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).collect();
+		for value in writes[..writes.len() - 2].iter().chain(&[0x10, 0x30]) {
+			sgb.write_joypad(*value);
+		}
+		assert!(sgb.mask() == Mask::Black);
+
+		// The VRAM transfers are done on the next frame.
+		send(&mut sgb, &[(CMD_CHR_TRN << 3) | 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(sgb.take_transfer() == Some(Transfer::BorderTiles { high: true }));
+		assert!(sgb.pending_transfer().is_none());
+	}
+
+	#[test]
+	fn test_colorize() {
+		let mut sgb = Sgb::new();
+
+		// The second row of tiles uses the second palette.
+		send(&mut sgb, &[(CMD_PAL01 << 3) | 1, 0x11, 0, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0, 0]);
+		send(&mut sgb, &[(CMD_ATTR_LIN << 3) | 1, 1, 0x80 | 0x20 | 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+		// The screen's top-left pixel is the darkest, the rest are the lightest.
+		let mut screen = vec![PALETTE[3]; WIDTH * HEIGHT];
+		screen[0] = PALETTE[0];
+		let mut buffer = vec![0; WIDTH * HEIGHT];
+		sgb.colorize(&screen, &mut buffer);
+
+		assert!(buffer[0] == Sgb::rgb(3) && buffer[1] == Sgb::rgb(0x11));
+		assert!(buffer[8 * WIDTH] == Sgb::rgb(0x11) && buffer[16 * WIDTH] == Sgb::rgb(0x11));
+		screen[8 * WIDTH] = PALETTE[1];
+		sgb.colorize(&screen, &mut buffer);
+		assert!(buffer[8 * WIDTH] == Sgb::rgb(5));
+
+		// The masks hide the screen, or keep its last frame.
+		send(&mut sgb, &[(CMD_MASK_EN << 3) | 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		sgb.colorize(&vec![PALETTE[3]; WIDTH * HEIGHT], &mut buffer);
+		assert!(buffer[0] == Sgb::rgb(3));
+
+		send(&mut sgb, &[(CMD_MASK_EN << 3) | 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		sgb.colorize(&screen, &mut buffer);
+		assert!(buffer.iter().all(|&pixel| pixel == 0));
+
+		send(&mut sgb, &[(CMD_MASK_EN << 3) | 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		sgb.colorize(&screen, &mut buffer);
+		assert!(buffer.iter().all(|&pixel| pixel == Sgb::rgb(0x11)));
+	}
+
+	#[test]
+	fn test_system_palettes() {
+		let mut sgb = Sgb::new();
+		let mut data = [0; TRANSFER_SIZE];
+
+		// The second system palette, and the first attribute file's first row.
+		data[8..16].copy_from_slice(&[1, 0, 2, 0, 3, 0, 4, 0]);
+		sgb.transfer(Transfer::Palettes, &data);
+		data = [0; TRANSFER_SIZE];
+		data[0] = 0b0001_1011;
+		sgb.transfer(Transfer::Attributes, &data);
+
+		// PAL_SET applies the system palettes and the attribute file, and
+		// cancels the mask.
+		send(&mut sgb, &[(CMD_MASK_EN << 3) | 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(sgb.mask() == Mask::Freeze);
+		send(&mut sgb, &[(CMD_PAL_SET << 3) | 1, 0, 0, 1, 0, 0, 0, 1, 0, 0xc0, 0, 0, 0, 0, 0, 0]);
+
+		assert!(sgb.palettes()[1] == [1, 2, 3, 4] && sgb.palettes()[3] == [1, 2, 3, 4]);
+		assert!(sgb.palettes()[0] == [0; 4]);
+		assert!(sgb.attributes()[..5] == [0, 1, 2, 3, 0]);
+		assert!(sgb.mask() == Mask::None);
+	}
+
+	#[test]
+	fn test_multiplayer() {
+		let mut sgb = Sgb::new();
+		assert!(sgb.player().is_none());
+
+		send(&mut sgb, &[(CMD_MLT_REQ << 3) | 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(sgb.player() == Some(0));
+
+		// The next joypad is selected when P15 goes high.
+		for (value, player) in [(0x20, 0), (0x30, 0), (0x10, 0), (0x30, 1), (0x10, 1), (0x30, 0)] {
+			sgb.write_joypad(value);
+			assert!(sgb.player() == Some(player));
+		}
+	}
+
+	#[test]
+	fn test_border() -> Result<(), GameboyError> {
+		let mut sgb = Sgb::new();
+		let mut data = [0; TRANSFER_SIZE];
+
+		// The second tile's first line holds the colors 0, 1, 8 and 15.
+		data[BORDER_TILE_BYTES..BORDER_TILE_BYTES + 2].copy_from_slice(&[0b0101_0000, 0b0001_0000]);
+		data[BORDER_TILE_BYTES + 16..BORDER_TILE_BYTES + 18].copy_from_slice(&[0b0001_0000, 0b0011_0000]);
+		sgb.transfer_border_tiles(&data, false);
+
+		// The tile is placed at the top-left corner, and flipped at the
+		// screen's top-left corner, with the second palette.
+		data = [0; TRANSFER_SIZE];
+		data[0] = 1;
+		let screen_entry = (SCREEN_Y / 8) * BORDER_COLUMNS + SCREEN_X / 8;
+		data[screen_entry * 2..screen_entry * 2 + 2].copy_from_slice(&0xc401_u16.to_le_bytes());
+
+		let palettes = &mut data[BORDER_PALETTES_OFFSET..];
+		palettes[2..4].copy_from_slice(&0x001f_u16.to_le_bytes());
+		palettes[16..18].copy_from_slice(&0x03e0_u16.to_le_bytes());
+		palettes[30..32].copy_from_slice(&0x7c00_u16.to_le_bytes());
+		palettes[32 + 30..32 + 32].copy_from_slice(&0x7fff_u16.to_le_bytes());
+		sgb.transfer_border_map(&data);
+
+		// The screen is colorized by the first palette.
+		let screen = vec![PALETTE[0]; WIDTH * HEIGHT];
+		let mut buffer = vec![0; SGB_WIDTH * SGB_HEIGHT];
+		sgb.render_border(&screen, &mut buffer)?;
+
+		let backdrop = Sgb::rgb(DEFAULT_PALETTE[0]);
+		let darkest = Sgb::rgb(DEFAULT_PALETTE[3]);
+		assert!(buffer[..5] == [backdrop, 0xff0000, 0x00ff00, 0x0000ff, backdrop]);
+
+		// The flipped tile's first line is drawn at the tile's bottom line.
+		let line = (SCREEN_Y + 7) * SGB_WIDTH + SCREEN_X;
+		assert!(buffer[line + 4] == 0xffffff);
+		assert!(buffer[line + 7] == darkest && buffer[line + 8] == darkest);
+		assert!(buffer[SCREEN_Y * SGB_WIDTH + SCREEN_X + 4] == darkest);
+
+		assert!(sgb.render_border(&screen[1..], &mut buffer).is_err());
+
+		Ok(())
+	}
+}
@@ -4,13 +4,15 @@
 #![allow(missing_docs)]
 //! Gameboy's timer controller.
 
-use super::Memory;
+use super::{Memory, Peripheral};
 use super::memory_range::*;
 
 use crate::GameboyError;
 
 use crate::config::*;
 use crate::cpu::interrupts::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 pub mod consts {
 	use super::*;
@@ -21,10 +23,14 @@ pub mod consts {
 	pub const IO_TAC: u16 = 0xFF07;
 
 	pub const MMAP_IO_TIMER: MemoryRange = make_range!(0xFF04, 0xFF07);
+
+	/// The cycles between TIMA's overflow and its reload from TMA.
+	pub const OVERFLOW_DELAY: u8 = 4;
 }
 
 use consts::*;
 
+#[derive(Clone)]
 pub struct Timer {
 	/// DIV consists of 2 bytes, and only the higher 8 bits are exposed to the cpu.
 	div: u16,
@@ -34,10 +40,14 @@ pub struct Timer {
 	tma: u8,
 	/// Timer control.
 	tac: Tac,
+	/// The cycles left until an overflowed TIMA is reloaded from TMA and the
+	/// interrupt is raised, or 0 if TIMA didn't overflow.
+	overflow_delay: u8,
 
 	interrupt_flag: InterruptMask,
 }
 
+#[derive(Clone)]
 struct Tac {
 	pub enable: bool,
 	pub frequency: u8,
@@ -51,7 +61,8 @@ impl Timer {
 			tima: 0,
 			tma: 0,
 			tac: Tac::new(),
-			interrupt_flag: 0,
+			overflow_delay: 0,
+			interrupt_flag: InterruptMask::empty(),
 		};
 
 		timer.reset(config);
@@ -59,44 +70,35 @@ impl Timer {
 		timer
 	}
 
-	/// Reset the peripheral to boot state.
-	pub fn reset(&mut self, config: &Config) {
-		match config.model {
-			HardwareModel::GB | HardwareModel::SGB => {
-				self.div = 0xabcc;
-			}
-			HardwareModel::GBC => {
-				// TODO div's value depends on whether it is a GBC or GB game.
-				self.div = 0x1ea0;
-			}
-			HardwareModel::GBP => {
-				self.div = 0x1ea4;
-			}
-		}
+	/// Returns the DIV register, the upper 8 bits of the internal counter.
+	pub fn div(&self) -> u8 {
+		(self.div >> 8) as u8
+	}
 
-		self.tima = 0;
-		self.tma = 0;
-		self.tac.reset();
+	/// Returns the full 16-bit internal counter that drives DIV.
+	pub fn div_counter(&self) -> u16 {
+		self.div
 	}
 
-	/// Update the timer's state according to the elapsed time.
-	pub fn process(&mut self, cycles: usize) {
-		let new_div = self.div.wrapping_add(cycles as u16);
+	/// Returns the timer counter (TIMA).
+	pub fn tima(&self) -> u8 {
+		self.tima
+	}
 
-		// Get the timer's frequency from the control register.
-		let div_bit = [512, 8, 32, 128][self.tac.frequency as usize];
-		
-		if self.tac.enable && (self.div & div_bit) != (new_div & div_bit) {
-			// Increment the timer.
-			self.tima = self.tima.wrapping_add(1);
+	/// Returns the timer modulo (TMA).
+	pub fn tma(&self) -> u8 {
+		self.tma
+	}
 
-			if self.tima == 0 {
-				self.interrupt_flag |= Interrupt::Timer.value();
-				self.tima = self.tma;
-			}
-		}
+	/// Returns the timer control register (TAC).
+	pub fn tac(&self) -> u8 {
+		self.tac.read()
+	}
 
-		self.div = new_div;
+	/// Returns the cycles left until an overflowed TIMA is reloaded from TMA
+	/// and the interrupt is raised, or 0 if no overflow is pending.
+	pub fn overflow_delay(&self) -> u8 {
+		self.overflow_delay
 	}
 }
 
@@ -108,7 +110,9 @@ impl Memory for Timer {
 				self.div = 0;
 			}
 			IO_TIMA => {
+				// Writing TIMA during the overflow's delay cancels the reload.
 				self.tima = value;
+				self.overflow_delay = 0;
 			}
 			IO_TMA => {
 				self.tma = value;
@@ -152,11 +156,116 @@ impl InterruptSource for Timer {
 	}
 
 	fn clear(&mut self) {
-		self.interrupt_flag = 0;
+		self.interrupt_flag = InterruptMask::empty();
+	}
+}
+
+#[cfg(feature = "alloc")]
+impl SaveState for Timer {
+	fn save_state(&self, writer: &mut StateWriter) {
+		writer.u16(self.div);
+		writer.u8(self.tima);
+		writer.u8(self.tma);
+		writer.u8(self.tac.read());
+		writer.u8(self.interrupt_flag.bits());
+		writer.u8(self.overflow_delay);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.div = reader.u16()?;
+		self.tima = reader.u8()?;
+		self.tma = reader.u8()?;
+		self.tac.write(reader.u8()?);
+		self.interrupt_flag = InterruptMask::from_bits(reader.u8()?);
+		self.overflow_delay = reader.u8()?;
+		if self.overflow_delay > OVERFLOW_DELAY {
+			return Err(GameboyError::Io("The savestate holds an invalid timer state."));
+		}
+
+		Ok(())
+	}
+}
+
+impl Peripheral for Timer {
+	/// Reset the peripheral to boot state.
+	fn reset(&mut self, config: &Config) {
+		match config.model {
+			HardwareModel::GB | HardwareModel::SGB => {
+				self.div = 0xabcc;
+			}
+			HardwareModel::GBC => {
+				// TODO div's value depends on whether it is a GBC or GB game.
+				self.div = 0x1ea0;
+			}
+			HardwareModel::GBP => {
+				self.div = 0x1ea4;
+			}
+		}
+
+		self.tima = 0;
+		self.tma = 0;
+		self.tac.reset();
+		self.overflow_delay = 0;
+	}
+
+	/// Update the timer's state according to the elapsed time.
+	fn process(&mut self, cycles: usize) {
+		// Get the timer's frequency from the control register.
+		let div_bit: u32 = [512, 8, 32, 128][self.tac.frequency as usize];
+		// The timer is incremented on each falling edge of the div's bit.
+		let period = 2 * div_bit;
+
+		let mut div = self.div as u32;
+		let end = div + cycles as u32;
+
+		while div < end {
+			// Advance to the next increment or reload, whichever comes first.
+			let tick = (div / period + 1) * period;
+			let mut next = end;
+			if self.tac.enable {
+				next = next.min(tick);
+			}
+			if self.overflow_delay > 0 {
+				next = next.min(div + self.overflow_delay as u32);
+				self.overflow_delay -= (next - div) as u8;
+
+				if self.overflow_delay == 0 {
+					self.interrupt_flag.insert(Interrupt::Timer);
+					self.tima = self.tma;
+				}
+			}
+
+			if self.tac.enable && next == tick {
+				// Increment the timer, which reads 0 until it's reloaded.
+				self.tima = self.tima.wrapping_add(1);
+
+				if self.tima == 0 {
+					self.overflow_delay = OVERFLOW_DELAY;
+				}
+			}
+
+			div = next;
+		}
+
+		self.div = end as u16;
+	}
+
+	/// Returns the number of cycles until the timer's interrupt is raised.
+	fn next_event(&self) -> usize {
+		if self.overflow_delay > 0 {
+			return self.overflow_delay as usize;
+		}
+		if !self.tac.enable {
+			return usize::MAX;
+		}
+
+		let period = 2 * [512, 8, 32, 128][self.tac.frequency as usize];
+		let first_tick = period - (self.div as usize % period);
+
+		first_tick + (0xFF - self.tima as usize) * period + OVERFLOW_DELAY as usize
 	}
 }
 
-#[allow(unused)]
 impl Tac {
 	pub fn new() -> Self {
 		Tac { enable: false, frequency: 0 }
@@ -176,3 +285,115 @@ impl Tac {
 		self.frequency + if self.enable { 4 } else { 0 }
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_timer_frequency() -> Result<(), GameboyError> {
+		let mut timer = Timer::new(&Config::default());
+
+		// Enable the timer at 4096Hz.
+		timer.write(IO_DIV, 0)?;
+		timer.write(IO_TAC, 0x04)?;
+
+		// The timer is incremented once every 1024 cycles.
+		for _ in 0..(1024 / 4 - 1) {
+			timer.process(4);
+		}
+		assert!(timer.read(IO_TIMA)? == 0);
+		timer.process(4);
+		assert!(timer.read(IO_TIMA)? == 1);
+
+		// Long instructions may pass several increments at 262144Hz.
+		timer.write(IO_TAC, 0x05)?;
+		timer.process(24);
+		assert!(timer.read(IO_TIMA)? == 2);
+		timer.process(24);
+		assert!(timer.read(IO_TIMA)? == 4);
+
+		// Overflows reload the modulo and raise an interrupt 4 cycles later.
+		timer.write(IO_TIMA, 0xFF)?;
+		timer.write(IO_TMA, 0x42)?;
+		timer.process(16);
+		assert!(timer.read(IO_TIMA)? == 0 && timer.interrupts().is_empty());
+		timer.process(4);
+		assert!(timer.read(IO_TIMA)? == 0x42);
+		assert!(timer.interrupts() == InterruptMask::from(Interrupt::Timer));
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_timer_state() -> Result<(), GameboyError> {
+		let mut timer = Timer::new(&Config::default());
+
+		timer.write(IO_DIV, 0)?;
+		timer.write(IO_TMA, 0x42)?;
+		timer.write(IO_TAC, 0x05)?;
+		timer.process(0x1234);
+
+		assert!(timer.div_counter() == 0x1234);
+		assert!(timer.div() == timer.read(IO_DIV)?);
+		assert!(timer.tima() == timer.read(IO_TIMA)?);
+		assert!(timer.tma() == 0x42);
+		assert!(timer.tac() == 0x05);
+
+		// TIMA overflows on the falling edge of the counter's bit 3, which
+		// is 12 cycles away, and it's reloaded 4 cycles later.
+		timer.clear();
+		timer.write(IO_TIMA, 0xFF)?;
+		assert!(timer.next_event() == 16);
+		timer.process(8);
+		assert!(timer.div_counter() == 0x123C && timer.overflow_delay() == 0);
+		timer.process(6);
+		assert!(timer.div_counter() == 0x1242 && timer.overflow_delay() == 2);
+		assert!(timer.tima() == 0 && timer.interrupts().is_empty());
+		assert!(timer.next_event() == 2);
+		timer.process(2);
+		assert!(timer.overflow_delay() == 0 && timer.interrupts() == InterruptMask::from(Interrupt::Timer));
+		assert!(timer.tima() == 0x42);
+
+		// Writing TIMA during the delay cancels the reload and the interrupt.
+		timer.clear();
+		timer.write(IO_TIMA, 0xFF)?;
+		timer.process(14);
+		assert!(timer.overflow_delay() == 2);
+		timer.write(IO_TIMA, 0x10)?;
+		timer.process(4);
+		assert!(timer.tima() == 0x10 && timer.interrupts().is_empty());
+
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "alloc")]
+	fn test_timer_savestate() -> Result<(), GameboyError> {
+		let mut timer = Timer::new(&Config::default());
+
+		timer.write(IO_DIV, 0)?;
+		timer.write(IO_TIMA, 0xFF)?;
+		timer.write(IO_TMA, 0x42)?;
+		timer.write(IO_TAC, 0x05)?;
+		timer.process(17);
+
+		let mut writer = StateWriter::new();
+		timer.save_state(&mut writer);
+		let state = writer.into_inner();
+
+		// The pending overflow is restored along with the registers.
+		let mut loaded = Timer::new(&Config::default());
+		loaded.load_state(&mut StateReader::new(&state))?;
+		assert!(loaded.div_counter() == 17 && loaded.overflow_delay() == 3);
+		loaded.process(3);
+		assert!(loaded.tima() == 0x42 && loaded.interrupts() == InterruptMask::from(Interrupt::Timer));
+
+		// A delay longer than the hardware's is rejected.
+		let mut invalid = state.clone();
+		*invalid.last_mut().unwrap() = OVERFLOW_DELAY + 1;
+		assert!(loaded.load_state(&mut StateReader::new(&invalid)).is_err());
+
+		Ok(())
+	}
+}
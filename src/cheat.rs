@@ -0,0 +1,306 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Parsing of cheat codes and cheat files.
+//!
+//! Two kinds of codes are supported:
+//! * GameShark codes patch the ram, and are applied again every frame.
+//! * Game Genie codes patch the rom, optionally only if the original byte
+//!   matches a compare value.
+//!
+//! Cheats are usually distributed in libretro's `.cht` files, or as plain
+//! lists with a code and a description on each line.
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::str::FromStr;
+
+use crate::GameboyError;
+
+/// A single decoded cheat code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatCode {
+	/// Writes a value to the ram, every frame.
+	GameShark {
+		/// The external ram bank, for addresses within the cartridge's ram.
+		bank: u8,
+		/// The patched address.
+		address: u16,
+		/// The written value.
+		value: u8,
+	},
+	/// Replaces a byte read from the rom.
+	GameGenie {
+		/// The patched address.
+		address: u16,
+		/// The value that is read instead.
+		value: u8,
+		/// If set, the byte is only replaced if it originally holds this value.
+		compare: Option<u8>,
+	},
+}
+
+/// A named cheat, made of one or more codes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+	/// The cheat's description.
+	pub name: String,
+	/// The codes that are applied together.
+	pub codes: Vec<CheatCode>,
+	/// Whether the cheat should be applied.
+	pub enabled: bool,
+}
+
+impl CheatCode {
+	/// Decodes a GameShark code (`ABCDEFGH`) or a Game Genie code
+	/// (`ABC-DEF` or `ABC-DEF-GHI`).
+	pub fn parse(code: &str) -> Result<Self, GameboyError> {
+		let code = code.trim();
+
+		if code.contains('-') {
+			CheatCode::parse_game_genie(code)
+		} else {
+			CheatCode::parse_game_shark(code)
+		}
+	}
+
+	fn parse_game_shark(code: &str) -> Result<Self, GameboyError> {
+		let digits = hex_digits::<8>(code)?;
+		let byte = |i: usize| (digits[i] << 4) | digits[i + 1];
+
+		// The address is stored in little-endian.
+		Ok(CheatCode::GameShark {
+			bank: byte(0),
+			value: byte(2),
+			address: ((byte(6) as u16) << 8) | byte(4) as u16,
+		})
+	}
+
+	fn parse_game_genie(code: &str) -> Result<Self, GameboyError> {
+		let mut chars = [0_u8; 9];
+		let mut len = 0;
+
+		// The digits are grouped by threes.
+		for (i, group) in code.split('-').enumerate() {
+			if i > 2 || group.len() != 3 {
+				return Err(GameboyError::Io("Game Genie codes are made of 3-digit groups."));
+			}
+			chars[len..len + 3].copy_from_slice(&hex_digits::<3>(group)?);
+			len += 3;
+		}
+
+		if len < 6 {
+			return Err(GameboyError::Io("Game Genie codes are made of 3-digit groups."));
+		}
+
+		let digit = |i: usize| chars[i] as u16;
+		let address = ((digit(5) ^ 0xF) << 12) | (digit(2) << 8) | (digit(3) << 4) | digit(4);
+
+		// The 8th digit isn't used by the hardware.
+		let compare = if len == 9 {
+			Some(((chars[6] << 4) | chars[8]).rotate_right(2) ^ 0xBA)
+		} else {
+			None
+		};
+
+		Ok(CheatCode::GameGenie {
+			address,
+			value: (chars[0] << 4) | chars[1],
+			compare,
+		})
+	}
+}
+
+impl FromStr for CheatCode {
+	type Err = GameboyError;
+
+	fn from_str(code: &str) -> Result<Self, Self::Err> {
+		CheatCode::parse(code)
+	}
+}
+
+/// Parses the codes of a single cheat, separated by `+`.
+fn parse_codes(codes: &str) -> Result<Vec<CheatCode>, GameboyError> {
+	codes.split('+')
+		.filter(|code| !code.trim().is_empty())
+		.map(CheatCode::parse)
+		.collect()
+}
+
+/// The largest number of cheats a cheat file may declare.
+pub const MAX_CHEATS: usize = 1024;
+
+/// Parses a libretro cheat file (`.cht`).
+///
+/// The file assigns `cheats = N`, followed by `cheatI_desc`, `cheatI_code`
+/// and `cheatI_enable` for each cheat. Other keys are ignored.
+///
+/// At most [`MAX_CHEATS`] cheats may be declared, and every index must be
+/// below the declared amount.
+pub fn parse_cht(text: &str) -> Result<Vec<Cheat>, GameboyError> {
+	let mut count = None;
+	let mut entries = Vec::new();
+
+	for line in text.lines() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let (key, value) = match line.find('=') {
+			Some(index) => (line[..index].trim(), unquote(line[index + 1..].trim())),
+			None => { return Err(GameboyError::Io("Expected a key and a value.")); }
+		};
+
+		if key == "cheats" {
+			count = Some(value.parse::<usize>().map_err(|_| GameboyError::Io("Invalid number of cheats."))?);
+		} else {
+			entries.push((key, value));
+		}
+	}
+
+	let count = count.ok_or(GameboyError::Io("The number of cheats is missing."))?;
+
+	if count > MAX_CHEATS {
+		return Err(GameboyError::Io("The file declares too many cheats."));
+	}
+
+	let mut cheats: Vec<(Option<String>, Option<&str>, bool)> = vec![(None, None, false); count];
+
+	for (key, value) in entries {
+		// Cheat keys are formatted as `cheat<index>_<field>`.
+		let (index, field) = match key.strip_prefix("cheat").and_then(|key| key.split_once('_')) {
+			Some((index, field)) => match index.parse::<usize>() {
+				Ok(index) => (index, field),
+				Err(_) => continue,
+			},
+			None => continue,
+		};
+
+		let cheat = cheats.get_mut(index)
+			.ok_or(GameboyError::Io("The file contains more cheats than declared."))?;
+
+		match field {
+			"desc" => { cheat.0 = Some(value.to_string()); }
+			"code" => { cheat.1 = Some(value); }
+			"enable" => { cheat.2 = value == "true"; }
+			_ => {}
+		}
+	}
+
+	cheats.into_iter().map(|(name, code, enabled)| {
+		let code = code.ok_or(GameboyError::Io("A cheat's code is missing."))?;
+
+		Ok(Cheat {
+			name: name.unwrap_or_default(),
+			codes: parse_codes(code)?,
+			enabled,
+		})
+	}).collect()
+}
+
+/// Parses a plain list of cheats, each on its own line as the code followed
+/// by an optional description.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_list(text: &str) -> Result<Vec<Cheat>, GameboyError> {
+	text.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(|line| {
+			let (code, name) = match line.split_once(char::is_whitespace) {
+				Some((code, name)) => (code, name.trim()),
+				None => (line, ""),
+			};
+
+			Ok(Cheat {
+				name: name.to_string(),
+				codes: parse_codes(code)?,
+				enabled: true,
+			})
+		})
+		.collect()
+}
+
+/// Strips the quotes around a value, if any.
+fn unquote(value: &str) -> &str {
+	value.strip_prefix('"')
+		.and_then(|value| value.strip_suffix('"'))
+		.unwrap_or(value)
+}
+
+/// Parses exactly `N` hexadecimal digits.
+fn hex_digits<const N: usize>(text: &str) -> Result<[u8; N], GameboyError> {
+	let mut digits = [0_u8; N];
+
+	if text.len() != N {
+		return Err(GameboyError::Io("Unexpected cheat code length."));
+	}
+
+	for (digit, c) in digits.iter_mut().zip(text.chars()) {
+		*digit = c.to_digit(16).ok_or(GameboyError::Io("Invalid hexadecimal digit."))? as u8;
+	}
+
+	Ok(digits)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_cheat_codes() -> Result<(), GameboyError> {
+		// The address is little-endian.
+		assert!(CheatCode::parse("01FF16D0")? == CheatCode::GameShark { bank: 0x01, value: 0xFF, address: 0xD016 });
+
+		assert!(CheatCode::parse("00A-17B-C49")? == CheatCode::GameGenie {
+			address: 0x4A17,
+			value: 0x00,
+			compare: Some(0xC8),
+		});
+		assert!(CheatCode::parse("3EA-17B")? == CheatCode::GameGenie { address: 0x4A17, value: 0x3E, compare: None });
+
+		assert!(CheatCode::parse("01FF16D").is_err());
+		assert!(CheatCode::parse("01FF16DX").is_err());
+		assert!(CheatCode::parse("00A-17B-C49-000").is_err());
+		assert!(CheatCode::parse("00A").is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_cheat_files() -> Result<(), GameboyError> {
+		let cht = "cheats = 2\n\n\
+			cheat0_desc = \"Infinite Lives\"\n\
+			cheat0_code = \"01FF16D0\"\n\
+			cheat0_enable = true\n\n\
+			cheat1_desc = \"Max Money\"\n\
+			cheat1_code = \"019990D1+019991D1\"\n\
+			cheat1_enable = false\n";
+
+		let cheats = parse_cht(cht)?;
+		assert!(cheats.len() == 2);
+		assert!(cheats[0].name == "Infinite Lives" && cheats[0].enabled);
+		assert!(cheats[1].codes.len() == 2 && !cheats[1].enabled);
+
+		// The number of cheats must match the declared amount.
+		assert!(parse_cht("cheats = 1\n").is_err());
+		assert!(parse_cht("cheat0_code = \"01FF16D0\"\n").is_err());
+
+		// Cheat indices are bounded by the declared amount, which is capped.
+		assert!(parse_cht("cheats = 1\ncheat18446744073709551615_desc = x\n").is_err());
+		assert!(parse_cht("cheats = 1\ncheat999999999999_desc = x\n").is_err());
+		assert!(parse_cht("cheats = 1\ncheat1_code = \"01FF16D0\"\n").is_err());
+		assert!(parse_cht("cheats = 999999999999\n").is_err());
+
+		let cheats = parse_list("# Comment\n01FF16D0 Infinite Lives\n\n00A-17B-C49\n")?;
+		assert!(cheats.len() == 2);
+		assert!(cheats[0].name == "Infinite Lives");
+		assert!(cheats[1].name.is_empty() && cheats[1].enabled);
+
+		Ok(())
+	}
+}
@@ -3,8 +3,24 @@
 
 #![deny(missing_docs)]
 //! Emulator hardware emulation configuration and preferences.
+//!
+//! # Examples
+//! ```
+//! # use gameboy_core::config::{Config, HardwareModel, EchoRamPolicy};
+//! let config = Config::builder()
+//!     .model(HardwareModel::GB)
+//!     .echo_ram(EchoRamPolicy::OpenBus)
+//!     .sample_rate(44100)
+//!     .build();
+//!
+//! assert!(config.sample_rate == 44100);
+//! ```
+
+use crate::resample::ResampleQuality;
+use crate::bus::ppu::consts::PALETTE;
 
 /// The hardware specification for the different models differ.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HardwareModel {
 	/// Original GameBoy
 	GB,
@@ -16,16 +32,245 @@ pub enum HardwareModel {
 	SGB,
 }
 
+/// How accesses to the echo ram (0xE000-0xFDFF) are handled.
+///
+/// The echo ram mirrors the internal ram, but Nintendo prohibited its use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EchoRamPolicy {
+	/// Mirror the internal ram, like the hardware does.
+	Mirror,
+	/// Mirror the internal ram, and raise a warning on each access.
+	Warn,
+	/// Ignore writes and read 0xFF, like an unmapped region.
+	OpenBus,
+}
+
+/// How the illegal opcodes (0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED,
+/// 0xF4, 0xFC and 0xFD) are handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IllegalOpcodePolicy {
+	/// Fail the emulation with `GameboyError::BadOpcode`.
+	Error,
+	/// Lock the cpu for good, like the hardware does. The peripherals
+	/// keep running, so the display keeps being drawn.
+	Lock,
+	/// Skip the opcode, as if it was a nop.
+	Nop,
+}
+
+/// The trade-off between the emulation's speed and its timing accuracy.
+///
+/// Both levels share the machine's state, so it may be switched between
+/// frames. The display is drawn line by line in both levels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Accuracy {
+	/// Shortcuts the timing where few games notice it, for slow hosts.
+	/// The OAM DMA transfer completes as soon as it's started, instead of
+	/// keeping the bus busy for 160 cycles.
+	Fast,
+	/// Follows the hardware's timing, such as copying the OAM DMA transfer
+	/// byte by byte while the OAM is inaccessible.
+	Accurate,
+}
+
+/// What advances the cartridge's real-time clock (MBC3).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcSource {
+	/// The emulated clock cycles, so the clock ticks at the same emulated
+	/// moments on every run, as needed for determinism and replays.
+	Emulated,
+	/// The host's time, so the clock keeps up with the real world, even
+	/// while the emulation is paused or fast-forwarded.
+	///
+	/// With the `std` feature, `Emulator::execute` advances the clock once
+	/// per frame's worth of cycles, whichever way the emulator is driven.
+	/// Otherwise, or when driving the `Cpu` directly, the frontend advances
+	/// it with `Rtc::advance_seconds`.
+	WallClock,
+}
+
+/// The range the stack pointer is expected to stay in, see `Config::stack_check`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackRange {
+	/// The lowest valid stack pointer.
+	pub low: u16,
+	/// The highest valid stack pointer, usually the stack's initial value.
+	pub high: u16,
+}
+
 /// Emulation settings and preferences goes here.
+///
+/// The configuration is small and cheap to copy, so components that need it
+/// keep their own copy instead of borrowing it. For the same reason, the boot
+/// rom isn't a part of it, see `EmulatorBuilder::boot_rom`.
+///
+/// New options are added with a default that keeps the previous behavior, so
+/// the configuration is constructed with `Config::builder` or `Default`.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
 pub struct Config {
 	/// The model of the emulated machine
 	pub model: HardwareModel,
+	/// The trade-off between speed and timing accuracy
+	pub accuracy: Accuracy,
+	/// The handling of echo ram accesses
+	pub echo_ram: EchoRamPolicy,
+	/// The handling of illegal opcodes
+	pub illegal_opcode: IllegalOpcodePolicy,
+	/// If set, recoverable errors are reported as warnings instead of
+	/// stopping the emulation, so imperfectly supported games keep running.
+	/// Illegal opcodes are skipped when their policy is `Error`, failed reads
+	/// return 0xFF and failed writes are ignored, like an open bus
+	pub lenient: bool,
+	/// If set, a warning is raised when the stack pointer leaves the range,
+	/// or when the stack is pushed to or popped from the rom or I/O registers
+	pub stack_check: Option<StackRange>,
+	/// The number of stereo frames the sound controller generates per second,
+	/// where 0 disables the audio output
+	pub sample_rate: u32,
+	/// If set, the sound channels are mixed at about 1MHz and resampled to
+	/// the sample rate with the given quality. Otherwise, the channels are
+	/// sampled directly at the sample rate, which aliases high frequencies
+	pub resample: Option<ResampleQuality>,
+	/// If set, the display's pixels aren't rendered, while its timing and
+	/// interrupts are still emulated
+	pub headless: bool,
+	/// What advances the cartridge's real-time clock
+	pub rtc_source: RtcSource,
+	/// The colors of the original GameBoy's 4 shades as `0x00RRGGBB`, from
+	/// the darkest to the lightest. The SGB model colors the shades with its
+	/// own palettes instead
+	pub palette: [u32; 4],
+}
+
+/// Builds a `Config`, starting from the default one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl Config {
+	/// Returns a builder for the configuration, see `ConfigBuilder`.
+	pub fn builder() -> ConfigBuilder {
+		ConfigBuilder::default()
+	}
+}
+
+impl ConfigBuilder {
+	/// Sets the model of the emulated machine.
+	pub fn model(mut self, model: HardwareModel) -> Self {
+		self.config.model = model;
+		self
+	}
+
+	/// Sets the trade-off between speed and timing accuracy.
+	pub fn accuracy(mut self, accuracy: Accuracy) -> Self {
+		self.config.accuracy = accuracy;
+		self
+	}
+
+	/// Sets the handling of echo ram accesses, which may be treated as an
+	/// open bus.
+	pub fn echo_ram(mut self, policy: EchoRamPolicy) -> Self {
+		self.config.echo_ram = policy;
+		self
+	}
+
+	/// Sets the handling of illegal opcodes.
+	pub fn illegal_opcode(mut self, policy: IllegalOpcodePolicy) -> Self {
+		self.config.illegal_opcode = policy;
+		self
+	}
+
+	/// Reports the recoverable errors as warnings, and keeps running.
+	pub fn lenient(mut self, lenient: bool) -> Self {
+		self.config.lenient = lenient;
+		self
+	}
+
+	/// Warns when the stack pointer leaves the given range.
+	pub fn stack_check(mut self, range: StackRange) -> Self {
+		self.config.stack_check = Some(range);
+		self
+	}
+
+	/// Sets the audio output's sample rate, where 0 disables it.
+	pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+		self.config.sample_rate = sample_rate;
+		self
+	}
+
+	/// Resamples the sound channels with the given quality.
+	pub fn resample(mut self, quality: ResampleQuality) -> Self {
+		self.config.resample = Some(quality);
+		self
+	}
+
+	/// Skips rendering the display's pixels.
+	pub fn headless(mut self, headless: bool) -> Self {
+		self.config.headless = headless;
+		self
+	}
+
+	/// Selects what advances the cartridge's real-time clock.
+	pub fn rtc_source(mut self, source: RtcSource) -> Self {
+		self.config.rtc_source = source;
+		self
+	}
+
+	/// Sets the colors of the original GameBoy's shades, from the darkest
+	/// to the lightest.
+	pub fn palette(mut self, palette: [u32; 4]) -> Self {
+		self.config.palette = palette;
+		self
+	}
+
+	/// Returns the configuration.
+	pub fn build(self) -> Config {
+		self.config
+	}
 }
 
 impl Default for Config {
 	fn default() -> Self {
 		Config {
-			model: HardwareModel::GB
+			model: HardwareModel::GB,
+			accuracy: Accuracy::Accurate,
+			echo_ram: EchoRamPolicy::Mirror,
+			illegal_opcode: IllegalOpcodePolicy::Error,
+			lenient: false,
+			stack_check: None,
+			sample_rate: 48000,
+			resample: None,
+			headless: false,
+			rtc_source: RtcSource::Emulated,
+			palette: PALETTE,
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_builder() {
+		let config = Config::builder()
+			.model(HardwareModel::GBC)
+			.echo_ram(EchoRamPolicy::OpenBus)
+			.illegal_opcode(IllegalOpcodePolicy::Lock)
+			.sample_rate(0)
+			.palette([0, 1, 2, 3])
+			.build();
+
+		assert!(config.model == HardwareModel::GBC && config.echo_ram == EchoRamPolicy::OpenBus);
+		assert!(config.illegal_opcode == IllegalOpcodePolicy::Lock);
+		assert!(config.sample_rate == 0 && config.palette == [0, 1, 2, 3]);
+
+		// The rest of the options keep their defaults.
+		let default = Config::default();
+		assert!(config.stack_check.is_none() && !config.headless);
+		assert!(config.rtc_source == default.rtc_source && config.resample == default.resample);
+		assert!(Config::builder().build().palette == PALETTE);
+	}
+}
@@ -17,7 +17,7 @@ pub mod alu8 {
 
 	/// Compare operations does not affect the lhs.
 	macro_rules! stores_result {
-		($op:tt) => (($op as usize) != (cp as usize))
+		($op:tt) => (($op as usize) != (cp as Alu8Op as usize))
 	}
 
 	/// Applies the given operation on two 8-bit registers.
@@ -44,7 +44,7 @@ pub mod alu8 {
 	/// Applies the given operation on the A register and the given 8-bit immediate.
 	pub fn op_imm(op: Alu8Op, cpu: &mut Cpu) -> InsnResult {
 		let left = cpu.registers.get(Register::A) as u8;
-		let imm = cpu.fetch::<u8>()?;
+		let imm = cpu.fetch_u8()?;
 
 		let result = op(cpu, left, imm) as u16;
 
@@ -278,6 +278,39 @@ pub mod alu8 {
 		result
 	}
 
+	/// Adjusts the result of a BCD addition or subtraction, according to the
+	/// N, H and C flags left by the operation, and sets the relevant flags.
+	pub fn daa(cpu: &mut Cpu, value: u8) -> u8 {
+		let subtract = cpu.registers.flag(Flag::N);
+		let half_carry = cpu.registers.flag(Flag::H);
+		let mut carry = cpu.registers.flag(Flag::C);
+
+		let mut correction: u8 = 0;
+
+		// Subtractions leave valid BCD digits unless they borrowed.
+		if carry || (!subtract && value > 0x99) {
+			correction |= 0x60;
+			carry = true;
+		}
+
+		if half_carry || (!subtract && (value & 0x0F) > 0x09) {
+			correction |= 0x06;
+		}
+
+		let result = if subtract {
+			value.wrapping_sub(correction)
+		} else {
+			value.wrapping_add(correction)
+		};
+
+		// Set the relevant flags (the subtraction flag is not affected)
+		cpu.registers.set_flag(Flag::Z, result == 0);
+		cpu.registers.set_flag(Flag::H, false);
+		cpu.registers.set_flag(Flag::C, carry);
+
+		result
+	}
+
 	/// Increment the given 8-bit register.
 	pub fn inc_register(cpu: &mut Cpu, reg: Register) -> InsnResult {
 		assert!(get_type(&reg) != RegisterType::Wide);
@@ -366,6 +399,35 @@ pub mod alu8 {
 
 			Ok(())
 		}
+
+		#[test]
+		fn test_daa() -> Result<(), GameboyError> {
+			crate::cpu::tests::with_cpu(|cpu| {
+				let bcd = |value: u8| ((value / 10) << 4) | (value % 10);
+
+				for lhs in 0..100_u8 {
+					for rhs in 0..100_u8 {
+						// Additions carry out of 99.
+						let sum = add(cpu, bcd(lhs), bcd(rhs));
+						let result = daa(cpu, sum);
+						assert!(result == bcd((lhs + rhs) % 100));
+						assert!(cpu.registers.flag(Flag::C) == (lhs + rhs >= 100));
+						assert!(cpu.registers.flag(Flag::Z) == (result == 0));
+						assert!(!cpu.registers.flag(Flag::H) && !cpu.registers.flag(Flag::N));
+
+						// Subtractions borrow below 0.
+						let difference = sub(cpu, bcd(lhs), bcd(rhs));
+						let result = daa(cpu, difference);
+						assert!(result == bcd((100 + lhs - rhs) % 100));
+						assert!(cpu.registers.flag(Flag::C) == (lhs < rhs));
+						assert!(cpu.registers.flag(Flag::Z) == (result == 0));
+						assert!(!cpu.registers.flag(Flag::H) && cpu.registers.flag(Flag::N));
+					}
+				}
+
+				Ok(())
+			})
+		}
 	}
 }
 
@@ -405,7 +467,7 @@ pub mod alu16 {
 		assert!(get_type(&lhs) == RegisterType::Wide);
 
 		let left: u16 = cpu.registers.get(lhs);
-		let right: u16 = cpu.fetch::<u8>()? as u16;
+		let right: u16 = cpu.fetch_u8()? as u16;
 
 		let result: u16 = op(cpu, left, right);
 
@@ -447,6 +509,23 @@ pub mod alu16 {
 		result
 	}
 
+	/// Adds a signed 8-bit offset to the stack pointer, sets the relevant flags
+	/// accordinately and returns the result.
+	///
+	/// The half carry and carry flags are set according to the addition of
+	/// the low byte, as if it was an unsigned 8-bit addition.
+	pub fn add_sp(cpu: &mut Cpu, lhs: u16, rhs: u16) -> u16 {
+		let offset = rhs as u8;
+		let result = lhs.wrapping_add(offset as i8 as u16);
+
+		cpu.registers.set_flag(Flag::Z, false);
+		cpu.registers.set_flag(Flag::N, false);
+		cpu.registers.set_flag(Flag::H, (lhs & 0x0F) + (offset as u16 & 0x0F) > 0x0F);
+		cpu.registers.set_flag(Flag::C, (lhs & 0xFF) + offset as u16 > 0xFF);
+
+		result
+	}
+
 	/// Increment the given 16-bit register.
 	pub fn inc_register(cpu: &mut Cpu, reg: Register) -> InsnResult
 	{
@@ -6,11 +6,15 @@
 
 use super::Cpu;
 use super::instructions::*;
+use super::state::registers::Register;
 use crate::GameboyError;
 
 impl<'a> Cpu<'a> {
 
 	/// Returns the instruction that matches the given opcode.
+	///
+	/// The opcode is assumed to have just been fetched, so a `BadOpcode` error
+	/// reports the address before the program counter.
 	pub fn decode(&mut self, opcode: u8) -> Result<Instruction, GameboyError> {
 		match opcode {
 			0x00 => Ok(opcode_00),
@@ -29,6 +33,7 @@ impl<'a> Cpu<'a> {
 			0x0d => Ok(opcode_0d),
 			0x0e => Ok(opcode_0e),
 			0x0f => Ok(opcode_0f),
+			0x10 => Ok(opcode_10),
 			0x11 => Ok(opcode_11),
 			0x12 => Ok(opcode_12),
 			0x13 => Ok(opcode_13),
@@ -51,6 +56,7 @@ impl<'a> Cpu<'a> {
 			0x24 => Ok(opcode_24),
 			0x25 => Ok(opcode_25),
 			0x26 => Ok(opcode_26),
+			0x27 => Ok(opcode_27),
 			0x28 => Ok(opcode_28),
 			0x29 => Ok(opcode_29),
 			0x2a => Ok(opcode_2a),
@@ -237,6 +243,7 @@ impl<'a> Cpu<'a> {
 			0xe5 => Ok(opcode_e5),
 			0xe6 => Ok(opcode_e6),
 			0xe7 => Ok(opcode_e7),
+			0xe8 => Ok(opcode_e8),
 			0xe9 => Ok(opcode_e9),
 			0xea => Ok(opcode_ea),
 			0xee => Ok(opcode_ee),
@@ -255,10 +262,13 @@ impl<'a> Cpu<'a> {
 			0xfe => Ok(opcode_fe),
 			0xff => Ok(opcode_ff),
 			0xcb => {
-				let next_byte = self.fetch()?;
+				let next_byte = self.fetch_u8()?;
 				self.decode_cb(next_byte)
 			},
-			_ => Err(GameboyError::BadOpcode(opcode))
+			_ => Err(GameboyError::BadOpcode {
+				opcode,
+				address: self.registers.get(Register::PC).wrapping_sub(1),
+			})
 		}
 	}
 
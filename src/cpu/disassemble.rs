@@ -5,14 +5,49 @@
 use super::Cpu;
 use crate::GameboyError;
 
+/// The number of bytes taken by each opcode, including its operands.
+///
+/// Opcodes that don't exist on the cpu are marked with 0. The 0xCB prefix
+/// is marked with the length of the complete prefixed instruction.
+pub const OPCODE_LENGTHS: [u8; 0x100] = [
+	//  0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+	    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x00
+	    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x10
+	    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x20
+	    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x30
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x40
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x50
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x60
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x70
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x80
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x90
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xa0
+	    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xb0
+	    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // 0xc0
+	    1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1, // 0xd0
+	    2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1, // 0xe0
+	    2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1, // 0xf0
+];
+
+/// Returns the number of bytes taken by the instruction at the given address,
+/// so the following instruction starts at `address + length`.
+///
+/// Opcodes that don't exist on the cpu are treated as a single byte, like
+/// `IllegalOpcodePolicy::Nop` does.
+pub fn instruction_length(cpu: &Cpu, address: u16) -> Result<u16, GameboyError> {
+	let opcode = cpu.mmap.peek(address)?;
+
+	Ok(OPCODE_LENGTHS[opcode as usize].max(1) as u16)
+}
+
 /// Returns a string that describes the opcode at the given address.
-pub fn disassemble<'a>(cpu: &'a Cpu, address: u16) -> Result<&'static str, GameboyError> {
+pub fn disassemble(cpu: &Cpu, address: u16) -> Result<&'static str, GameboyError> {
 	// Get the opcode at the given address.
-	let mut opcode: u16 = cpu.mmap.read(address)? as u16;
+	let mut opcode: u16 = cpu.mmap.peek(address)? as u16;
 
 	if opcode == 0xcb {
 		opcode <<= 8;
-		opcode |= cpu.mmap.read(address + 1)? as u16;
+		opcode |= cpu.mmap.peek(address + 1)? as u16;
 	}
 
 	let disassembly = match opcode {
@@ -30,6 +65,7 @@ pub fn disassemble<'a>(cpu: &'a Cpu, address: u16) -> Result<&'static str, Gameb
 		0x0c => "inc C",
 		0x0d => "dec C",
 		0x0e => "ld C, n",
+		0x10 => "stop",
 		0x11 => "ld DE, nn",
 		0x12 => "ld (DE), A",
 		0x13 => "inc DE",
@@ -50,6 +86,7 @@ pub fn disassemble<'a>(cpu: &'a Cpu, address: u16) -> Result<&'static str, Gameb
 		0x24 => "inc H",
 		0x25 => "dec H",
 		0x26 => "ld H, n",
+		0x27 => "daa",
 		0x28 => "jr Z, n",
 		0x29 => "add HL, HL",
 		0x2a => "ld A, (HL+)",
@@ -229,6 +266,7 @@ pub fn disassemble<'a>(cpu: &'a Cpu, address: u16) -> Result<&'static str, Gameb
 		0xe2 => "ld (C), A",
 		0xe5 => "push HL",
 		0xe6 => "and A, #",
+		0xe8 => "add SP, n",
 		0xe9 => "jp (HL)",
 		0xea => "ld (nn), A",
 		0xee => "xor A, #",
@@ -387,6 +425,70 @@ pub fn disassemble<'a>(cpu: &'a Cpu, address: u16) -> Result<&'static str, Gameb
 		0xcbbd => "res 7, L",
 		0xcbbe => "res 7, (HL)",
 		0xcbbf => "res 7, A",
+		0xcbc0 => "set 0, B",
+		0xcbc1 => "set 0, C",
+		0xcbc2 => "set 0, D",
+		0xcbc3 => "set 0, E",
+		0xcbc4 => "set 0, H",
+		0xcbc5 => "set 0, L",
+		0xcbc6 => "set 0, (HL)",
+		0xcbc7 => "set 0, A",
+		0xcbc8 => "set 1, B",
+		0xcbc9 => "set 1, C",
+		0xcbca => "set 1, D",
+		0xcbcb => "set 1, E",
+		0xcbcc => "set 1, H",
+		0xcbcd => "set 1, L",
+		0xcbce => "set 1, (HL)",
+		0xcbcf => "set 1, A",
+		0xcbd0 => "set 2, B",
+		0xcbd1 => "set 2, C",
+		0xcbd2 => "set 2, D",
+		0xcbd3 => "set 2, E",
+		0xcbd4 => "set 2, H",
+		0xcbd5 => "set 2, L",
+		0xcbd6 => "set 2, (HL)",
+		0xcbd7 => "set 2, A",
+		0xcbd8 => "set 3, B",
+		0xcbd9 => "set 3, C",
+		0xcbda => "set 3, D",
+		0xcbdb => "set 3, E",
+		0xcbdc => "set 3, H",
+		0xcbdd => "set 3, L",
+		0xcbde => "set 3, (HL)",
+		0xcbdf => "set 3, A",
+		0xcbe0 => "set 4, B",
+		0xcbe1 => "set 4, C",
+		0xcbe2 => "set 4, D",
+		0xcbe3 => "set 4, E",
+		0xcbe4 => "set 4, H",
+		0xcbe5 => "set 4, L",
+		0xcbe6 => "set 4, (HL)",
+		0xcbe7 => "set 4, A",
+		0xcbe8 => "set 5, B",
+		0xcbe9 => "set 5, C",
+		0xcbea => "set 5, D",
+		0xcbeb => "set 5, E",
+		0xcbec => "set 5, H",
+		0xcbed => "set 5, L",
+		0xcbee => "set 5, (HL)",
+		0xcbef => "set 5, A",
+		0xcbf0 => "set 6, B",
+		0xcbf1 => "set 6, C",
+		0xcbf2 => "set 6, D",
+		0xcbf3 => "set 6, E",
+		0xcbf4 => "set 6, H",
+		0xcbf5 => "set 6, L",
+		0xcbf6 => "set 6, (HL)",
+		0xcbf7 => "set 6, A",
+		0xcbf8 => "set 7, B",
+		0xcbf9 => "set 7, C",
+		0xcbfa => "set 7, D",
+		0xcbfb => "set 7, E",
+		0xcbfc => "set 7, H",
+		0xcbfd => "set 7, L",
+		0xcbfe => "set 7, (HL)",
+		0xcbff => "set 7, A",
 		_ => "unk"
 	};
 
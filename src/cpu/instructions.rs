@@ -7,10 +7,12 @@
 use super::Cpu;
 use super::alu::*;
 use super::state::registers::*;
+use super::timing::INTERRUPT_CYCLES;
 
 use crate::GameboyError;
+use crate::bus::timer::consts::IO_DIV;
 
-/// Instructions implementations returns the amount of cycles taken,
+/// Instructions implementations returns the amount of T-cycles taken,
 /// of the relevant error if occured.
 pub type InsnResult = Result<usize, GameboyError>;
 /// An instruction's method.
@@ -26,7 +28,7 @@ mod util {
 
 		assert!(get_type(&reg) != RegisterType::Wide);
 
-		let value: u8 = cpu.fetch()?;
+		let value: u8 = cpu.fetch_u8()?;
 		cpu.registers.set(reg, value as u16);
 
 		Ok(8)
@@ -38,7 +40,7 @@ mod util {
 
 		assert!(get_type(&reg) == RegisterType::Wide);
 
-		let value: u16 = cpu.fetch()?;
+		let value: u16 = cpu.fetch_u16()?;
 		cpu.registers.set(reg, value);
 
 		Ok(12)
@@ -107,9 +109,11 @@ mod util {
 		cpu.registers.set(Register::SP, address.wrapping_sub(2));
 
 		address = address.wrapping_sub(1);
+		cpu.check_stack_access(address);
 		cpu.mmap.write(address, ((value >> 8) & 0xFF) as u8)?;
 
 		address = address.wrapping_sub(1);
+		cpu.check_stack_access(address);
 		cpu.mmap.write(address, (value & 0xFF) as u8)?;
 
 		Ok(16)
@@ -123,10 +127,11 @@ mod util {
 
 		let address: u16 = cpu.registers.get(Register::SP);
 
-		let low = cpu.mmap.read(address)? as u16;
-		let high = cpu.mmap.read(address.wrapping_add(1))? as u16;
+		cpu.check_stack_access(address);
+		cpu.check_stack_access(address.wrapping_add(1));
 
-		cpu.registers.set(reg, (high << 8) + low);
+		let value = cpu.mmap.read16(address)?;
+		cpu.registers.set(reg, value);
 
 		// Increment the stack pointer.
 		cpu.registers.set(Register::SP, address.wrapping_add(2));
@@ -135,25 +140,26 @@ mod util {
 	}
 
 	pub fn jump_relative(cpu: &mut Cpu) -> InsnResult {
-		let offset: i8 = cpu.fetch::<u8>()? as i8;
+		let offset: i8 = cpu.fetch_u8()? as i8;
 		let address: u16 = cpu.registers.get(Register::PC);
 
 		// Add the offset to the program counter (preserving the offset's sign)
 		cpu.registers.set(Register::PC, address.wrapping_add((offset as i16) as u16));
 
-		Ok(8)
+		Ok(12)
 	}
 
 	/// Performs a conditional jump instruction.
 	pub fn jump_relative_conditional(cpu: &mut Cpu,
 							flag: Flag,
 							expected_state: bool) -> InsnResult {
-		let offset: i8 = cpu.fetch::<u8>()? as i8;
+		let offset: i8 = cpu.fetch_u8()? as i8;
 		let address: u16 = cpu.registers.get(Register::PC);
 
 		if cpu.registers.flag(flag) == expected_state {
 			// Add the offset to the program counter (preserving the offset's sign)
 			cpu.registers.set(Register::PC, address.wrapping_add((offset as i16) as u16));
+			return Ok(12);
 		}
 
 		Ok(8)
@@ -163,10 +169,11 @@ mod util {
 	pub fn jump_conditional(cpu: &mut Cpu,
 							flag: Flag,
 							expected_state: bool) -> InsnResult {
-		let dest: u16 = cpu.fetch()?;
+		let dest: u16 = cpu.fetch_u16()?;
 
 		if cpu.registers.flag(flag) == expected_state {
 			cpu.registers.set(Register::PC, dest);
+			return Ok(16);
 		}
 
 		Ok(12)
@@ -176,11 +183,12 @@ mod util {
 	pub fn call_conditional(cpu: &mut Cpu,
 							flag: Flag,
 							expected_state: bool) -> InsnResult {
-		let dest: u16 = cpu.fetch()?;
+		let dest: u16 = cpu.fetch_u16()?;
 
 		if cpu.registers.flag(flag) == expected_state {
 			push_nn(cpu, Register::PC)?;
 			cpu.registers.set(Register::PC, dest);
+			return Ok(24);
 		}
 
 		Ok(12)
@@ -192,6 +200,7 @@ mod util {
 
 		if cpu.registers.flag(flag) == expected_state {
 			pop_nn(cpu, Register::PC)?;
+			return Ok(20);
 		}
 
 		Ok(8)
@@ -224,7 +233,8 @@ mod util {
 		cpu.registers.set_flag(Flag::H, true);
 		// Carry is not affected.
 
-		Ok(16)
+		// Unlike the rest of the (HL) bit operations, the memory isn't written back.
+		Ok(12)
 	}
 
 	/// Resets the given bit of the given 8-bit register.
@@ -284,6 +294,18 @@ mod util {
 		Ok(8)
 	}
 
+	/// Swaps the nibbles of the memory data pointed by HL.
+	pub fn swap_memory(cpu: &mut Cpu) -> InsnResult {
+		let address = cpu.registers.get(Register::HL);
+		let data = cpu.mmap.read(address)?;
+
+		let result = alu8::swap(cpu, data);
+
+		cpu.mmap.write(address, result)?;
+
+		Ok(16)
+	}
+
 	/// Rotates right the given register, possibly rotates the carry
 	/// flag too.
 	pub fn rotate_right_register(cpu: &mut Cpu,
@@ -330,6 +352,26 @@ mod util {
 		Ok(8)
 	}
 
+	/// Rotates the A register right, unlike the prefixed rotations the
+	/// zero flag is always reset.
+	pub fn rotate_right_accumulator(cpu: &mut Cpu, carry: bool) -> InsnResult {
+		rotate_right_register(cpu, Register::A, carry)?;
+		cpu.registers.set_flag(Flag::Z, false);
+
+		// The accumulator's rotations are shorter than the prefixed ones.
+		Ok(4)
+	}
+
+	/// Rotates the A register left, unlike the prefixed rotations the
+	/// zero flag is always reset.
+	pub fn rotate_left_accumulator(cpu: &mut Cpu, carry: bool) -> InsnResult {
+		rotate_left_register(cpu, Register::A, carry)?;
+		cpu.registers.set_flag(Flag::Z, false);
+
+		// The accumulator's rotations are shorter than the prefixed ones.
+		Ok(4)
+	}
+
 	/// Rotates left the given memory data pointed by HL, possibly rotates
 	/// the carry flag too.
 	pub fn rotate_left_memory(cpu: &mut Cpu,
@@ -402,25 +444,46 @@ mod util {
 		push_nn(cpu, Register::PC)?;
 		cpu.registers.set(Register::PC, rst_vector);
 
-		Ok(32)
+		Ok(16)
 	}
 }
 
 use util::*;
 
-/// Enter the given interrupt vector.
-pub fn enter_interrupt(cpu: &mut Cpu, int_vector: u16) -> InsnResult {
-	assert!(int_vector & 0xFF00 == 0);
+/// Enter the service routine of the pending interrupt with the highest priority.
+///
+/// The interrupt is only selected (and acknowledged) once the program counter's
+/// high byte is pushed, so a push that overwrites IE may change it. If no
+/// interrupt is pending by then, the dispatch is cancelled and the execution
+/// continues from 0x0000.
+pub fn enter_interrupt(cpu: &mut Cpu) -> InsnResult {
+	cpu.registers.set_ime(false);
 
-	let cycles = push_nn(cpu, Register::PC)? + 8;
+	// Pushing the program counter is preceded by two wait states.
+	let mut pc: u16 = cpu.registers.get(Register::PC);
 
-	// Disable interrupts, takes 4 cycles
-	cpu.registers.set_ime(false);
+	// The halt bug's repeated byte is the halt itself, which is returned to.
+	if cpu.registers.halt_bug {
+		cpu.registers.halt_bug = false;
+		pc = pc.wrapping_sub(1);
+	}
+	let mut address: u16 = cpu.registers.get(Register::SP);
+
+	address = address.wrapping_sub(1);
+	cpu.check_stack_access(address);
+	cpu.mmap.write(address, (pc >> 8) as u8)?;
+
+	let int_vector = cpu.mmap.fetch_interrupt().map_or(0x0000, |interrupt| interrupt.vector());
+
+	address = address.wrapping_sub(1);
+	cpu.check_stack_access(address);
+	cpu.mmap.write(address, (pc & 0xFF) as u8)?;
+	cpu.registers.set(Register::SP, address);
 
-	// Jump to the interrupt vector, takes 4 cycles.
+	// Jump to the interrupt vector.
 	cpu.registers.set(Register::PC, int_vector);
 
-	Ok(cycles)
+	Ok(INTERRUPT_CYCLES)
 }
 
 /// nop
@@ -460,16 +523,15 @@ pub fn opcode_06(cpu: &mut Cpu) -> InsnResult {
 
 /// rlca
 pub fn opcode_07(cpu: &mut Cpu) -> InsnResult {
-	rotate_left_register(cpu, Register::A, false)
+	rotate_left_accumulator(cpu, false)
 }
 
 /// ld (nn), SP
 pub fn opcode_08(cpu: &mut Cpu) -> InsnResult {
-	let address: u16 = cpu.fetch()?;
+	let address: u16 = cpu.fetch_u16()?;
 	let value = cpu.registers.get(Register::SP);
 
-	cpu.mmap.write(address, (value & 0xFF) as u8)?;
-	cpu.mmap.write(address.wrapping_add(1), ((value >> 8) & 0xFF) as u8)?;
+	cpu.mmap.write16(address, value)?;
 
 	Ok(20)
 }
@@ -506,7 +568,24 @@ pub fn opcode_0e(cpu: &mut Cpu) -> InsnResult {
 
 /// rrca
 pub fn opcode_0f(cpu: &mut Cpu) -> InsnResult {
-	rotate_right_register(cpu, Register::A, false)
+	rotate_right_accumulator(cpu, false)
+}
+
+/// stop
+pub fn opcode_10(cpu: &mut Cpu) -> InsnResult {
+	// The opcode is followed by an ignored byte.
+	cpu.fetch_u8()?;
+
+	// The divider is reset when entering the low-power mode.
+	cpu.mmap.write(IO_DIV, 0)?;
+
+	// On the GBC, stop switches the cpu's speed if it was prepared by KEY1,
+	// otherwise the cpu stops until a key is pressed.
+	if !cpu.mmap.switch_speed()? {
+		cpu.stop();
+	}
+
+	Ok(4)
 }
 
 /// ld DE, nn
@@ -541,7 +620,7 @@ pub fn opcode_16(cpu: &mut Cpu) -> InsnResult {
 
 /// rla
 pub fn opcode_17(cpu: &mut Cpu) -> InsnResult {
-	rotate_left_register(cpu, Register::A, true)
+	rotate_left_accumulator(cpu, true)
 }
 
 /// jr n
@@ -581,7 +660,7 @@ pub fn opcode_1e(cpu: &mut Cpu) -> InsnResult {
 
 /// rra
 pub fn opcode_1f(cpu: &mut Cpu) -> InsnResult {
-	rotate_right_register(cpu, Register::A, true)
+	rotate_right_accumulator(cpu, true)
 }
 
 
@@ -633,6 +712,16 @@ pub fn opcode_26(cpu: &mut Cpu) -> InsnResult {
 	load_imm8_to_register(cpu, Register::H)
 }
 
+/// daa
+pub fn opcode_27(cpu: &mut Cpu) -> InsnResult {
+	let value: u8 = cpu.registers.get(Register::A) as u8;
+	let result = alu8::daa(cpu, value);
+
+	cpu.registers.set(Register::A, result as u16);
+
+	Ok(4)
+}
+
 /// jr Z, n
 pub fn opcode_28(cpu: &mut Cpu) -> InsnResult {
 	jump_relative_conditional(cpu, Flag::Z, true)
@@ -679,6 +768,10 @@ pub fn opcode_2f(cpu: &mut Cpu) -> InsnResult {
 	let value: u8 = cpu.registers.get(Register::A) as u8;
 	cpu.registers.set(Register::A, (!value) as u16);
 
+	// The zero and carry flags are not affected.
+	cpu.registers.set_flag(Flag::N, true);
+	cpu.registers.set_flag(Flag::H, true);
+
 	Ok(4)
 }
 
@@ -721,7 +814,7 @@ pub fn opcode_35(cpu: &mut Cpu) -> InsnResult {
 
 /// ld (HL), n
 pub fn opcode_36(cpu: &mut Cpu) -> InsnResult {
-	let value: u8 = cpu.fetch()?;
+	let value: u8 = cpu.fetch_u8()?;
 	let address = cpu.registers.get(Register::HL);
 
 	cpu.mmap.write(address, value)?;
@@ -732,6 +825,8 @@ pub fn opcode_36(cpu: &mut Cpu) -> InsnResult {
 /// scf
 pub fn opcode_37(cpu: &mut Cpu) -> InsnResult {
 	// Set the carry flag.
+	cpu.registers.set_flag(Flag::N, false);
+	cpu.registers.set_flag(Flag::H, false);
 	cpu.registers.set_flag(Flag::C, true);
 
 	Ok(4)
@@ -774,7 +869,7 @@ pub fn opcode_3d(cpu: &mut Cpu) -> InsnResult {
 
 /// ld A, #
 pub fn opcode_3e(cpu: &mut Cpu) -> InsnResult {
-	let value: u8 = cpu.fetch()?;
+	let value: u8 = cpu.fetch_u8()?;
 	cpu.registers.set(Register::A, value as u16);
 
 	Ok(8)
@@ -783,6 +878,8 @@ pub fn opcode_3e(cpu: &mut Cpu) -> InsnResult {
 /// ccf
 pub fn opcode_3f(cpu: &mut Cpu) -> InsnResult {
 	// Complement the carry flag.
+	cpu.registers.set_flag(Flag::N, false);
+	cpu.registers.set_flag(Flag::H, false);
 	cpu.registers.set_flag(Flag::C, !cpu.registers.flag(Flag::C));
 
 	Ok(4)
@@ -1447,10 +1544,10 @@ pub fn opcode_c2(cpu: &mut Cpu) -> InsnResult {
 
 /// jp nn
 pub fn opcode_c3(cpu: &mut Cpu) -> InsnResult {
-	let dest: u16 = cpu.fetch()?;
+	let dest: u16 = cpu.fetch_u16()?;
 	cpu.registers.set(Register::PC, dest);
 
-	Ok(12)
+	Ok(16)
 }
 
 /// call NZ, nn
@@ -1482,7 +1579,7 @@ pub fn opcode_c8(cpu: &mut Cpu) -> InsnResult {
 pub fn opcode_c9(cpu: &mut Cpu) -> InsnResult {
 	pop_nn(cpu, Register::PC)?;
 
-	Ok(8)
+	Ok(16)
 }
 
 /// jp Z, nn
@@ -1497,12 +1594,12 @@ pub fn opcode_cc(cpu: &mut Cpu) -> InsnResult {
 
 /// call nn
 pub fn opcode_cd(cpu: &mut Cpu) -> InsnResult {
-	let dest: u16 = cpu.fetch()?;
+	let dest: u16 = cpu.fetch_u16()?;
 
 	push_nn(cpu, Register::PC)?;
 	cpu.registers.set(Register::PC, dest);
 
-	Ok(12)
+	Ok(24)
 }
 
 /// adc A, #
@@ -1561,7 +1658,7 @@ pub fn opcode_d9(cpu: &mut Cpu) -> InsnResult {
 
 	cpu.registers.set_ime(true);
 
-	Ok(8)
+	Ok(16)
 }
 
 /// jp C, nn
@@ -1586,7 +1683,7 @@ pub fn opcode_df(cpu: &mut Cpu) -> InsnResult {
 
 /// ld (n), A
 pub fn opcode_e0(cpu: &mut Cpu) -> InsnResult {
-	let low_byte = cpu.fetch::<u8>()? as u16;
+	let low_byte = cpu.fetch_u8()? as u16;
 	let address: u16 = 0xFF00 | low_byte;
 
 	let value: u8 = cpu.registers.get(Register::A) as u8;
@@ -1632,6 +1729,11 @@ pub fn opcode_e7(cpu: &mut Cpu) -> InsnResult {
 	restart(cpu, 0x20)
 }
 
+/// add SP, n
+pub fn opcode_e8(cpu: &mut Cpu) -> InsnResult {
+	alu16::op_imm(alu16::add_sp, cpu, Register::SP)
+}
+
 /// jp (HL)
 pub fn opcode_e9(cpu: &mut Cpu) -> InsnResult {
 	let address: u16 = cpu.registers.get(Register::HL);
@@ -1643,7 +1745,7 @@ pub fn opcode_e9(cpu: &mut Cpu) -> InsnResult {
 
 /// ld (nn), A
 pub fn opcode_ea(cpu: &mut Cpu) -> InsnResult {
-	let address: u16 = cpu.fetch::<u16>()?;
+	let address: u16 = cpu.fetch_u16()?;
 	let value: u8 = cpu.registers.get(Register::A) as u8;
 
 	// TODO remove this!
@@ -1669,7 +1771,7 @@ pub fn opcode_ef(cpu: &mut Cpu) -> InsnResult {
 
 /// ldh A, (n)
 pub fn opcode_f0(cpu: &mut Cpu) -> InsnResult {
-	let low_byte = cpu.fetch::<u8>()? as u16;
+	let low_byte = cpu.fetch_u8()? as u16;
 	let address: u16 = 0xFF00 | low_byte;
 
 	let value: u8 = cpu.mmap.read(address)?;
@@ -1703,6 +1805,7 @@ pub fn opcode_f2(cpu: &mut Cpu) -> InsnResult {
 /// di
 pub fn opcode_f3(cpu: &mut Cpu) -> InsnResult {
 	cpu.registers.set_ime(false);
+	cpu.registers.ime_delay = false;
 
 	Ok(4)
 }
@@ -1724,16 +1827,14 @@ pub fn opcode_f7(cpu: &mut Cpu) -> InsnResult {
 
 /// ld HL, SP+n
 pub fn opcode_f8(cpu: &mut Cpu) -> InsnResult {
-	let offset: u16 = cpu.fetch::<u8>()? as u16;
+	let offset: u16 = cpu.fetch_u8()? as u16;
 	let sp = cpu.registers.get(Register::SP);
 
-	let result = alu16::add(cpu, sp, offset);
+	// The offset is signed, and the flags are set like in add SP, n.
+	let result = alu16::add_sp(cpu, sp, offset);
 
 	cpu.registers.set(Register::HL, result);
 
-	// According to the manual, this instruction always resets the zero flag.
-	cpu.registers.set_flag(Flag::Z, false);
-
 	Ok(12)
 }
 
@@ -1744,7 +1845,7 @@ pub fn opcode_f9(cpu: &mut Cpu) -> InsnResult {
 
 /// ld A, (nn)
 pub fn opcode_fa(cpu: &mut Cpu) -> InsnResult {
-	let address: u16 = cpu.fetch::<u16>()?;
+	let address: u16 = cpu.fetch_u16()?;
 	let value: u8 = cpu.mmap.read(address)?;
 
 	cpu.registers.set(Register::A, value as u16);
@@ -2041,14 +2142,7 @@ pub fn opcode_cb35(cpu: &mut Cpu) -> InsnResult {
 
 /// swap (HL)
 pub fn opcode_cb36(cpu: &mut Cpu) -> InsnResult {
-	// Swap memory at (HL)
-	let address: u16 = cpu.registers.get(Register::HL);
-	let value: u8 = cpu.mmap.read(address)?;
-
-	let result = alu8::swap(cpu, value);
-	cpu.mmap.write(address, result)?;
-
-	Ok(16)
+	swap_memory(cpu)
 }
 
 /// swap A
@@ -3056,6 +3150,7 @@ pub fn opcode_cbff(cpu: &mut Cpu) -> InsnResult {
 	set_register_bit(cpu, Register::A, 7)
 }
 
+/// Instructions tests.
 #[cfg(test)]
 #[allow(dead_code)]
 pub mod tests {
@@ -3107,6 +3202,53 @@ pub mod tests {
 		})
 	}
 
+	#[test]
+	fn test_jump_hl() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::HL, 0x1234);
+
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(cpu.registers.get(Register::PC), &[/* JP (HL) */ 0xe9])?;
+
+			assert!(cpu.execute_single()? == 4);
+			assert!(cpu.registers.get(Register::PC) == 0x1234);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_rotate_accumulator() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			// The opcode, the initial A and F, and the expected A and F.
+			let cases: [(u8, u8, u8, u8, u8); 6] = [
+				(/* RLCA */ 0x07, 0x80, 0x00, 0x01, 0x10),
+				(/* RRCA */ 0x0f, 0x01, 0x00, 0x80, 0x10),
+				(/* RLA */ 0x17, 0x80, 0x00, 0x00, 0x10),
+				(/* RRA */ 0x1f, 0x01, 0x00, 0x00, 0x10),
+				(/* RLA */ 0x17, 0x00, 0xF0, 0x01, 0x00),
+				(/* RRA */ 0x1f, 0x00, 0xF0, 0x80, 0x00),
+			];
+
+			for &(opcode, a, f, result, flags) in cases.iter() {
+				cpu.registers.set(Register::PC, 0xA000);
+				cpu.registers.set(Register::A, a as u16);
+				cpu.registers.set(Register::F, f as u16);
+				cpu.mmap.write(0xA000, opcode)?;
+
+				// The zero flag is reset even if the result is zero.
+				assert!(cpu.execute_single()? == 4);
+				assert!(cpu.registers.get(Register::A) == result as u16);
+				assert!(cpu.registers.get(Register::F) == flags as u16);
+			}
+
+			Ok(())
+		})
+	}
+
 	#[test]
 	fn test_cpl() -> Result<(), GameboyError> {
 		super::super::tests::with_cpu(|cpu| {
@@ -3122,11 +3264,398 @@ pub mod tests {
 
 			cpu.execute_single()?;
 
-			// Make sure BC contains the same value.
-			assert!(cpu.registers.get(Register::AF) == 0xed34);
+			// A is complemented, and the N and H flags are set.
+			assert!(cpu.registers.get(Register::AF) == 0xed74);
 
 			Ok(())
 		})
 	}
 
+	#[test]
+	fn test_carry_flag() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::AF, 0x12e0);
+
+			let data: &[u8] = &[/* SCF */ 0x37,
+								/* CCF */ 0x3f,
+								/* CCF */ 0x3f];
+
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(cpu.registers.get(Register::PC), data)?;
+
+			// The N and H flags are reset, and the zero flag is kept.
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::AF) == 0x1290);
+
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::AF) == 0x1280);
+
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::AF) == 0x1290);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_restart() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			for vector in (0x00..=0x38_u16).step_by(8) {
+				cpu.registers.set(Register::PC, 0xA000);
+				cpu.registers.set(Register::SP, 0xD000);
+
+				cpu.mmap.write(0xA000, 0xc7 | vector as u8)?;
+				assert!(cpu.execute_single()? == 16);
+
+				// The address of the next instruction is pushed.
+				assert!(cpu.registers.get(Register::PC) == vector);
+				assert!(cpu.registers.get(Register::SP) == 0xCFFE);
+				assert!(cpu.mmap.read(0xCFFE)? == 0x01);
+				assert!(cpu.mmap.read(0xCFFF)? == 0xA0);
+			}
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_base_opcodes() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			// Only the opcodes that don't exist on the cpu fail to decode.
+			let illegal = [0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd];
+
+			for opcode in 0..=0xff_u8 {
+				if opcode != 0xcb {
+					assert!(cpu.decode(opcode).is_err() == illegal.contains(&opcode),
+						"Opcode {:02x} is decoded incorrectly", opcode);
+				}
+			}
+
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::SP, 0xD000);
+			cpu.registers.set(Register::A, 0x15);
+
+			let data: &[u8] = &[/* ADD A, 0x27 */ 0xc6, 0x27,
+								/* DAA */ 0x27,
+								/* ADD SP, -2 */ 0xe8, 0xfe,
+								/* STOP */ 0x10, 0x00,
+								/* NOP */ 0x00];
+
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(cpu.registers.get(Register::PC), data)?;
+
+			// The sum is adjusted to BCD.
+			cpu.execute_single()?;
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::A) == 0x42);
+
+			// The offset is signed.
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::SP) == 0xCFFE);
+
+			// Stop is followed by a padding byte.
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::PC) == 0xA007);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_add16() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::F, 0x80);
+			cpu.registers.set(Register::HL, 0x8FFF);
+			cpu.registers.set(Register::BC, 0x0001);
+			cpu.registers.set(Register::SP, 0xD0F8);
+
+			let data: &[u8] = &[/* ADD HL, BC */ 0x09,
+								/* ADD HL, HL */ 0x29,
+								/* ADD SP, 8 */ 0xe8, 0x08,
+								/* ADD SP, -1 */ 0xe8, 0xff,
+								/* LD HL, SP-2 */ 0xf8, 0xfe];
+
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(cpu.registers.get(Register::PC), data)?;
+
+			// The half carry is taken from bit 11, and the zero flag is kept.
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::HL) == 0x9000);
+			assert!(cpu.registers.get(Register::F) == 0xA0);
+
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::HL) == 0x2000);
+			assert!(cpu.registers.get(Register::F) == 0x90);
+
+			// The flags are taken from the low byte's unsigned addition.
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::SP) == 0xD100);
+			assert!(cpu.registers.get(Register::F) == 0x30);
+
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::SP) == 0xD0FF);
+			assert!(cpu.registers.get(Register::F) == 0x00);
+
+			cpu.execute_single()?;
+			assert!(cpu.registers.get(Register::HL) == 0xD0FD);
+			assert!(cpu.registers.get(Register::SP) == 0xD0FF);
+			assert!(cpu.registers.get(Register::F) == 0x30);
+
+			Ok(())
+		})
+	}
+
+	/// The operands of the 0xCB-prefixed opcodes by their lowest 3 bits,
+	/// where `None` stands for (HL).
+	const CB_OPERANDS: [Option<Register>; 8] = [
+		Some(Register::B), Some(Register::C), Some(Register::D), Some(Register::E),
+		Some(Register::H), Some(Register::L), None, Some(Register::A),
+	];
+
+	/// Executes a 0xCB-prefixed opcode on the given operand value and flags,
+	/// and returns the resulting operand value and flags.
+	fn execute_cb(cpu: &mut Cpu, opcode: u8, value: u8, flags: u8) -> Result<(u8, u8), GameboyError> {
+		let operand = CB_OPERANDS[(opcode & 0x07) as usize];
+
+		cpu.registers.set(Register::PC, 0xA000);
+		cpu.registers.set(Register::HL, 0xC000);
+		cpu.registers.set(Register::F, flags as u16);
+
+		match operand {
+			Some(reg) => cpu.registers.set(reg, value as u16),
+			None => cpu.mmap.write(0xC000, value)?,
+		}
+
+		cpu.mmap.write_all(0xA000, &[0xcb, opcode])?;
+		cpu.execute_single()?;
+
+		let result = match operand {
+			Some(reg) => cpu.registers.get(reg) as u8,
+			None => cpu.mmap.read(0xC000)?,
+		};
+
+		Ok((result, cpu.registers.get(Register::F) as u8))
+	}
+
+	#[test]
+	fn test_cb_rotates() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			for opcode in 0x00..=0x1f_u8 {
+				for &value in &[0x00_u8, 0x01, 0x80, 0x85] {
+					for &carry in &[false, true] {
+						// rlc, rrc, rl and rr.
+						let (expected, new_carry) = match opcode >> 3 {
+							0 => (value.rotate_left(1), value & 0x80 != 0),
+							1 => (value.rotate_right(1), value & 0x01 != 0),
+							2 => ((value << 1) | carry as u8, value & 0x80 != 0),
+							_ => ((value >> 1) | ((carry as u8) << 7), value & 0x01 != 0),
+						};
+
+						// The N and H flags are always reset.
+						let expected_flags = if expected == 0 { 0x80 } else { 0 } | if new_carry { 0x10 } else { 0 };
+						let flags = if carry { 0x70 } else { 0x60 };
+
+						let (result, result_flags) = execute_cb(cpu, opcode, value, flags)?;
+
+						assert!(result == expected && result_flags == expected_flags,
+							"Opcode cb {:02x} on {:02x} resulted in {:02x} (flags {:02x})",
+							opcode, value, result, result_flags);
+					}
+				}
+			}
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_cb_shifts() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			// sla, sra and srl, skipping swap.
+			let opcodes = (0x20..=0x2f_u8).chain(0x38..=0x3f);
+
+			for opcode in opcodes {
+				for &value in &[0x00_u8, 0x01, 0x80, 0xc5] {
+					let (expected, new_carry) = match opcode >> 3 {
+						4 => (value << 1, value & 0x80 != 0),
+						// The arithmetic shift keeps the sign bit.
+						5 => ((value >> 1) | (value & 0x80), value & 0x01 != 0),
+						_ => (value >> 1, value & 0x01 != 0),
+					};
+
+					// The previous carry is shifted out, and N and H are reset.
+					let expected_flags = if expected == 0 { 0x80 } else { 0 } | if new_carry { 0x10 } else { 0 };
+
+					let (result, result_flags) = execute_cb(cpu, opcode, value, 0x70)?;
+
+					assert!(result == expected && result_flags == expected_flags,
+						"Opcode cb {:02x} on {:02x} resulted in {:02x} (flags {:02x})",
+						opcode, value, result, result_flags);
+				}
+			}
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_cb_swap() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			for opcode in 0x30..=0x37_u8 {
+				// All of the flags but Z are reset.
+				assert!(execute_cb(cpu, opcode, 0x5a, 0xf0)? == (0xa5, 0x00));
+				assert!(execute_cb(cpu, opcode, 0x00, 0x70)? == (0x00, 0x80));
+			}
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_cb_bit() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			for opcode in 0x40..=0x7f_u8 {
+				let bit = (opcode >> 3) & 0x07;
+
+				// The operand isn't modified, H is set and the carry is kept.
+				for &carry in &[0x00, 0x10] {
+					assert!(execute_cb(cpu, opcode, 1 << bit, 0x40 | carry)? == (1 << bit, 0x20 | carry));
+					assert!(execute_cb(cpu, opcode, !(1 << bit), 0x40 | carry)? == (!(1 << bit), 0xa0 | carry));
+				}
+			}
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_cb_res_set() -> Result<(), GameboyError> {
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			for opcode in 0x80..=0xff_u8 {
+				let mask = 1 << ((opcode >> 3) & 0x07);
+
+				// Only the selected bit changes, and the flags aren't affected.
+				let expected = if opcode < 0xc0 { 0x5a & !mask } else { 0x5a | mask };
+				assert!(execute_cb(cpu, opcode, 0x5a, 0xb0)? == (expected, 0xb0));
+			}
+
+			Ok(())
+		})
+	}
+
+	/// Executes the given opcode and returns the number of cycles it took.
+	fn execute_timed(cpu: &mut Cpu, opcode: &[u8], flags: u16) -> InsnResult {
+		// Point the registers to writable memory.
+		cpu.registers.set(Register::PC, 0xA000);
+		cpu.registers.set(Register::SP, 0xD000);
+		cpu.registers.set(Register::HL, 0xC000);
+		cpu.registers.set(Register::BC, 0xC180);
+		cpu.registers.set(Register::DE, 0xC180);
+		cpu.registers.set(Register::AF, 0x0100 | flags);
+
+		// Immediate operands point to the high ram as well.
+		cpu.mmap.write_all(0xA000, &[0x00, 0x80, 0xC0])?;
+		cpu.mmap.write_all(0xA000, opcode)?;
+
+		cpu.execute_single()
+	}
+
+	#[test]
+	fn test_opcode_timing() -> Result<(), GameboyError> {
+		use super::super::timing::*;
+
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			for opcode in 0..=0xff_u8 {
+				// Skip the prefix and opcodes that aren't implemented.
+				if opcode == 0xcb || cpu.decode(opcode).is_err() {
+					continue;
+				}
+
+				// Check both outcomes of conditional branches.
+				for &flags in &[0x00, 0xf0] {
+					let taken = match (opcode >> 3) & 3 {
+						// NZ, NC
+						0 | 2 => flags == 0,
+						// Z, C
+						_ => flags != 0,
+					};
+
+					let cycles = execute_timed(cpu, &[opcode, 0x80], flags)?;
+					let expected = opcode_cycles(opcode, false, taken);
+
+					assert!(cycles == expected,
+						"Opcode {:02x} took {} cycles instead of {}", opcode, cycles, expected);
+				}
+			}
+
+			for opcode in 0..=0xff_u8 {
+				let cycles = execute_timed(cpu, &[0xcb, opcode], 0)?;
+				let expected = opcode_cycles(opcode, true, false);
+
+				assert!(cycles == expected,
+					"Opcode cb {:02x} took {} cycles instead of {}", opcode, cycles, expected);
+			}
+
+			assert!(enter_interrupt(cpu)? == INTERRUPT_CYCLES);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_opcode_lengths() -> Result<(), GameboyError> {
+		// Jumps that are always taken, so the program counter can't be compared.
+		let jumps = [0x18, 0xc3, 0xc9, 0xcd, 0xd9, 0xe9];
+
+		super::super::tests::with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+
+			for opcode in 0..=0xff_u8 {
+				let restart = opcode & 0xc7 == 0xc7;
+				if cpu.decode(opcode).is_err() || jumps.contains(&opcode) || restart {
+					continue;
+				}
+
+				// Make sure that conditional branches aren't taken.
+				let flags = match (opcode >> 3) & 3 {
+					// NZ, NC
+					0 | 2 => 0xf0,
+					// Z, C
+					_ => 0x00,
+				};
+
+				// Halting may trigger the halt bug, which would affect the next opcode.
+				cpu.registers.halt_bug = false;
+
+				execute_timed(cpu, &[opcode, 0x80], flags)?;
+				let length = cpu.instruction_length_at(0xA000)?;
+
+				assert!(cpu.registers.get(Register::PC) == 0xA000 + length,
+					"Opcode {:02x} isn't {} bytes long", opcode, length);
+			}
+
+			for opcode in 0..=0xff_u8 {
+				execute_timed(cpu, &[0xcb, opcode], 0)?;
+				assert!(cpu.registers.get(Register::PC) == 0xA000 + cpu.instruction_length_at(0xA000)?);
+			}
+
+			Ok(())
+		})
+	}
 }
@@ -3,10 +3,14 @@
 
 //! Abstraction for the cpu's interrupts.
 
-use core::iter::Iterator;
+use core::fmt;
+use core::iter::{FromIterator, Iterator};
+use core::ops::{BitAnd, BitOr, BitOrAssign};
 
-/// Marks which interrupts are currently active.
-pub type InterruptMask = u8;
+/// Marks which interrupts are currently active, as held by the IF and IE
+/// registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InterruptMask(u8);
 
 /// Represents a peripheral that may raise interrupts.
 pub trait InterruptSource {
@@ -18,6 +22,7 @@ pub trait InterruptSource {
 }
 
 /// Interrupts that can be thrown by peripherals.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Interrupt {
 	/// Triggered when the LCD controller enters V-Blank at scanline 144.
 	VerticalBlank,
@@ -31,6 +36,15 @@ pub enum Interrupt {
 	Joypad,
 }
 
+/// The interrupts, from the highest priority to the lowest.
+const INTERRUPTS: [Interrupt; 5] = [
+	Interrupt::VerticalBlank,
+	Interrupt::LcdStat,
+	Interrupt::Timer,
+	Interrupt::Serial,
+	Interrupt::Joypad,
+];
+
 impl Interrupt {
 	/// Get the identifier of the given interrupt.
 	pub fn ordinal(&self) -> u8 {
@@ -47,9 +61,126 @@ impl Interrupt {
 	pub fn value(&self) -> u8 {
 		1 << self.ordinal()
 	}
+
+	/// Get the address of the given interrupt's service routine.
+	pub fn vector(&self) -> u16 {
+		0x0040 + 8 * self.ordinal() as u16
+	}
+
+	/// Get the interrupt's name.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Interrupt::VerticalBlank => "V-Blank",
+			Interrupt::LcdStat => "LCD STAT",
+			Interrupt::Timer => "Timer",
+			Interrupt::Serial => "Serial",
+			Interrupt::Joypad => "Joypad",
+		}
+	}
 }
 
-/// Iterates over interrupts that the Ppu has raised.
+impl InterruptMask {
+	/// Create a mask without any interrupts.
+	pub const fn empty() -> Self {
+		InterruptMask(0)
+	}
+
+	/// Create a mask from a register's value. The bits that don't belong to
+	/// any interrupt are kept, as the registers hold them.
+	pub const fn from_bits(bits: u8) -> Self {
+		InterruptMask(bits)
+	}
+
+	/// Returns the mask's register value.
+	pub const fn bits(&self) -> u8 {
+		self.0
+	}
+
+	/// Returns whether none of the interrupts are active.
+	pub fn is_empty(&self) -> bool {
+		self.iter().next().is_none()
+	}
+
+	/// Returns whether the given interrupt is active.
+	pub fn contains(&self, interrupt: Interrupt) -> bool {
+		self.0 & interrupt.value() != 0
+	}
+
+	/// Marks the given interrupt as active.
+	pub fn insert(&mut self, interrupt: Interrupt) {
+		self.0 |= interrupt.value();
+	}
+
+	/// Marks the given interrupt as inactive.
+	pub fn remove(&mut self, interrupt: Interrupt) {
+		self.0 &= !interrupt.value();
+	}
+
+	/// Iterates over the active interrupts, from the highest priority to
+	/// the lowest.
+	pub fn iter(&self) -> InterruptIter {
+		InterruptIter::new(*self)
+	}
+}
+
+impl From<Interrupt> for InterruptMask {
+	fn from(interrupt: Interrupt) -> Self {
+		InterruptMask(interrupt.value())
+	}
+}
+
+impl FromIterator<Interrupt> for InterruptMask {
+	fn from_iter<I: IntoIterator<Item = Interrupt>>(interrupts: I) -> Self {
+		let mut mask = InterruptMask::empty();
+		for interrupt in interrupts {
+			mask.insert(interrupt);
+		}
+
+		mask
+	}
+}
+
+impl BitOr for InterruptMask {
+	type Output = Self;
+
+	fn bitor(self, other: Self) -> Self {
+		InterruptMask(self.0 | other.0)
+	}
+}
+
+impl BitOrAssign for InterruptMask {
+	fn bitor_assign(&mut self, other: Self) {
+		self.0 |= other.0;
+	}
+}
+
+impl BitAnd for InterruptMask {
+	type Output = Self;
+
+	fn bitand(self, other: Self) -> Self {
+		InterruptMask(self.0 & other.0)
+	}
+}
+
+impl fmt::Display for InterruptMask {
+	/// Lists the active interrupts' names, or "None".
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.is_empty() {
+			return f.write_str("None");
+		}
+
+		for (index, interrupt) in self.iter().enumerate() {
+			if index > 0 {
+				f.write_str(", ")?;
+			}
+			f.write_str(interrupt.name())?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Iterates over the interrupts of a mask, see `InterruptMask::iter`.
 pub struct InterruptIter {
 	/// The iterator's active interrupts mask.
 	/// Iterated interrupts are popped from the mask.
@@ -69,26 +200,39 @@ impl Iterator for InterruptIter {
 	type Item = Interrupt;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		// V-Blank interrupt
-		if self.mask & 1 != 0 {
-			self.mask &= !1;
-			Some(Interrupt::VerticalBlank)
-		// LCD Status interrupt
-		} else if self.mask & 2 != 0 {
-			self.mask &= !2;
-			Some(Interrupt::LcdStat)
-		} else if self.mask & 4 != 0 {
-			self.mask &= !4;
-			Some(Interrupt::Timer)
-		} else if self.mask & 8 != 0 {
-			self.mask &= !8;
-			Some(Interrupt::Serial)
-		} else if self.mask & 16 != 0 {
-			self.mask &= !16;
-			Some(Interrupt::Joypad)
-		} else {
-			None
-		}
+		let interrupt = INTERRUPTS.iter().copied().find(|&interrupt| self.mask.contains(interrupt))?;
+		self.mask.remove(interrupt);
+
+		Some(interrupt)
 	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-}
\ No newline at end of file
+	#[test]
+	fn test_interrupt_mask() {
+		let mut mask: InterruptMask = [Interrupt::Joypad, Interrupt::VerticalBlank].iter().copied().collect();
+		mask.insert(Interrupt::Timer);
+		mask.remove(Interrupt::Joypad);
+
+		assert!(mask.contains(Interrupt::Timer) && !mask.contains(Interrupt::Joypad));
+		assert!(mask.iter().eq([Interrupt::VerticalBlank, Interrupt::Timer].iter().copied()));
+
+		// The bits that don't belong to any interrupt are kept, but ignored.
+		let unused = InterruptMask::from_bits(0xE0);
+		assert!(unused.is_empty() && (unused | mask).bits() == 0xE5);
+		assert!((unused & mask).is_empty());
+	}
+
+	#[test]
+	#[cfg(feature = "alloc")]
+	fn test_display() {
+		use alloc::string::ToString;
+
+		let mask = InterruptMask::from(Interrupt::LcdStat) | InterruptMask::from(Interrupt::Serial);
+		assert!(mask.to_string() == "LCD STAT, Serial");
+		assert!(InterruptMask::empty().to_string() == "None");
+	}
+}
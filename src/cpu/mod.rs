@@ -10,22 +10,25 @@ pub mod decode;
 pub mod interrupts;
 pub mod disassemble;
 pub mod instructions;
-
-use num::PrimInt;
-use core::mem::size_of;
-use core::ops::{AddAssign, Shl};
+pub mod timing;
 
 use state::*;
 use state::registers::*;
 use instructions::{Instruction, enter_interrupt};
+use timing::HALT_EXIT_CYCLES;
 
 use crate::GameboyError;
-use crate::config::Config;
+use crate::config::{Config, HardwareModel, IllegalOpcodePolicy};
+use crate::warning::{Warning, Warnings};
 use crate::bus::joypad::Controller;
 
 use crate::bus::*;
+use crate::bus::boot::*;
 use crate::bus::cartridge::*;
-use crate::cpu::interrupts::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
+#[cfg(feature = "embedded-graphics")]
+use embedded_graphics_core::{draw_target::DrawTarget, pixelcolor::Rgb888};
 
 /// The gameboy's processor.
 ///
@@ -35,137 +38,407 @@ pub struct Cpu<'a> {
 	// Interrupts, system tick, cpu speed, serial ports and etc. should come here
 
 	/// The cpu's registers.
-	registers: CpuState<'a>,
+	registers: CpuState,
 	/// The devices' memory mapping
 	pub mmap: SystemBus<'a>,
 	/// The emulator's configuration
-	pub config: &'a Config,
-
-	/// Whether the processor is currently halting and waiting for an external interrupt
-	/// in order to resume.
-	halting: bool,
-	/// If we halt the cpu when interrupts are disabled, the original cpu had a bug
-	/// in which it fetches the byte after the halt twice.
-	halt_bug: bool,
-	/// The processor has a delay of a single instruction after EI before actually
-	/// enabling interrupts.
-	ime_delay: bool,
+	pub config: Config,
+
+	/// Whether the processor is in its low-power mode after STOP, waiting for
+	/// a key press. The peripherals are paused as well.
+	stopped: bool,
+	/// Whether the processor locked up after executing an illegal opcode.
+	locked: bool,
+	/// Whether the stack pointer is outside of the configured stack range.
+	stack_out_of_range: bool,
+	/// The number of clock cycles executed since the machine was created.
+	total_cycles: u64,
+}
+
+/// A copy of the machine's state, see `Cpu::snapshot`.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct CpuSnapshot {
+	registers: CpuState,
+	bus: BusSnapshot,
+	stopped: bool,
+	locked: bool,
+	stack_out_of_range: bool,
+	total_cycles: u64,
 }
 
 impl<'a> Cpu<'a> {
 	/// Initializes a new virtual cpu
 	#[inline(always)]
-	pub fn new(config: &'a Config, cartridge: &'a mut Cartridge<'a>) -> Self {
+	pub fn new(config: Config, cartridge: Cartridge<'a>) -> Self {
 		Cpu {
 			registers: CpuState::new(config),
 			mmap: SystemBus::new(&config, cartridge),
 			config,
-			halting: false,
-			halt_bug: false,
-			ime_delay: false,
+			stopped: false,
+			locked: false,
+			stack_out_of_range: false,
+			total_cycles: 0,
 		}
 	}
 
-	/// Halt the cpu.
-	pub fn halt(&mut self) {
-		self.halting = true;
+	/// Maps the given boot rom and moves the cpu to its power-on state,
+	/// so that execution starts from the boot rom.
+	pub fn set_boot_rom(&mut self, boot_rom: BootRom<'a>) {
+		self.mmap.set_boot_rom(boot_rom);
+		self.registers.power_on();
+	}
 
-		if !self.registers.ime() {
-			self.halt_bug = true;
+	/// Resets the machine, as if it was power cycled without reloading the
+	/// cartridge.
+	///
+	/// With a boot rom, the execution starts from the boot rom again.
+	/// Otherwise, the machine starts at the boot rom's exit state.
+	pub fn reset(&mut self) {
+		self.mmap.reset();
+
+		if self.mmap.boot_rom_mapped() {
+			self.registers.power_on();
+		} else {
+			self.registers.reset();
+		}
+
+		self.stopped = false;
+		self.locked = false;
+		self.stack_out_of_range = false;
+	}
+
+	/// Copies the state of the cpu and the devices on its bus.
+	///
+	/// Restoring the snapshot resumes the emulation deterministically, so
+	/// the same input yields the same frames.
+	#[cfg(feature = "alloc")]
+	pub fn snapshot(&self) -> CpuSnapshot {
+		CpuSnapshot {
+			registers: self.registers.clone(),
+			bus: self.mmap.snapshot(),
+			stopped: self.stopped,
+			locked: self.locked,
+			stack_out_of_range: self.stack_out_of_range,
+			total_cycles: self.total_cycles,
+		}
+	}
+
+	/// Restores the state copied by `snapshot`, which must have been taken
+	/// with the same cartridge.
+	#[cfg(feature = "alloc")]
+	pub fn restore(&mut self, snapshot: &CpuSnapshot) -> Result<(), GameboyError> {
+		self.mmap.restore(&snapshot.bus)?;
+		self.registers = snapshot.registers.clone();
+		self.stopped = snapshot.stopped;
+		self.locked = snapshot.locked;
+		self.stack_out_of_range = snapshot.stack_out_of_range;
+		self.total_cycles = snapshot.total_cycles;
+
+		Ok(())
+	}
+
+	/// Halt the cpu, until an interrupt is pending.
+	///
+	/// If an interrupt is already pending, the cpu doesn't halt: it's entered
+	/// right away when the interrupts are enabled, and otherwise the halt bug
+	/// is triggered. An EI right before the halt hasn't enabled them yet.
+	pub fn halt(&mut self) {
+		if self.mmap.pending_interrupts().is_empty() {
+			self.registers.halting = true;
+		} else if !self.registers.ime() {
+			self.registers.halt_bug = true;
 		}
 	}
 
+	/// Enter the low-power mode, until a key is pressed.
+	pub fn stop(&mut self) {
+		self.stopped = true;
+	}
+
+	/// Returns whether the cpu is halting, waiting for an interrupt.
+	pub fn halted(&self) -> bool {
+		self.registers.halting
+	}
+
+	/// Returns whether the interrupts are enabled (IME).
+	pub fn ime(&self) -> bool {
+		self.registers.ime()
+	}
+
+	/// Returns whether the cpu is in its low-power mode.
+	pub fn stopped(&self) -> bool {
+		self.stopped
+	}
+
+	/// Returns the cpu's registers, for inspecting its state.
+	pub fn registers(&self) -> &CpuState {
+		&self.registers
+	}
+
+	/// Copies the cpu's registers, IME and halting flags, without the rest of
+	/// the machine's state, see `CpuState::to_bytes`.
+	pub fn export_state(&self) -> CpuState {
+		self.registers.clone()
+	}
+
+	/// Replaces the cpu's registers, IME and halting flags, leaving the rest
+	/// of the machine as is.
+	pub fn import_state(&mut self, state: &CpuState) {
+		self.registers = state.clone();
+	}
+
+	/// Returns the number of clock cycles executed since the machine was
+	/// created, which keeps increasing across resets.
+	///
+	/// The counter is a part of the savestates, so it may be used for
+	/// timestamping traces and savestates.
+	pub fn total_cycles(&self) -> u64 {
+		self.total_cycles
+	}
+
+	/// Returns the number of frames the display has completed since the
+	/// machine was created, see `Ppu::frame_count`.
+	pub fn frame_count(&self) -> usize {
+		self.mmap.ppu.frame_count()
+	}
+
+	/// Returns whether the cpu locked up, see `IllegalOpcodePolicy::Lock`.
+	pub fn locked(&self) -> bool {
+		self.locked
+	}
+
 	/// Enable interrupts with a delay of a single instruction.
 	pub fn toggle_ime_delayed(&mut self) {
-		self.ime_delay = true;
+		self.registers.ime_delay = true;
+	}
+
+	/// Returns the queue of warnings raised during the emulation.
+	pub fn warnings(&self) -> &Warnings {
+		&self.mmap.warnings
 	}
 
 	/// Apply the given closure to the game controller.
 	pub fn with_controller<F>(&mut self, closure: F)
-		where F: FnOnce(&mut dyn Controller) -> () {
+		where F: FnOnce(&mut dyn Controller) {
 			closure(&mut self.mmap.joypad);
 	}
 
-	/// Reads the next instruction bytes and increments the program counter appropriately.
+	/// Reads the next instruction byte and increments the program counter appropriately.
+	pub fn fetch_u8(&mut self) -> Result<u8, GameboyError> {
+		let pc: u16 = self.registers.get(Register::PC);
+		let data = self.mmap.read(pc)?;
+
+		if self.registers.halt_bug {
+			// The halt bug prevents the program counter from being incremented once.
+			self.registers.halt_bug = false;
+		} else {
+			// Move the PC forward.
+			self.registers.set(Register::PC, pc.wrapping_add(1));
+		}
+
+		Ok(data)
+	}
+
+	/// Reads the next 2 instruction bytes, see `fetch_u8`.
 	///
-	/// The function works in little-endian, that is, when reading 2 bytes,
-	/// the first byte will be the least-significant one.
-	pub fn fetch<T: PrimInt + AddAssign + Shl<Output=T>>(&mut self) -> Result<T, GameboyError> {
-		let mut result: T = num::cast(0).unwrap();
-
-		for i in 0..size_of::<T>() {
-			// Read the next byte.
-			let pc: u16 = self.registers.get(Register::PC);
-			let data: T = num::cast::<u8, T>(self.mmap.read(pc)?).unwrap();
-
-			// We're using little-endianity.
-			result += data << num::cast::<usize, T>(8 * i).unwrap();
-
-			if self.halt_bug {
-				// The halt bug prevents the program counter from being incremented once.
-				self.halt_bug = false;
-			} else {
-				// Move the PC forward.
-				self.registers.set(Register::PC, pc + 1);
-			}
+	/// The function works in little-endian, that is, the first byte will be
+	/// the least-significant one.
+	pub fn fetch_u16(&mut self) -> Result<u16, GameboyError> {
+		let low = self.fetch_u8()?;
+		let high = self.fetch_u8()?;
+
+		Ok(u16::from_le_bytes([low, high]))
+	}
+
+	/// Returns the number of bytes taken by the instruction at the given address,
+	/// without executing it, see `disassemble::instruction_length`.
+	pub fn instruction_length_at(&self, address: u16) -> Result<u16, GameboyError> {
+		disassemble::instruction_length(self, address)
+	}
+
+	/// Fills the buffer with the memory around the given address, for inspecting
+	/// the code that led to an error such as `GameboyError::BadOpcode`.
+	///
+	/// The address is placed at the middle of the buffer, and the address of the
+	/// buffer's first byte is returned. The memory is read without side effects,
+	/// see `SystemBus::peek`, and the addresses wrap around the address space.
+	pub fn surrounding_bytes(&self, address: u16, buffer: &mut [u8]) -> Result<u16, GameboyError> {
+		let start = address.wrapping_sub((buffer.len() / 2) as u16);
+
+		for (offset, value) in buffer.iter_mut().enumerate() {
+			*value = self.mmap.peek(start.wrapping_add(offset as u16))?;
 		}
 
-		Ok(result)
+		Ok(start)
 	}
 
 	/// Writes the display's data to the given frame buffer.
+	///
+	/// The SGB model colorizes the display, see `Sgb::colorize`.
 	pub fn flush(&mut self, frame_buffer: &mut [u32]) {
-		self.mmap.ppu.flush(frame_buffer);
+		if self.config.model == HardwareModel::SGB {
+			self.mmap.sgb.colorize(self.mmap.ppu.screen(), frame_buffer);
+		} else {
+			self.mmap.ppu.flush(frame_buffer);
+		}
+	}
+
+	/// Writes the display's data inside the Super GameBoy's border to the
+	/// given frame buffer, see `Sgb::render_border`.
+	///
+	/// The border is only available when emulating the SGB model.
+	pub fn flush_border(&mut self, frame_buffer: &mut [u32]) -> Result<(), GameboyError> {
+		if self.config.model != HardwareModel::SGB {
+			return Err(GameboyError::Io("The border is only displayed by the SGB model."));
+		}
+
+		self.mmap.sgb.render_border(self.mmap.ppu.screen(), frame_buffer)
+	}
+
+	/// Draws the display's data to the given target, see `Ppu::draw`.
+	#[cfg(feature = "embedded-graphics")]
+	pub fn draw<D>(&self, target: &mut D, scale: u32) -> Result<(), D::Error>
+	where
+		D: DrawTarget,
+		D::Color: From<Rgb888>,
+	{
+		self.mmap.ppu.draw(target, scale)
 	}
 
 	/// Emulates the execution of a single instruction.
-	///	This function also processes the peripherals and enters interrupts if any.
+	/// This function also processes the peripherals and enters interrupts if any.
 	///
 	/// Returns the number of clock cycles the instruction has taken.
 	pub fn execute(&mut self) -> Result<usize, GameboyError> {
+		let num_cycles = self.step()?;
+		self.total_cycles += num_cycles as u64;
+
+		Ok(num_cycles)
+	}
+
+	/// Executes a single instruction, see `execute`.
+	fn step(&mut self) -> Result<usize, GameboyError> {
+		// The cpu is paused while a DMA transfer is in progress.
+		if self.mmap.stall_cycles > 0 {
+			let num_cycles = self.mmap.stall_cycles.min(4);
+			self.mmap.stall_cycles -= num_cycles;
+			self.mmap.process(num_cycles)?;
+
+			return Ok(num_cycles);
+		}
+
+		// A locked cpu never resumes, but the peripherals keep running.
+		if self.locked {
+			self.mmap.process(4)?;
+
+			return Ok(4);
+		}
+
+		// Nothing is clocked in the low-power mode, until a key is pressed.
+		if self.stopped {
+			if self.mmap.joypad.pressed() == 0 {
+				return Ok(4);
+			}
+			self.stopped = false;
+		}
+
 		// Enter an interrupt if any (and if interrupts are enabled).
 		let mut num_cycles = self.handle_interrupts()?;
 
-		if !self.halting {
+		// Interrupts enabled by EI are only enabled after the next instruction,
+		// so a halt still sees them disabled, and a DI still cancels them.
+		let enable_ime = self.registers.ime_delay;
+
+		if !self.registers.halting {
 			num_cycles += self.execute_single()?;
 		} else {
 			num_cycles += 4;
 		}
 
-		// Enable interrupts if needed
-		if self.ime_delay {
+		if enable_ime && self.registers.ime_delay {
+			self.registers.ime_delay = false;
 			self.registers.set_ime(true);
 		}
 
+		self.check_stack_pointer();
+
 		// Progress the peripherals.
-		self.mmap.process(num_cycles);
+		self.mmap.process(num_cycles)?;
 
 		Ok(num_cycles)
 	}
 
+	/// Warns once the stack pointer leaves the configured stack range.
+	fn check_stack_pointer(&mut self) {
+		let range = match self.config.stack_check {
+			Some(range) => range,
+			None => { return; }
+		};
+
+		let sp = self.registers.get(Register::SP);
+		let out_of_range = sp < range.low || sp > range.high;
+
+		// Warn only when leaving the range, rather than on every instruction.
+		if out_of_range && !self.stack_out_of_range {
+			self.mmap.warnings.push(Warning::StackOutOfRange(sp));
+		}
+		self.stack_out_of_range = out_of_range;
+	}
+
+	/// Warns if the stack is accessed at the rom or I/O registers.
+	pub(crate) fn check_stack_access(&self, address: u16) {
+		if self.config.stack_check.is_none() {
+			return;
+		}
+
+		if SystemBus::is_rom_or_io(address) {
+			self.mmap.warnings.push(Warning::StackInvalidAccess(address));
+		}
+	}
+
 	/// Emulates the execution of a single instruction.
 	///
 	/// Returns the number of clock cycles the instruction has taken.
 	pub fn execute_single(&mut self) -> Result<usize, GameboyError> {
-		let _address: u16 = self.registers.get(Register::PC);
+		let address: u16 = self.registers.get(Register::PC);
 
 		// Fetch the opcode from the memory.
-		let opcode: u8 = self.fetch()?;
+		let opcode: u8 = self.fetch_u8()?;
 
 		// TODO remove this!
 		#[cfg(feature = "debug")]
 		{
-			println!("0x{:04x}: ({:02x}) {}", _address, opcode, disassemble::disassemble(self, _address)?);
+			println!("0x{:04x}: ({:02x}) {}", address, opcode, disassemble::disassemble(self, address)?);
 			if opcode == 0xcd {
-				println!("Branch target: {:02x} {:02x}", self.mmap.read(_address + 1)?, self.mmap.read(_address + 2)?);
+				println!("Branch target: {:04x}", self.mmap.read16(address.wrapping_add(1))?);
 			}
 		}
 
 		// Decode the given opcode.
-		let insn: Instruction = self.decode(opcode)?;
+		let insn: Instruction = match self.decode(opcode) {
+			Ok(insn) => insn,
+			Err(GameboyError::BadOpcode { opcode, .. }) => {
+				// The halt bug may keep the program counter in place, so report
+				// the address that was recorded before the fetch.
+				return match self.config.illegal_opcode {
+					IllegalOpcodePolicy::Error if self.config.lenient => {
+						self.mmap.warnings.push(Warning::IllegalOpcode(address));
+						Ok(4)
+					}
+					IllegalOpcodePolicy::Error => Err(GameboyError::BadOpcode { opcode, address }),
+					IllegalOpcodePolicy::Lock => {
+						self.locked = true;
+						Ok(4)
+					}
+					IllegalOpcodePolicy::Nop => Ok(4),
+				};
+			}
+			Err(error) => { return Err(error); }
+		};
 
 		// Execute and return the number of cycles taken.
-		Ok(insn(self)?)
+		insn(self)
 	}
 
 	fn handle_interrupts(&mut self) -> Result<usize, GameboyError> {
@@ -173,36 +446,64 @@ impl<'a> Cpu<'a> {
 			// Stop halting if there's any active interrupt.
 			// We wake the cpu in a case of an interrupt, but we won't
 			// enter the ISR if interrupts are disabled.
-			if self.halting && self.mmap.interrupt_flag != 0 {
-				self.halting = false;
+			if self.registers.halting && !self.mmap.pending_interrupts().is_empty() {
+				self.registers.halting = false;
 			}
 			return Ok(0);
 		}
 
-		if let Some(interrupt) = self.mmap.fetch_interrupt() {
-			// Stop halting (if relevant) and enter the ISR.
-			self.halting = false;
-
-			let isr = match interrupt {
-				Interrupt::VerticalBlank => 0x0040,
-				Interrupt::LcdStat => 0x0048,
-				Interrupt::Timer => 0x0050,
-				Interrupt::Serial => 0x0058,
-				Interrupt::Joypad => 0x0060,
-			};
+		// The interrupt that's entered is only selected during the dispatch.
+		if !self.mmap.pending_interrupts().is_empty() {
+			// Waking up from halting delays the ISR by another cycle.
+			let wake_cycles = if self.registers.halting { HALT_EXIT_CYCLES } else { 0 };
+			self.registers.halting = false;
 
-			return Ok(enter_interrupt(self, isr)?);
+			return Ok(enter_interrupt(self)? + wake_cycles);
 		}
 
 		Ok(0)
 	}
 }
 
+#[cfg(feature = "alloc")]
+impl<'a> SaveState for Cpu<'a> {
+	fn save_state(&self, writer: &mut StateWriter) {
+		self.registers.save_state(writer);
+		let registers = &self.registers;
+		for flag in [registers.halting, registers.halt_bug, self.stopped, self.locked, registers.ime_delay, self.stack_out_of_range] {
+			writer.bool(flag);
+		}
+		self.mmap.save_state(writer);
+		writer.u64(self.total_cycles);
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		self.registers.load_state(reader)?;
+		let registers = &mut self.registers;
+		for flag in [&mut registers.halting, &mut registers.halt_bug, &mut self.stopped, &mut self.locked,
+			&mut registers.ime_delay, &mut self.stack_out_of_range] {
+			*flag = reader.bool()?;
+		}
+		self.mmap.load_state(reader)?;
+		self.total_cycles = reader.u64()?;
+
+		Ok(())
+	}
+}
+
+/// Cpu test utilities.
 #[cfg(test)]
 #[cfg(feature = "alloc")]
 pub mod tests {
 	use super::*;
+	use crate::config::StackRange;
+	use crate::bus::joypad::Key;
 	use alloc::boxed::Box;
+	use alloc::vec::Vec;
+	use super::timing::INTERRUPT_CYCLES;
+	use super::interrupts::{Interrupt, InterruptMask};
+	use crate::bus::memory_range::MemoryRange;
+	use crate::bus::consts::MMAP_RAM_INTERNAL;
 
 	/// With-closure for running logic with an initialized cpu instance.
 	pub fn with_cpu<F>(callback: F) -> Result<(), GameboyError>
@@ -211,13 +512,47 @@ pub mod tests {
 		let config = Config::default();
 		let mut rom = cartridge::tests::empty_rom(CartridgeType::MBC3);
 		let mut ram: Box<[u8]> = Cartridge::make_ram(&rom)?;
-		let mut cartridge = Cartridge::new(&mut rom, &mut ram)?;
+		let cartridge = Cartridge::new(&mut rom, &mut ram)?;
 
-		let mut cpu = Cpu::new(&config, &mut cartridge);
+		let mut cpu = Cpu::new(config, cartridge);
 
 		callback(&mut cpu)
 	}
 
+	/// A single address that was changed, see `memory_diff`.
+	#[derive(Clone, Copy, Debug, PartialEq)]
+	pub struct MemoryChange {
+		/// The changed address.
+		pub address: u16,
+		/// The address' value before running the closure.
+		pub before: u8,
+		/// The address' value after running the closure.
+		pub after: u8,
+	}
+
+	/// Runs the closure on the cpu, and returns the addresses in the given
+	/// memory range that it changed, in ascending order.
+	///
+	/// The memory is compared using `SystemBus::dump_range`.
+	pub fn memory_diff<F>(cpu: &mut Cpu, range: MemoryRange, callback: F) -> Result<Vec<MemoryChange>, GameboyError>
+		where F: FnOnce(&mut Cpu) -> Result<(), GameboyError> {
+		let start = (range >> 16) as u16;
+		let size = (range & 0xFFFF) as usize + 1 - start as usize;
+
+		let mut before = vec![0; size];
+		cpu.mmap.dump_range(range, &mut before)?;
+
+		callback(cpu)?;
+
+		let mut after = vec![0; size];
+		cpu.mmap.dump_range(range, &mut after)?;
+
+		Ok(before.iter().zip(after.iter()).enumerate()
+			.filter(|(_, (before, after))| before != after)
+			.map(|(offset, (&before, &after))| MemoryChange { address: start + offset as u16, before, after })
+			.collect())
+	}
+
 	#[test]
 	fn test_fetch() -> Result<(), GameboyError> {
 		with_cpu(|cpu| {
@@ -230,8 +565,361 @@ pub mod tests {
 			cpu.mmap.write_all(cpu.registers.get(Register::PC), data)?;
 
 			// Make sure that fetch works as expected.
-			assert!(cpu.fetch::<u16>()? == 0x0201);
-			assert!(cpu.fetch::<u8>()? == 0x03);
+			assert!(cpu.fetch_u16()? == 0x0201);
+			assert!(cpu.fetch_u8()? == 0x03);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_memory_diff() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::SP, 0xD000);
+			cpu.registers.set(Register::BC, 0x1234);
+			cpu.registers.set(Register::HL, 0xC000);
+			cpu.registers.set(Register::A, 0x56);
+
+			// push BC; ld (HL), A
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0xc5, 0x77])?;
+
+			let changes = memory_diff(cpu, MMAP_RAM_INTERNAL, |cpu| {
+				cpu.execute()?;
+				cpu.execute()?;
+				Ok(())
+			})?;
+
+			assert!(changes == [
+				MemoryChange { address: 0xC000, before: 0, after: 0x56 },
+				MemoryChange { address: 0xCFFE, before: 0, after: 0x34 },
+				MemoryChange { address: 0xCFFF, before: 0, after: 0x12 },
+			]);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_stack_check() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.config.stack_check = Some(StackRange { low: 0xD000, high: 0xD002 });
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::SP, 0xD002);
+
+			// push BC; push BC; pop BC; pop BC
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0xc5, 0xc5, 0xc1, 0xc1])?;
+
+			cpu.execute()?;
+			assert!(cpu.warnings().is_empty());
+
+			// Leaving the range is reported once.
+			cpu.execute()?;
+			assert!(cpu.warnings().pop() == Some(Warning::StackOutOfRange(0xCFFE)));
+			cpu.execute()?;
+			assert!(cpu.warnings().is_empty());
+
+			// Popping from the rom.
+			cpu.registers.set(Register::SP, 0x0100);
+			cpu.execute()?;
+			assert!(cpu.warnings().pop() == Some(Warning::StackInvalidAccess(0x0100)));
+			assert!(cpu.warnings().pop() == Some(Warning::StackInvalidAccess(0x0101)));
+			assert!(cpu.warnings().pop() == Some(Warning::StackOutOfRange(0x0102)));
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_reti() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::SP, 0xCFFE);
+			cpu.registers.set_ime(false);
+
+			// reti, returning to 0xA010.
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0xd9])?;
+			cpu.mmap.write_all(0xCFFE, &[0x10, 0xA0])?;
+
+			cpu.mmap.interrupts.set_enable(Interrupt::Timer.into());
+			cpu.mmap.interrupts.set_flag(Interrupt::Timer.into());
+
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::PC) == 0xA010);
+			assert!(cpu.registers.get(Register::SP) == 0xD000);
+			assert!(cpu.registers.ime());
+
+			// Interrupts are enabled immediately, so the pending one is entered.
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::SP) == 0xCFFE);
+			assert!(cpu.mmap.read(0xCFFE)? == 0x10 && cpu.mmap.read(0xCFFF)? == 0xA0);
+			assert!(!cpu.registers.ime());
+			assert!(cpu.mmap.interrupts.flag().is_empty());
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_ei_delay() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::SP, 0xD000);
+			cpu.registers.set_ime(false);
+
+			// ei; di; ei; nop; nop
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0xfb, 0xf3, 0xfb, 0x00, 0x00])?;
+
+			cpu.mmap.interrupts.set_enable(Interrupt::Timer.into());
+			cpu.mmap.interrupts.set_flag(Interrupt::Timer.into());
+
+			// Disabling interrupts right after enabling them cancels the enable.
+			cpu.execute()?;
+			cpu.execute()?;
+			assert!(!cpu.registers.ime());
+
+			// The instruction after EI is executed before entering the interrupt.
+			cpu.execute()?;
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::PC) == 0xA004);
+			assert!(cpu.registers.ime());
+
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::SP) == 0xCFFE);
+			assert!(cpu.mmap.read(0xCFFE)? == 0x04 && cpu.mmap.read(0xCFFF)? == 0xA0);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_stop() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+
+			// stop; nop
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0x10, 0x00, 0x00])?;
+
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::PC) == 0xA002);
+			assert!(cpu.stopped());
+
+			// Nothing is clocked while stopped, and the divider was reset.
+			for _ in 0..16 {
+				assert!(cpu.execute()? == 4);
+			}
+			assert!(cpu.registers.get(Register::PC) == 0xA002);
+			cpu.mmap.sync()?;
+			assert!(cpu.mmap.timer().div_counter() == 4);
+
+			// A key press resumes the execution.
+			cpu.with_controller(|controller| controller.down(Key::Start));
+			cpu.execute()?;
+			assert!(!cpu.stopped());
+			assert!(cpu.registers.get(Register::PC) == 0xA003);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_halt() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::SP, 0xD000);
+			cpu.registers.set_ime(false);
+			cpu.mmap.interrupts.set_enable(Interrupt::Timer.into());
+
+			// halt; inc A; halt; inc A
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0x76, 0x3c, 0x76, 0x3c])?;
+
+			// Without a pending interrupt, the cpu halts until there's one.
+			cpu.execute()?;
+			assert!(cpu.halted());
+			assert!(cpu.execute()? == 4);
+			assert!(cpu.registers.get(Register::PC) == 0xA001);
+
+			// The interrupts are disabled, so the execution resumes after the halt.
+			cpu.mmap.interrupts.set_flag(Interrupt::Timer.into());
+			cpu.registers.set(Register::A, 0);
+			cpu.execute()?;
+			assert!(!cpu.halted());
+			assert!(cpu.registers.get(Register::PC) == 0xA002);
+			assert!(cpu.registers.get(Register::A) == 1);
+
+			// With a pending interrupt, the cpu doesn't halt, and the byte after
+			// the halt is read twice.
+			cpu.execute()?;
+			assert!(!cpu.halted());
+			cpu.execute()?;
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::PC) == 0xA004);
+			assert!(cpu.registers.get(Register::A) == 3);
+
+			// With the interrupts enabled, waking up delays entering the interrupt.
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set_ime(true);
+			cpu.mmap.interrupts.set_flag(InterruptMask::empty());
+			cpu.execute()?;
+			assert!(cpu.halted());
+
+			cpu.mmap.interrupts.set_flag(Interrupt::Timer.into());
+			// The interrupt's first instruction is a nop.
+			assert!(cpu.execute()? == INTERRUPT_CYCLES + HALT_EXIT_CYCLES + 4);
+			assert!(cpu.registers.get(Register::PC) == 0x0051);
+			assert!(cpu.mmap.read16(0xCFFE)? == 0xA001);
+
+			// When the interrupts are enabled right before the halt, they're
+			// still disabled when an interrupt is already pending, so the halt
+			// bug triggers, and the interrupt returns to the halt.
+			// ei; halt
+			cpu.mmap.write_all(0xA010, &[0xfb, 0x76])?;
+			cpu.registers.set(Register::PC, 0xA010);
+			cpu.mmap.interrupts.set_flag(Interrupt::Timer.into());
+			cpu.execute()?;
+			cpu.execute()?;
+			assert!(!cpu.halted());
+			assert!(cpu.execute()? == INTERRUPT_CYCLES + 4);
+			assert!(cpu.mmap.read16(0xCFFC)? == 0xA011);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_interrupt_dispatch() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			// Pushing the program counter's high byte overwrites IE, which
+			// disables the pending interrupt and cancels the dispatch.
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.registers.set(Register::SP, 0x0000);
+			cpu.registers.set_ime(true);
+			cpu.mmap.interrupts.set_enable(Interrupt::Timer.into());
+			cpu.mmap.interrupts.set_flag(Interrupt::Timer.into());
+
+			cpu.execute()?;
+			assert!(cpu.mmap.interrupts.enable().bits() == 0xA0);
+			assert!(cpu.registers.get(Register::PC) == 0x0001);
+			assert!(cpu.registers.get(Register::SP) == 0xFFFE);
+			assert!(!cpu.registers.ime());
+
+			// The interrupt is selected by the overwritten IE.
+			cpu.registers.set(Register::PC, 0x0200);
+			cpu.registers.set(Register::SP, 0x0000);
+			cpu.registers.set_ime(true);
+			cpu.mmap.interrupts.set_enable([Interrupt::VerticalBlank, Interrupt::Timer].iter().copied().collect());
+			cpu.mmap.interrupts.set_flag([Interrupt::VerticalBlank, Interrupt::LcdStat].iter().copied().collect());
+
+			cpu.execute()?;
+			assert!(cpu.mmap.interrupts.enable() == InterruptMask::from(Interrupt::LcdStat));
+			assert!(cpu.registers.get(Register::PC) == 0x0049);
+			assert!(cpu.mmap.read16(0xFFFE)? == 0x0200);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_import_state() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			// Seed a halted cpu, with a pending halt bug.
+			let mut state = CpuState::new(cpu.config);
+			state.set(Register::PC, 0xA000);
+			state.set(Register::A, 0x42);
+			state.halting = true;
+			state.halt_bug = true;
+			let data = state.to_bytes();
+
+			cpu.import_state(&CpuState::from_bytes(cpu.config, &data)?);
+			assert!(cpu.halted() && cpu.registers.get(Register::A) == 0x42);
+			assert!(cpu.export_state().to_bytes() == data);
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_illegal_opcode() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0xd3, 0x00])?;
+
+			cpu.registers.set(Register::PC, 0xA000);
+			match cpu.execute() {
+				Err(GameboyError::BadOpcode { opcode, address }) => {
+					assert!(opcode == 0xd3 && address == 0xA000);
+				}
+				_ => panic!("The illegal opcode wasn't reported"),
+			}
+
+			// The opcode is placed at the middle, after the end of the video ram.
+			let mut bytes = [0xaa; 5];
+			assert!(cpu.surrounding_bytes(0xA000, &mut bytes)? == 0x9FFE);
+			assert!(bytes[2..] == [0xd3, 0x00, 0x00]);
+			assert!(bytes[..2] == [cpu.mmap.peek(0x9FFE)?, cpu.mmap.peek(0x9FFF)?]);
+
+			cpu.config.illegal_opcode = IllegalOpcodePolicy::Nop;
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.execute()?;
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::PC) == 0xA002);
+
+			// The lenient mode skips the opcode, and reports it.
+			cpu.config.illegal_opcode = IllegalOpcodePolicy::Error;
+			cpu.config.lenient = true;
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.execute()?;
+			assert!(cpu.registers.get(Register::PC) == 0xA001);
+			assert!(cpu.warnings().pop() == Some(Warning::IllegalOpcode(0xA000)));
+			cpu.config.lenient = false;
+
+			// A locked cpu doesn't execute anything, but the time still passes.
+			cpu.config.illegal_opcode = IllegalOpcodePolicy::Lock;
+			cpu.registers.set(Register::PC, 0xA000);
+			cpu.execute()?;
+			assert!(cpu.locked());
+
+			cpu.mmap.sync()?;
+			let div = cpu.mmap.timer().div_counter();
+			for _ in 0..4 {
+				assert!(cpu.execute()? == 4);
+			}
+			assert!(cpu.registers.get(Register::PC) == 0xA001);
+			cpu.mmap.sync()?;
+			assert!(cpu.mmap.timer().div_counter() == div.wrapping_add(16));
+
+			Ok(())
+		})
+	}
+
+	#[test]
+	fn test_reset() -> Result<(), GameboyError> {
+		with_cpu(|cpu| {
+			cpu.registers.set(Register::PC, 0xA000);
+
+			// stop; nop
+			cpu.mmap.cartridge.set_ram_enabled(true);
+			cpu.mmap.write_all(0xA000, &[0x10, 0x00])?;
+			cpu.mmap.write(0xC000, 0x42)?;
+			cpu.mmap.write(0xFFFF, 0x1F)?;
+			cpu.execute()?;
+			assert!(cpu.stopped());
+
+			// The machine starts over at the boot rom's exit state.
+			cpu.reset();
+			assert!(!cpu.stopped());
+			assert!(cpu.registers.get(Register::PC) == 0x0100);
+			assert!(cpu.mmap.interrupts.enable().is_empty());
+
+			// The cartridge's ram is disabled again, while the memory is kept.
+			assert!(cpu.mmap.read(0xA000).is_err());
+			assert!(cpu.mmap.read(0xC000)? == 0x42);
 
 			Ok(())
 		})
@@ -3,8 +3,11 @@
 
 //! Gameboy's processor state.
 
+use crate::GameboyError;
 use crate::config::{Config, HardwareModel};
 use registers::*;
+#[cfg(feature = "alloc")]
+use crate::savestate::{SaveState, StateWriter, StateReader};
 
 #[allow(missing_docs)]
 pub mod registers {
@@ -88,19 +91,34 @@ pub mod registers {
 	}
 }
 
+/// The size of a serialized cpu state, see `CpuState::to_bytes`.
+pub const CPU_STATE_SIZE: usize = 2 * NUM_REGS + 3;
+
 /// Structure holding the current processor state.
 #[derive(Clone)]
-pub struct CpuState<'a> {
+pub struct CpuState {
 	regs: RegisterFile,
-	config: &'a Config,
+	config: Config,
+	/// Whether the processor is currently halting and waiting for an external interrupt
+	/// in order to resume.
+	pub(crate) halting: bool,
+	/// If we halt the cpu when interrupts are disabled, the original cpu had a bug
+	/// in which it fetches the byte after the halt twice.
+	pub(crate) halt_bug: bool,
+	/// The processor has a delay of a single instruction after EI before actually
+	/// enabling interrupts.
+	pub(crate) ime_delay: bool,
 }
 
-impl<'a> CpuState<'a> {
+impl CpuState {
 	/// Initializes a new cpu state
-	pub fn new(config: &'a Config) -> Self {
-		let mut state: CpuState<'a> = CpuState {
+	pub fn new(config: Config) -> Self {
+		let mut state: CpuState = CpuState {
 			regs: [0; NUM_REGS],
-			config
+			config,
+			halting: false,
+			halt_bug: false,
+			ime_delay: false,
 		};
 
 		// Reset the registers.
@@ -118,6 +136,7 @@ impl<'a> CpuState<'a> {
 		self.set(Register::SP, 0xFFFE);
 		self.set(Register::PC, 0x0100);
 		self.set(Register::IME, 0x00);
+		self.clear_flags();
 
 		match self.config.model {
 			HardwareModel::GB | HardwareModel::SGB => {
@@ -132,11 +151,62 @@ impl<'a> CpuState<'a> {
 		}
 	}
 
+	/// Clear the registers to their power-on state, before the boot rom
+	/// has been executed.
+	pub fn power_on(&mut self) {
+		self.regs = [0; NUM_REGS];
+		self.clear_flags();
+	}
+
+	fn clear_flags(&mut self) {
+		self.halting = false;
+		self.halt_bug = false;
+		self.ime_delay = false;
+	}
+
+	/// Serializes the registers, followed by the halting, the halt bug and the
+	/// delayed EI flags.
+	///
+	/// Unlike savestates, the state is independent of the rest of the machine,
+	/// so it can be compared with other emulators' or used to seed tests.
+	pub fn to_bytes(&self) -> [u8; CPU_STATE_SIZE] {
+		let mut data = [0; CPU_STATE_SIZE];
+
+		for (index, reg) in self.regs.iter().enumerate() {
+			data[index * 2..index * 2 + 2].copy_from_slice(&reg.to_le_bytes());
+		}
+		data[2 * NUM_REGS] = self.halting as u8;
+		data[2 * NUM_REGS + 1] = self.halt_bug as u8;
+		data[2 * NUM_REGS + 2] = self.ime_delay as u8;
+
+		data
+	}
+
+	/// Deserializes a state that was serialized with `to_bytes`, where the
+	/// configuration determines the registers' reset state.
+	pub fn from_bytes(config: Config, data: &[u8; CPU_STATE_SIZE]) -> Result<Self, GameboyError> {
+		let mut state = CpuState::new(config);
+
+		for (index, reg) in state.regs.iter_mut().enumerate() {
+			*reg = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+		}
+
+		let flags = &data[2 * NUM_REGS..];
+		if flags.iter().any(|&flag| flag > 1) {
+			return Err(GameboyError::Io("The cpu state holds an invalid flag."));
+		}
+		state.halting = flags[0] != 0;
+		state.halt_bug = flags[1] != 0;
+		state.ime_delay = flags[2] != 0;
+
+		Ok(state)
+	}
+
 	/// Writes a value to a given register.
 	///
 	/// * `reg` - The register file identifier to write into.
 	/// * `value` - The value to write. In cases of 8-bit register,
-	///     the higher 8 bits will be discarded.
+	///   the higher 8 bits will be discarded.
 	pub fn set(&mut self, reg: Register, value: u16) {
 		let reg_type: RegisterType = get_type(&reg);
 		let reg: &mut u16 = &mut self.regs[get_index(&reg)];
@@ -196,14 +266,30 @@ impl<'a> CpuState<'a> {
 	}
 }
 
+#[cfg(feature = "alloc")]
+impl SaveState for CpuState {
+	fn save_state(&self, writer: &mut StateWriter) {
+		for reg in self.regs.iter() {
+			writer.u16(*reg);
+		}
+	}
+
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError> {
+		for reg in self.regs.iter_mut() {
+			*reg = reader.u16()?;
+		}
+
+		Ok(())
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 
 	#[test]
 	fn test_registers_rw() {
-		let cfg: &Config = &Config::default();
-		let mut cpu: CpuState = CpuState::new(&cfg);
+		let mut cpu: CpuState = CpuState::new(Config::default());
 
 		assert_eq!(0x0013, cpu.get(Register::BC));
 
@@ -219,9 +305,30 @@ mod tests {
 	}
 
 	#[test]
+	fn test_serialization() -> Result<(), GameboyError> {
+		let mut cpu: CpuState = CpuState::new(Config::default());
+		cpu.set(Register::HL, 0xC123);
+		cpu.set_ime(true);
+		cpu.halting = true;
+
+		let data = cpu.to_bytes();
+		assert!(data[6..8] == [0x23, 0xC1] && data[12..] == [1, 0, 1, 0, 0]);
+
+		let state = CpuState::from_bytes(Config::default(), &data)?;
+		assert!(state.to_bytes() == data);
+		assert!(state.get(Register::HL) == 0xC123 && state.ime() && state.halting);
+
+		let mut invalid = data;
+		invalid[CPU_STATE_SIZE - 1] = 2;
+		assert!(CpuState::from_bytes(Config::default(), &invalid).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	#[allow(clippy::bool_assert_comparison)]
 	fn test_cpu_flags() {
-		let cfg: &Config = &Config::default();
-		let mut cpu: CpuState = CpuState::new(&cfg);
+		let mut cpu: CpuState = CpuState::new(Config::default());
 
 		cpu.set(Register::F, 0b10010000);
 		//                    ^ZNHC
@@ -0,0 +1,155 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Instruction timing metadata.
+//!
+//! The core counts time in T-cycles, the cpu's 4.194304MHz clock.
+//! Every instruction takes a multiple of 4 T-cycles (a single M-cycle),
+//! and the peripherals are processed by the same amount of T-cycles.
+//!
+//! The helpers at the bottom convert T-cycles to wall-clock time, given the
+//! clock rate of the emulated model, see `clock_rate`.
+
+use core::time::Duration;
+
+use crate::config::HardwareModel;
+use crate::bus::ppu::consts::CYCLES_PER_FRAME;
+
+/// The cpu's clock rate in T-cycles per second.
+pub const CLOCK_RATE: u32 = 4_194_304;
+
+/// The Super GameBoy's clock rate, which is derived from the SNES's clock
+/// and runs about 2.4% faster.
+pub const SGB_CLOCK_RATE: u32 = 4_295_454;
+
+/// The display's refresh rate in frames per second, about 59.7275Hz.
+pub const FRAME_RATE: f64 = CLOCK_RATE as f64 / CYCLES_PER_FRAME as f64;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// The number of T-cycles taken by each opcode, when no branch is taken.
+///
+/// Opcodes that don't exist on the cpu are marked with 0. The 0xCB prefix
+/// is marked with the time it takes to fetch it, `cb_opcode_cycles`
+/// already includes it.
+pub const OPCODE_CYCLES: [u8; 0x100] = [
+	//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+	    4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x00
+	    4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 0x10
+	    8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 0x20
+	    8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 0x30
+	    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x40
+	    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x50
+	    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x60
+	    8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 0x70
+	    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x80
+	    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0x90
+	    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0xa0
+	    4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 0xb0
+	    8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  4, 12, 24,  8, 16, // 0xc0
+	    8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16, // 0xd0
+	   12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // 0xe0
+	   12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // 0xf0
+];
+
+/// The number of T-cycles it takes to dispatch an interrupt.
+pub const INTERRUPT_CYCLES: usize = 20;
+
+/// The number of T-cycles an interrupt's dispatch is delayed by, when it
+/// wakes the cpu up from halting.
+pub const HALT_EXIT_CYCLES: usize = 4;
+
+/// Returns the number of T-cycles taken by a conditional opcode when its
+/// branch is taken, or `None` for opcodes that don't branch conditionally.
+pub const fn branch_taken_cycles(opcode: u8) -> Option<usize> {
+	match opcode {
+		// jr cc, n
+		0x20 | 0x28 | 0x30 | 0x38 => Some(12),
+		// ret cc
+		0xc0 | 0xc8 | 0xd0 | 0xd8 => Some(20),
+		// jp cc, nn
+		0xc2 | 0xca | 0xd2 | 0xda => Some(16),
+		// call cc, nn
+		0xc4 | 0xcc | 0xd4 | 0xdc => Some(24),
+		_ => None,
+	}
+}
+
+/// Returns the number of T-cycles taken by a 0xCB-prefixed opcode,
+/// including the prefix itself.
+pub const fn cb_opcode_cycles(opcode: u8) -> usize {
+	// Operations on registers are the same length.
+	if opcode & 0x07 != 0x06 {
+		return 8;
+	}
+
+	// BIT only reads (HL), the rest of the operations also write it back.
+	match opcode {
+		0x40..=0x7f => 12,
+		_ => 16,
+	}
+}
+
+/// Returns the number of T-cycles taken by the given opcode.
+///
+/// For 0xCB-prefixed opcodes, `opcode` is the byte following the prefix.
+pub fn opcode_cycles(opcode: u8, prefixed: bool, branch_taken: bool) -> usize {
+	if prefixed {
+		return cb_opcode_cycles(opcode);
+	}
+
+	match branch_taken_cycles(opcode) {
+		Some(cycles) if branch_taken => cycles,
+		_ => OPCODE_CYCLES[opcode as usize] as usize,
+	}
+}
+
+/// Returns the number of T-cycles the cpu executes per second.
+///
+/// In double speed mode (GBC), the cpu executes twice as many T-cycles,
+/// while the display's timing stays the same.
+pub const fn clock_rate(model: HardwareModel, double_speed: bool) -> u32 {
+	let rate = match model {
+		HardwareModel::SGB => SGB_CLOCK_RATE,
+		_ => CLOCK_RATE,
+	};
+
+	if double_speed { rate * 2 } else { rate }
+}
+
+/// Returns the wall-clock time it takes to execute the given number of
+/// T-cycles, at the given clock rate, rounded up to the nanosecond.
+pub fn cycles_to_duration(cycles: usize, clock_rate: u32) -> Duration {
+	let nanos = (cycles as u128 * NANOS_PER_SECOND).div_ceil(clock_rate as u128);
+
+	Duration::new((nanos / NANOS_PER_SECOND) as u64, (nanos % NANOS_PER_SECOND) as u32)
+}
+
+/// Returns the number of T-cycles executed within the given wall-clock time,
+/// at the given clock rate, rounded down.
+pub fn duration_to_cycles(duration: Duration, clock_rate: u32) -> usize {
+	(duration.as_nanos() * clock_rate as u128 / NANOS_PER_SECOND) as usize
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_time_conversion() {
+		let rate = clock_rate(HardwareModel::GB, false);
+		assert!(cycles_to_duration(CLOCK_RATE as usize, rate) == Duration::from_secs(1));
+		assert!(duration_to_cycles(Duration::from_millis(500), rate) == CLOCK_RATE as usize / 2);
+
+		// A frame takes about 16.74ms.
+		let frame = cycles_to_duration(CYCLES_PER_FRAME, rate);
+		assert!(frame.as_micros() == 16742);
+		assert!(duration_to_cycles(frame, rate) == CYCLES_PER_FRAME);
+
+		// Double speed executes twice as many cycles in the same time.
+		let double = clock_rate(HardwareModel::GBC, true);
+		assert!(cycles_to_duration(2 * CYCLES_PER_FRAME, double) == frame);
+		assert!(clock_rate(HardwareModel::SGB, false) > rate);
+	}
+}
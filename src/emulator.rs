@@ -0,0 +1,1047 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! A self-contained emulator that owns the cartridge's storage.
+//!
+//! Unlike the zero-copy `Cpu` API, the emulator doesn't require the caller
+//! to keep the rom, ram, cartridge and configuration alive separately.
+//!
+//! # Examples
+//! ```
+//! # use gameboy_core::GameboyError;
+//! # use gameboy_core::emulator::Emulator;
+//! # use gameboy_core::config::HardwareModel;
+//! # fn main() -> Result<(), GameboyError> {
+//! # let mut rom = vec![0_u8; 0x8000];
+//! let mut emulator = Emulator::builder()
+//!     .rom(rom)
+//!     .model(HardwareModel::GB)
+//!     .build()?;
+//!
+//! let _cycles = emulator.execute()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+
+use crate::GameboyError;
+use crate::cpu::{Cpu, CpuSnapshot};
+use crate::cpu::timing;
+use crate::savestate;
+use crate::filter;
+use crate::config::{Config, HardwareModel, Accuracy, EchoRamPolicy, IllegalOpcodePolicy, StackRange, RtcSource};
+use crate::warning::Warnings;
+use crate::save::{SaveSink, SavePolicy, SaveInterval};
+use crate::resample::ResampleQuality;
+use crate::bus::joypad::Controller;
+use crate::bus::boot::BootRom;
+use crate::bus::cartridge::{Cartridge, Storage};
+use crate::bus::ppu::{Layers, PpuMode, FrameStats};
+use crate::bus::apu::Channel;
+use crate::bus::apu::log::RegisterWrite;
+use crate::cpu::interrupts::InterruptMask;
+use crate::bus::ppu::consts::{WIDTH, HEIGHT, CYCLES_PER_FRAME};
+#[cfg(feature = "embedded-graphics")]
+use embedded_graphics_core::{draw_target::DrawTarget, pixelcolor::Rgb888};
+
+/// The parameters of the 64-bit FNV-1a hash, used for the frames' digests.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// An emulated machine, owning all of its state.
+pub struct Emulator {
+	/// The cpu is large, so it's kept on the heap.
+	cpu: Box<Cpu<'static>>,
+	last_frame: Option<Box<[u32]>>,
+	/// The previous frame's weight when blending the frames, see `set_ghosting`.
+	ghosting: u8,
+	/// Persists the battery-backed ram according to its policy.
+	autosave: Option<(SavePolicy, Box<dyn SaveSink>)>,
+	/// The host's time the real-time clock was last advanced to, see
+	/// `RtcSource::WallClock`.
+	#[cfg(feature = "std")]
+	pub(crate) rtc_sync: std::time::Instant,
+	/// The clock cycles emulated since the real-time clock was last compared
+	/// with the host's time.
+	#[cfg(feature = "std")]
+	rtc_cycles: usize,
+}
+
+/// A completed frame of the display.
+pub struct Frame {
+	/// The frame's pixels, row by row.
+	pub pixels: Box<[u32]>,
+	/// The number of clock cycles it took to emulate the frame.
+	pub cycles: usize,
+	/// Whether the frame differs from the previously emulated frame.
+	pub dirty: bool,
+}
+
+/// A summary of the machine's state, for status bars and debuggers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MachineStatus {
+	/// Whether the cpu is halting, waiting for an interrupt.
+	pub halted: bool,
+	/// Whether the cpu is in its low-power mode, waiting for a key press.
+	pub stopped: bool,
+	/// Whether the cpu locked up after executing an illegal opcode.
+	pub locked: bool,
+	/// Whether the interrupts are enabled (IME).
+	pub ime: bool,
+	/// The ppu's current mode.
+	pub ppu_mode: PpuMode,
+	/// The line that is currently drawn (LY).
+	pub ly: u8,
+	/// The interrupts that are both requested and enabled.
+	pub pending_interrupts: InterruptMask,
+	/// Whether the cpu runs in double speed mode (GBC).
+	pub double_speed: bool,
+}
+
+/// An iterator over the emulator's frames, see `Emulator::frames`.
+pub struct Frames<'e> {
+	emulator: &'e mut Emulator,
+	failed: bool,
+}
+
+/// Configures and constructs an `Emulator`.
+pub struct EmulatorBuilder {
+	rom: Option<Vec<u8>>,
+	boot_rom: Option<Vec<u8>>,
+	config: Config,
+	autosave: Option<(SavePolicy, Box<dyn SaveSink>)>,
+}
+
+impl Emulator {
+	/// Returns a builder for configuring a new emulator.
+	pub fn builder() -> EmulatorBuilder {
+		EmulatorBuilder::new()
+	}
+
+	/// Returns the emulated cpu.
+	pub fn cpu(&self) -> &Cpu<'static> {
+		&self.cpu
+	}
+
+	/// Returns the emulated cpu for mutation.
+	pub fn cpu_mut(&mut self) -> &mut Cpu<'static> {
+		&mut self.cpu
+	}
+
+	/// Emulates the execution of a single instruction.
+	///
+	/// If the instruction disabled the cartridge's ram after the game changed
+	/// it, the ram is saved right away, see `EmulatorBuilder::save_sink`.
+	/// Once per frame's worth of cycles, the real-time clock catches up with
+	/// the host's time, see `RtcSource::WallClock`.
+	///
+	/// Returns the number of clock cycles the instruction has taken.
+	pub fn execute(&mut self) -> Result<usize, GameboyError> {
+		let cycles = self.cpu.execute()?;
+
+		#[cfg(feature = "std")]
+		{
+			self.rtc_cycles += cycles;
+			if self.rtc_cycles >= CYCLES_PER_FRAME {
+				self.rtc_cycles = 0;
+				self.sync_rtc();
+			}
+		}
+
+		// Games disable the ram once their save is complete.
+		if let Some((policy, sink)) = &mut self.autosave {
+			policy.release(&mut self.cpu.mmap.cartridge, sink.as_mut())?;
+		}
+
+		Ok(cycles)
+	}
+
+	/// Resets the machine, as if it was power cycled without reloading the
+	/// cartridge, see `Cpu::reset`.
+	pub fn reset(&mut self) {
+		self.cpu.reset();
+	}
+
+	/// Emulates the machine until the display completes a frame.
+	///
+	/// While the lcd is powered off, or the cpu is stopped, a frame is
+	/// completed after the time it would have taken to draw one.
+	pub fn run_frame(&mut self) -> Result<Frame, GameboyError> {
+		let cycles = self.emulate_frame()?;
+
+		self.finish_frame(cycles)
+	}
+
+	/// Emulates the given number of frames without pacing, for fast-forwarding.
+	///
+	/// Only every `render_every`-th frame is rendered and passed to the
+	/// closure, while the rest skip the rendering as in headless mode. A
+	/// `render_every` of 0 skips the rendering of all the frames.
+	///
+	/// Returns the number of clock cycles it took to emulate the frames.
+	pub fn run_frames<F>(&mut self, count: usize, render_every: usize, mut on_frame: F) -> Result<usize, GameboyError>
+		where F: FnMut(Frame) {
+		let mut total = 0;
+
+		for index in 1..=count {
+			let render = render_every != 0 && index % render_every == 0;
+			let cycles = self.emulate_frame_rendering(render)?;
+			total += cycles;
+
+			if render {
+				on_frame(self.finish_frame(cycles)?);
+			} else {
+				self.cpu.mmap.sync()?;
+				self.autosave(cycles)?;
+			}
+		}
+
+		Ok(total)
+	}
+
+	/// Emulates a frame, then runs the given number of frames ahead and rolls
+	/// them back, to hide the game's input latency.
+	///
+	/// The returned frame is the last frame that was run ahead, showing the
+	/// current input as if it was given that many frames earlier. Only the
+	/// first frame advances the machine, so the audio and the saves of the
+	/// frames that were run ahead are dropped. A `frames` of 0 behaves like
+	/// `run_frame`.
+	pub fn run_frame_ahead(&mut self, frames: usize) -> Result<Frame, GameboyError> {
+		if frames == 0 {
+			return self.run_frame();
+		}
+
+		let cycles = self.emulate_frame_rendering(false)?;
+		self.cpu.mmap.sync()?;
+		self.autosave(cycles)?;
+
+		// The frames that are run ahead are rolled back, so they aren't saved,
+		// and the host's time they applied to the clock is applied again later.
+		let snapshot = self.cpu.snapshot();
+		let autosave = self.autosave.take();
+		#[cfg(feature = "std")]
+		let rtc_sync = (self.rtc_sync, self.rtc_cycles);
+		let frame = self.run_ahead(frames);
+		self.autosave = autosave;
+		#[cfg(feature = "std")]
+		{
+			(self.rtc_sync, self.rtc_cycles) = rtc_sync;
+		}
+		self.cpu.restore(&snapshot)?;
+
+		let mut frame = frame?;
+		frame.cycles = cycles;
+
+		Ok(frame)
+	}
+
+	/// Emulates the given number of frames, rendering only the last one.
+	fn run_ahead(&mut self, frames: usize) -> Result<Frame, GameboyError> {
+		for _ in 1..frames {
+			self.emulate_frame_rendering(false)?;
+		}
+
+		let cycles = self.emulate_frame()?;
+
+		self.collect_frame(cycles)
+	}
+
+	/// Copies the state of the machine, to be restored later with `restore`.
+	pub fn snapshot(&self) -> CpuSnapshot {
+		self.cpu.snapshot()
+	}
+
+	/// Restores the state copied by `snapshot`.
+	///
+	/// The emulation resumes deterministically from the snapshot, so the same
+	/// input yields the same frames.
+	pub fn restore(&mut self, snapshot: &CpuSnapshot) -> Result<(), GameboyError> {
+		self.cpu.restore(snapshot)
+	}
+
+	/// Serializes the machine's state into a savestate, see `savestate`.
+	pub fn save_state(&self) -> Vec<u8> {
+		savestate::save(&self.cpu)
+	}
+
+	/// Loads a savestate of the same game and hardware model.
+	///
+	/// If the savestate is invalid, the machine's state is left unchanged.
+	pub fn load_state(&mut self, data: &[u8]) -> Result<(), GameboyError> {
+		savestate::load(&mut self.cpu, data)
+	}
+
+	/// Emulates a frame like `emulate_frame`, skipping the rendering of its
+	/// pixels unless `render` is set.
+	fn emulate_frame_rendering(&mut self, render: bool) -> Result<usize, GameboyError> {
+		let headless = self.cpu.mmap.ppu.headless();
+		self.cpu.mmap.ppu.set_headless(headless || !render);
+
+		let cycles = self.emulate_frame();
+		self.cpu.mmap.ppu.set_headless(headless);
+
+		cycles
+	}
+
+	/// Emulates the machine until the display completes a frame, and returns
+	/// the number of cycles it took.
+	fn emulate_frame(&mut self) -> Result<usize, GameboyError> {
+		let start = self.frame_count();
+		let mut cycles = 0;
+
+		loop {
+			cycles += self.execute()?;
+
+			if self.frame_done(start, cycles) {
+				return Ok(cycles);
+			}
+		}
+	}
+
+	/// Advances the real-time clock by the whole seconds that passed on the
+	/// host since it was last advanced, if it follows the host's time.
+	#[cfg(feature = "std")]
+	fn sync_rtc(&mut self) {
+		if self.cpu.config.rtc_source != RtcSource::WallClock {
+			return;
+		}
+
+		let seconds = self.rtc_sync.elapsed().as_secs();
+		if seconds > 0 {
+			self.cpu.mmap.cartridge.rtc_mut().advance_seconds(seconds);
+			self.rtc_sync += core::time::Duration::from_secs(seconds);
+		}
+	}
+
+	/// Returns the number of frames the display has completed, see
+	/// `Cpu::frame_count`.
+	pub fn frame_count(&self) -> usize {
+		self.cpu.frame_count()
+	}
+
+	/// Returns the rendering statistics of the last completed frame, see
+	/// `FrameStats`.
+	pub fn frame_stats(&self) -> FrameStats {
+		self.cpu.mmap.ppu.frame_stats()
+	}
+
+	/// Returns the number of clock cycles emulated since the emulator was
+	/// built, see `Cpu::total_cycles`.
+	pub fn total_cycles(&self) -> u64 {
+		self.cpu.total_cycles()
+	}
+
+	/// Returns whether a frame that started at the given frame count has
+	/// completed, after the given number of cycles.
+	pub(crate) fn frame_done(&self, start: usize, cycles: usize) -> bool {
+		let ppu = &self.cpu.mmap.ppu;
+
+		let paused = !ppu.lcd_enabled() || self.cpu.stopped();
+
+		ppu.frame_count() != start || (paused && cycles >= CYCLES_PER_FRAME)
+	}
+
+	/// Collects the completed frame, which took the given number of cycles.
+	pub(crate) fn finish_frame(&mut self, cycles: usize) -> Result<Frame, GameboyError> {
+		let frame = self.collect_frame(cycles)?;
+		self.autosave(cycles)?;
+
+		Ok(frame)
+	}
+
+	/// Collects the completed frame's pixels, without notifying the autosave
+	/// policy.
+	fn collect_frame(&mut self, cycles: usize) -> Result<Frame, GameboyError> {
+		// Catch the peripherals up, so the frame's samples are ready to be drained.
+		self.cpu.mmap.sync()?;
+
+		// The SGB keeps showing the last frame while its screen is frozen.
+		let mut pixels = match &self.last_frame {
+			Some(last_frame) => last_frame.clone(),
+			None => vec![0_u32; WIDTH * HEIGHT].into_boxed_slice(),
+		};
+		self.cpu.flush(&mut pixels);
+
+		let dirty = match &self.last_frame {
+			Some(last_frame) => *last_frame != pixels,
+			None => true,
+		};
+		let previous = self.last_frame.replace(pixels.clone());
+
+		// The previous frame is kept unblended, so the ghosting doesn't accumulate.
+		if let (Some(previous), true) = (previous, self.ghosting > 0) {
+			filter::blend(&mut pixels, &previous, self.ghosting)?;
+		}
+
+		Ok(Frame { pixels, cycles, dirty })
+	}
+
+	/// Lets the autosave policy know that a frame was completed.
+	fn autosave(&mut self, cycles: usize) -> Result<(), GameboyError> {
+		if let Some((policy, sink)) = &mut self.autosave {
+			policy.frame(cycles, &mut self.cpu.mmap.cartridge, sink.as_mut())?;
+		}
+
+		Ok(())
+	}
+
+	/// Notifies the emulator that the frontend paused the emulation.
+	///
+	/// Pending changes to the battery-backed ram are saved immediately.
+	pub fn pause(&mut self) -> Result<(), GameboyError> {
+		if let Some((policy, sink)) = &mut self.autosave {
+			policy.flush(&mut self.cpu.mmap.cartridge, sink.as_mut())?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns an iterator that emulates and yields the upcoming frames.
+	///
+	/// The iterator ends after yielding the first error.
+	pub fn frames(&mut self) -> Frames<'_> {
+		Frames { emulator: self, failed: false }
+	}
+
+	/// Returns the cartridge's ram, for exporting the game's save.
+	pub fn cartridge_ram(&self) -> &[u8] {
+		self.cpu.mmap.cartridge.ram()
+	}
+
+	/// Returns the cartridge's ram for mutation, for importing a save from
+	/// another emulator, see `Cartridge::ram_mut`.
+	pub fn cartridge_ram_mut(&mut self) -> &mut [u8] {
+		self.cpu.mmap.cartridge.ram_mut()
+	}
+
+	/// Sets the cartridge's real-time clock to the given time, see
+	/// `Rtc::set_time`.
+	pub fn set_rtc_time(&mut self, days: u16, hours: u8, minutes: u8, seconds: u8) -> Result<(), GameboyError> {
+		self.cpu.mmap.cartridge.rtc_mut().set_time(days, hours, minutes, seconds)
+	}
+
+	/// Returns whether the cartridge's ram is kept by a battery, so it
+	/// should be saved.
+	pub fn has_battery(&self) -> bool {
+		self.cpu.mmap.cartridge.has_battery()
+	}
+
+	/// Returns the queue of warnings raised during the emulation.
+	pub fn warnings(&self) -> &Warnings {
+		self.cpu.warnings()
+	}
+
+	/// Returns the number of clock cycles the cpu currently executes per
+	/// second, see `timing::clock_rate`.
+	pub fn clock_rate(&self) -> u32 {
+		timing::clock_rate(self.cpu.config.model, self.cpu.mmap.double_speed())
+	}
+
+	/// Returns a summary of the machine's state.
+	///
+	/// The peripherals' state is up to date after each completed frame, and
+	/// otherwise may lag behind by the cycles since they were last advanced.
+	pub fn status(&self) -> MachineStatus {
+		let mmap = &self.cpu.mmap;
+
+		MachineStatus {
+			halted: self.cpu.halted(),
+			stopped: self.cpu.stopped(),
+			locked: self.cpu.locked(),
+			ime: self.cpu.ime(),
+			ppu_mode: mmap.ppu.mode(),
+			ly: mmap.ppu.ly(),
+			pending_interrupts: mmap.pending_interrupts(),
+			double_speed: mmap.double_speed(),
+		}
+	}
+
+	/// Writes the display's data to the given frame buffer.
+	pub fn flush(&mut self, frame_buffer: &mut [u32]) {
+		self.cpu.flush(frame_buffer);
+	}
+
+	/// Writes the display's data inside the Super GameBoy's border to the
+	/// given frame buffer of `SGB_WIDTH` by `SGB_HEIGHT` pixels.
+	///
+	/// The border is only available when emulating the SGB model.
+	pub fn flush_border(&mut self, frame_buffer: &mut [u32]) -> Result<(), GameboyError> {
+		self.cpu.flush_border(frame_buffer)
+	}
+
+	/// Draws the display's data to the given target, see `Ppu::draw`.
+	#[cfg(feature = "embedded-graphics")]
+	pub fn draw<D>(&self, target: &mut D, scale: u32) -> Result<(), D::Error>
+	where
+		D: DrawTarget,
+		D::Color: From<Rgb888>,
+	{
+		self.cpu.draw(target, scale)
+	}
+
+	/// Selects the display's layers that are drawn, as a debugging aid.
+	pub fn set_layers(&mut self, layers: Layers) {
+		self.cpu.mmap.ppu.set_layers(layers);
+	}
+
+	/// Blends each frame with the previous one, with the given weight out of
+	/// 256 for the previous frame, see `filter::blend`. Zero disables the
+	/// blending.
+	pub fn set_ghosting(&mut self, strength: u8) {
+		self.ghosting = strength;
+	}
+
+	/// Moves the generated audio into the given buffer, as interleaved left
+	/// and right samples at the configured sample rate.
+	///
+	/// Returns the number of samples written.
+	pub fn drain_samples(&mut self, samples: &mut [i16]) -> usize {
+		self.cpu.mmap.apu_mut().drain_samples(samples)
+	}
+
+	/// Leaves the sound channel out of the audio output, or puts it back.
+	pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+		self.cpu.mmap.apu_mut().set_muted(channel, muted);
+	}
+
+	/// Starts logging the writes to the sound registers, or stops and
+	/// discards the log, see `Apu::set_logging`.
+	pub fn set_apu_logging(&mut self, enabled: bool) {
+		self.cpu.mmap.apu_mut().set_logging(enabled);
+	}
+
+	/// Moves the logged writes to the sound registers out of the log.
+	pub fn take_apu_log(&mut self) -> Vec<RegisterWrite> {
+		self.cpu.mmap.apu_mut().take_log()
+	}
+
+	/// Mutes all of the sound channels except for the given one.
+	pub fn solo_channel(&mut self, channel: Channel) {
+		self.cpu.mmap.apu_mut().solo(channel);
+	}
+
+	/// Silences the audio output, which also skips the mixing.
+	pub fn set_audio_muted(&mut self, muted: bool) {
+		self.cpu.mmap.apu_mut().set_master_muted(muted);
+	}
+
+	/// Apply the given closure to the game controller.
+	pub fn with_controller<F>(&mut self, closure: F)
+		where F: FnOnce(&mut dyn Controller) {
+			self.cpu.with_controller(closure);
+	}
+}
+
+impl EmulatorBuilder {
+	/// Initialize a builder with the default settings.
+	pub fn new() -> Self {
+		EmulatorBuilder {
+			rom: None,
+			boot_rom: None,
+			config: Config::default(),
+			autosave: None,
+		}
+	}
+
+	/// Sets the game's rom data.
+	pub fn rom(mut self, rom: Vec<u8>) -> Self {
+		self.rom = Some(rom);
+		self
+	}
+
+	/// Replaces the whole configuration, such as one made by
+	/// `Config::builder`. The options set before are overridden.
+	pub fn config(mut self, config: Config) -> Self {
+		self.config = config;
+		self
+	}
+
+	/// Sets the emulated hardware model.
+	pub fn model(mut self, model: HardwareModel) -> Self {
+		self.config.model = model;
+		self
+	}
+
+	/// Sets the trade-off between speed and timing accuracy, see `Accuracy`.
+	pub fn accuracy(mut self, accuracy: Accuracy) -> Self {
+		self.config.accuracy = accuracy;
+		self
+	}
+
+	/// Sets how accesses to the echo ram are handled.
+	pub fn echo_ram(mut self, policy: EchoRamPolicy) -> Self {
+		self.config.echo_ram = policy;
+		self
+	}
+
+	/// Sets how illegal opcodes are handled.
+	pub fn illegal_opcode(mut self, policy: IllegalOpcodePolicy) -> Self {
+		self.config.illegal_opcode = policy;
+		self
+	}
+
+	/// Sets the number of stereo frames generated per second, where 0
+	/// disables the audio output.
+	pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+		self.config.sample_rate = sample_rate;
+		self
+	}
+
+	/// Resamples the audio output from the sound channels' rate, instead of
+	/// sampling the channels directly.
+	pub fn resample(mut self, quality: ResampleQuality) -> Self {
+		self.config.resample = Some(quality);
+		self
+	}
+
+	/// Skips rendering the display's pixels, while keeping its timing and
+	/// interrupts. Useful for test runners and other setups that only
+	/// observe the memory or the serial output.
+	pub fn headless(mut self, headless: bool) -> Self {
+		self.config.headless = headless;
+		self
+	}
+
+	/// Sets the colors of the original GameBoy's shades, see `Config::palette`.
+	pub fn palette(mut self, palette: [u32; 4]) -> Self {
+		self.config.palette = palette;
+		self
+	}
+
+	/// Keeps running on recoverable errors, such as illegal opcodes and
+	/// accesses to unmapped addresses, and reports them as warnings instead.
+	/// See `Config::lenient`.
+	pub fn lenient(mut self, lenient: bool) -> Self {
+		self.config.lenient = lenient;
+		self
+	}
+
+	/// Selects what advances the cartridge's real-time clock, the emulated
+	/// cycles by default.
+	pub fn rtc_source(mut self, source: RtcSource) -> Self {
+		self.config.rtc_source = source;
+		self
+	}
+
+	/// Warns when the stack pointer leaves the given range, or when the stack
+	/// is accessed at the rom or I/O registers.
+	pub fn stack_check(mut self, range: StackRange) -> Self {
+		self.config.stack_check = Some(range);
+		self
+	}
+
+	/// Sets where the battery-backed ram is saved, and how often.
+	///
+	/// The ram is also saved when the emulator is paused or dropped, and as
+	/// soon as the game disables it after changing it.
+	pub fn save_sink<S: SaveSink + 'static>(mut self, sink: S, interval: SaveInterval) -> Self {
+		self.autosave = Some((SavePolicy::new(interval), Box::new(sink)));
+		self
+	}
+
+	/// Sets a boot rom to execute before the game starts.
+	///
+	/// Without a boot rom, the machine starts at the boot rom's exit state.
+	pub fn boot_rom(mut self, boot_rom: Vec<u8>) -> Self {
+		self.boot_rom = Some(boot_rom);
+		self
+	}
+
+	/// Constructs the emulator.
+	pub fn build(self) -> Result<Emulator, GameboyError> {
+		let rom = match self.rom {
+			Some(rom) => rom,
+			None => {
+				return Err(GameboyError::Cartridge("No rom was supplied."));
+			}
+		};
+
+		let cartridge = Cartridge::from_rom(rom.into_boxed_slice())?;
+		let mut cpu = Box::new(Cpu::new(self.config, cartridge));
+
+		if let Some(boot_rom) = self.boot_rom {
+			let boot_rom: Box<[u8]> = boot_rom.into_boxed_slice();
+			cpu.set_boot_rom(BootRom::new(Storage::Owned(boot_rom))?);
+		}
+
+		Ok(Emulator {
+			cpu,
+			last_frame: None,
+			ghosting: 0,
+			autosave: self.autosave,
+			#[cfg(feature = "std")]
+			rtc_sync: std::time::Instant::now(),
+			#[cfg(feature = "std")]
+			rtc_cycles: 0,
+		})
+	}
+}
+
+impl Drop for Emulator {
+	fn drop(&mut self) {
+		// There's no one to report the failure to.
+		let _ = self.pause();
+	}
+}
+
+impl Frame {
+	/// Returns a 64-bit FNV-1a digest of the frame's pixels.
+	///
+	/// The digest is cheap to compute and to store, so regression tests can
+	/// compare the emulated frames against previously recorded digests.
+	pub fn digest(&self) -> u64 {
+		self.pixels.iter()
+			.flat_map(|pixel| pixel.to_le_bytes())
+			.fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+	}
+}
+
+impl<'e> Iterator for Frames<'e> {
+	type Item = Result<Frame, GameboyError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.failed {
+			return None;
+		}
+
+		let frame = self.emulator.run_frame();
+		self.failed = frame.is_err();
+		Some(frame)
+	}
+}
+
+impl Default for EmulatorBuilder {
+	fn default() -> Self {
+		EmulatorBuilder::new()
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::bus::cartridge::CartridgeType;
+	use crate::bus::cartridge::consts::ROM_GLOBAL_CHECKSUM;
+	use crate::bus::cartridge::tests::empty_rom;
+	use crate::cpu::state::registers::Register;
+	use std::rc::Rc;
+	use std::cell::Cell;
+
+	struct SharedSink(Rc<Cell<usize>>);
+
+	impl SaveSink for SharedSink {
+		fn save(&mut self, _ram: &[u8]) -> Result<(), GameboyError> {
+			self.0.set(self.0.get() + 1);
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_build() -> Result<(), GameboyError> {
+		let rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		let mut emulator = Emulator::builder()
+			.rom(rom)
+			.model(HardwareModel::GBC)
+			.build()?;
+
+		// The first instruction is a nop.
+		assert!(emulator.execute()? == 4);
+
+		// A rom is mandatory.
+		assert!(Emulator::builder().build().is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_frames() -> Result<(), GameboyError> {
+		let mut emulator = Emulator::builder().rom(idle_rom()).build()?;
+
+		let frames: Vec<Frame> = emulator.frames().take(3).collect::<Result<_, _>>()?;
+
+		// The first frame ends on entering V-Blank, the rest are full frames.
+		assert!(frames[0].cycles == 144 * 456);
+		assert!(frames[1].cycles == CYCLES_PER_FRAME);
+		assert!(frames[2].cycles == CYCLES_PER_FRAME);
+
+		// The rom never changes the display.
+		assert!(frames[0].dirty);
+		assert!(!frames[1].dirty && !frames[2].dirty);
+		assert!(frames[0].pixels.len() == WIDTH * HEIGHT);
+
+		// The counters keep increasing across resets.
+		assert!(emulator.frame_count() == 3);
+		assert!(emulator.total_cycles() == frames.iter().map(|frame| frame.cycles as u64).sum::<u64>());
+		emulator.reset();
+		emulator.run_frame()?;
+		assert!(emulator.frame_count() == 4);
+		assert!(emulator.total_cycles() == (144 * 456 * 2 + 2 * CYCLES_PER_FRAME) as u64);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_headless() -> Result<(), GameboyError> {
+		let rom = idle_rom();
+		let first_frame = |rom: Vec<u8>, headless: bool| -> Result<Frame, GameboyError> {
+			Emulator::builder().rom(rom).headless(headless).build()?.run_frame()
+		};
+		let rendered = first_frame(rom.clone(), false)?;
+		let headless = first_frame(rom.clone(), true)?;
+
+		// The timing is kept, but nothing is drawn.
+		assert!(rendered.cycles == headless.cycles);
+		assert!(rendered.pixels.iter().all(|&pixel| pixel != 0));
+		assert!(headless.pixels.iter().all(|&pixel| pixel == 0));
+
+		// The frames are drawn with the configured palette.
+		let config = Config::builder().palette([1, 2, 3, 4]).build();
+		let frame = Emulator::builder().rom(rom).config(config).build()?.run_frame()?;
+		assert!(frame.pixels.iter().all(|&pixel| pixel == 4));
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_run_frames() -> Result<(), GameboyError> {
+		let mut emulator = Emulator::builder().rom(idle_rom()).build()?;
+		emulator.run_frame()?;
+
+		// Only every third frame is rendered.
+		let mut rendered = Vec::new();
+		let cycles = emulator.run_frames(7, 3, |frame| rendered.push(frame))?;
+		assert!(cycles == 7 * CYCLES_PER_FRAME);
+		assert!(rendered.len() == 2);
+		assert!(rendered.iter().all(|frame| frame.cycles == CYCLES_PER_FRAME));
+		assert!(rendered[0].pixels.iter().all(|&pixel| pixel != 0));
+
+		assert!(emulator.run_frames(2, 0, |_| panic!("No frame should be rendered"))? == 2 * CYCLES_PER_FRAME);
+		assert!(!emulator.cpu().mmap.ppu().headless());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_status() -> Result<(), GameboyError> {
+		let mut rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		// halt, without any enabled interrupt.
+		rom[0x100] = 0x76;
+		let mut emulator = Emulator::builder().rom(rom).build()?;
+		emulator.run_frame()?;
+
+		let status = emulator.status();
+		assert!(status.halted && !status.stopped && !status.ime);
+		assert!(status.ppu_mode == PpuMode::Vblank && status.ly == 144);
+		assert!(status.pending_interrupts.is_empty());
+		assert!(!status.double_speed);
+
+		Ok(())
+	}
+
+	/// Returns a rom that loops forever without doing anything.
+	pub(crate) fn idle_rom() -> Vec<u8> {
+		let mut rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		// jr -2
+		rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]);
+
+		rom
+	}
+
+	/// Builds an emulator that keeps changing the background's palette.
+	pub(crate) fn palette_cycle(model: HardwareModel, checksum: u8) -> Result<Emulator, GameboyError> {
+		let mut rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		// inc A; nop; ldh (BGP), A; jr -6
+		rom[0x100..0x106].copy_from_slice(&[0x3c, 0x00, 0xe0, 0x47, 0x18, 0xfa]);
+		rom[ROM_GLOBAL_CHECKSUM] = checksum;
+
+		Emulator::builder().rom(rom).model(model).build()
+	}
+
+	#[test]
+	fn test_ghosting() -> Result<(), GameboyError> {
+		let mut emulator = palette_cycle(HardwareModel::GB, 0)?;
+		let first = emulator.run_frame()?;
+
+		emulator.set_ghosting(128);
+		let second = emulator.run_frame()?;
+		let mut raw = vec![0; WIDTH * HEIGHT];
+		emulator.flush(&mut raw);
+
+		// The frame is blended with the previous one, which is kept unblended.
+		assert!(second.dirty && *second.pixels != *raw);
+		filter::blend(&mut raw, &first.pixels, 128)?;
+		assert!(second.pixels == raw.into_boxed_slice());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_snapshot() -> Result<(), GameboyError> {
+		let mut emulator = palette_cycle(HardwareModel::GB, 0)?;
+		emulator.run_frame()?;
+
+		let snapshot = emulator.snapshot();
+		let first = emulator.run_frame()?;
+		let a = emulator.cpu().registers().get(Register::A);
+
+		// The emulation resumes deterministically.
+		emulator.restore(&snapshot)?;
+		let second = emulator.run_frame()?;
+		assert!(first.pixels == second.pixels);
+		assert!(first.digest() == second.digest());
+		assert!(emulator.cpu().registers().get(Register::A) == a);
+
+		// The palette changes on every frame.
+		assert!(emulator.run_frame()?.digest() != second.digest());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_run_frame_ahead() -> Result<(), GameboyError> {
+		let run = |count: usize, ahead: usize| -> Result<(Vec<Frame>, u16), GameboyError> {
+			let mut emulator = palette_cycle(HardwareModel::GB, 0)?;
+			let frames = (0..count).map(|_| emulator.run_frame_ahead(ahead)).collect::<Result<_, _>>()?;
+			Ok((frames, emulator.cpu().registers().get(Register::A)))
+		};
+		let (frames, a) = run(3, 2)?;
+		let (expected, _) = run(5, 0)?;
+		let (_, expected_a) = run(3, 0)?;
+
+		// Only the first frame advances the machine.
+		assert!(a == expected_a);
+		assert!(frames.iter().zip(&expected).all(|(frame, expected)| frame.cycles == expected.cycles));
+
+		// The returned frames are the ones that are two frames ahead.
+		assert!(frames[2].pixels == expected[4].pixels);
+		assert!(frames[2].pixels != expected[2].pixels);
+
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn test_run_frame_ahead_wall_clock() -> Result<(), GameboyError> {
+		let mut emulator = Emulator::builder().rom(idle_rom()).rtc_source(RtcSource::WallClock).build()?;
+		emulator.rtc_sync -= std::time::Duration::from_secs(2);
+
+		// The host's time isn't lost when the frames that are run ahead are
+		// rolled back.
+		for _ in 0..3 {
+			emulator.run_frame_ahead(2)?;
+		}
+		assert!(emulator.cpu().mmap.cartridge().rtc().state().clock[0] >= 2);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_boot_rom() -> Result<(), GameboyError> {
+		let rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		let mut boot_rom = vec![0_u8; 0x100];
+
+		// ld A, 1; ld (0xFF50), A
+		boot_rom[0..4].copy_from_slice(&[0x3e, 0x01, 0xe0, 0x50]);
+		// Mark a byte that is also mapped by the cartridge.
+		boot_rom[0x80] = 0x42;
+
+		let mut emulator = Emulator::builder()
+			.rom(rom)
+			.boot_rom(boot_rom)
+			.build()?;
+
+		// The boot rom is mapped over the cartridge.
+		assert!(emulator.cpu().mmap.peek(0x0080)? == 0x42);
+
+		emulator.execute()?;
+		emulator.execute()?;
+
+		// The boot rom unmapped itself.
+		assert!(emulator.cpu().mmap.peek(0x0080)? == 0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_autosave() -> Result<(), GameboyError> {
+		let mut rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		// ld A, 0x0A; ld (0x0000), A; ld (0xA000), A; jr -2
+		rom[0x100..0x10a].copy_from_slice(&[0x3e, 0x0a, 0xea, 0x00, 0x00, 0xea, 0x00, 0xa0, 0x18, 0xfe]);
+
+		let saves = Rc::new(Cell::new(0));
+		let mut emulator = Emulator::builder()
+			.rom(rom)
+			.save_sink(SharedSink(saves.clone()), SaveInterval::Frames(2))
+			.build()?;
+
+		emulator.run_frame()?;
+		assert!(saves.get() == 0);
+		emulator.run_frame()?;
+		assert!(saves.get() == 1);
+
+		// Unchanged ram isn't saved on pause.
+		emulator.pause()?;
+		assert!(saves.get() == 1);
+
+		// Pending changes are saved when the emulator is dropped.
+		emulator.cpu_mut().mmap.write(0xA001, 0x01)?;
+		drop(emulator);
+		assert!(saves.get() == 2);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_autosave_release() -> Result<(), GameboyError> {
+		let mut rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		// ld A, 0x0A; ld (0x0000), A; ld (0xA000), A; xor A; ld (0x0000), A; jr -2
+		rom[0x100..0x10e].copy_from_slice(&[
+			0x3e, 0x0a, 0xea, 0x00, 0x00, 0xea, 0x00, 0xa0,
+			0xaf, 0xea, 0x00, 0x00, 0x18, 0xfe,
+		]);
+
+		let saves = Rc::new(Cell::new(0));
+		let mut emulator = Emulator::builder()
+			.rom(rom)
+			.save_sink(SharedSink(saves.clone()), SaveInterval::Frames(1000))
+			.build()?;
+
+		// The ram is saved by the instruction that disables it, mid-frame.
+		for _ in 0..4 {
+			emulator.execute()?;
+		}
+		assert!(saves.get() == 0);
+		emulator.execute()?;
+		assert!(saves.get() == 1);
+
+		emulator.run_frame()?;
+		assert!(saves.get() == 1);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_rtc_source() -> Result<(), GameboyError> {
+		let rom = idle_rom();
+		let rtc_cycles = |rom: Vec<u8>, source: RtcSource| -> Result<u32, GameboyError> {
+			let mut emulator = Emulator::builder().rom(rom).rtc_source(source).build()?;
+			emulator.run_frame()?;
+			Ok(emulator.cpu().mmap.cartridge().rtc().subsecond_cycles())
+		};
+
+		// Only the emulated clock is advanced by the cpu's cycles.
+		assert!(rtc_cycles(rom.clone(), RtcSource::Emulated)? == 144 * 456);
+		assert!(rtc_cycles(rom.clone(), RtcSource::WallClock)? == 0);
+
+		let mut emulator = Emulator::builder().rom(rom).build()?;
+		emulator.set_rtc_time(2, 12, 30, 0)?;
+		assert!(emulator.set_rtc_time(0, 0, 60, 0).is_err());
+		assert!(emulator.cpu().mmap.cartridge().rtc().state().clock == [0, 30, 12, 2, 0]);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,193 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Post-processing of the display's frames.
+//!
+//! The filters operate on frames of `0x00RRGGBB` pixels, such as the ones
+//! written by `Ppu::flush`, and are independent of the rest of the core.
+
+use crate::GameboyError;
+
+/// Blends the frame with the previous one, to mimic the slow response of
+/// the original lcd.
+///
+/// Some games flicker sprites on alternate frames, and rely on the blending
+/// to display them as transparent.
+///
+/// * `frame` - The current frame, which is blended in place.
+/// * `previous` - The previous frame, of the same size.
+/// * `strength` - The previous frame's weight, out of 256.
+pub fn blend(frame: &mut [u32], previous: &[u32], strength: u8) -> Result<(), GameboyError> {
+	if frame.len() != previous.len() {
+		return Err(GameboyError::Io("The frames' sizes differ."));
+	}
+
+	let strength = strength as u32;
+
+	for (pixel, &old) in frame.iter_mut().zip(previous.iter()) {
+		let mut blended = 0;
+
+		for shift in [0, 8, 16].iter().copied() {
+			let new_channel = (*pixel >> shift) & 0xFF;
+			let old_channel = (old >> shift) & 0xFF;
+			let channel = (new_channel * (256 - strength) + old_channel * strength) / 256;
+			blended |= channel << shift;
+		}
+
+		*pixel = blended;
+	}
+
+	Ok(())
+}
+
+/// Upscales the frame by an integer factor, repeating each pixel.
+///
+/// * `frame` - The frame's pixels, row by row.
+/// * `width` - The frame's width, such as `WIDTH` for the display's frames.
+/// * `factor` - The scaling factor, usually 2, 3 or 4.
+/// * `buffer` - The output, of `factor * factor` times the frame's size.
+pub fn upscale(frame: &[u32], width: usize, factor: usize, buffer: &mut [u32]) -> Result<(), GameboyError> {
+	if width == 0 || factor == 0 || !frame.chunks_exact(width).remainder().is_empty() {
+		return Err(GameboyError::Io("The frame's size is invalid."));
+	}
+	if buffer.len() != frame.len() * factor * factor {
+		return Err(GameboyError::Io("The buffer's size is invalid."));
+	}
+
+	let scaled_width = width * factor;
+
+	for (row, scaled_rows) in frame.chunks(width).zip(buffer.chunks_mut(scaled_width * factor)) {
+		let (first, rest) = scaled_rows.split_at_mut(scaled_width);
+
+		for (pixel, scaled) in row.iter().zip(first.chunks_mut(factor)) {
+			for output in scaled.iter_mut() {
+				*output = *pixel;
+			}
+		}
+
+		// The rest of the rows repeat the first one.
+		for scaled_row in rest.chunks_mut(scaled_width) {
+			scaled_row.copy_from_slice(first);
+		}
+	}
+
+	Ok(())
+}
+
+/// Upscales the frame by a factor of 2 with the Scale2x algorithm, which
+/// smooths diagonal edges without blending any colors.
+///
+/// * `frame` - The frame's pixels, row by row.
+/// * `width` - The frame's width, such as `WIDTH` for the display's frames.
+/// * `buffer` - The output, of 4 times the frame's size.
+pub fn scale2x(frame: &[u32], width: usize, buffer: &mut [u32]) -> Result<(), GameboyError> {
+	if width == 0 || !frame.chunks_exact(width).remainder().is_empty() {
+		return Err(GameboyError::Io("The frame's size is invalid."));
+	}
+	if buffer.len() != frame.len() * 4 {
+		return Err(GameboyError::Io("The buffer's size is invalid."));
+	}
+
+	let height = frame.len() / width;
+	// The pixels beyond the frame's edges repeat the edges.
+	let pixel = |x: usize, y: usize| frame[y * width + x];
+
+	for y in 0..height {
+		for x in 0..width {
+			let center = pixel(x, y);
+			let up = pixel(x, y.saturating_sub(1));
+			let down = pixel(x, (y + 1).min(height - 1));
+			let left = pixel(x.saturating_sub(1), y);
+			let right = pixel((x + 1).min(width - 1), y);
+
+			let mut scaled = [center; 4];
+			if up != down && left != right {
+				if left == up { scaled[0] = up; }
+				if up == right { scaled[1] = right; }
+				if left == down { scaled[2] = left; }
+				if down == right { scaled[3] = down; }
+			}
+
+			let offset = 2 * y * 2 * width + 2 * x;
+			buffer[offset..offset + 2].copy_from_slice(&scaled[0..2]);
+			buffer[offset + 2 * width..offset + 2 * width + 2].copy_from_slice(&scaled[2..4]);
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_blend() -> Result<(), GameboyError> {
+		let previous = [0x00FF_FFFF, 0x0000_0000, 0x0012_3456];
+		let mut frame = [0x0000_0000, 0x0000_0000, 0x0012_3456];
+
+		blend(&mut frame, &previous, 128)?;
+		assert!(frame == [0x007F_7F7F, 0x0000_0000, 0x0012_3456]);
+
+		// Without any strength, the frame is kept as is.
+		let mut frame = [0x0080_4020];
+		blend(&mut frame, &[0x00FF_FFFF], 0)?;
+		assert!(frame == [0x0080_4020]);
+
+		// The frames must be of the same size.
+		assert!(blend(&mut frame, &previous, 128).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_upscale() -> Result<(), GameboyError> {
+		let frame = [1, 2, 3, 4, 5, 6];
+		let mut buffer = [0; 6 * 4];
+
+		upscale(&frame, 3, 2, &mut buffer)?;
+		assert!(buffer == [
+			1, 1, 2, 2, 3, 3,
+			1, 1, 2, 2, 3, 3,
+			4, 4, 5, 5, 6, 6,
+			4, 4, 5, 5, 6, 6,
+		]);
+
+		let mut buffer = [0; 6 * 9];
+		upscale(&frame, 3, 3, &mut buffer)?;
+		assert!(buffer[..9] == [1, 1, 1, 2, 2, 2, 3, 3, 3] && buffer[45..] == [4, 4, 4, 5, 5, 5, 6, 6, 6]);
+
+		// The frame must consist of whole rows, and fit the buffer.
+		assert!(upscale(&frame, 4, 3, &mut buffer).is_err());
+		assert!(upscale(&frame, 3, 2, &mut buffer).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_scale2x() -> Result<(), GameboyError> {
+		// A diagonal edge is smoothed, while the flat areas are kept.
+		let frame = [
+			1, 0, 0,
+			1, 1, 0,
+			1, 1, 1,
+		];
+		let mut buffer = [0; 9 * 4];
+
+		scale2x(&frame, 3, &mut buffer)?;
+		assert!(buffer == [
+			1, 1, 0, 0, 0, 0,
+			1, 1, 1, 0, 0, 0,
+			1, 1, 1, 0, 0, 0,
+			1, 1, 1, 1, 1, 0,
+			1, 1, 1, 1, 1, 1,
+			1, 1, 1, 1, 1, 1,
+		]);
+
+		assert!(scale2x(&frame, 0, &mut buffer).is_err());
+		assert!(scale2x(&frame, 3, &mut buffer[1..]).is_err());
+
+		Ok(())
+	}
+}
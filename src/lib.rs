@@ -18,6 +18,22 @@ extern crate alloc;
 pub mod bus;
 pub mod cpu;
 pub mod config;
+pub mod warning;
+pub mod resample;
+pub mod filter;
+pub mod save;
+#[cfg(feature = "std")]
+pub mod pacing;
+#[cfg(feature = "alloc")]
+pub mod savestate;
+#[cfg(feature = "alloc")]
+pub mod emulator;
+#[cfg(feature = "alloc")]
+pub mod link;
+#[cfg(feature = "alloc")]
+pub mod cheat;
+#[cfg(feature = "alloc")]
+pub mod wav;
 
 use core::fmt;
 
@@ -32,7 +48,14 @@ pub enum GameboyError {
 	/// Unexpected address error.
 	BadAddress(u16),
 	/// Invalid opcode error.
-	BadOpcode(u8),
+	///
+	/// The bytes around the opcode can be inspected with `Cpu::surrounding_bytes`.
+	BadOpcode {
+		/// The opcode that failed to decode.
+		opcode: u8,
+		/// The address the opcode was fetched from.
+		address: u16,
+	},
 	/// Invalid value written to a register.
 	BadValue(u8),
 }
@@ -44,7 +67,7 @@ impl fmt::Display for GameboyError {
             GameboyError::Cartridge(ref info) => write!(f, "Cartridge error: {}", info),
             GameboyError::Io(ref info) => write!(f, "IO error: {}", info),
             GameboyError::BadAddress(address) => write!(f, "Bad address: 0x{:x}", address),
-            GameboyError::BadOpcode(value) => write!(f, "Bad opcode: 0x{:x}", value),
+			GameboyError::BadOpcode { opcode, address } => write!(f, "Bad opcode: 0x{:02x} at 0x{:04x}", opcode, address),
             GameboyError::BadValue(value) => write!(f, "Bad value: {}", value),
         }
 	}
@@ -0,0 +1,176 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Two emulators connected by an in-process link cable.
+//!
+//! The emulators are stepped in lockstep, always running the one that is
+//! behind in emulated time, so a transfer is never exchanged with a partner
+//! that is more than a single instruction away.
+//!
+//! The side whose SC register selects the internal clock is the master, and
+//! drives the transfer. Once it shifted its byte out, the byte is exchanged
+//! with the other side if it's waiting for an external clock. Otherwise,
+//! the master receives 0xFF, as if nothing was linked.
+
+use alloc::boxed::Box;
+
+use crate::GameboyError;
+use crate::emulator::{Emulator, Frame};
+
+/// Owns two emulators, with their serial ports linked to each other, for
+/// local two-player games such as trading and battling.
+pub struct LinkedPair {
+	/// The emulators are large, so they're kept on the heap.
+	emulators: Box<[Emulator; 2]>,
+}
+
+impl LinkedPair {
+	/// Links the given emulators.
+	pub fn new(first: Emulator, second: Emulator) -> Self {
+		let mut emulators = Box::new([first, second]);
+
+		for emulator in emulators.iter_mut() {
+			emulator.cpu_mut().mmap.serial_mut().set_linked(true);
+		}
+
+		LinkedPair { emulators }
+	}
+
+	/// Returns the first emulator.
+	pub fn first(&self) -> &Emulator {
+		&self.emulators[0]
+	}
+
+	/// Returns the second emulator.
+	pub fn second(&self) -> &Emulator {
+		&self.emulators[1]
+	}
+
+	/// Returns the first emulator for mutation.
+	pub fn first_mut(&mut self) -> &mut Emulator {
+		&mut self.emulators[0]
+	}
+
+	/// Returns the second emulator for mutation.
+	pub fn second_mut(&mut self) -> &mut Emulator {
+		&mut self.emulators[1]
+	}
+
+	/// Unlinks the emulators.
+	pub fn into_inner(self) -> (Emulator, Emulator) {
+		let [mut first, mut second] = *self.emulators;
+
+		first.cpu_mut().mmap.serial_mut().set_linked(false);
+		second.cpu_mut().mmap.serial_mut().set_linked(false);
+
+		(first, second)
+	}
+
+	/// Emulates both machines until each of their displays completes a frame.
+	///
+	/// The emulator that completes its frame first waits for the other one.
+	pub fn run_frame_both(&mut self) -> Result<(Frame, Frame), GameboyError> {
+		let starts = [self.emulators[0].frame_count(), self.emulators[1].frame_count()];
+		let mut cycles = [0; 2];
+		// The elapsed time, which passes slower for a cpu in double speed.
+		let mut time = [0; 2];
+		let mut done = [false; 2];
+
+		while !(done[0] && done[1]) {
+			let side = if done[0] {
+				1
+			} else if done[1] || time[0] <= time[1] {
+				0
+			} else {
+				1
+			};
+
+			let emulator = &mut self.emulators[side];
+			let elapsed = emulator.execute()?;
+			cycles[side] += elapsed;
+			time[side] += if emulator.cpu().mmap.double_speed() { elapsed / 2 } else { elapsed };
+
+			self.exchange(side);
+			done[side] = self.emulators[side].frame_done(starts[side], cycles[side]);
+		}
+
+		let [first, second] = &mut *self.emulators;
+
+		Ok((first.finish_frame(cycles[0])?, second.finish_frame(cycles[1])?))
+	}
+
+	/// Exchanges the bytes of a transfer that the given side has clocked out.
+	fn exchange(&mut self, master: usize) {
+		let [first, second] = &mut *self.emulators;
+		let (master, slave) = if master == 0 { (first, second) } else { (second, first) };
+
+		if let Some(sent) = master.cpu_mut().mmap.serial_mut().take_outgoing() {
+			let received = slave.cpu_mut().mmap.serial_mut().clock_in(sent);
+			master.cpu_mut().mmap.serial_mut().complete_transfer(received);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec::Vec;
+	use crate::bus::cartridge::CartridgeType;
+	use crate::bus::cartridge::tests::empty_rom;
+
+	/// Builds an emulator that starts a transfer of the given byte.
+	fn transfer(data: u8, control: u8) -> Result<Emulator, GameboyError> {
+		let mut rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		// ld A, data; ldh (SB), A; ld A, control; ldh (SC), A; jr -2
+		rom[0x100..0x10a].copy_from_slice(&[0x3e, data, 0xe0, 0x01, 0x3e, control, 0xe0, 0x02, 0x18, 0xfe]);
+
+		Emulator::builder().rom(rom).build()
+	}
+
+	#[test]
+	fn test_linked_transfer() -> Result<(), GameboyError> {
+		let mut pair = LinkedPair::new(transfer(0x12, 0x81)?, transfer(0x34, 0x80)?);
+
+		let (first, second) = pair.run_frame_both()?;
+		assert!(first.cycles == second.cycles);
+
+		// The bytes are exchanged, and both transfers are complete.
+		let master = pair.first().cpu().mmap.serial();
+		let slave = pair.second().cpu().mmap.serial();
+		assert!(master.sb() == 0x34 && slave.sb() == 0x12);
+		assert!(!master.transferring() && !slave.transferring());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_linked_masters() -> Result<(), GameboyError> {
+		// Two masters don't respond to each other.
+		let mut pair = LinkedPair::new(transfer(0x12, 0x81)?, transfer(0x34, 0x81)?);
+		pair.run_frame_both()?;
+		assert!(pair.first().cpu().mmap.serial().sb() == 0xFF);
+		assert!(pair.second().cpu().mmap.serial().sb() == 0xFF);
+
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn test_linked_wall_clock() -> Result<(), GameboyError> {
+		use std::time::Duration;
+		use crate::config::RtcSource;
+		use crate::emulator::tests::idle_rom;
+
+		let wall_clock = || Emulator::builder().rom(idle_rom()).rtc_source(RtcSource::WallClock).build();
+		let mut pair = LinkedPair::new(wall_clock()?, wall_clock()?);
+
+		// The host's time advances the clock of linked emulators as well.
+		pair.first_mut().rtc_sync -= Duration::from_secs(2);
+		pair.run_frame_both()?;
+		pair.run_frame_both()?;
+		assert!(pair.first().cpu().mmap.cartridge().rtc().state().clock[0] >= 2);
+
+		Ok(())
+	}
+}
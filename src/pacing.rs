@@ -0,0 +1,164 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Paces the emulation to the display's refresh rate, about 59.7275Hz.
+//!
+//! The host's timer rarely lines up with the emulated frames, so the clock
+//! keeps the time that wasn't spent on a whole frame yet, and lets the
+//! frontend run as many frames as are due.
+//!
+//! # Examples
+//! ```no_run
+//! # use gameboy_core::GameboyError;
+//! # use gameboy_core::emulator::Emulator;
+//! # use gameboy_core::config::HardwareModel;
+//! # use gameboy_core::pacing::FrameClock;
+//! # fn main() -> Result<(), GameboyError> {
+//! # let mut emulator = Emulator::builder().rom(vec![0_u8; 0x8000]).build()?;
+//! let mut clock = FrameClock::new(HardwareModel::GB);
+//!
+//! loop {
+//!     for _ in 0..clock.tick() {
+//!         let _frame = emulator.run_frame()?;
+//!     }
+//!
+//!     std::thread::sleep(clock.until_next_frame());
+//! }
+//! # }
+//! ```
+
+use std::time::{Duration, Instant};
+
+use crate::config::HardwareModel;
+use crate::cpu::timing;
+use crate::bus::ppu::consts::CYCLES_PER_FRAME;
+
+/// The default number of frames that are run at once to catch up.
+pub const DEFAULT_MAX_FRAMES: usize = 4;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// Tells the frontend how many frames to run, and how long to sleep.
+pub struct FrameClock {
+	/// The number of clock cycles per second, which sets the frame rate.
+	clock_rate: u32,
+	/// The host time that wasn't spent on a frame yet, in nanoseconds
+	/// multiplied by the clock rate, so the frames don't drift.
+	credit: u128,
+	/// The maximal number of frames that are due at once, at the normal speed.
+	max_frames: usize,
+	/// The number of frames that are due per display refresh, used to fast-forward.
+	speed: u32,
+	/// The moment of the last `tick`.
+	last_tick: Instant,
+}
+
+impl FrameClock {
+	/// Initialize a clock that paces the given model's frames, starting now.
+	pub fn new(model: HardwareModel) -> Self {
+		FrameClock {
+			clock_rate: timing::clock_rate(model, false),
+			credit: 0,
+			max_frames: DEFAULT_MAX_FRAMES,
+			speed: 1,
+			last_tick: Instant::now(),
+		}
+	}
+
+	/// Sets the maximal number of frames that are due at once.
+	///
+	/// When the host falls further behind, for example after the frontend
+	/// was paused, the rest of the frames are dropped instead of being run
+	/// in a burst.
+	pub fn set_max_frames(&mut self, max_frames: usize) {
+		self.max_frames = max_frames;
+	}
+
+	/// Sets the emulation's speed, as a multiple of the normal speed.
+	///
+	/// While fast-forwarding, proportionally more frames are due, and the
+	/// maximal number of frames that are due at once grows accordingly. A
+	/// speed of 0 is treated as 1.
+	pub fn set_speed(&mut self, speed: u32) {
+		self.speed = speed.max(1);
+	}
+
+	/// Returns the emulation's speed, as a multiple of the normal speed.
+	pub fn speed(&self) -> u32 {
+		self.speed
+	}
+
+	/// Returns the number of frames that are due since the last tick.
+	pub fn tick(&mut self) -> usize {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last_tick);
+		self.last_tick = now;
+
+		self.advance(elapsed)
+	}
+
+	/// Advances the clock by the given host time, and returns the number of
+	/// frames that are due.
+	pub fn advance(&mut self, elapsed: Duration) -> usize {
+		let frame = self.frame_cost();
+		self.credit += elapsed.as_nanos() * self.clock_rate as u128 * self.speed as u128;
+
+		let frames = (self.credit / frame) as usize;
+		let max_frames = self.max_frames.saturating_mul(self.speed as usize);
+		if frames > max_frames {
+			self.credit %= frame;
+			return max_frames;
+		}
+
+		self.credit -= frames as u128 * frame;
+		frames
+	}
+
+	/// Returns how long to wait until the next frame is due.
+	pub fn until_next_frame(&self) -> Duration {
+		let remaining = self.frame_cost().saturating_sub(self.credit);
+		let nanos = remaining.div_ceil(self.clock_rate as u128 * self.speed as u128);
+
+		Duration::new((nanos / NANOS_PER_SECOND) as u64, (nanos % NANOS_PER_SECOND) as u32)
+	}
+
+	/// Returns a frame's duration in the units of `credit`.
+	fn frame_cost(&self) -> u128 {
+		CYCLES_PER_FRAME as u128 * NANOS_PER_SECOND
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_frame_clock() {
+		let mut clock = FrameClock::new(HardwareModel::GB);
+		let frame = timing::cycles_to_duration(CYCLES_PER_FRAME, timing::CLOCK_RATE);
+
+		// The leftover time is kept for the next frame.
+		assert!(clock.advance(frame / 2) == 0);
+		assert!(clock.until_next_frame() <= frame / 2 + Duration::from_nanos(1));
+		assert!(clock.advance(frame - frame / 2) == 1);
+
+		// A second holds about 59.7275 frames, without drifting.
+		let frames: usize = (0..1000).map(|_| clock.advance(Duration::from_millis(1))).sum();
+		assert!(frames == 59);
+		assert!(clock.advance(Duration::from_secs(59)) == DEFAULT_MAX_FRAMES);
+
+		// The leftover of the dropped frames is kept, so a minute holds 3583
+		// or 3584 frames.
+		clock.set_max_frames(usize::MAX);
+		assert!((3583..=3584).contains(&clock.advance(Duration::from_secs(60))));
+
+		// Fast-forwarding runs the frames in a fraction of the time.
+		clock.set_max_frames(DEFAULT_MAX_FRAMES);
+		clock.set_speed(4);
+		clock.advance(frame);
+		assert!(clock.until_next_frame() <= frame / 4 + Duration::from_nanos(1));
+		assert!(clock.advance(frame / 4) == 1);
+		assert!(clock.advance(Duration::from_secs(1)) == 4 * DEFAULT_MAX_FRAMES);
+	}
+}
@@ -0,0 +1,342 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Convert audio between sample rates.
+//!
+//! The sound channels are clocked at about 1MHz, far above the rate at which
+//! frontends play audio. The sound controller resamples its output when
+//! `Config::resample` is set, but the resampler is independent of the rest of
+//! the core, and can be used standalone on any stream of stereo frames.
+
+/// A single stereo sample, left channel first.
+pub type StereoFrame = [i16; 2];
+
+/// The number of input samples the band-limited filter is applied to.
+const TAPS: usize = 64;
+/// The number of sub-sample positions the filter's kernel is computed for.
+const PHASES: usize = 32;
+/// The filter's cutoff, relative to the output's nyquist frequency.
+const CUTOFF: f32 = 0.85;
+
+const PI: f32 = core::f32::consts::PI;
+
+/// The trade-off between the quality and the cost of resampling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResampleQuality {
+	/// Interpolate linearly between adjacent samples.
+	///
+	/// This is cheap, but frequencies above the output's nyquist frequency
+	/// are folded back into the audible range.
+	Linear,
+	/// Average the input down to an intermediate rate, then interpolate
+	/// with a windowed-sinc low-pass filter.
+	///
+	/// This removes the frequencies the output rate can't represent, at the
+	/// cost of a short delay and more computation.
+	BandLimited,
+}
+
+/// Converts a stream of stereo frames from one sample rate to another.
+#[derive(Clone)]
+pub struct Resampler {
+	quality: ResampleQuality,
+	input_rate: u32,
+	output_rate: u32,
+	/// The number of input frames averaged into each filtered frame.
+	decimation: u32,
+	/// The number of input frames averaged so far.
+	decimation_count: u32,
+	/// The sum of the input frames averaged so far.
+	decimation_sum: [i32; 2],
+	/// The distance between output frames, in (decimated) input frames,
+	/// as a 32.32 fixed point number.
+	step: u64,
+	/// The position of the next output frame after the oldest interpolated
+	/// frame, as a 32.32 fixed point number.
+	position: u64,
+	/// The last input frames, stored twice so the newest `TAPS` frames are
+	/// always contiguous.
+	history: [[f32; 2]; 2 * TAPS],
+	/// The index of the oldest frame in the history.
+	history_index: usize,
+	/// The filter's coefficients for each sub-sample position.
+	kernel: [[f32; TAPS]; PHASES + 1],
+}
+
+impl Resampler {
+	/// Initialize a resampler between the given rates, in Hz.
+	///
+	/// # Panics
+	///
+	/// Panics if either rate is zero.
+	pub fn new(input_rate: u32, output_rate: u32, quality: ResampleQuality) -> Self {
+		assert!(input_rate != 0 && output_rate != 0, "Sample rates must be positive");
+
+		// Averaging keeps the intermediate rate at least twice the output rate,
+		// so the averaging itself barely affects the audible range.
+		let decimation = match quality {
+			ResampleQuality::Linear => 1,
+			ResampleQuality::BandLimited => (input_rate / (2 * output_rate)).max(1),
+		};
+
+		let step = ((input_rate as u64) << 32) / (output_rate as u64 * decimation as u64);
+
+		let mut resampler = Resampler {
+			quality,
+			input_rate,
+			output_rate,
+			decimation,
+			decimation_count: 0,
+			decimation_sum: [0; 2],
+			step,
+			position: 0,
+			history: [[0.0; 2]; 2 * TAPS],
+			history_index: 0,
+			kernel: [[0.0; TAPS]; PHASES + 1],
+		};
+
+		if quality == ResampleQuality::BandLimited {
+			// The cutoff is relative to the intermediate rate.
+			let intermediate_rate = input_rate as f32 / decimation as f32;
+			let cutoff = (CUTOFF * output_rate as f32 / intermediate_rate).min(CUTOFF);
+			resampler.compute_kernel(cutoff);
+		}
+
+		resampler
+	}
+
+	/// Returns the rate of the input frames.
+	pub fn input_rate(&self) -> u32 {
+		self.input_rate
+	}
+
+	/// Returns the rate of the output frames.
+	pub fn output_rate(&self) -> u32 {
+		self.output_rate
+	}
+
+	/// Returns the resampling quality.
+	pub fn quality(&self) -> ResampleQuality {
+		self.quality
+	}
+
+	/// Discards the buffered frames, as if the resampler was just created.
+	pub fn reset(&mut self) {
+		self.decimation_count = 0;
+		self.decimation_sum = [0; 2];
+		self.position = 0;
+		self.history = [[0.0; 2]; 2 * TAPS];
+		self.history_index = 0;
+	}
+
+	/// Feeds a single input frame, passing the output frames that became
+	/// available to `sink`.
+	pub fn push<F: FnMut(StereoFrame)>(&mut self, frame: StereoFrame, mut sink: F) {
+		self.decimation_sum[0] += frame[0] as i32;
+		self.decimation_sum[1] += frame[1] as i32;
+		self.decimation_count += 1;
+
+		if self.decimation_count < self.decimation {
+			return;
+		}
+
+		let count = self.decimation_count as f32;
+		let averaged = [self.decimation_sum[0] as f32 / count, self.decimation_sum[1] as f32 / count];
+		self.decimation_sum = [0; 2];
+		self.decimation_count = 0;
+
+		// Keep the history's newest frames contiguous.
+		self.history[self.history_index] = averaged;
+		self.history[self.history_index + TAPS] = averaged;
+		self.history_index = (self.history_index + 1) % TAPS;
+
+		const ONE: u64 = 1 << 32;
+
+		while self.position < ONE {
+			let fraction = (self.position as f32) / (ONE as f32);
+			let output = match self.quality {
+				ResampleQuality::Linear => self.interpolate_linear(fraction),
+				ResampleQuality::BandLimited => self.interpolate_filtered(fraction),
+			};
+			sink([to_sample(output[0]), to_sample(output[1])]);
+			self.position += self.step;
+		}
+
+		self.position -= ONE;
+	}
+
+	/// Feeds the given input frames, passing the output frames to `sink`.
+	pub fn resample<F: FnMut(StereoFrame)>(&mut self, frames: &[StereoFrame], mut sink: F) {
+		for &frame in frames {
+			self.push(frame, &mut sink);
+		}
+	}
+
+	/// Returns the newest `TAPS` frames, oldest first.
+	fn window(&self) -> &[[f32; 2]] {
+		&self.history[self.history_index..self.history_index + TAPS]
+	}
+
+	fn interpolate_linear(&self, fraction: f32) -> [f32; 2] {
+		let window = self.window();
+		let (previous, current) = (window[TAPS - 2], window[TAPS - 1]);
+
+		[
+			previous[0] + (current[0] - previous[0]) * fraction,
+			previous[1] + (current[1] - previous[1]) * fraction,
+		]
+	}
+
+	fn interpolate_filtered(&self, fraction: f32) -> [f32; 2] {
+		// Interpolate the coefficients between the closest computed positions.
+		let phase = fraction * PHASES as f32;
+		let index = (phase as usize).min(PHASES - 1);
+		let weight = phase - index as f32;
+		let (lower, upper) = (&self.kernel[index], &self.kernel[index + 1]);
+
+		let mut output = [0.0; 2];
+
+		for (tap, frame) in self.window().iter().enumerate() {
+			let coefficient = lower[tap] + (upper[tap] - lower[tap]) * weight;
+			output[0] += frame[0] * coefficient;
+			output[1] += frame[1] * coefficient;
+		}
+
+		output
+	}
+
+	/// Computes a blackman-windowed sinc filter with the given cutoff,
+	/// relative to the nyquist frequency.
+	fn compute_kernel(&mut self, cutoff: f32) {
+		let half = (TAPS / 2) as f32;
+
+		for (phase, coefficients) in self.kernel.iter_mut().enumerate() {
+			let fraction = phase as f32 / PHASES as f32;
+			let mut sum = 0.0;
+
+			for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+				// The output frame lies between the two middle taps.
+				let x = (half - 1.0 + fraction) - tap as f32;
+
+				let sinc = if x == 0.0 {
+					1.0
+				} else {
+					sin(PI * cutoff * x) / (PI * cutoff * x)
+				};
+				let window = 0.42 + 0.5 * cos(PI * x / half) + 0.08 * cos(2.0 * PI * x / half);
+
+				*coefficient = if x > -half && x < half { sinc * window } else { 0.0 };
+				sum += *coefficient;
+			}
+
+			// Normalize the filter's gain, so silence stays silent.
+			for coefficient in coefficients.iter_mut() {
+				*coefficient /= sum;
+			}
+		}
+	}
+}
+
+/// Rounds and saturates a filtered value into a sample.
+fn to_sample(value: f32) -> i16 {
+	let rounded = if value < 0.0 { value - 0.5 } else { value + 0.5 };
+	// Float to integer casts saturate.
+	rounded as i16
+}
+
+/// Approximates the sine function, since `core` doesn't provide it.
+fn sin(x: f32) -> f32 {
+	// Reduce the angle to [-pi, pi].
+	let turns = x / (2.0 * PI);
+	let turns = turns - (turns as i32) as f32;
+	let mut x = turns * 2.0 * PI;
+	if x > PI {
+		x -= 2.0 * PI;
+	} else if x < -PI {
+		x += 2.0 * PI;
+	}
+
+	// Use the symmetry around pi/2 to keep the series short.
+	if x > PI / 2.0 {
+		x = PI - x;
+	} else if x < -PI / 2.0 {
+		x = -PI - x;
+	}
+
+	let x2 = x * x;
+	x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0))))
+}
+
+fn cos(x: f32) -> f32 {
+	sin(x + PI / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::vec::Vec;
+
+	const INPUT_RATE: u32 = 1_048_576;
+	const OUTPUT_RATE: u32 = 48_000;
+
+	/// Resamples a second of a tone at the given frequency.
+	fn resample_tone(frequency: f64, quality: ResampleQuality) -> Vec<StereoFrame> {
+		let mut resampler = Resampler::new(INPUT_RATE, OUTPUT_RATE, quality);
+		let mut output = Vec::new();
+
+		for i in 0..INPUT_RATE {
+			let t = i as f64 / INPUT_RATE as f64;
+			let sample = (10000.0 * (2.0 * std::f64::consts::PI * frequency * t).sin()) as i16;
+			resampler.push([sample, sample], |frame| output.push(frame));
+		}
+
+		output
+	}
+
+	fn rms(frames: &[StereoFrame]) -> f64 {
+		let sum: f64 = frames.iter().map(|frame| (frame[0] as f64).powi(2)).sum();
+		(sum / frames.len() as f64).sqrt()
+	}
+
+	#[test]
+	fn test_sine_approximation() {
+		for i in -100..100 {
+			let x = i as f32 * 0.1;
+			assert!((sin(x) - x.sin()).abs() < 1e-4);
+			assert!((cos(x) - x.cos()).abs() < 1e-4);
+		}
+	}
+
+	#[test]
+	fn test_output_rate() {
+		for &quality in &[ResampleQuality::Linear, ResampleQuality::BandLimited] {
+			let output = resample_tone(0.0, quality);
+			assert!((output.len() as i64 - OUTPUT_RATE as i64).abs() <= 1);
+
+			// Silence stays silent.
+			assert!(output.iter().all(|&frame| frame == [0, 0]));
+		}
+
+		// A constant level passes through once the filter is filled.
+		let mut resampler = Resampler::new(INPUT_RATE, OUTPUT_RATE, ResampleQuality::BandLimited);
+		let mut output = Vec::new();
+		resampler.resample(&[[1000, -1000]; 4096], |frame| output.push(frame));
+		assert!(output.last() == Some(&[1000, -1000]));
+	}
+
+	#[test]
+	fn test_band_limited_aliasing() {
+		// An audible tone passes through both resamplers.
+		let linear = rms(&resample_tone(1000.0, ResampleQuality::Linear));
+		let filtered = rms(&resample_tone(1000.0, ResampleQuality::BandLimited));
+		assert!(linear > 6500.0 && filtered > 6500.0);
+
+		// A tone above the output's nyquist frequency is folded back by linear
+		// interpolation, but removed by the band-limited filter.
+		let linear = rms(&resample_tone(30000.0, ResampleQuality::Linear));
+		let filtered = rms(&resample_tone(30000.0, ResampleQuality::BandLimited));
+		assert!(linear > 6500.0);
+		assert!(filtered < linear / 100.0);
+	}
+}
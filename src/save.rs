@@ -0,0 +1,186 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Persistence of the cartridge's battery-backed ram.
+//!
+//! Writing the ram after every change would wear out the frontend's storage,
+//! so the save policy flushes it at most once per interval, and whenever the
+//! emulation is paused or dropped. Games disable the ram once they finish
+//! writing their save, so the emulator also flushes the ram right after the
+//! instruction that disabled it.
+
+use crate::GameboyError;
+use crate::bus::cartridge::Cartridge;
+use crate::bus::rtc::CYCLES_PER_SECOND;
+
+/// A destination for the cartridge's battery-backed ram.
+pub trait SaveSink {
+	/// Persists the given ram content.
+	fn save(&mut self, ram: &[u8]) -> Result<(), GameboyError>;
+}
+
+/// The minimal time between two saves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveInterval {
+	/// A number of emulated frames.
+	Frames(u32),
+	/// A number of emulated seconds.
+	Seconds(u32),
+}
+
+/// Decides when the battery-backed ram should be saved.
+pub struct SavePolicy {
+	interval: SaveInterval,
+	/// The number of frames since the last save.
+	frames: u32,
+	/// The number of clock cycles since the last save.
+	cycles: usize,
+}
+
+impl SavePolicy {
+	/// Initialize a policy that saves at most once per the given interval.
+	pub fn new(interval: SaveInterval) -> Self {
+		SavePolicy {
+			interval,
+			frames: 0,
+			cycles: 0,
+		}
+	}
+
+	/// Returns the minimal time between two saves.
+	pub fn interval(&self) -> SaveInterval {
+		self.interval
+	}
+
+	/// Notifies the policy that a frame has been emulated, saving the ram
+	/// if it changed and the interval has elapsed since the last save, or
+	/// the game disabled the ram after changing it.
+	///
+	/// Returns whether the ram was saved.
+	pub fn frame<S>(&mut self, cycles: usize, cartridge: &mut Cartridge, sink: &mut S) -> Result<bool, GameboyError>
+		where S: SaveSink + ?Sized {
+		self.frames = self.frames.saturating_add(1);
+		self.cycles = self.cycles.saturating_add(cycles);
+
+		let elapsed = match self.interval {
+			SaveInterval::Frames(frames) => self.frames >= frames,
+			SaveInterval::Seconds(seconds) => self.cycles >= seconds as usize * CYCLES_PER_SECOND as usize,
+		};
+
+		if !elapsed && !cartridge.ram_released() {
+			return Ok(false);
+		}
+
+		self.flush(cartridge, sink)
+	}
+
+	/// Saves the ram if the game disabled it after changing it, regardless of
+	/// the interval.
+	///
+	/// Returns whether the ram was saved.
+	pub fn release<S>(&mut self, cartridge: &mut Cartridge, sink: &mut S) -> Result<bool, GameboyError>
+		where S: SaveSink + ?Sized {
+		if !cartridge.ram_released() {
+			return Ok(false);
+		}
+
+		self.flush(cartridge, sink)
+	}
+
+	/// Saves the ram if it changed since the last save, regardless of the interval.
+	///
+	/// Returns whether the ram was saved.
+	pub fn flush<S>(&mut self, cartridge: &mut Cartridge, sink: &mut S) -> Result<bool, GameboyError>
+		where S: SaveSink + ?Sized {
+		if !cartridge.has_battery() || !cartridge.ram_dirty() {
+			return Ok(false);
+		}
+
+		sink.save(cartridge.ram())?;
+		cartridge.clear_ram_dirty();
+
+		self.frames = 0;
+		self.cycles = 0;
+
+		Ok(true)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::bus::Memory;
+	use crate::bus::cartridge::CartridgeType;
+	use crate::bus::cartridge::tests::empty_rom;
+
+	struct CountingSink {
+		saves: usize,
+	}
+
+	impl SaveSink for CountingSink {
+		fn save(&mut self, ram: &[u8]) -> Result<(), GameboyError> {
+			assert!(ram[0] == 0x42);
+			self.saves += 1;
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_save_policy() -> Result<(), GameboyError> {
+		let mut rom = empty_rom(CartridgeType::MBC3);
+		let mut ram = [0_u8; 0x2000];
+		let mut cartridge = Cartridge::new(&mut rom, &mut ram)?;
+		let mut sink = CountingSink { saves: 0 };
+		let mut policy = SavePolicy::new(SaveInterval::Frames(3));
+
+		cartridge.write(0x0000, 0x0A)?;
+		cartridge.write(0xA000, 0x42)?;
+
+		// The ram is saved at most every 3 frames.
+		assert!(!policy.frame(0, &mut cartridge, &mut sink)?);
+		assert!(!policy.frame(0, &mut cartridge, &mut sink)?);
+		assert!(policy.frame(0, &mut cartridge, &mut sink)?);
+		assert!(sink.saves == 1);
+
+		// An unchanged ram isn't saved again.
+		for _ in 0..5 {
+			assert!(!policy.frame(0, &mut cartridge, &mut sink)?);
+		}
+
+		// Flushing ignores the interval.
+		cartridge.write(0xA001, 0x01)?;
+		assert!(policy.flush(&mut cartridge, &mut sink)?);
+		assert!(sink.saves == 2);
+
+		// Disabling the ram after changing it saves it on the next frame.
+		cartridge.write(0xA001, 0x03)?;
+		cartridge.write(0x0000, 0x00)?;
+		assert!(cartridge.ram_released());
+		assert!(policy.frame(0, &mut cartridge, &mut sink)?);
+		assert!(sink.saves == 3 && !cartridge.ram_released());
+
+		// Unchanged ram isn't saved when it's disabled.
+		cartridge.write(0x0000, 0x0A)?;
+		cartridge.write(0x0000, 0x00)?;
+		assert!(!policy.frame(0, &mut cartridge, &mut sink)?);
+		cartridge.write(0x0000, 0x0A)?;
+
+		// The released ram may be saved before the frame ends.
+		cartridge.write(0xA001, 0x04)?;
+		assert!(!policy.release(&mut cartridge, &mut sink)?);
+		cartridge.write(0x0000, 0x00)?;
+		assert!(policy.release(&mut cartridge, &mut sink)?);
+		assert!(sink.saves == 4 && !cartridge.ram_released());
+		cartridge.write(0x0000, 0x0A)?;
+
+		// The interval may be measured in emulated time.
+		let mut policy = SavePolicy::new(SaveInterval::Seconds(1));
+		cartridge.write(0xA001, 0x02)?;
+		assert!(!policy.frame(CYCLES_PER_SECOND as usize / 2, &mut cartridge, &mut sink)?);
+		assert!(policy.frame(CYCLES_PER_SECOND as usize / 2, &mut cartridge, &mut sink)?);
+		assert!(sink.saves == 5);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,597 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Serialization of the machine's complete state.
+//!
+//! A savestate starts with a header, followed by the serialized state of the
+//! cpu and of the devices on its bus:
+//! * The magic bytes `GBST`.
+//! * The format's version, as a little-endian u16.
+//! * The emulated hardware model.
+//! * The cartridge's global checksum, as found in its header.
+//!
+//! The multi-byte values are serialized in little-endian byte order. The
+//! frontend's settings, such as the audio output and the displayed layers,
+//! aren't part of the state.
+//!
+//! Whenever the format changes, `VERSION` is bumped by adding a migration
+//! that upgrades the previous version's payload, so older savestates keep
+//! loading. Savestates of newer versions are rejected.
+
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+
+use core::convert::TryFrom;
+
+use crate::GameboyError;
+use crate::config::HardwareModel;
+use crate::cpu::Cpu;
+use crate::bus::sgb::consts::*;
+use crate::bus::ppu::consts::{WIDTH, HEIGHT, VRAM_SIZE, OAM_SIZE};
+use crate::bus::io::consts::IO_SIZE;
+use crate::cpu::state::registers::NUM_REGS;
+
+/// The magic bytes that savestates start with.
+pub const MAGIC: [u8; 4] = *b"GBST";
+
+/// The current version of the format.
+pub const VERSION: u16 = MIGRATIONS.len() as u16 + 1;
+
+/// The size of the savestate's header.
+pub const HEADER_SIZE: usize = 9;
+
+/// Upgrades a payload to the next version of the format.
+type Migration = fn(&[u8]) -> Result<Vec<u8>, GameboyError>;
+
+/// The migrations between the versions, where the first one upgrades the
+/// payload of version 1 to version 2.
+const MIGRATIONS: [Migration; 5] = [
+	add_sgb_border, add_sgb_commands, add_total_cycles, add_memory_model, add_timer_overflow,
+];
+
+/// The size of the Super GameBoy's border state, which version 2 appended.
+const SGB_BORDER_STATE_SIZE: usize =
+	BORDER_TILES_SIZE + 2 * (BORDER_COLUMNS * BORDER_ROWS + BORDER_PALETTES * BORDER_PALETTE_SIZE);
+
+/// The size of the ppu's state: the frame, the video ram, the OAM and its
+/// copy, the OAM scan, the registers, the mode, the counters and the interrupt.
+const PPU_STATE_SIZE: usize = 4 * WIDTH * HEIGHT + VRAM_SIZE + 2 * OAM_SIZE + 9 + 13 + 16 + 1;
+
+/// The size of the timer's state before version 6 added the pending overflow.
+const OLD_TIMER_STATE_SIZE: usize = 6;
+
+/// A part of the machine that is serialized into savestates.
+pub trait SaveState {
+	/// Serializes the state.
+	fn save_state(&self, writer: &mut StateWriter);
+
+	/// Deserializes a state that was serialized with `save_state`.
+	fn load_state(&mut self, reader: &mut StateReader) -> Result<(), GameboyError>;
+}
+
+/// Serializes values into a savestate.
+pub struct StateWriter {
+	data: Vec<u8>,
+}
+
+/// Deserializes values from a savestate.
+pub struct StateReader<'d> {
+	data: &'d [u8],
+}
+
+impl StateWriter {
+	/// Initialize an empty writer.
+	pub fn new() -> Self {
+		StateWriter { data: Vec::new() }
+	}
+
+	/// Returns the serialized data.
+	pub fn into_inner(self) -> Vec<u8> {
+		self.data
+	}
+
+	/// Writes a byte.
+	pub fn u8(&mut self, value: u8) {
+		self.data.push(value);
+	}
+
+	/// Writes a 16-bit value.
+	pub fn u16(&mut self, value: u16) {
+		self.bytes(&value.to_le_bytes());
+	}
+
+	/// Writes a 32-bit value.
+	pub fn u32(&mut self, value: u32) {
+		self.bytes(&value.to_le_bytes());
+	}
+
+	/// Writes a 64-bit value.
+	pub fn u64(&mut self, value: u64) {
+		self.bytes(&value.to_le_bytes());
+	}
+
+	/// Writes a size or a counter, as a 64-bit value.
+	pub fn usize(&mut self, value: usize) {
+		self.u64(value as u64);
+	}
+
+	/// Writes a boolean, as a single byte.
+	pub fn bool(&mut self, value: bool) {
+		self.u8(value as u8);
+	}
+
+	/// Writes the given bytes, without their length.
+	pub fn bytes(&mut self, data: &[u8]) {
+		self.data.extend_from_slice(data);
+	}
+}
+
+impl Default for StateWriter {
+	fn default() -> Self {
+		StateWriter::new()
+	}
+}
+
+impl<'d> StateReader<'d> {
+	/// Initialize a reader of the given data.
+	pub fn new(data: &'d [u8]) -> Self {
+		StateReader { data }
+	}
+
+	/// Returns whether all of the data was read.
+	pub fn is_empty(&self) -> bool {
+		self.data.is_empty()
+	}
+
+	/// Reads a byte.
+	pub fn u8(&mut self) -> Result<u8, GameboyError> {
+		Ok(self.array::<1>()?[0])
+	}
+
+	/// Reads a 16-bit value.
+	pub fn u16(&mut self) -> Result<u16, GameboyError> {
+		Ok(u16::from_le_bytes(self.array()?))
+	}
+
+	/// Reads a 32-bit value.
+	pub fn u32(&mut self) -> Result<u32, GameboyError> {
+		Ok(u32::from_le_bytes(self.array()?))
+	}
+
+	/// Reads a 64-bit value.
+	pub fn u64(&mut self) -> Result<u64, GameboyError> {
+		Ok(u64::from_le_bytes(self.array()?))
+	}
+
+	/// Reads a size or a counter.
+	pub fn usize(&mut self) -> Result<usize, GameboyError> {
+		usize::try_from(self.u64()?).map_err(|_| GameboyError::Io("The savestate holds an out of range value."))
+	}
+
+	/// Reads a boolean.
+	pub fn bool(&mut self) -> Result<bool, GameboyError> {
+		match self.u8()? {
+			0 => Ok(false),
+			1 => Ok(true),
+			_ => Err(GameboyError::Io("The savestate holds an invalid boolean.")),
+		}
+	}
+
+	/// Fills the given buffer.
+	pub fn bytes(&mut self, buffer: &mut [u8]) -> Result<(), GameboyError> {
+		if self.data.len() < buffer.len() {
+			return Err(GameboyError::Io("The savestate is truncated."));
+		}
+
+		let (data, rest) = self.data.split_at(buffer.len());
+		buffer.copy_from_slice(data);
+		self.data = rest;
+
+		Ok(())
+	}
+
+	fn array<const N: usize>(&mut self) -> Result<[u8; N], GameboyError> {
+		let mut array = [0_u8; N];
+		self.bytes(&mut array)?;
+
+		Ok(array)
+	}
+}
+
+/// Serializes a hardware model.
+pub(crate) fn model_to_u8(model: HardwareModel) -> u8 {
+	match model {
+		HardwareModel::GB => 0,
+		HardwareModel::GBC => 1,
+		HardwareModel::GBP => 2,
+		HardwareModel::SGB => 3,
+	}
+}
+
+/// Serializes the machine's state, including the header.
+pub fn save(cpu: &Cpu) -> Vec<u8> {
+	let mut writer = StateWriter::new();
+
+	writer.bytes(&MAGIC);
+	writer.u16(VERSION);
+	writer.u8(model_to_u8(cpu.config.model));
+	writer.u16(cpu.mmap.cartridge.global_checksum());
+	cpu.save_state(&mut writer);
+
+	writer.into_inner()
+}
+
+/// Restores the machine's state from a savestate of the same game and
+/// hardware model, migrating it from an older version if needed.
+///
+/// If the savestate is invalid, the machine's state is left unchanged.
+pub fn load(cpu: &mut Cpu, data: &[u8]) -> Result<(), GameboyError> {
+	if data.len() < HEADER_SIZE || data[0..4] != MAGIC {
+		return Err(GameboyError::Io("The data isn't a savestate."));
+	}
+
+	let mut header = StateReader::new(&data[4..HEADER_SIZE]);
+	let version = header.u16()?;
+	let model = header.u8()?;
+	let checksum = header.u16()?;
+
+	if model != model_to_u8(cpu.config.model) {
+		return Err(GameboyError::Io("The savestate belongs to a different hardware model."));
+	}
+	if checksum != cpu.mmap.cartridge.global_checksum() {
+		return Err(GameboyError::Io("The savestate belongs to a different game."));
+	}
+
+	let payload = migrate(version, &data[HEADER_SIZE..])?;
+
+	// A partially loaded state is rolled back.
+	let snapshot = cpu.snapshot();
+	let mut reader = StateReader::new(&payload);
+
+	let result = cpu.load_state(&mut reader).and_then(|_| {
+		if reader.is_empty() {
+			Ok(())
+		} else {
+			Err(GameboyError::Io("The savestate has unexpected trailing data."))
+		}
+	});
+
+	if result.is_err() {
+		cpu.restore(&snapshot)?;
+	}
+
+	result
+}
+
+/// Appends an empty Super GameBoy border to a version 1 payload.
+fn add_sgb_border(payload: &[u8]) -> Result<Vec<u8>, GameboyError> {
+	let mut upgraded = payload.to_vec();
+	upgraded.resize(payload.len() + SGB_BORDER_STATE_SIZE, 0);
+
+	Ok(upgraded)
+}
+
+/// Appends the state of the SGB's commands to a version 2 payload, as if
+/// no command was received.
+fn add_sgb_commands(payload: &[u8]) -> Result<Vec<u8>, GameboyError> {
+	let mut writer = StateWriter::new();
+	writer.bytes(payload);
+
+	// The received packets, and the state of the joypad's lines.
+	writer.bytes(&[0; PACKET_SIZE * MAX_PACKETS + 5]);
+	for color in DEFAULT_PALETTE.iter().cycle().take(4 * PALETTES) {
+		writer.u16(*color);
+	}
+	writer.bytes(&[0; 2 * 4 * SYSTEM_PALETTES + ATTR_COLUMNS * ATTR_ROWS + ATTR_FILES * ATTR_FILE_SIZE]);
+	// The mask, the single player and the pending transfer.
+	writer.bytes(&[0, 1, 0, 0]);
+
+	Ok(writer.into_inner())
+}
+
+/// Appends the cpu's cycle counter to a version 3 payload, restarting it.
+fn add_total_cycles(payload: &[u8]) -> Result<Vec<u8>, GameboyError> {
+	let mut writer = StateWriter::new();
+	writer.bytes(payload);
+	writer.u64(0);
+
+	Ok(writer.into_inner())
+}
+
+/// Returns the end of the cartridge's state in a payload, whose cartridge
+/// state ends with the given number of flags.
+fn cartridge_state_end(payload: &[u8], flags: usize) -> Result<usize, GameboyError> {
+	// The cartridge's state follows the cpu's registers and flags.
+	let start = 2 * NUM_REGS + 6;
+	let ram_size = StateReader::new(payload.get(start..).unwrap_or(&[])).usize()?;
+
+	// The ram's size and content, the rtc, the banks and the flags.
+	ram_size.checked_add(start + 8 + 16 + flags)
+		.filter(|end| *end <= payload.len())
+		.ok_or(GameboyError::Io("The savestate is too short."))
+}
+
+/// Inserts the MBC1's memory model after the cartridge's state in a version 4
+/// payload, selecting the default model.
+fn add_memory_model(payload: &[u8]) -> Result<Vec<u8>, GameboyError> {
+	let end = cartridge_state_end(payload, 5)?;
+
+	let mut writer = StateWriter::new();
+	writer.bytes(&payload[..end]);
+	writer.bool(false);
+	writer.bytes(&payload[end..]);
+
+	Ok(writer.into_inner())
+}
+
+/// Inserts the timer's overflow delay after the timer's state in a version 5
+/// payload, as if TIMA didn't overflow.
+fn add_timer_overflow(payload: &[u8]) -> Result<Vec<u8>, GameboyError> {
+	// The timer follows the cartridge, the boot rom's flag, the ppu and the io ports.
+	let end = cartridge_state_end(payload, 6)? + 1 + PPU_STATE_SIZE + IO_SIZE + OLD_TIMER_STATE_SIZE;
+	if end > payload.len() {
+		return Err(GameboyError::Io("The savestate is too short."));
+	}
+
+	let mut writer = StateWriter::new();
+	writer.bytes(&payload[..end]);
+	writer.u8(0);
+	writer.bytes(&payload[end..]);
+
+	Ok(writer.into_inner())
+}
+
+/// Upgrades the payload of the given version to the current version.
+fn migrate(version: u16, payload: &[u8]) -> Result<Cow<'_, [u8]>, GameboyError> {
+	if version == 0 {
+		return Err(GameboyError::Io("The savestate's version is invalid."));
+	}
+	if version > VERSION {
+		return Err(GameboyError::Io("The savestate was created by a newer version."));
+	}
+
+	let mut payload = Cow::Borrowed(payload);
+
+	for migration in &MIGRATIONS[version as usize - 1..] {
+		payload = Cow::Owned(migration(&payload)?);
+	}
+
+	Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::boxed::Box;
+	use crate::emulator::Emulator;
+	use crate::emulator::tests::palette_cycle;
+	use crate::bus::cartridge::{Cartridge, CartridgeType};
+	use crate::bus::cartridge::tests::empty_rom;
+	use crate::cpu::state::registers::Register;
+
+	#[test]
+	fn test_savestate() -> Result<(), GameboyError> {
+		let mut emulator = palette_cycle(HardwareModel::GB, 0)?;
+		emulator.run_frame()?;
+
+		let state = emulator.save_state();
+		assert!(state[0..4] == MAGIC && state[4..6] == VERSION.to_le_bytes());
+
+		let first = emulator.run_frame()?;
+		emulator.load_state(&state)?;
+		let second = emulator.run_frame()?;
+		assert!(first.pixels == second.pixels);
+
+		// Invalid savestates are rejected, and leave the state unchanged.
+		let current = emulator.save_state();
+		assert!(emulator.load_state(&state[..state.len() - 1]).is_err());
+		assert!(emulator.load_state(&[state.as_slice(), &[0]].concat()).is_err());
+		assert!(emulator.load_state(&state[1..]).is_err());
+
+		let mut newer = state.clone();
+		newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+		assert!(emulator.load_state(&newer).is_err());
+		assert!(emulator.save_state() == current);
+
+		Ok(())
+	}
+
+	/// Builds an emulator that latches the cartridge's clock and keeps
+	/// updating its ram, with a palette that depends on the clock and on LY.
+	fn clock_latch() -> Result<Emulator, GameboyError> {
+		let mut rom: Vec<u8> = empty_rom(CartridgeType::MBC3).to_vec();
+		rom[0x100..0x12c].copy_from_slice(&[
+			// Enable the ram, and select the first switchable rom bank.
+			0x3e, 0x0a, 0xea, 0x00, 0x00,
+			0x3e, 0x01, 0xea, 0x00, 0x20,
+			// Map the clock's seconds and latch them.
+			0x3e, 0x08, 0xea, 0x00, 0x40,
+			0x3e, 0x00, 0xea, 0x00, 0x60,
+			0x3c, 0xea, 0x00, 0x60,
+			// ld A, (seconds); ld B, A; ldh A, (LY); xor B; ldh (BGP), A
+			0xfa, 0x00, 0xa0, 0x47, 0xf0, 0x44, 0xa8, 0xe0, 0x47,
+			// Map the first ram bank, and increment its first byte.
+			0x3e, 0x00, 0xea, 0x00, 0x40,
+			0x21, 0x00, 0xa0, 0x34,
+			// jr -34
+			0x18, 0xde,
+		]);
+
+		Emulator::builder().rom(rom).build()
+	}
+
+	/// Hashes the frames and registers of the next emulated frames.
+	fn execution_hash(emulator: &mut Emulator, frames: usize) -> Result<u64, GameboyError> {
+		let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+		let mut update = |value: u32| {
+			for byte in value.to_le_bytes().iter() {
+				hash = (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+			}
+		};
+
+		for _ in 0..frames {
+			let frame = emulator.run_frame()?;
+			frame.pixels.iter().for_each(|pixel| update(*pixel));
+			update(emulator.cpu().registers().get(Register::PC) as u32);
+			update(emulator.cpu().registers().get(Register::AF) as u32);
+			update(emulator.cpu().registers().get(Register::HL) as u32);
+		}
+
+		Ok(hash)
+	}
+
+	#[test]
+	fn test_savestate_round_trip() -> Result<(), GameboyError> {
+		// Run long enough for the clock's seconds to tick after the savestate.
+		const FRAMES: usize = 70;
+
+		let saved = || -> Result<(Vec<u8>, u64), GameboyError> {
+			let mut emulator = clock_latch()?;
+			execution_hash(&mut emulator, 20)?;
+
+			// Save in the middle of a frame, while the ppu is busy.
+			for _ in 0..1000 {
+				emulator.execute()?;
+			}
+			let state = emulator.save_state();
+
+			Ok((state, execution_hash(&mut emulator, FRAMES)?))
+		};
+		let loaded = |state: &[u8]| -> Result<u64, GameboyError> {
+			let mut emulator = clock_latch()?;
+			emulator.load_state(state)?;
+
+			execution_hash(&mut emulator, FRAMES)
+		};
+
+		// A fresh machine that loads the savestate runs exactly the same.
+		let (state, expected) = saved()?;
+		assert!(loaded(&state)? == expected);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_savestate_mismatch() -> Result<(), GameboyError> {
+		let state = palette_cycle(HardwareModel::GB, 0)?.save_state();
+
+		let loads = |model: HardwareModel, checksum: u8| -> Result<bool, GameboyError> {
+			Ok(palette_cycle(model, checksum)?.load_state(&state).is_ok())
+		};
+		assert!(!loads(HardwareModel::GBC, 0)?);
+		assert!(!loads(HardwareModel::GB, 1)?);
+		assert!(loads(HardwareModel::GB, 0)?);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_savestate_banks() -> Result<(), GameboyError> {
+		let mut cartridge = Cartridge::from_rom(Box::new(empty_rom(CartridgeType::MBC3)))?;
+		let mut writer = StateWriter::new();
+		cartridge.save_state(&mut writer);
+		let state = writer.into_inner();
+
+		// The banks are followed by four flags.
+		let rom_bank = state.len() - 6;
+		let ram_bank = state.len() - 5;
+
+		let mut corrupt = state.clone();
+		corrupt[rom_bank] = 2;
+		assert!(cartridge.load_state(&mut StateReader::new(&corrupt)).is_err());
+
+		let mut corrupt = state.clone();
+		corrupt[ram_bank] = 1;
+		assert!(cartridge.load_state(&mut StateReader::new(&corrupt)).is_err());
+
+		// Banks that don't exist are rejected before anything is restored.
+		let mut writer = StateWriter::new();
+		cartridge.save_state(&mut writer);
+		assert!(writer.into_inner() == state);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_state_reader() -> Result<(), GameboyError> {
+		let mut writer = StateWriter::new();
+		writer.u8(0x12);
+		writer.u16(0x3456);
+		writer.bool(true);
+		writer.usize(0x789A);
+		let data = writer.into_inner();
+		assert!(data[1..3] == [0x56, 0x34]);
+
+		let mut reader = StateReader::new(&data);
+		assert!(reader.u8()? == 0x12);
+		assert!(reader.u16()? == 0x3456);
+		assert!(reader.bool()?);
+		assert!(reader.usize()? == 0x789A);
+		assert!(reader.is_empty());
+
+		// Reading past the end, or an invalid boolean, fails.
+		assert!(reader.u8().is_err());
+		assert!(StateReader::new(&[2]).bool().is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_migration() -> Result<(), GameboyError> {
+		assert!(migrate(VERSION, &[1, 2, 3])?.as_ref() == [1, 2, 3]);
+		assert!(migrate(0, &[]).is_err());
+		assert!(migrate(VERSION + 1, &[]).is_err());
+
+		// Savestates of the older versions lack the timer's overflow delay,
+		// the fourth version lacks the MBC1's memory model as well, the third
+		// version lacks the cycle counter, the second version lacks the SGB's
+		// commands, and the first version also lacks the SGB's border.
+		let mut emulator = palette_cycle(HardwareModel::GB, 0)?;
+		emulator.run_frame()?;
+		let state = emulator.save_state();
+
+		// The memory model follows the cartridge's 8KB ram, rtc, banks and flags,
+		// and the overflow delay ends the timer's state.
+		let model = HEADER_SIZE + 2 * NUM_REGS + 6 + 8 + 0x2000 + 16 + 5;
+		let delay = model + 2 + PPU_STATE_SIZE + IO_SIZE + OLD_TIMER_STATE_SIZE;
+		let div = emulator.cpu().mmap.timer().div_counter().to_le_bytes();
+		assert!(state[delay - OLD_TIMER_STATE_SIZE..][..2] == div);
+		let mut old = state.clone();
+		assert!(old.remove(delay) == 0);
+		old[4..6].copy_from_slice(&5_u16.to_le_bytes());
+		emulator.run_frame()?;
+		emulator.load_state(&old)?;
+		assert!(emulator.save_state() == state);
+		assert!(emulator.load_state(&old[..delay]).is_err());
+
+		assert!(old.remove(model) == 0);
+		old[4..6].copy_from_slice(&4_u16.to_le_bytes());
+		emulator.run_frame()?;
+		emulator.load_state(&old)?;
+		assert!(emulator.save_state() == state);
+		assert!(emulator.load_state(&old[..HEADER_SIZE + 40]).is_err());
+
+		// The counter restarts when it's missing.
+		let counter = old.len() - 8;
+		let mut restarted = state.clone();
+		let restarted_len = restarted.len();
+		restarted[restarted_len - 8..].copy_from_slice(&[0; 8]);
+
+		old.truncate(counter);
+		for version in [3_u16, 2, 1] {
+			match version {
+				2 => { old.truncate(old.len() - add_sgb_commands(&[])?.len()); }
+				1 => { old.truncate(old.len() - SGB_BORDER_STATE_SIZE); }
+				_ => {}
+			}
+			old[4..6].copy_from_slice(&version.to_le_bytes());
+
+			emulator.run_frame()?;
+			emulator.load_state(&old)?;
+			assert!(emulator.save_state() == restarted);
+		}
+
+		Ok(())
+	}
+}
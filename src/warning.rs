@@ -0,0 +1,149 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Non-fatal conditions that are reported during the emulation.
+//!
+//! Unlike errors, warnings don't stop the emulation. They are queued until
+//! the caller drains them, and the oldest warnings are dropped if the caller
+//! doesn't keep up.
+
+use core::fmt;
+use core::cell::Cell;
+
+/// The number of warnings that are kept until they are drained.
+pub const WARNINGS_CAPACITY: usize = 16;
+
+/// A non-fatal condition detected by the emulator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Warning {
+	/// The software read from the echo ram, at the given address.
+	EchoRamRead(u16),
+	/// The software wrote to the echo ram, at the given address.
+	EchoRamWrite(u16),
+	/// The stack pointer left the configured stack range, with the given value.
+	StackOutOfRange(u16),
+	/// The stack was pushed to or popped from the rom or I/O registers,
+	/// at the given address.
+	StackInvalidAccess(u16),
+	/// An illegal opcode was skipped at the given address, see `Config::lenient`.
+	IllegalOpcode(u16),
+	/// A read from the given address failed, and read 0xFF instead, see
+	/// `Config::lenient`.
+	BadRead(u16),
+	/// A write to the given address failed, and was ignored instead, see
+	/// `Config::lenient`.
+	BadWrite(u16),
+}
+
+/// A bounded queue of warnings.
+///
+/// Warnings may be raised while reading the memory, so the queue can be
+/// pushed to through a shared reference.
+#[derive(Clone)]
+pub struct Warnings {
+	queue: [Cell<Option<Warning>>; WARNINGS_CAPACITY],
+	head: Cell<usize>,
+	len: Cell<usize>,
+	dropped: Cell<usize>,
+}
+
+impl Warnings {
+	/// Initialize an empty warnings queue.
+	pub fn new() -> Self {
+		Warnings {
+			queue: Default::default(),
+			head: Cell::new(0),
+			len: Cell::new(0),
+			dropped: Cell::new(0),
+		}
+	}
+
+	/// Queues the given warning, dropping the oldest warning if the queue is full.
+	pub fn push(&self, warning: Warning) {
+		if self.len.get() == WARNINGS_CAPACITY {
+			self.pop();
+			self.dropped.set(self.dropped.get() + 1);
+		}
+
+		let tail = (self.head.get() + self.len.get()) % WARNINGS_CAPACITY;
+		self.queue[tail].set(Some(warning));
+		self.len.set(self.len.get() + 1);
+	}
+
+	/// Removes and returns the oldest queued warning.
+	pub fn pop(&self) -> Option<Warning> {
+		if self.len.get() == 0 {
+			return None;
+		}
+
+		let warning = self.queue[self.head.get()].take();
+		self.head.set((self.head.get() + 1) % WARNINGS_CAPACITY);
+		self.len.set(self.len.get() - 1);
+
+		warning
+	}
+
+	/// Returns the number of queued warnings.
+	pub fn len(&self) -> usize {
+		self.len.get()
+	}
+
+	/// Returns whether there are no queued warnings.
+	pub fn is_empty(&self) -> bool {
+		self.len.get() == 0
+	}
+
+	/// Returns the number of warnings that were dropped since the queue was full.
+	pub fn dropped(&self) -> usize {
+		self.dropped.get()
+	}
+
+	/// Returns an iterator that removes the queued warnings, oldest first.
+	pub fn drain(&self) -> impl Iterator<Item = Warning> + '_ {
+		core::iter::from_fn(move || self.pop())
+	}
+}
+
+impl Default for Warnings {
+	fn default() -> Self {
+		Warnings::new()
+	}
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Warning::EchoRamRead(address) => write!(f, "Echo ram read at 0x{:04x}", address),
+			Warning::EchoRamWrite(address) => write!(f, "Echo ram write at 0x{:04x}", address),
+			Warning::StackOutOfRange(sp) => write!(f, "Stack pointer out of range: 0x{:04x}", sp),
+			Warning::StackInvalidAccess(address) => write!(f, "Stack access at 0x{:04x}", address),
+			Warning::IllegalOpcode(address) => write!(f, "Illegal opcode skipped at 0x{:04x}", address),
+			Warning::BadRead(address) => write!(f, "Bad read at 0x{:04x}", address),
+			Warning::BadWrite(address) => write!(f, "Bad write at 0x{:04x}", address),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_warnings_queue() {
+		let warnings = Warnings::new();
+		assert!(warnings.pop().is_none());
+
+		for i in 0..(WARNINGS_CAPACITY + 2) {
+			warnings.push(Warning::EchoRamRead(i as u16));
+		}
+
+		// The oldest warnings are dropped.
+		assert!(warnings.len() == WARNINGS_CAPACITY);
+		assert!(warnings.dropped() == 2);
+		assert!(warnings.pop() == Some(Warning::EchoRamRead(2)));
+
+		assert!(warnings.drain().count() == WARNINGS_CAPACITY - 1);
+		assert!(warnings.is_empty());
+	}
+}
@@ -0,0 +1,152 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![deny(missing_docs)]
+//! Record the audio output into a WAV file, for debugging the sound
+//! controller.
+//!
+//! The recorder is fed with the samples drained from the emulator, so it
+//! records whatever the frontend plays. A single channel is recorded by
+//! soloing it with `Emulator::solo_channel` first.
+//!
+//! # Examples
+//! ```no_run
+//! # use gameboy_core::GameboyError;
+//! # use gameboy_core::emulator::Emulator;
+//! # use gameboy_core::wav::WavRecorder;
+//! # use core::time::Duration;
+//! # fn main() -> Result<(), GameboyError> {
+//! # let mut emulator = Emulator::builder().rom(vec![0_u8; 0x8000]).build()?;
+//! let mut recorder = WavRecorder::new(48000, Duration::from_secs(10));
+//! let mut samples = [0_i16; 4096];
+//!
+//! while !recorder.is_complete() {
+//!     emulator.run_frame()?;
+//!     let count = emulator.drain_samples(&mut samples);
+//!     recorder.push(&samples[..count]);
+//! }
+//!
+//! let wav = recorder.to_bytes();
+//! # Ok(())
+//! # }
+//! ```
+
+use alloc::vec::Vec;
+
+use core::time::Duration;
+
+/// The size of the WAV header, before the samples.
+pub const WAV_HEADER_SIZE: usize = 44;
+
+/// The number of interleaved channels in the recording.
+const CHANNELS: u16 = 2;
+/// The size of a single sample, in bytes.
+const SAMPLE_SIZE: u16 = 2;
+
+/// Collects interleaved stereo samples, up to a configured duration.
+pub struct WavRecorder {
+	sample_rate: u32,
+	/// The number of samples the recording ends at.
+	max_samples: usize,
+	samples: Vec<i16>,
+}
+
+impl WavRecorder {
+	/// Initialize a recorder of the given duration, where the sample rate is
+	/// the one the emulator was configured with.
+	pub fn new(sample_rate: u32, duration: Duration) -> Self {
+		let frames = duration.as_millis() * sample_rate as u128 / 1000;
+
+		WavRecorder {
+			sample_rate,
+			max_samples: frames as usize * CHANNELS as usize,
+			samples: Vec::new(),
+		}
+	}
+
+	/// Appends interleaved left and right samples, until the recording is
+	/// complete.
+	///
+	/// Returns the number of samples that were recorded.
+	pub fn push(&mut self, samples: &[i16]) -> usize {
+		let count = samples.len().min(self.max_samples - self.samples.len());
+		self.samples.extend_from_slice(&samples[..count]);
+
+		count
+	}
+
+	/// Returns whether the recording reached its duration.
+	pub fn is_complete(&self) -> bool {
+		self.samples.len() == self.max_samples
+	}
+
+	/// Returns the recorded samples.
+	pub fn samples(&self) -> &[i16] {
+		&self.samples
+	}
+
+	/// Discards the recorded samples, and starts over.
+	pub fn clear(&mut self) {
+		self.samples.clear();
+	}
+
+	/// Encodes the recorded samples as a 16-bit PCM WAV file.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let data_size = (self.samples.len() * SAMPLE_SIZE as usize) as u32;
+		let block_align = CHANNELS * SAMPLE_SIZE;
+		let mut wav = Vec::with_capacity(WAV_HEADER_SIZE + data_size as usize);
+
+		wav.extend_from_slice(b"RIFF");
+		wav.extend_from_slice(&(data_size + WAV_HEADER_SIZE as u32 - 8).to_le_bytes());
+		wav.extend_from_slice(b"WAVE");
+
+		// The format chunk, of uncompressed PCM.
+		wav.extend_from_slice(b"fmt ");
+		wav.extend_from_slice(&16_u32.to_le_bytes());
+		wav.extend_from_slice(&1_u16.to_le_bytes());
+		wav.extend_from_slice(&CHANNELS.to_le_bytes());
+		wav.extend_from_slice(&self.sample_rate.to_le_bytes());
+		wav.extend_from_slice(&(self.sample_rate * block_align as u32).to_le_bytes());
+		wav.extend_from_slice(&block_align.to_le_bytes());
+		wav.extend_from_slice(&(8 * SAMPLE_SIZE).to_le_bytes());
+
+		wav.extend_from_slice(b"data");
+		wav.extend_from_slice(&data_size.to_le_bytes());
+		for sample in self.samples.iter() {
+			wav.extend_from_slice(&sample.to_le_bytes());
+		}
+
+		wav
+	}
+
+	/// Writes the WAV file to the given writer, see `to_bytes`.
+	#[cfg(feature = "std")]
+	pub fn write_to<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+		writer.write_all(&self.to_bytes())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_recorder() {
+		let mut recorder = WavRecorder::new(1000, Duration::from_millis(3));
+
+		// The samples beyond the duration are dropped.
+		assert!(recorder.push(&[1, -1, 2]) == 3 && !recorder.is_complete());
+		assert!(recorder.push(&[-2, 3, -3, 4]) == 3 && recorder.is_complete());
+		assert!(recorder.samples() == [1, -1, 2, -2, 3, -3]);
+
+		let wav = recorder.to_bytes();
+		assert!(wav.len() == WAV_HEADER_SIZE + 12);
+		assert!(wav[0..4] == *b"RIFF" && wav[4..8] == 48_u32.to_le_bytes());
+		assert!(wav[22..24] == 2_u16.to_le_bytes() && wav[24..28] == 1000_u32.to_le_bytes());
+		assert!(wav[40..44] == 12_u32.to_le_bytes());
+		assert!(wav[44..48] == [1, 0, 0xFF, 0xFF]);
+
+		recorder.clear();
+		assert!(recorder.samples().is_empty());
+	}
+}
@@ -0,0 +1,117 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs blargg's test roms, which report their results through the serial port.
+//!
+//! The roms aren't distributed with the crate. To run them, set `BLARGG_ROMS`
+//! to a checkout of the gb-test-roms repository, otherwise the tests are skipped:
+//! ```bash
+//! BLARGG_ROMS=path/to/gb-test-roms cargo test --test blargg
+//! ```
+
+#![cfg(feature = "alloc")]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use gameboy_core::GameboyError;
+use gameboy_core::bus::rtc::CYCLES_PER_SECOND;
+use gameboy_core::emulator::Emulator;
+
+/// The emulated time a rom is given to report its result.
+const TIMEOUT_SECONDS: usize = 120;
+
+/// Reads a rom, given its path within the test roms' directory.
+///
+/// Returns `None` if the test roms weren't supplied.
+fn load_rom(name: &str) -> Option<Vec<u8>> {
+	let path: PathBuf = [env::var_os("BLARGG_ROMS")?, name.into()].iter().collect();
+
+	match fs::read(&path) {
+		Ok(rom) => Some(rom),
+		Err(error) => panic!("Failed to read {}: {}", path.display(), error),
+	}
+}
+
+/// Runs the rom until it reports whether it passed, and returns its output.
+///
+/// The harness links itself to the serial port to collect the output, and
+/// answers each transfer with 0xFF, as if nothing was linked.
+fn run_rom(rom: Vec<u8>) -> Result<String, GameboyError> {
+	let mut emulator = Emulator::builder().rom(rom).build()?;
+	emulator.cpu_mut().mmap.serial_mut().set_linked(true);
+	let mut output = String::new();
+	let mut cycles = 0;
+
+	while cycles < TIMEOUT_SECONDS * CYCLES_PER_SECOND as usize {
+		cycles += emulator.execute()?;
+
+		let serial = emulator.cpu_mut().mmap.serial_mut();
+		if let Some(byte) = serial.take_outgoing() {
+			serial.complete_transfer(0xFF);
+			output.push(byte as char);
+
+			// The result's line is printed last.
+			let done = output.contains("Passed") || output.contains("Failed");
+			if done && output.ends_with('\n') {
+				break;
+			}
+		}
+	}
+
+	Ok(output)
+}
+
+/// Runs the given test rom, if supplied, and checks that it passed.
+fn check_rom(name: &str) -> Result<(), GameboyError> {
+	let rom = match load_rom(name) {
+		Some(rom) => rom,
+		None => {
+			println!("BLARGG_ROMS isn't set, skipping {}", name);
+			return Ok(());
+		}
+	};
+
+	let output = run_rom(rom)?;
+	assert!(output.contains("Passed") && !output.contains("Failed"), "{} failed:\n{}", name, output);
+
+	Ok(())
+}
+
+#[test]
+fn test_cpu_instrs() -> Result<(), GameboyError> {
+	check_rom("cpu_instrs/cpu_instrs.gb")
+}
+
+#[test]
+fn test_instr_timing() -> Result<(), GameboyError> {
+	check_rom("instr_timing/instr_timing.gb")
+}
+
+#[test]
+fn test_mbc1_harness() -> Result<(), GameboyError> {
+	// A 64KB MBC1 rom, like blargg's, that reports its result from the third bank.
+	let mut rom = vec![0_u8; 0x10000];
+	rom[0x147] = 0x01;
+	rom[0x148] = 0x01;
+	// ld A, 2; ld (0x2000), A; jp 0x4000
+	rom[0x100..0x108].copy_from_slice(&[0x3e, 0x02, 0xea, 0x00, 0x20, 0xc3, 0x00, 0x40]);
+	rom[0x8000..0x8017].copy_from_slice(&[
+		// ld HL, 0x4020
+		0x21, 0x20, 0x40,
+		// ld A, (HL+); or A; jr z, +14
+		0x2a, 0xb7, 0x28, 0x0e,
+		// ldh (SB), A; ld A, 0x81; ldh (SC), A
+		0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02,
+		// ldh A, (SC); bit 7, A; jr nz, -6
+		0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xfa,
+		// jr -18; jr -2
+		0x18, 0xee, 0x18, 0xfe,
+	]);
+	rom[0x8020..0x8027].copy_from_slice(b"Passed\n");
+
+	assert!(run_rom(rom)? == "Passed\n");
+
+	Ok(())
+}
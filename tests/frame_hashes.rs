@@ -0,0 +1,124 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compares the digests of the emulated frames against recorded digests, so
+//! rendering regressions are caught.
+//!
+//! Each rom in the directory set by `FRAME_HASH_ROMS` is run for a fixed
+//! number of frames, and the digest of every frame is compared against the
+//! rom's `.hashes` file, which holds a digest per line. Roms without a
+//! `.hashes` file are skipped. If the directory isn't set, the test is skipped:
+//! ```bash
+//! FRAME_HASH_ROMS=path/to/roms cargo test --test frame_hashes -- --nocapture
+//! ```
+//!
+//! To record the digests of the current build instead, e.g. after a change
+//! that is known to alter the rendering, set `FRAME_HASH_RECORD`:
+//! ```bash
+//! FRAME_HASH_ROMS=path/to/roms FRAME_HASH_RECORD=1 cargo test --test frame_hashes
+//! ```
+
+#![cfg(feature = "alloc")]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gameboy_core::GameboyError;
+use gameboy_core::emulator::Emulator;
+
+/// The number of frames each rom is run for, about 10 seconds.
+const FRAMES: usize = 600;
+
+/// The extension of the files that hold the recorded digests.
+const HASHES_EXTENSION: &str = "hashes";
+
+/// Runs the rom, and returns the digests of its frames.
+fn frame_hashes(rom: Vec<u8>) -> Result<Vec<u64>, GameboyError> {
+	let mut emulator = Emulator::builder().rom(rom).build()?;
+
+	(0..FRAMES).map(|_| Ok(emulator.run_frame()?.digest())).collect()
+}
+
+/// Parses a `.hashes` file.
+fn parse_hashes(path: &Path, contents: &str) -> Vec<u64> {
+	contents.lines()
+		.map(|line| u64::from_str_radix(line.trim(), 16)
+			.unwrap_or_else(|_| panic!("{} holds an invalid digest: {}", path.display(), line)))
+		.collect()
+}
+
+/// Formats the digests as a `.hashes` file.
+fn format_hashes(hashes: &[u64]) -> String {
+	hashes.iter().map(|hash| format!("{:016x}\n", hash)).collect()
+}
+
+/// Returns the roms within the directory, sorted by name.
+fn find_roms(directory: &Path) -> Vec<PathBuf> {
+	let entries = fs::read_dir(directory)
+		.unwrap_or_else(|error| panic!("Failed to list {}: {}", directory.display(), error));
+
+	let mut roms: Vec<PathBuf> = entries
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|path| path.extension().is_some_and(|extension| extension == "gb" || extension == "gbc"))
+		.collect();
+	roms.sort();
+
+	roms
+}
+
+#[test]
+fn test_frame_hashes() -> Result<(), GameboyError> {
+	let directory = match env::var_os("FRAME_HASH_ROMS") {
+		Some(directory) => PathBuf::from(directory),
+		None => {
+			println!("FRAME_HASH_ROMS isn't set, skipping the frame hashes");
+			return Ok(());
+		}
+	};
+	let record = env::var_os("FRAME_HASH_RECORD").is_some();
+
+	let mut mismatches = Vec::new();
+
+	for rom_path in find_roms(&directory) {
+		let hashes_path = rom_path.with_extension(HASHES_EXTENSION);
+		let name = rom_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+		if !record && !hashes_path.exists() {
+			println!("{}: no recorded digests, skipping", name);
+			continue;
+		}
+
+		let rom = fs::read(&rom_path)
+			.unwrap_or_else(|error| panic!("Failed to read {}: {}", rom_path.display(), error));
+		let hashes = frame_hashes(rom)?;
+
+		if record {
+			fs::write(&hashes_path, format_hashes(&hashes))
+				.unwrap_or_else(|error| panic!("Failed to write {}: {}", hashes_path.display(), error));
+			println!("{}: recorded {} digests", name, hashes.len());
+			continue;
+		}
+
+		let contents = fs::read_to_string(&hashes_path)
+			.unwrap_or_else(|error| panic!("Failed to read {}: {}", hashes_path.display(), error));
+		let expected = parse_hashes(&hashes_path, &contents);
+
+		// Report the first frame that differs, as the rest usually follow.
+		match hashes.iter().zip(&expected).position(|(hash, expected)| hash != expected) {
+			Some(frame) => {
+				println!("{}: frame {} differs", name, frame);
+				mismatches.push(name);
+			}
+			None if expected.len() != hashes.len() => {
+				println!("{}: recorded {} digests, expected {}", name, expected.len(), hashes.len());
+				mismatches.push(name);
+			}
+			None => println!("{}: matches", name),
+		}
+	}
+
+	assert!(mismatches.is_empty(), "The frames of {} changed", mismatches.join(", "));
+
+	Ok(())
+}
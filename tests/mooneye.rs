@@ -0,0 +1,136 @@
+// Copyright 2021 Nir H. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs mooneye-gb's acceptance test roms, and reports the result of each rom.
+//!
+//! A mooneye test ends by executing `ld B, B`, which is used as a breakpoint.
+//! The test passed if the registers hold the fibonacci numbers 3, 5, 8, 13, 21
+//! and 34 at that point.
+//!
+//! The roms aren't distributed with the crate. To run them, set `MOONEYE_ROMS`
+//! to the test suite's build directory, otherwise the test is skipped:
+//! ```bash
+//! MOONEYE_ROMS=path/to/mts/build cargo test --test mooneye -- --nocapture
+//! ```
+//!
+//! The roms are run on the default hardware model, so roms that target other
+//! models are expected to fail.
+
+#![cfg(feature = "alloc")]
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gameboy_core::GameboyError;
+use gameboy_core::bus::rtc::CYCLES_PER_SECOND;
+use gameboy_core::cpu::state::registers::Register;
+use gameboy_core::emulator::Emulator;
+
+/// The suites that are run, relative to the build directory.
+const SUITES: [&str; 3] = ["acceptance/timer", "acceptance/oam_dma", "acceptance/ppu"];
+
+/// The emulated time a rom is given to reach its breakpoint.
+const TIMEOUT_SECONDS: usize = 20;
+
+/// The opcode of `ld B, B`, which marks the end of the test.
+const BREAKPOINT: u8 = 0x40;
+
+/// The registers' values that indicate a successful test.
+const FIBONACCI: [(Register, u16); 6] = [
+	(Register::B, 3), (Register::C, 5), (Register::D, 8),
+	(Register::E, 13), (Register::H, 21), (Register::L, 34),
+];
+
+/// The outcome of a single test rom.
+enum Outcome {
+	Passed,
+	Failed,
+	/// The breakpoint wasn't reached in time.
+	Timeout,
+	/// The emulation failed.
+	Error(GameboyError),
+}
+
+impl fmt::Display for Outcome {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Outcome::Passed => write!(f, "passed"),
+			Outcome::Failed => write!(f, "failed"),
+			Outcome::Timeout => write!(f, "timed out"),
+			Outcome::Error(error) => write!(f, "error ({})", error),
+		}
+	}
+}
+
+/// Runs the rom until it reaches the breakpoint.
+fn run_rom(rom: Vec<u8>) -> Result<Outcome, GameboyError> {
+	let mut emulator = Emulator::builder().rom(rom).build()?;
+	let mut cycles = 0;
+
+	while cycles < TIMEOUT_SECONDS * CYCLES_PER_SECOND as usize {
+		let cpu = emulator.cpu();
+		let pc = cpu.registers().get(Register::PC);
+
+		if cpu.mmap.peek(pc)? == BREAKPOINT {
+			let passed = FIBONACCI.iter().all(|&(reg, value)| cpu.registers().get(reg) == value);
+
+			return Ok(if passed { Outcome::Passed } else { Outcome::Failed });
+		}
+
+		cycles += emulator.execute()?;
+	}
+
+	Ok(Outcome::Timeout)
+}
+
+/// Lists the roms of the given suite, sorted by name.
+fn suite_roms(suite: &Path) -> Vec<PathBuf> {
+	let entries = match fs::read_dir(suite) {
+		Ok(entries) => entries,
+		Err(error) => panic!("Failed to list {}: {}", suite.display(), error),
+	};
+
+	let mut roms: Vec<PathBuf> = entries
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|path| path.extension().is_some_and(|extension| extension == "gb"))
+		.collect();
+	roms.sort();
+
+	roms
+}
+
+#[test]
+fn test_acceptance() {
+	let build = match env::var_os("MOONEYE_ROMS") {
+		Some(build) => PathBuf::from(build),
+		None => {
+			println!("MOONEYE_ROMS isn't set, skipping the acceptance tests");
+			return;
+		}
+	};
+
+	let mut failed = 0;
+	let mut total = 0;
+
+	for suite in SUITES.iter() {
+		for path in suite_roms(&build.join(suite)) {
+			let rom = match fs::read(&path) {
+				Ok(rom) => rom,
+				Err(error) => panic!("Failed to read {}: {}", path.display(), error),
+			};
+
+			let outcome = run_rom(rom).unwrap_or_else(Outcome::Error);
+			let name = path.strip_prefix(&build).unwrap_or(&path);
+			println!("{}: {}", name.display(), outcome);
+
+			total += 1;
+			if !matches!(outcome, Outcome::Passed) {
+				failed += 1;
+			}
+		}
+	}
+
+	assert!(failed == 0, "{} of {} acceptance tests failed", failed, total);
+}